@@ -0,0 +1,263 @@
+use aquatic_common::announce_events::AnnounceEventSink;
+use aquatic_common::cli::LogLevel;
+use aquatic_common::privileges::{PrivilegeConfig, PrivilegeDropper};
+use aquatic_common::shared_swarm::create_shared_swarm_channels;
+use aquatic_toml_config::TomlConfig;
+use serde::Deserialize;
+use signal_hook::consts::{SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+
+pub const APP_NAME: &str = "aquatic: BitTorrent tracker (UDP, HTTP and WebTorrent combined)";
+
+/// Configuration for running several trackers in one process
+///
+/// Logging, privilege dropping and shutdown grace period are configured
+/// here. The corresponding settings in the tracker sections are ignored.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LogLevel,
+    /// After receiving SIGTERM, let workers finish handling pending
+    /// requests for at most this many seconds before exiting
+    pub shutdown_grace_period: u64,
+    /// Run UDP tracker
+    pub enable_udp: bool,
+    /// Run HTTP tracker
+    pub enable_http: bool,
+    /// Run WebTorrent tracker
+    ///
+    /// Note that the HTTP and WebTorrent trackers listen on the same TCP
+    /// port by default, so the address of one of them needs to be changed
+    /// if both are enabled.
+    pub enable_ws: bool,
+    /// Let UDP swarm workers handle HTTP requests too, so that peers
+    /// announcing over UDP and HTTP see each other
+    ///
+    /// Requires enable_udp and enable_http. HTTP swarm workers are not
+    /// started, so HTTP snapshots, full scrapes and swarm_workers are not
+    /// used. Peers announcing over HTTP are cleaned according to the UDP
+    /// cleaning and access list settings.
+    pub shared_swarm: bool,
+    /// Privileges are dropped once all enabled trackers have created their
+    /// sockets
+    pub privileges: PrivilegeConfig,
+    pub udp: aquatic_udp::config::Config,
+    pub http: aquatic_http::config::Config,
+    pub ws: aquatic_ws::config::Config,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: LogLevel::default(),
+            shutdown_grace_period: 10,
+            enable_udp: true,
+            enable_http: false,
+            enable_ws: false,
+            shared_swarm: false,
+            privileges: PrivilegeConfig::default(),
+            udp: Default::default(),
+            http: Default::default(),
+            ws: Default::default(),
+        }
+    }
+}
+
+impl aquatic_common::cli::Config for Config {
+    fn get_log_level(&self) -> Option<LogLevel> {
+        Some(self.log_level)
+    }
+}
+
+enum Tracker {
+    Udp(aquatic_udp::Tracker),
+    Http(aquatic_http::Tracker),
+    Ws(aquatic_ws::Tracker),
+}
+
+impl Tracker {
+    fn reload(&self) {
+        match self {
+            Self::Udp(tracker) => tracker.reload(),
+            Self::Http(tracker) => tracker.reload(),
+            Self::Ws(tracker) => tracker.reload(),
+        }
+    }
+
+    fn trigger_shutdown(&self) -> ::std::time::Instant {
+        match self {
+            Self::Udp(tracker) => tracker.trigger_shutdown(),
+            Self::Http(tracker) => tracker.trigger_shutdown(),
+            Self::Ws(tracker) => tracker.trigger_shutdown(),
+        }
+    }
+
+    fn wait(self, deadline: ::std::time::Instant) -> anyhow::Result<()> {
+        match self {
+            Self::Udp(tracker) => tracker.wait(deadline),
+            Self::Http(tracker) => tracker.wait(deadline),
+            Self::Ws(tracker) => tracker.wait(deadline),
+        }
+    }
+}
+
+pub fn run(mut config: Config) -> anyhow::Result<()> {
+    if !(config.enable_udp || config.enable_http || config.enable_ws) {
+        return Err(anyhow::anyhow!(
+            "at least one of enable_udp, enable_http and enable_ws must be set"
+        ));
+    }
+
+    if config.shared_swarm && !(config.enable_udp && config.enable_http) {
+        return Err(anyhow::anyhow!(
+            "shared_swarm requires enable_udp and enable_http to be set"
+        ));
+    }
+
+    // Binding a control socket replaces any existing file at the same path
+    let control_paths = [
+        (config.enable_udp, &config.udp.control),
+        (config.enable_http, &config.http.control),
+        (config.enable_ws, &config.ws.control),
+    ]
+    .into_iter()
+    .filter(|(enabled, control)| *enabled && control.active)
+    .map(|(_, control)| &control.path)
+    .collect::<Vec<_>>();
+
+    for (i, path) in control_paths.iter().enumerate() {
+        if control_paths[..i].contains(path) {
+            return Err(anyhow::anyhow!(
+                "trackers must use different control socket paths, but several use {}",
+                path.display()
+            ));
+        }
+    }
+
+    // Several writers appending to and rotating the same file would corrupt
+    // it. Sending datagrams to the same socket is fine.
+    let announce_event_file_paths = [
+        (config.enable_udp, &config.udp.announce_events),
+        (config.enable_http, &config.http.announce_events),
+        (config.enable_ws, &config.ws.announce_events),
+    ]
+    .into_iter()
+    .filter(|(enabled, announce_events)| {
+        *enabled && announce_events.active && announce_events.sink == AnnounceEventSink::File
+    })
+    .map(|(_, announce_events)| &announce_events.path)
+    .collect::<Vec<_>>();
+
+    for (i, path) in announce_event_file_paths.iter().enumerate() {
+        if announce_event_file_paths[..i].contains(path) {
+            return Err(anyhow::anyhow!(
+                "trackers must use different announce event file paths, but several use {}",
+                path.display()
+            ));
+        }
+    }
+
+    let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
+
+    let mut num_socket_workers = 0;
+
+    if config.enable_udp {
+        num_socket_workers += config.udp.socket_workers;
+    }
+    if config.enable_http {
+        num_socket_workers += config.http.socket_workers;
+    }
+    if config.enable_ws {
+        num_socket_workers += config.ws.socket_workers;
+    }
+
+    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), num_socket_workers);
+
+    let (opt_shared_swarm, opt_shared_request_receivers) = if config.shared_swarm {
+        let (sender, receivers) =
+            create_shared_swarm_channels(config.udp.swarm_workers, config.udp.worker_channel_size);
+
+        (Some(sender), Some(receivers))
+    } else {
+        (None, None)
+    };
+
+    let mut trackers = Vec::new();
+
+    if config.enable_udp {
+        let mut udp_config = config.udp;
+
+        udp_config.log_level = config.log_level;
+        udp_config.shutdown_grace_period = config.shutdown_grace_period;
+        udp_config.privileges = config.privileges.clone();
+
+        trackers.push(Tracker::Udp(aquatic_udp::Tracker::start(
+            udp_config,
+            priv_dropper.clone(),
+            opt_shared_request_receivers,
+        )?));
+    }
+    if config.enable_http {
+        let mut http_config = config.http;
+
+        http_config.log_level = config.log_level;
+        http_config.shutdown_grace_period = config.shutdown_grace_period;
+        http_config.privileges = config.privileges.clone();
+
+        trackers.push(Tracker::Http(aquatic_http::Tracker::start(
+            http_config,
+            priv_dropper.clone(),
+            opt_shared_swarm,
+        )?));
+    }
+    if config.enable_ws {
+        let mut ws_config = config.ws;
+
+        ws_config.log_level = config.log_level;
+        ws_config.shutdown_grace_period = config.shutdown_grace_period;
+        ws_config.privileges = config.privileges.clone();
+
+        trackers.push(Tracker::Ws(aquatic_ws::Tracker::start(
+            ws_config,
+            priv_dropper,
+        )?));
+    }
+
+    for signal in &mut signals {
+        match signal {
+            SIGUSR1 => {
+                for tracker in trackers.iter() {
+                    tracker.reload();
+                }
+            }
+            SIGTERM => break,
+            _ => unreachable!(),
+        }
+    }
+
+    ::log::info!("shutting down");
+
+    let deadlines = trackers
+        .iter()
+        .map(|tracker| tracker.trigger_shutdown())
+        .collect::<Vec<_>>();
+
+    let mut result = Ok(());
+
+    for (tracker, deadline) in trackers.into_iter().zip(deadlines) {
+        if let Err(err) = tracker.wait(deadline) {
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    ::aquatic_toml_config::gen_serialize_deserialize_test!(Config);
+}
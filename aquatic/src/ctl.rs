@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+
+use aquatic_common::cli::LogLevel;
+use aquatic_common::control::{
+    parse_hex_20, send_control_request, ControlConfig, ControlRequest, ControlResponse,
+};
+
+/// Send command to control socket of running tracker. Returns exit code.
+pub fn run<I>(arg_iter: I) -> i32
+where
+    I: Iterator<Item = String>,
+{
+    let (path, request) = match parse_args(arg_iter) {
+        Ok(parsed) => parsed,
+        Err(opt_err) => {
+            print_help(opt_err.as_deref());
+
+            return if opt_err.is_some() { 1 } else { 0 };
+        }
+    };
+
+    match send_control_request(&path, &request) {
+        Ok(response) => {
+            match serde_json::to_string_pretty(&response) {
+                Ok(json) => println!("{}", json),
+                Err(err) => eprintln!("Error: {:#}", err),
+            }
+
+            if let ControlResponse::Error { .. } = response {
+                1
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+
+            1
+        }
+    }
+}
+
+fn parse_args<I>(mut arg_iter: I) -> Result<(PathBuf, ControlRequest), Option<String>>
+where
+    I: Iterator<Item = String>,
+{
+    let mut path = ControlConfig::default().path;
+
+    let command = loop {
+        match arg_iter.next().as_deref() {
+            Some("-s" | "--socket") => {
+                path = arg_iter
+                    .next()
+                    .ok_or_else(|| Some("No socket path given".to_string()))?
+                    .into();
+            }
+            Some("-h" | "--help") | None => return Err(None),
+            Some(command) => break command.to_string(),
+        }
+    };
+
+    let mut next_hex_arg = |name: &str| -> Result<[u8; 20], Option<String>> {
+        let arg = arg_iter
+            .next()
+            .ok_or_else(|| Some(format!("No {} given", name)))?;
+
+        parse_hex_20(&arg).map_err(|err| Some(format!("Invalid {}: {:#}", name, err)))
+    };
+
+    let request = match command.as_str() {
+        "reload" => ControlRequest::Reload,
+        "statistics" => ControlRequest::Statistics,
+        "torrent" => ControlRequest::Torrent {
+            info_hash: next_hex_arg("info hash")?,
+        },
+        "remove-torrent" => ControlRequest::RemoveTorrent {
+            info_hash: next_hex_arg("info hash")?,
+        },
+        "remove-peer" => ControlRequest::RemovePeer {
+            info_hash: next_hex_arg("info hash")?,
+            peer_id: next_hex_arg("peer id")?,
+        },
+        "log-level" => {
+            let level = match arg_iter.next().as_deref() {
+                Some("off") => LogLevel::Off,
+                Some("error") => LogLevel::Error,
+                Some("warn") => LogLevel::Warn,
+                Some("info") => LogLevel::Info,
+                Some("debug") => LogLevel::Debug,
+                Some("trace") => LogLevel::Trace,
+                Some(_) => return Err(Some("Invalid log level".to_string())),
+                None => return Err(Some("No log level given".to_string())),
+            };
+
+            ControlRequest::SetLogLevel { level }
+        }
+        _ => return Err(Some("Unrecognized command".to_string())),
+    };
+
+    if arg_iter.next().is_some() {
+        return Err(Some("Too many arguments".to_string()));
+    }
+
+    Ok((path, request))
+}
+
+fn print_help(opt_error: Option<&str>) {
+    let app_path = ::std::env::args().next().unwrap();
+
+    println!("Send command to running tracker over its control socket");
+    println!("\nUsage: {} ctl [OPTIONS] COMMAND [ARGS]", app_path);
+    println!("\nOptions:");
+    println!(
+        "    -s, --socket PATH     Control socket path (default: {})",
+        ControlConfig::default().path.display()
+    );
+    println!("    -h, --help            Print this help message");
+    println!("\nCommands:");
+    println!("    reload                         Reload access lists and TLS config");
+    println!("    statistics                     Print metrics");
+    println!("    torrent INFO_HASH              Print torrent statistics and peers");
+    println!("    remove-torrent INFO_HASH       Remove torrent and its peers");
+    println!("    remove-peer INFO_HASH PEER_ID  Remove peer from torrent");
+    println!(
+        "    log-level LEVEL                Set log level (off, error, warn, info, debug, trace)"
+    );
+    println!("\nInfo hashes and peer ids are given as 40 hex characters.");
+
+    if let Some(error) = opt_error {
+        println!("\nError: {}.", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(PathBuf, ControlRequest), Option<String>> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let info_hash = "ab".repeat(20);
+
+        assert_eq!(
+            parse(&["-s", "/run/aquatic.sock", "torrent", &info_hash]),
+            Ok((
+                PathBuf::from("/run/aquatic.sock"),
+                ControlRequest::Torrent {
+                    info_hash: [0xab; 20]
+                }
+            ))
+        );
+        assert_eq!(
+            parse(&["log-level", "debug"]).map(|(_, request)| request),
+            Ok(ControlRequest::SetLogLevel {
+                level: LogLevel::Debug
+            })
+        );
+
+        assert_eq!(parse(&[]), Err(None));
+        assert!(parse(&["remove-peer", &info_hash]).is_err());
+        assert!(parse(&["torrent", "abcd"]).is_err());
+        assert!(parse(&["reload", "extra"]).is_err());
+    }
+}
@@ -1,3 +1,6 @@
+mod combined;
+mod ctl;
+
 use aquatic_common::cli::{print_help, run_app_with_cli_and_config, Options};
 use aquatic_http::config::Config as HttpConfig;
 use aquatic_udp::config::Config as UdpConfig;
@@ -7,6 +10,7 @@ use aquatic_ws::config::Config as WsConfig;
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 const APP_NAME: &str = "aquatic: BitTorrent tracker";
+const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
     ::std::process::exit(match run() {
@@ -33,6 +37,10 @@ fn run() -> Result<(), Option<String>> {
         return Err(None);
     };
 
+    if protocol == "ctl" {
+        ::std::process::exit(ctl::run(arg_iter));
+    }
+
     let options = match Options::parse_args(arg_iter) {
         Ok(options) => options,
         Err(opt_err) => {
@@ -59,6 +67,12 @@ fn run() -> Result<(), Option<String>> {
             aquatic_ws::run,
             Some(options),
         ),
+        "combined" => run_app_with_cli_and_config::<combined::Config>(
+            combined::APP_NAME,
+            APP_VERSION,
+            combined::run,
+            Some(options),
+        ),
         arg => {
             let opt_err = if arg == "-h" || arg == "--help" {
                 None
@@ -86,6 +100,9 @@ fn gen_info() -> String {
     info.push_str("\n    udp                   BitTorrent over UDP");
     info.push_str("\n    http                  BitTorrent over HTTP");
     info.push_str("\n    ws                    WebTorrent");
+    info.push_str("\n    combined              Any of the above in one process");
+    info.push_str("\n\nOther commands:");
+    info.push_str("\n    ctl                   Control running tracker (see ctl --help)");
 
     info
 }
@@ -0,0 +1,518 @@
+//! Export of individual announce requests for analytics
+//!
+//! Swarm workers pass [`AnnounceRecord`]s to a background writer thread over
+//! a bounded channel. When the channel is full or the sink can't keep up,
+//! records are dropped and counted instead of slowing down request handling.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::metrics::{Counter, MetricsRegistry};
+
+/// Flush buffered file output at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Destination of exported announce records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnounceEventSink {
+    /// Append JSON Lines to file
+    File,
+    /// Send one JSON object per datagram to Unix datagram socket
+    UnixDatagram,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnounceEventField {
+    /// Time of announce in milliseconds since the Unix epoch
+    Timestamp,
+    InfoHash,
+    /// First `peer_id_prefix_len` bytes of peer id, hex-encoded
+    PeerIdPrefix,
+    Event,
+    BytesLeft,
+    BytesUploaded,
+    BytesDownloaded,
+    IpVersion,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnounceEventsConfig {
+    /// Export announce events from swarm workers
+    pub active: bool,
+    /// Where to export events. Available values are file (JSON Lines) and
+    /// unix_datagram (one JSON object per datagram).
+    pub sink: AnnounceEventSink,
+    /// Path of file or Unix datagram socket
+    pub path: PathBuf,
+    /// Rotate file once it grows larger than this many bytes. Zero disables
+    /// rotation.
+    pub rotate_size: u64,
+    /// Number of rotated files to keep (named path.1, path.2, ...)
+    pub rotate_keep: usize,
+    /// Fraction of announce events to export, between 0.0 and 1.0
+    pub sample_rate: f64,
+    /// Fields to include. Available fields are timestamp, info_hash,
+    /// peer_id_prefix, event, bytes_left, bytes_uploaded, bytes_downloaded
+    /// and ip_version. Byte counts are omitted when the protocol doesn't
+    /// provide them.
+    pub fields: Vec<AnnounceEventField>,
+    /// Number of peer id bytes to include. The start of the peer id
+    /// usually identifies the client software.
+    pub peer_id_prefix_len: usize,
+    /// Maximum number of events waiting to be written. Further events are
+    /// dropped until there is room.
+    pub channel_size: usize,
+}
+
+impl Default for AnnounceEventsConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            sink: AnnounceEventSink::File,
+            path: "./announce-events.jsonl".into(),
+            rotate_size: 100 * 1024 * 1024,
+            rotate_keep: 5,
+            sample_rate: 1.0,
+            fields: vec![
+                AnnounceEventField::Timestamp,
+                AnnounceEventField::InfoHash,
+                AnnounceEventField::PeerIdPrefix,
+                AnnounceEventField::Event,
+                AnnounceEventField::BytesLeft,
+                AnnounceEventField::BytesUploaded,
+                AnnounceEventField::BytesDownloaded,
+                AnnounceEventField::IpVersion,
+            ],
+            peer_id_prefix_len: 8,
+            channel_size: 65_536,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceRecordEvent {
+    Started,
+    Stopped,
+    Completed,
+    None,
+}
+
+impl AnnounceRecordEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Stopped => "stopped",
+            Self::Completed => "completed",
+            Self::None => "none",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnnounceRecord {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub event: AnnounceRecordEvent,
+    pub bytes_left: Option<u64>,
+    pub bytes_uploaded: Option<u64>,
+    pub bytes_downloaded: Option<u64>,
+    pub ipv4: bool,
+}
+
+/// Handle for passing announce records to writer thread
+#[derive(Clone)]
+pub struct AnnounceRecordSender {
+    sender: SyncSender<(SystemTime, AnnounceRecord)>,
+    sample_rate: f64,
+    dropped: Counter,
+}
+
+impl AnnounceRecordSender {
+    /// Pass on record unless it is sampled out or the channel is full.
+    /// Never blocks.
+    ///
+    /// The record is only created if it is to be sent.
+    #[inline]
+    pub fn send<F: FnOnce() -> AnnounceRecord>(&self, create_record: F) {
+        if self.sample_rate < 1.0 && rand::random::<f64>() >= self.sample_rate {
+            return;
+        }
+
+        match self.sender.try_send((SystemTime::now(), create_record())) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.dropped.increment(),
+            // Writer has exited after logging an error
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+}
+
+/// Open sink and start writer thread
+///
+/// The sink is opened before returning, so this can be called before
+/// dropping privileges.
+pub fn spawn_announce_record_writer(
+    config: &AnnounceEventsConfig,
+    metrics_registry: &MetricsRegistry,
+) -> anyhow::Result<AnnounceRecordSender> {
+    if !(0.0..=1.0).contains(&config.sample_rate) {
+        return Err(anyhow::anyhow!(
+            "announce_events.sample_rate must be between 0.0 and 1.0"
+        ));
+    }
+
+    let sink = match config.sink {
+        AnnounceEventSink::File => Sink::File(
+            FileSink::open(config)
+                .with_context(|| format!("open announce event file {}", config.path.display()))?,
+        ),
+        AnnounceEventSink::UnixDatagram => {
+            let socket = UnixDatagram::unbound().with_context(|| "create datagram socket")?;
+
+            socket.set_nonblocking(true)?;
+
+            Sink::UnixDatagram(socket, config.path.clone())
+        }
+    };
+
+    let (sender, receiver) = sync_channel(config.channel_size);
+
+    let dropped = metrics_registry.counter(
+        "announce_events_dropped",
+        "Announce events dropped because export couldn't keep up",
+        &[],
+    );
+    let exported =
+        metrics_registry.counter("announce_events_exported", "Announce events exported", &[]);
+
+    let writer = Writer {
+        fields: config.fields.clone(),
+        peer_id_prefix_len: config.peer_id_prefix_len.min(20),
+        sink,
+        dropped: dropped.clone(),
+        exported,
+    };
+
+    ::std::thread::Builder::new()
+        .name("announce-events".into())
+        .spawn(move || writer.run(receiver))
+        .with_context(|| "spawn announce event writer")?;
+
+    Ok(AnnounceRecordSender {
+        sender,
+        sample_rate: config.sample_rate,
+        dropped,
+    })
+}
+
+struct Writer {
+    fields: Vec<AnnounceEventField>,
+    peer_id_prefix_len: usize,
+    sink: Sink,
+    dropped: Counter,
+    exported: Counter,
+}
+
+impl Writer {
+    fn run(mut self, receiver: Receiver<(SystemTime, AnnounceRecord)>) {
+        let mut buffer = Vec::new();
+
+        loop {
+            match receiver.recv_timeout(FLUSH_INTERVAL) {
+                Ok((timestamp, record)) => {
+                    buffer.clear();
+
+                    let json =
+                        record_to_json(&self.fields, self.peer_id_prefix_len, timestamp, &record);
+
+                    // Serializing a map of plain values can't fail
+                    serde_json::to_writer(&mut buffer, &json).unwrap();
+
+                    match self.sink.write(&mut buffer) {
+                        Ok(true) => self.exported.increment(),
+                        Ok(false) => self.dropped.increment(),
+                        Err(err) => {
+                            ::log::error!("announce event export failed, stopping: {:#}", err);
+
+                            return;
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(err) = self.sink.flush() {
+                        ::log::error!("announce event export failed, stopping: {:#}", err);
+
+                        return;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = self.sink.flush();
+
+                    return;
+                }
+            }
+        }
+    }
+}
+
+enum Sink {
+    File(FileSink),
+    UnixDatagram(UnixDatagram, PathBuf),
+}
+
+impl Sink {
+    /// Write serialized record, returning whether it was accepted by the
+    /// sink. Errors are fatal.
+    fn write(&mut self, buffer: &mut Vec<u8>) -> anyhow::Result<bool> {
+        match self {
+            Self::File(sink) => {
+                buffer.push(b'\n');
+
+                sink.write(buffer)?;
+
+                Ok(true)
+            }
+            // Missing, full or otherwise unavailable receiver just causes
+            // records to be dropped
+            Self::UnixDatagram(socket, path) => match socket.send_to(buffer, &path) {
+                Ok(_) => Ok(true),
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock {
+                        ::log::debug!("announce event datagram send error: {:#}", err);
+                    }
+
+                    Ok(false)
+                }
+            },
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        match self {
+            Self::File(sink) => sink
+                .file
+                .flush()
+                .with_context(|| "flush announce event file"),
+            Self::UnixDatagram(..) => Ok(()),
+        }
+    }
+}
+
+struct FileSink {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    rotate_size: u64,
+    rotate_keep: usize,
+}
+
+impl FileSink {
+    fn open(config: &AnnounceEventsConfig) -> anyhow::Result<Self> {
+        let (file, size) = open_append(&config.path)?;
+
+        Ok(Self {
+            path: config.path.clone(),
+            file,
+            size,
+            rotate_size: config.rotate_size,
+            rotate_keep: config.rotate_keep,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.file
+            .write_all(bytes)
+            .with_context(|| "write announce event file")?;
+
+        self.size += bytes.len() as u64;
+
+        if self.rotate_size != 0 && self.size >= self.rotate_size {
+            self.rotate()
+                .with_context(|| format!("rotate announce event file {}", self.path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+
+        if self.rotate_keep == 0 {
+            ::std::fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.rotate_keep).rev() {
+                let from = rotated_path(&self.path, i);
+
+                if from.exists() {
+                    ::std::fs::rename(from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+
+            ::std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        let (file, size) = open_append(&self.path)?;
+
+        self.file = file;
+        self.size = size;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> anyhow::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    Ok((BufWriter::new(file), size))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+
+    path.push(format!(".{}", index));
+
+    path.into()
+}
+
+fn record_to_json(
+    fields: &[AnnounceEventField],
+    peer_id_prefix_len: usize,
+    timestamp: SystemTime,
+    record: &AnnounceRecord,
+) -> Map<String, Value> {
+    let mut map = Map::new();
+
+    for field in fields {
+        let (key, value) = match field {
+            AnnounceEventField::Timestamp => {
+                let millis = timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_millis() as u64);
+
+                ("timestamp", Value::from(millis))
+            }
+            AnnounceEventField::InfoHash => ("info_hash", hex::encode(record.info_hash).into()),
+            AnnounceEventField::PeerIdPrefix => (
+                "peer_id_prefix",
+                hex::encode(&record.peer_id[..peer_id_prefix_len]).into(),
+            ),
+            AnnounceEventField::Event => ("event", record.event.as_str().into()),
+            AnnounceEventField::BytesLeft => match record.bytes_left {
+                Some(bytes) => ("bytes_left", bytes.into()),
+                None => continue,
+            },
+            AnnounceEventField::BytesUploaded => match record.bytes_uploaded {
+                Some(bytes) => ("bytes_uploaded", bytes.into()),
+                None => continue,
+            },
+            AnnounceEventField::BytesDownloaded => match record.bytes_downloaded {
+                Some(bytes) => ("bytes_downloaded", bytes.into()),
+                None => continue,
+            },
+            AnnounceEventField::IpVersion => {
+                ("ip_version", Value::from(if record.ipv4 { 4 } else { 6 }))
+            }
+        };
+
+        map.insert(key.to_string(), value);
+    }
+
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> AnnounceRecord {
+        AnnounceRecord {
+            info_hash: [1; 20],
+            peer_id: *b"-qB4500-abcdefghijkl",
+            event: AnnounceRecordEvent::Started,
+            bytes_left: Some(100),
+            bytes_uploaded: None,
+            bytes_downloaded: Some(0),
+            ipv4: false,
+        }
+    }
+
+    #[test]
+    fn test_record_to_json() {
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1_500);
+
+        let json = record_to_json(
+            &AnnounceEventsConfig::default().fields,
+            8,
+            timestamp,
+            &record(),
+        );
+
+        assert_eq!(
+            serde_json::to_string(&json).unwrap(),
+            format!(
+                r#"{{"bytes_downloaded":0,"bytes_left":100,"event":"started","info_hash":"{}","ip_version":6,"peer_id_prefix":"{}","timestamp":1500}}"#,
+                "01".repeat(20),
+                hex::encode("-qB4500-"),
+            )
+        );
+
+        let json = record_to_json(
+            &[AnnounceEventField::Event, AnnounceEventField::BytesUploaded],
+            8,
+            timestamp,
+            &record(),
+        );
+
+        assert_eq!(
+            serde_json::to_string(&json).unwrap(),
+            r#"{"event":"started"}"#
+        );
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let dir = ::std::env::temp_dir().join(format!(
+            "aquatic-announce-events-test-{}",
+            ::std::process::id()
+        ));
+
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        let config = AnnounceEventsConfig {
+            path: dir.join("events.jsonl"),
+            rotate_size: 10,
+            rotate_keep: 2,
+            ..Default::default()
+        };
+
+        let mut sink = FileSink::open(&config).unwrap();
+
+        for line in ["aaaaaaaaaaa\n", "bbbbbbbbbbb\n", "ccccccccccc\n", "ddd\n"] {
+            sink.write(line.as_bytes()).unwrap();
+        }
+
+        sink.file.flush().unwrap();
+
+        let read = |path| ::std::fs::read_to_string(path).unwrap();
+
+        assert_eq!(read(config.path.clone()), "ddd\n");
+        assert_eq!(read(rotated_path(&config.path, 1)), "ccccccccccc\n");
+        assert_eq!(read(rotated_path(&config.path, 2)), "bbbbbbbbbbb\n");
+        assert!(!rotated_path(&config.path, 3).exists());
+
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+}
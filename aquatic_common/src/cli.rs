@@ -26,6 +26,19 @@ impl Default for LogLevel {
     }
 }
 
+impl LogLevel {
+    fn to_level_filter(self) -> LevelFilter {
+        match self {
+            Self::Off => LevelFilter::Off,
+            Self::Error => LevelFilter::Error,
+            Self::Warn => LevelFilter::Warn,
+            Self::Info => LevelFilter::Info,
+            Self::Debug => LevelFilter::Debug,
+            Self::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
 pub trait Config: Default + TomlConfig + DeserializeOwned + std::fmt::Debug {
     fn get_log_level(&self) -> Option<LogLevel> {
         None
@@ -200,22 +213,22 @@ where
     <T as TomlConfig>::default_to_string()
 }
 
-fn start_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
-    let level_filter = match log_level {
-        LogLevel::Off => LevelFilter::Off,
-        LogLevel::Error => LevelFilter::Error,
-        LogLevel::Warn => LevelFilter::Warn,
-        LogLevel::Info => LevelFilter::Info,
-        LogLevel::Debug => LevelFilter::Debug,
-        LogLevel::Trace => LevelFilter::Trace,
-    };
+/// Change log level of running application
+pub fn set_log_level(log_level: LogLevel) {
+    ::log::set_max_level(log_level.to_level_filter());
+}
 
+fn start_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
+    // Logger lets all messages through so that the level can be raised at
+    // runtime. Filtering is done by the global max level.
     SimpleLogger::new()
-        .with_level(level_filter)
+        .with_level(LevelFilter::Trace)
         .with_utc_timestamps()
         .init()
         .context("Couldn't initialize logger")?;
 
+    set_log_level(log_level);
+
     Ok(())
 }
 
@@ -0,0 +1,367 @@
+//! Runtime control over a Unix domain socket
+//!
+//! Clients connect, write a single JSON-encoded [`ControlRequest`] followed
+//! by a newline and read back a single JSON-encoded [`ControlResponse`].
+//! Commands concerning a single torrent are passed on to the swarm worker
+//! responsible for it as a [`SwarmCommandRequest`].
+
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{set_log_level, LogLevel};
+use crate::metrics::MetricsRegistry;
+
+/// Maximum time to wait for client or swarm worker
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Accept runtime commands (e.g., from `aquatic ctl`) on a Unix domain
+    /// socket
+    pub active: bool,
+    /// Path of control socket. Any existing file at this path is replaced.
+    /// Access is restricted to the user running the tracker.
+    pub path: PathBuf,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            path: "./aquatic-control.sock".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Reload access lists, and TLS configuration where applicable
+    Reload,
+    /// Dump current metric values
+    Statistics,
+    Torrent {
+        #[serde(with = "hex_20")]
+        info_hash: [u8; 20],
+    },
+    RemoveTorrent {
+        #[serde(with = "hex_20")]
+        info_hash: [u8; 20],
+    },
+    RemovePeer {
+        #[serde(with = "hex_20")]
+        info_hash: [u8; 20],
+        #[serde(with = "hex_20")]
+        peer_id: [u8; 20],
+    },
+    SetLogLevel {
+        level: LogLevel,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok,
+    Error {
+        message: String,
+    },
+    Statistics {
+        metrics: BTreeMap<String, i64>,
+    },
+    Torrent {
+        torrent: Option<TorrentInfo>,
+    },
+    /// Whether torrent or peer was found and removed
+    Removed {
+        removed: bool,
+    },
+}
+
+/// Torrent statistics and peers, with IPv4 and IPv6 swarms combined
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TorrentInfo {
+    pub seeders: usize,
+    pub leechers: usize,
+    pub completed: usize,
+    pub peers: Vec<PeerInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    #[serde(with = "hex_20")]
+    pub peer_id: [u8; 20],
+    pub ip_address: IpAddr,
+    pub port: u16,
+    pub seeding: bool,
+    /// Seconds left until peer is removed unless it announces again
+    pub valid_for: u64,
+}
+
+/// Command handled by the swarm worker responsible for a torrent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwarmCommand {
+    Torrent {
+        info_hash: [u8; 20],
+    },
+    RemoveTorrent {
+        info_hash: [u8; 20],
+    },
+    RemovePeer {
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    },
+}
+
+impl SwarmCommand {
+    pub fn info_hash(&self) -> [u8; 20] {
+        match self {
+            Self::Torrent { info_hash }
+            | Self::RemoveTorrent { info_hash }
+            | Self::RemovePeer { info_hash, .. } => *info_hash,
+        }
+    }
+}
+
+pub struct SwarmCommandRequest {
+    pub command: SwarmCommand,
+    pub response_sender: Sender<ControlResponse>,
+}
+
+/// Bind control socket and handle commands from a background thread
+///
+/// `reload` is called on [`ControlRequest::Reload`]. `send_swarm_command`
+/// is expected to pass on the request to the swarm worker responsible for
+/// the torrent and to return the channel the response will arrive on.
+///
+/// The socket is bound before returning, so this can be called before
+/// dropping privileges.
+pub fn spawn_control_server<R, S>(
+    config: &ControlConfig,
+    metrics_registry: MetricsRegistry,
+    reload: R,
+    send_swarm_command: S,
+) -> anyhow::Result<()>
+where
+    R: Fn() -> anyhow::Result<()> + Send + 'static,
+    S: Fn(SwarmCommand) -> anyhow::Result<Receiver<ControlResponse>> + Send + 'static,
+{
+    let listener = bind(&config.path)
+        .with_context(|| format!("bind control socket to {}", config.path.display()))?;
+
+    ::std::thread::Builder::new()
+        .name("control".into())
+        .spawn(move || {
+            let handle_request = |request| match request {
+                ControlRequest::Reload => match reload() {
+                    Ok(()) => ControlResponse::Ok,
+                    Err(err) => ControlResponse::Error {
+                        message: format!("{:#}", err),
+                    },
+                },
+                ControlRequest::Statistics => ControlResponse::Statistics {
+                    metrics: metrics_registry.values(),
+                },
+                ControlRequest::Torrent { info_hash } => {
+                    handle_swarm_command(&send_swarm_command, SwarmCommand::Torrent { info_hash })
+                }
+                ControlRequest::RemoveTorrent { info_hash } => handle_swarm_command(
+                    &send_swarm_command,
+                    SwarmCommand::RemoveTorrent { info_hash },
+                ),
+                ControlRequest::RemovePeer { info_hash, peer_id } => handle_swarm_command(
+                    &send_swarm_command,
+                    SwarmCommand::RemovePeer { info_hash, peer_id },
+                ),
+                ControlRequest::SetLogLevel { level } => {
+                    set_log_level(level);
+
+                    ControlResponse::Ok
+                }
+            };
+
+            // Connections are handled one at a time, which is fine for
+            // occasional administrative use
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = handle_connection(&handle_request, stream) {
+                            ::log::debug!("control connection error: {:#}", err);
+                        }
+                    }
+                    Err(err) => {
+                        ::log::warn!("control socket accept error: {:#}", err);
+                    }
+                }
+            }
+        })
+        .with_context(|| "spawn control server")?;
+
+    Ok(())
+}
+
+/// Send request to control socket and wait for response
+pub fn send_control_request(
+    path: &Path,
+    request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("connect to control socket {}", path.display()))?;
+
+    stream.set_read_timeout(Some(TIMEOUT * 2))?;
+
+    let mut line = serde_json::to_string(request)?;
+
+    line.push('\n');
+
+    stream.write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .with_context(|| "read response")?;
+
+    serde_json::from_str(&line).with_context(|| format!("parse response: {}", line))
+}
+
+/// Parse hex-encoded info hash or peer id
+pub fn parse_hex_20(input: &str) -> anyhow::Result<[u8; 20]> {
+    let mut bytes = [0u8; 20];
+
+    hex::decode_to_slice(input, &mut bytes)
+        .with_context(|| format!("expected 40 hex characters, got {:?}", input))?;
+
+    Ok(bytes)
+}
+
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    // Remove socket left behind by previous run
+    match ::std::fs::remove_file(path) {
+        Ok(()) => (),
+        Err(err) if err.kind() == ::std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(|| "remove existing file"),
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    ::std::fs::set_permissions(path, Permissions::from_mode(0o600))
+        .with_context(|| "set socket permissions")?;
+
+    Ok(listener)
+}
+
+fn handle_swarm_command<S>(send_swarm_command: &S, command: SwarmCommand) -> ControlResponse
+where
+    S: Fn(SwarmCommand) -> anyhow::Result<Receiver<ControlResponse>>,
+{
+    let result = send_swarm_command(command).and_then(|receiver| {
+        receiver
+            .recv_timeout(TIMEOUT)
+            .with_context(|| "wait for response from swarm worker")
+    });
+
+    result.unwrap_or_else(|err| ControlResponse::Error {
+        message: format!("{:#}", err),
+    })
+}
+
+fn handle_connection<F>(handle_request: &F, stream: UnixStream) -> anyhow::Result<()>
+where
+    F: Fn(ControlRequest) -> ControlResponse,
+{
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut line = String::new();
+
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => {
+            ::log::info!("control request: {:?}", request);
+
+            handle_request(request)
+        }
+        Err(err) => ControlResponse::Error {
+            message: format!("parse request: {:#}", err),
+        },
+    };
+
+    let mut line = serde_json::to_string(&response)?;
+
+    line.push('\n');
+
+    (&stream).write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+mod hex_20 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8; 20], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 20], D::Error> {
+        let input = String::deserialize(deserializer)?;
+
+        super::parse_hex_20(&input).map_err(|err| serde::de::Error::custom(format!("{:#}", err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_deserialize_requests() {
+        let info_hash = [0xab; 20];
+
+        let request = ControlRequest::RemovePeer {
+            info_hash,
+            peer_id: [1; 20],
+        };
+        let json = serde_json::to_string(&request).unwrap();
+
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"command":"remove_peer","info_hash":"{}","peer_id":"{}"}}"#,
+                "ab".repeat(20),
+                "01".repeat(20)
+            )
+        );
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(&json).unwrap(),
+            request
+        );
+
+        assert_eq!(
+            serde_json::from_str::<ControlRequest>(
+                r#"{"command":"set_log_level","level":"debug"}"#
+            )
+            .unwrap(),
+            ControlRequest::SetLogLevel {
+                level: LogLevel::Debug
+            }
+        );
+        assert!(serde_json::from_str::<ControlRequest>(
+            r#"{"command":"torrent","info_hash":"abcd"}"#
+        )
+        .is_err());
+    }
+}
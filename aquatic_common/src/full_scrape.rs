@@ -0,0 +1,209 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use hashbrown::HashMap;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FullScrapeConfig {
+    /// Serve scrape requests without info hashes with statistics for all
+    /// torrents
+    ///
+    /// Responses are served from a cache that is periodically rebuilt from
+    /// statistics contributed by the swarm workers, so the cost of serving
+    /// them doesn't depend on the number of requests.
+    pub active: bool,
+    /// Rebuild cached full scrape response this often (seconds)
+    pub update_interval: u64,
+    /// Serve at most one full scrape request per client IP address this
+    /// often (seconds). Limits are enforced separately by each socket
+    /// worker. Set to zero to disable.
+    pub min_interval_per_ip: u64,
+}
+
+impl Default for FullScrapeConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            update_interval: 60,
+            min_interval_per_ip: 60,
+        }
+    }
+}
+
+/// Torrent statistics for full scrape responses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FullScrapeStatistics {
+    pub seeders: usize,
+    pub leechers: usize,
+    pub completed: usize,
+}
+
+type WorkerStatistics = Vec<([u8; 20], FullScrapeStatistics)>;
+
+/// Full scrape state shared between workers
+///
+/// Swarm workers periodically store statistics for their torrents. One
+/// socket worker periodically collects them, builds a protocol-specific
+/// response of type `T` and stores it for all socket workers to serve.
+pub struct FullScrapeState<T> {
+    worker_statistics: Vec<ArcSwap<WorkerStatistics>>,
+    response: ArcSwapOption<T>,
+}
+
+impl<T> FullScrapeState<T> {
+    pub fn new(num_swarm_workers: usize) -> Self {
+        Self {
+            worker_statistics: (0..num_swarm_workers)
+                .map(|_| Default::default())
+                .collect(),
+            response: Default::default(),
+        }
+    }
+
+    /// Replace statistics previously stored by swarm worker
+    pub fn set_worker_statistics(
+        &self,
+        worker_index: usize,
+        statistics: Vec<([u8; 20], FullScrapeStatistics)>,
+    ) {
+        self.worker_statistics[worker_index].store(Arc::new(statistics));
+    }
+
+    /// Collect statistics stored by all swarm workers, sorted by info hash
+    pub fn collect_statistics(&self) -> Vec<([u8; 20], FullScrapeStatistics)> {
+        let mut statistics = Vec::new();
+
+        for worker_statistics in self.worker_statistics.iter() {
+            statistics.extend_from_slice(&worker_statistics.load());
+        }
+
+        statistics.sort_unstable_by_key(|(info_hash, _)| *info_hash);
+
+        statistics
+    }
+
+    pub fn set_response(&self, response: T) {
+        self.response.store(Some(Arc::new(response)));
+    }
+
+    /// Returns None if no response has been built yet
+    pub fn load_response(&self) -> Option<Arc<T>> {
+        self.response.load_full()
+    }
+}
+
+/// Tracks when full scrape responses were last served to client IP addresses
+#[derive(Default)]
+pub struct FullScrapeRateLimiter {
+    last_served: HashMap<IpAddr, Instant>,
+}
+
+impl FullScrapeRateLimiter {
+    /// Returns true if request should be served
+    pub fn check(&mut self, config: &FullScrapeConfig, ip: IpAddr, now: Instant) -> bool {
+        if config.min_interval_per_ip == 0 {
+            return true;
+        }
+
+        let min_interval = Duration::from_secs(config.min_interval_per_ip);
+
+        match self.last_served.get_mut(&ip) {
+            Some(last_served) if now.saturating_duration_since(*last_served) < min_interval => {
+                false
+            }
+            Some(last_served) => {
+                *last_served = now;
+
+                true
+            }
+            None => {
+                self.last_served.insert(ip, now);
+
+                true
+            }
+        }
+    }
+
+    /// Forget addresses that would be served again
+    pub fn clean(&mut self, config: &FullScrapeConfig, now: Instant) {
+        let min_interval = Duration::from_secs(config.min_interval_per_ip);
+
+        self.last_served
+            .retain(|_, last_served| now.saturating_duration_since(*last_served) < min_interval);
+
+        self.last_served.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_scrape_state() {
+        let state = FullScrapeState::<()>::new(2);
+
+        let stats = |seeders| FullScrapeStatistics {
+            seeders,
+            ..Default::default()
+        };
+
+        state.set_worker_statistics(0, vec![([2; 20], stats(2)), ([0; 20], stats(0))]);
+        state.set_worker_statistics(1, vec![([1; 20], stats(1))]);
+
+        assert_eq!(
+            state.collect_statistics(),
+            vec![([0; 20], stats(0)), ([1; 20], stats(1)), ([2; 20], stats(2))]
+        );
+
+        state.set_worker_statistics(0, Vec::new());
+
+        assert_eq!(state.collect_statistics(), vec![([1; 20], stats(1))]);
+
+        assert!(state.load_response().is_none());
+
+        state.set_response(());
+
+        assert!(state.load_response().is_some());
+    }
+
+    #[test]
+    fn test_full_scrape_rate_limiter() {
+        let config = FullScrapeConfig {
+            active: true,
+            min_interval_per_ip: 10,
+            ..Default::default()
+        };
+
+        let mut rate_limiter = FullScrapeRateLimiter::default();
+        let mut now = Instant::now();
+
+        let a = "10.0.0.1".parse().unwrap();
+        let b = "10.0.0.2".parse().unwrap();
+
+        assert!(rate_limiter.check(&config, a, now));
+        assert!(!rate_limiter.check(&config, a, now));
+        assert!(rate_limiter.check(&config, b, now));
+
+        now += Duration::from_secs(5);
+
+        assert!(!rate_limiter.check(&config, a, now));
+
+        rate_limiter.clean(&config, now);
+
+        assert_eq!(rate_limiter.last_served.len(), 2);
+
+        now += Duration::from_secs(5);
+
+        assert!(rate_limiter.check(&config, a, now));
+
+        rate_limiter.clean(&config, now);
+
+        assert_eq!(rate_limiter.last_served.len(), 1);
+    }
+}
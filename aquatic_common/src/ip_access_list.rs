@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use serde::Deserialize;
+
+use crate::access_list::AccessListMode;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpAccessListConfig {
+    /// Access list mode. Available modes are allow, deny and off.
+    ///
+    /// In allow mode, only clients with addresses matching an entry in the
+    /// file are served. In deny mode, clients with matching addresses are
+    /// ignored.
+    pub mode: AccessListMode,
+    /// Path to IP access list file consisting of newline-separated IP
+    /// addresses or CIDR ranges, e.g., 192.0.2.0/24 or 2001:db8::/32.
+    /// Empty lines and lines starting with # are ignored.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+}
+
+impl Default for IpAccessListConfig {
+    fn default() -> Self {
+        Self {
+            mode: AccessListMode::Off,
+            path: "".into(),
+        }
+    }
+}
+
+/// Set of IPv4 and IPv6 address ranges
+///
+/// Ranges are stored sorted and merged, so lookups are binary searches.
+#[derive(Default, Clone, Debug)]
+pub struct IpAccessList {
+    ipv4: Vec<(u32, u32)>,
+    ipv6: Vec<(u128, u128)>,
+}
+
+impl IpAccessList {
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+
+        Self::create_from_reader(BufReader::new(file))
+    }
+
+    fn create_from_reader(reader: impl BufRead) -> anyhow::Result<Self> {
+        let lines = reader.lines().collect::<Result<Vec<String>, _>>()?;
+
+        Self::create_from_entries(lines.iter().map(|line| line.as_str()))
+    }
+
+    /// Create from IP addresses or CIDR ranges. Empty entries and entries
+    /// starting with # are ignored.
+    pub fn create_from_entries<'a>(
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<Self> {
+        let mut new_list = Self::default();
+
+        for entry in entries {
+            let trimmed = entry.trim();
+
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            match parse_ip_range(trimmed)
+                .with_context(|| format!("Invalid IP access list entry: {}", entry))?
+            {
+                IpRange::V4(start, end) => new_list.ipv4.push((start, end)),
+                IpRange::V6(start, end) => new_list.ipv6.push((start, end)),
+            }
+        }
+
+        merge_ranges(&mut new_list.ipv4);
+        merge_ranges(&mut new_list.ipv6);
+
+        Ok(new_list)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => ranges_contain(&self.ipv4, u32::from(ip)),
+            IpAddr::V6(ip) => {
+                if let Some(ip) = ipv4_from_mapped(ip) {
+                    ranges_contain(&self.ipv4, u32::from(ip))
+                } else {
+                    ranges_contain(&self.ipv6, u128::from(ip))
+                }
+            }
+        }
+    }
+
+    pub fn allows(&self, mode: AccessListMode, ip: IpAddr) -> bool {
+        match mode {
+            AccessListMode::Allow => self.contains(ip),
+            AccessListMode::Deny => !self.contains(ip),
+            AccessListMode::Off => true,
+        }
+    }
+
+    /// Number of address ranges after merging overlapping entries
+    pub fn len(&self) -> usize {
+        self.ipv4.len() + self.ipv6.len()
+    }
+}
+
+pub type IpAccessListArcSwap = ArcSwap<IpAccessList>;
+pub type IpAccessListCache = Cache<Arc<IpAccessListArcSwap>, Arc<IpAccessList>>;
+
+pub fn create_ip_access_list_cache(arc_swap: &Arc<IpAccessListArcSwap>) -> IpAccessListCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_ip_access_list(
+    config: &IpAccessListConfig,
+    ip_access_list: &Arc<IpAccessListArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match IpAccessList::create_from_path(&config.path) {
+            Ok(new_list) => {
+                ip_access_list.store(Arc::new(new_list));
+
+                ::log::info!("IP access list updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating IP access list failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IpRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+fn parse_ip_range(input: &str) -> anyhow::Result<IpRange> {
+    let (ip, opt_prefix_len) = match input.split_once('/') {
+        Some((ip, prefix_len)) => (ip, Some(prefix_len.parse::<u32>()?)),
+        None => (input, None),
+    };
+
+    match ip.parse::<IpAddr>()? {
+        IpAddr::V4(ip) => {
+            let prefix_len = opt_prefix_len.unwrap_or(32);
+
+            if prefix_len > 32 {
+                return Err(anyhow::anyhow!("prefix length too large"));
+            }
+
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+
+            Ok(IpRange::V4(start, start | !mask))
+        }
+        IpAddr::V6(ip) => {
+            let prefix_len = opt_prefix_len.unwrap_or(128);
+
+            if prefix_len > 128 {
+                return Err(anyhow::anyhow!("prefix length too large"));
+            }
+
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+
+            Ok(IpRange::V6(start, start | !mask))
+        }
+    }
+}
+
+fn ipv4_from_mapped(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// Sort ranges and merge overlapping ones
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+
+    for (start, end) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end => {
+                *last_end = (*last_end).max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged.shrink_to_fit();
+
+    *ranges = merged;
+}
+
+fn ranges_contain<T: Ord + Copy>(ranges: &[(T, T)], value: T) -> bool {
+    let index = ranges.partition_point(|(start, _)| *start <= value);
+
+    index > 0 && ranges[index - 1].1 >= value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ip_range() {
+        assert_eq!(
+            parse_ip_range("192.0.2.1").unwrap(),
+            IpRange::V4(0xc0000201, 0xc0000201)
+        );
+        assert_eq!(
+            parse_ip_range("192.0.2.77/24").unwrap(),
+            IpRange::V4(0xc0000200, 0xc00002ff)
+        );
+        assert_eq!(
+            parse_ip_range("1.2.3.4/0").unwrap(),
+            IpRange::V4(0, u32::MAX)
+        );
+        assert_eq!(
+            parse_ip_range("2001:db8::1/32").unwrap(),
+            IpRange::V6(0x20010db8 << 96, (0x20010db8 << 96) | (u128::MAX >> 32))
+        );
+
+        assert!(parse_ip_range("192.0.2.1/33").is_err());
+        assert!(parse_ip_range("2001:db8::/129").is_err());
+        assert!(parse_ip_range("192.0.2.1/").is_err());
+        assert!(parse_ip_range("192.0.2").is_err());
+        assert!(parse_ip_range("aaaabbbbccccddddeeeeaaaabbbbccccddddeeee").is_err());
+    }
+
+    #[test]
+    fn test_ip_access_list() {
+        let input = "\
+# comment
+10.0.0.0/8
+10.1.0.0/16
+
+192.0.2.5
+2001:db8::/32
+";
+        let list = IpAccessList::create_from_reader(input.as_bytes()).unwrap();
+
+        // Overlapping ranges are merged
+        assert_eq!(list.len(), 3);
+
+        let f = |ip: &str| list.contains(ip.parse().unwrap());
+
+        assert!(f("10.0.0.0"));
+        assert!(f("10.255.255.255"));
+        assert!(f("192.0.2.5"));
+        assert!(f("::ffff:10.2.3.4"));
+        assert!(f("2001:db8:ffff::1"));
+
+        assert!(!f("9.255.255.255"));
+        assert!(!f("11.0.0.0"));
+        assert!(!f("192.0.2.4"));
+        assert!(!f("192.0.2.6"));
+        assert!(!f("2001:db9::"));
+        assert!(!f("::1"));
+
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert!(list.allows(AccessListMode::Allow, ip));
+        assert!(!list.allows(AccessListMode::Deny, ip));
+        assert!(list.allows(AccessListMode::Off, ip));
+
+        assert!(IpAccessList::create_from_reader("10.0.0.0/8\nfoo\n".as_bytes()).is_err());
+    }
+}
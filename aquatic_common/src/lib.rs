@@ -5,13 +5,26 @@ use std::time::{Duration, Instant};
 
 use ahash::RandomState;
 use rand::Rng;
+use serde::{Deserialize, Deserializer};
 
 pub mod access_list;
+pub mod announce_events;
 pub mod cli;
+pub mod control;
 pub mod cpu_pinning;
+pub mod full_scrape;
+pub mod ip_access_list;
+pub mod metrics;
 pub mod privileges;
+pub mod reverse_proxy;
 #[cfg(feature = "rustls")]
 pub mod rustls_config;
+#[cfg(feature = "shared-swarm")]
+pub mod shared_swarm;
+pub mod shutdown;
+pub mod socket_activation;
+pub mod snapshot;
+pub mod url_access_list;
 
 /// Amortized IndexMap using AHash hasher
 pub type AmortizedIndexMap<K, V> = indexmap_amortized::IndexMap<K, V, RandomState>;
@@ -31,6 +44,11 @@ impl ValidUntil {
     pub fn new_with_now(now: Instant, offset_seconds: u64) -> Self {
         Self(now + Duration::from_secs(offset_seconds))
     }
+
+    /// Number of whole seconds left until expiry
+    pub fn seconds_left(&self, now: Instant) -> u64 {
+        self.0.saturating_duration_since(now).as_secs()
+    }
 }
 
 pub struct PanicSentinelWatcher(Arc<AtomicBool>);
@@ -60,17 +78,21 @@ impl Drop for PanicSentinel {
             let already_triggered = self.0.fetch_or(true, Ordering::SeqCst);
 
             if !already_triggered {
-                if unsafe { libc::raise(15) } == -1 {
-                    panic!(
-                        "Could not raise SIGTERM: {:#}",
-                        ::std::io::Error::last_os_error()
-                    )
-                }
+                raise_sigterm();
             }
         }
     }
 }
 
+fn raise_sigterm() {
+    if unsafe { libc::raise(15) } == -1 {
+        panic!(
+            "Could not raise SIGTERM: {:#}",
+            ::std::io::Error::last_os_error()
+        )
+    }
+}
+
 /// Extract response peers
 ///
 /// If there are more peers in map than `max_num_peers_to_take`, do a
@@ -182,3 +204,54 @@ impl CanonicalSocketAddr {
         self.0.is_ipv4()
     }
 }
+
+/// Deserialize either a single value or a list of values
+///
+/// Use with `#[serde(deserialize_with = "deserialize_one_or_many")]` to
+/// turn a config field into a list while still accepting existing configs.
+pub fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_one_or_many() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "deserialize_one_or_many")]
+            address: Vec<SocketAddr>,
+        }
+
+        let f = |s: &str| toml::from_str::<Config>(s).unwrap().address;
+
+        assert_eq!(
+            f("address = '127.0.0.1:3000'"),
+            vec!["127.0.0.1:3000".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            f("address = ['127.0.0.1:3000', '[::1]:3001']"),
+            vec![
+                "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
+                "[::1]:3001".parse().unwrap()
+            ]
+        );
+        assert!(f("address = []").is_empty());
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::Deserialize;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Maximum number of metrics connections handled at a time
+const MAX_CONNECTIONS: i64 = 16;
+/// Time allowed for reading request and writing response
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve metrics in OpenMetrics text format over HTTP
+    pub active: bool,
+    /// Address to bind metrics listener to. Metrics are served on path
+    /// /metrics.
+    pub address: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            address: SocketAddr::from(([127, 0, 0, 1], 9000)),
+        }
+    }
+}
+
+/// Monotonically increasing metric
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    #[inline]
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Metric that can go up and down
+#[derive(Clone, Debug, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, value: i64) {
+        self.0.store(value as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed) as i64
+    }
+
+    /// Increment gauge, decrementing it again when returned guard is dropped
+    pub fn increment_scoped(&self) -> GaugeGuard {
+        self.increment();
+
+        GaugeGuard(self.clone())
+    }
+}
+
+pub struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.decrement();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MetricType {
+    Counter,
+    Gauge,
+}
+
+struct MetricFamily {
+    name: String,
+    help: &'static str,
+    metric_type: MetricType,
+    /// Rendered label sets and values
+    metrics: Vec<(String, Arc<AtomicU64>)>,
+}
+
+/// Collection of metrics that can be rendered in OpenMetrics text format
+///
+/// Metrics are registered at startup. The returned handles are cheap to
+/// update and can be passed to worker threads.
+#[derive(Clone)]
+pub struct MetricsRegistry {
+    prefix: &'static str,
+    families: Arc<Mutex<Vec<MetricFamily>>>,
+}
+
+impl MetricsRegistry {
+    /// Create registry where all metric names are prefixed with `prefix`
+    pub fn new(prefix: &'static str) -> Self {
+        Self {
+            prefix,
+            families: Default::default(),
+        }
+    }
+
+    pub fn counter(&self, name: &str, help: &'static str, labels: &[(&str, &str)]) -> Counter {
+        Counter(self.register(name, help, MetricType::Counter, labels))
+    }
+
+    pub fn gauge(&self, name: &str, help: &'static str, labels: &[(&str, &str)]) -> Gauge {
+        Gauge(self.register(name, help, MetricType::Gauge, labels))
+    }
+
+    fn register(
+        &self,
+        name: &str,
+        help: &'static str,
+        metric_type: MetricType,
+        labels: &[(&str, &str)],
+    ) -> Arc<AtomicU64> {
+        let name = format!("{}_{}", self.prefix, name);
+        let labels = render_labels(labels);
+        let value = Arc::new(AtomicU64::new(0));
+
+        let mut families = self.families.lock().unwrap();
+
+        if let Some(family) = families.iter_mut().find(|family| family.name == name) {
+            assert_eq!(
+                family.metric_type, metric_type,
+                "metric {} registered with different types",
+                name
+            );
+
+            if let Some((_, value)) = family.metrics.iter().find(|(l, _)| *l == labels) {
+                return value.clone();
+            }
+
+            family.metrics.push((labels, value.clone()));
+        } else {
+            families.push(MetricFamily {
+                name,
+                help,
+                metric_type,
+                metrics: vec![(labels, value.clone())],
+            });
+        }
+
+        value
+    }
+
+    /// Render all metrics in OpenMetrics text format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        for family in self.families.lock().unwrap().iter() {
+            let (type_str, suffix) = match family.metric_type {
+                MetricType::Counter => ("counter", "_total"),
+                MetricType::Gauge => ("gauge", ""),
+            };
+
+            let _ = writeln!(output, "# TYPE {} {}", family.name, type_str);
+            let _ = writeln!(output, "# HELP {} {}", family.name, family.help);
+
+            for (labels, value) in family.metrics.iter() {
+                let value = value.load(Ordering::Relaxed);
+
+                let _ = match family.metric_type {
+                    MetricType::Counter => {
+                        writeln!(output, "{}{}{} {}", family.name, suffix, labels, value)
+                    }
+                    MetricType::Gauge => {
+                        writeln!(output, "{}{} {}", family.name, labels, value as i64)
+                    }
+                };
+            }
+        }
+
+        output.push_str("# EOF\n");
+
+        output
+    }
+
+    /// Current values, keyed by series name as rendered by
+    /// [`MetricsRegistry::render`]
+    pub fn values(&self) -> BTreeMap<String, i64> {
+        let mut values = BTreeMap::new();
+
+        for family in self.families.lock().unwrap().iter() {
+            let suffix = match family.metric_type {
+                MetricType::Counter => "_total",
+                MetricType::Gauge => "",
+            };
+
+            for (labels, value) in family.metrics.iter() {
+                let key = format!("{}{}{}", family.name, suffix, labels);
+
+                values.insert(key, value.load(Ordering::Relaxed) as i64);
+            }
+        }
+
+        values
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Bind metrics listener and serve metrics from a background thread
+///
+/// The listener is bound before returning, so this can be called before
+/// dropping privileges.
+pub fn spawn_metrics_server(
+    config: &MetricsConfig,
+    registry: MetricsRegistry,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(config.address)
+        .with_context(|| format!("bind metrics listener to {}", config.address))?;
+
+    ::std::thread::Builder::new()
+        .name("metrics".into())
+        .spawn(move || run_metrics_server(listener, registry))
+        .with_context(|| "spawn metrics server")?;
+
+    Ok(())
+}
+
+/// Accept connections and handle each of them in a thread of its own, so
+/// that slow clients don't hold up others
+///
+/// Connections exceeding MAX_CONNECTIONS are closed right away.
+fn run_metrics_server(listener: TcpListener, registry: MetricsRegistry) {
+    let num_connections = Gauge::default();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                ::log::warn!("metrics listener accept error: {:#}", err);
+
+                continue;
+            }
+        };
+
+        if num_connections.get() >= MAX_CONNECTIONS {
+            ::log::warn!("too many metrics connections, closing new connection");
+
+            continue;
+        }
+
+        let connection_guard = num_connections.increment_scoped();
+        let registry = registry.clone();
+
+        let result = ::std::thread::Builder::new()
+            .name("metrics-conn".into())
+            .spawn(move || {
+                let _connection_guard = connection_guard;
+
+                if let Err(err) = handle_connection(&registry, stream) {
+                    ::log::debug!("metrics connection error: {:#}", err);
+                }
+            });
+
+        if let Err(err) = result {
+            ::log::warn!("spawn metrics connection thread: {:#}", err);
+        }
+    }
+}
+
+fn handle_connection(registry: &MetricsRegistry, mut stream: TcpStream) -> anyhow::Result<()> {
+    let deadline = Instant::now() + CONNECTION_TIMEOUT;
+
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+    let mut buffer = [0u8; 2048];
+    let mut bytes_read = 0;
+
+    // Read until end of request headers. Request bodies are not supported.
+    loop {
+        // Limit total time spent reading, not only time per read
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        if timeout.is_zero() {
+            return Err(anyhow::anyhow!("timed out reading request"));
+        }
+
+        stream.set_read_timeout(Some(timeout))?;
+
+        let n = stream.read(&mut buffer[bytes_read..])?;
+
+        if n == 0 {
+            return Ok(());
+        }
+
+        bytes_read += n;
+
+        if buffer[..bytes_read].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+        if bytes_read == buffer.len() {
+            return Err(anyhow::anyhow!("request headers too long"));
+        }
+    }
+
+    let is_metrics_request = buffer[..bytes_read].starts_with(b"GET /metrics ")
+        || buffer[..bytes_read].starts_with(b"GET /metrics?");
+
+    if is_metrics_request {
+        let body = registry.render();
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            CONTENT_TYPE,
+            body.len(),
+            body
+        )?;
+    } else {
+        stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )?;
+    }
+
+    stream.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let registry = MetricsRegistry::new("aquatic");
+
+        let a = registry.counter("requests", "Requests received", &[("ip_version", "4")]);
+        let b = registry.counter("requests", "Requests received", &[("ip_version", "6")]);
+        let c = registry.gauge("connections", "Open connections", &[]);
+
+        a.add(3);
+        b.increment();
+        c.decrement();
+
+        {
+            let _guard = c.increment_scoped();
+
+            assert_eq!(c.get(), 0);
+        }
+
+        // Registering an existing metric returns a handle to the same value
+        registry
+            .counter("requests", "Requests received", &[("ip_version", "4")])
+            .increment();
+
+        let expected = "\
+# TYPE aquatic_requests counter
+# HELP aquatic_requests Requests received
+aquatic_requests_total{ip_version=\"4\"} 4
+aquatic_requests_total{ip_version=\"6\"} 1
+# TYPE aquatic_connections gauge
+# HELP aquatic_connections Open connections
+aquatic_connections -1
+# EOF
+";
+
+        assert_eq!(registry.render(), expected);
+
+        let values = registry.values();
+
+        assert_eq!(values["aquatic_requests_total{ip_version=\"4\"}"], 4);
+        assert_eq!(values["aquatic_connections"], -1);
+    }
+
+    #[test]
+    fn test_render_labels() {
+        assert_eq!(render_labels(&[]), "");
+        assert_eq!(
+            render_labels(&[("a", "x"), ("b", "\"y\"\\")]),
+            "{a=\"x\",b=\"\\\"y\\\"\\\\\"}"
+        );
+    }
+
+    #[test]
+    fn test_metrics_server_idle_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let registry = MetricsRegistry::new("aquatic");
+
+        ::std::thread::spawn(move || run_metrics_server(listener, registry));
+
+        // Connection that never sends a request
+        let _idle_stream = TcpStream::connect(address).unwrap();
+
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        stream
+            .set_read_timeout(Some(CONNECTION_TIMEOUT / 2))
+            .unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("# EOF\n"));
+    }
+}
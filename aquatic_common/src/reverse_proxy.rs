@@ -0,0 +1,425 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::access_list::AccessListMode;
+use crate::ip_access_list::IpAccessList;
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+/// Maximum length of version 1 header, including CRLF
+const PROXY_V1_MAX_LEN: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_FIXED_LEN: usize = 16;
+
+/// HTTP header to read client address from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpHeader {
+    Off,
+    /// Use rightmost address in header that is not a trusted proxy
+    XForwardedFor,
+    XRealIp,
+}
+
+impl ClientIpHeader {
+    pub fn is_on(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+
+    pub fn header_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Off => None,
+            Self::XForwardedFor => Some("X-Forwarded-For"),
+            Self::XRealIp => Some("X-Real-IP"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReverseProxyConfig {
+    /// IP addresses or CIDR ranges of trusted reverse proxies, e.g.,
+    /// ["127.0.0.1", "10.0.0.0/8"]. Client address information is only
+    /// used when sent by these.
+    pub trusted_proxies: Vec<String>,
+    /// Expect HAProxy PROXY protocol header (version 1 or 2) on all
+    /// connections from trusted proxies and use the client address from it.
+    /// Connections from trusted proxies without a valid header are closed.
+    ///
+    /// If enabled, IP access list checks are done against the client
+    /// address from the header.
+    pub proxy_protocol: bool,
+    /// Use client address from HTTP header in requests sent by trusted
+    /// proxies. Available values are off, x-forwarded-for and x-real-ip.
+    pub client_ip_header: ClientIpHeader,
+}
+
+impl Default for ReverseProxyConfig {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            client_ip_header: ClientIpHeader::Off,
+        }
+    }
+}
+
+impl ReverseProxyConfig {
+    pub fn is_on(&self) -> bool {
+        self.proxy_protocol || self.client_ip_header.is_on()
+    }
+
+    pub fn parse_trusted_proxies(&self) -> anyhow::Result<IpAccessList> {
+        IpAccessList::create_from_entries(self.trusted_proxies.iter().map(|s| s.as_str()))
+    }
+}
+
+/// Number of additional bytes needed to read PROXY protocol header, given
+/// the bytes read so far
+///
+/// Never returns more bytes than are part of the header, so no data after
+/// it will be consumed if reading exactly this many bytes. Returns zero
+/// when the header is complete.
+pub fn proxy_protocol_bytes_needed(bytes: &[u8]) -> anyhow::Result<usize> {
+    // Shortest valid version 1 header ("PROXY UNKNOWN\r\n") is longer than
+    // the version 2 signature
+    if bytes.len() < PROXY_V2_SIGNATURE.len() {
+        let len = bytes.len();
+
+        if !PROXY_V1_PREFIX.starts_with(&bytes[..len.min(PROXY_V1_PREFIX.len())])
+            && !PROXY_V2_SIGNATURE.starts_with(bytes)
+        {
+            return Err(anyhow::anyhow!("no PROXY protocol header"));
+        }
+
+        return Ok(PROXY_V2_SIGNATURE.len() - len);
+    }
+
+    if bytes.starts_with(PROXY_V2_SIGNATURE) {
+        if bytes.len() < PROXY_V2_FIXED_LEN {
+            return Ok(PROXY_V2_FIXED_LEN - bytes.len());
+        }
+
+        let address_len = u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+
+        Ok((PROXY_V2_FIXED_LEN + address_len).saturating_sub(bytes.len()))
+    } else if bytes.starts_with(PROXY_V1_PREFIX) {
+        if bytes.ends_with(b"\r\n") {
+            Ok(0)
+        } else if bytes.len() >= PROXY_V1_MAX_LEN {
+            Err(anyhow::anyhow!("PROXY protocol header too long"))
+        } else {
+            Ok(1)
+        }
+    } else {
+        Err(anyhow::anyhow!("no PROXY protocol header"))
+    }
+}
+
+/// Parse complete PROXY protocol header
+///
+/// Returns source address, or None if the header says the connection was
+/// not proxied (e.g., health checks), in which case the connection address
+/// should be used.
+pub fn parse_proxy_protocol_header(bytes: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if bytes.starts_with(PROXY_V2_SIGNATURE) {
+        parse_proxy_protocol_v2(bytes)
+    } else if let Some(line) = bytes
+        .strip_prefix(PROXY_V1_PREFIX)
+        .and_then(|line| line.strip_suffix(b"\r\n"))
+    {
+        parse_proxy_protocol_v1(line)
+    } else {
+        Err(anyhow::anyhow!("invalid PROXY protocol header"))
+    }
+}
+
+fn parse_proxy_protocol_v1(line: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    let line = ::std::str::from_utf8(line)?;
+    let mut parts = line.split(' ');
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => (),
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(anyhow::anyhow!("unsupported PROXY protocol family")),
+    }
+
+    let source_ip: IpAddr = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no source address"))?
+        .parse()?;
+    let _destination_ip: IpAddr = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no destination address"))?
+        .parse()?;
+    let source_port: u16 = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no source port"))?
+        .parse()?;
+
+    Ok(Some(SocketAddr::new(source_ip, source_port)))
+}
+
+fn parse_proxy_protocol_v2(bytes: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if bytes.len() < PROXY_V2_FIXED_LEN {
+        return Err(anyhow::anyhow!("PROXY protocol header too short"));
+    }
+
+    let version_command = bytes[12];
+    let family = bytes[13];
+    let address_len = u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+    let addresses = bytes
+        .get(PROXY_V2_FIXED_LEN..PROXY_V2_FIXED_LEN + address_len)
+        .ok_or_else(|| anyhow::anyhow!("PROXY protocol header too short"))?;
+
+    if version_command >> 4 != 2 {
+        return Err(anyhow::anyhow!("unsupported PROXY protocol version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => (),
+        _ => return Err(anyhow::anyhow!("unsupported PROXY protocol command")),
+    }
+
+    // Only address family is checked, since transport protocol doesn't
+    // matter here
+    match family >> 4 {
+        // AF_UNSPEC
+        0 => Ok(None),
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let mut ip = [0u8; 4];
+
+            ip.copy_from_slice(&addresses[..4]);
+
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+
+            ip.copy_from_slice(&addresses[..16]);
+
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNIX, which doesn't contain useful information
+        3 => Ok(None),
+        _ => Err(anyhow::anyhow!("invalid PROXY protocol address block")),
+    }
+}
+
+/// Extract client IP from value of configured client IP header
+///
+/// For X-Forwarded-For, the rightmost address that doesn't belong to a
+/// trusted proxy is used, since addresses to the left of it can be set by
+/// clients.
+pub fn client_ip_from_header(
+    header: ClientIpHeader,
+    trusted_proxies: &IpAccessList,
+    value: &[u8],
+) -> Option<IpAddr> {
+    let value = ::std::str::from_utf8(value).ok()?;
+
+    match header {
+        ClientIpHeader::Off => None,
+        ClientIpHeader::XRealIp => parse_header_ip(value),
+        ClientIpHeader::XForwardedFor => {
+            let mut opt_ip = None;
+
+            for ip in value.rsplit(',') {
+                let ip = parse_header_ip(ip)?;
+
+                opt_ip = Some(ip);
+
+                if !trusted_proxies.contains(ip) {
+                    break;
+                }
+            }
+
+            opt_ip
+        }
+    }
+}
+
+/// Extract client IP from value of configured client IP header and check it
+/// against the IP access list
+///
+/// Returns the client IP as error if it is not allowed.
+pub fn allowed_client_ip_from_header(
+    header: ClientIpHeader,
+    trusted_proxies: &IpAccessList,
+    ip_access_list: &IpAccessList,
+    ip_access_list_mode: AccessListMode,
+    value: &[u8],
+) -> Result<Option<IpAddr>, IpAddr> {
+    match client_ip_from_header(header, trusted_proxies, value) {
+        Some(ip) if !ip_access_list.allows(ip_access_list_mode, ip) => Err(ip),
+        opt_ip => Ok(opt_ip),
+    }
+}
+
+/// Parse IP address, optionally with port
+fn parse_header_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_header(input: &[u8]) -> anyhow::Result<(usize, Option<SocketAddr>)> {
+        let mut len = 0;
+
+        loop {
+            match proxy_protocol_bytes_needed(&input[..len])? {
+                0 => return Ok((len, parse_proxy_protocol_header(&input[..len])?)),
+                n => len += n,
+            }
+
+            if len > input.len() {
+                return Err(anyhow::anyhow!("input ended"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_v1() {
+        let input = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+
+        assert_eq!(
+            read_header(input).unwrap(),
+            (45, Some("192.0.2.1:56324".parse().unwrap()))
+        );
+
+        let input = b"PROXY TCP6 2001:db8::1 2001:db8::2 1000 443\r\n";
+
+        assert_eq!(
+            read_header(input).unwrap().1,
+            Some("[2001:db8::1]:1000".parse().unwrap())
+        );
+
+        assert_eq!(read_header(b"PROXY UNKNOWN\r\nabcd").unwrap(), (15, None));
+
+        assert!(read_header(b"GET /announce HTTP/1.1\r\n").is_err());
+        assert!(read_header(b"PROXY TCP4 192.0.2.1\r\n").is_err());
+        assert!(read_header(&[PROXY_V1_PREFIX, &[b'0'; 200]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2() {
+        let mut input = PROXY_V2_SIGNATURE.to_vec();
+
+        // PROXY command, TCP over IPv4, 12 bytes of addresses
+        input.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        input.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0x12, 0x34, 1, 187]);
+        // TLS data
+        input.extend_from_slice(&[0x16, 0x03, 0x01]);
+
+        assert_eq!(
+            read_header(&input).unwrap(),
+            (28, Some("192.0.2.1:4660".parse().unwrap()))
+        );
+
+        // LOCAL command
+        input[12] = 0x20;
+
+        assert_eq!(read_header(&input).unwrap(), (28, None));
+
+        // Unsupported version
+        input[12] = 0x11;
+
+        assert!(read_header(&input).is_err());
+    }
+
+    #[test]
+    fn test_client_ip_from_header() {
+        let trusted_proxies =
+            IpAccessList::create_from_entries(["10.0.0.0/8", "2001:db8::/32"]).unwrap();
+
+        let f = |header, value: &str| {
+            client_ip_from_header(header, &trusted_proxies, value.as_bytes())
+                .map(|ip| ip.to_string())
+        };
+
+        assert_eq!(
+            f(
+                ClientIpHeader::XForwardedFor,
+                "192.0.2.9, 192.0.2.1, 10.0.0.2"
+            ),
+            Some("192.0.2.1".into())
+        );
+        assert_eq!(
+            f(
+                ClientIpHeader::XForwardedFor,
+                "[2001:db9::1]:80,2001:db8::5"
+            ),
+            Some("2001:db9::1".into())
+        );
+        // Leftmost address is used if all are trusted
+        assert_eq!(
+            f(ClientIpHeader::XForwardedFor, "10.0.0.1,10.0.0.2"),
+            Some("10.0.0.1".into())
+        );
+        assert_eq!(f(ClientIpHeader::XForwardedFor, "192.0.2.1, foo"), None);
+        assert_eq!(
+            f(ClientIpHeader::XRealIp, " 192.0.2.1 "),
+            Some("192.0.2.1".into())
+        );
+        assert_eq!(f(ClientIpHeader::XRealIp, "192.0.2.1, 192.0.2.2"), None);
+        assert_eq!(f(ClientIpHeader::Off, "192.0.2.1"), None);
+    }
+
+    #[test]
+    fn test_allowed_client_ip_from_header() {
+        let trusted_proxies = IpAccessList::create_from_entries(["10.0.0.0/8"]).unwrap();
+        let ip_access_list = IpAccessList::create_from_entries(["192.0.2.1"]).unwrap();
+
+        let f = |mode, value: &str| {
+            allowed_client_ip_from_header(
+                ClientIpHeader::XForwardedFor,
+                &trusted_proxies,
+                &ip_access_list,
+                mode,
+                value.as_bytes(),
+            )
+            .map(|opt_ip| opt_ip.map(|ip| ip.to_string()))
+            .map_err(|ip| ip.to_string())
+        };
+
+        assert_eq!(
+            f(AccessListMode::Deny, "192.0.2.1, 10.0.0.2"),
+            Err("192.0.2.1".into())
+        );
+        assert_eq!(
+            f(AccessListMode::Deny, "192.0.2.2, 10.0.0.2"),
+            Ok(Some("192.0.2.2".into()))
+        );
+        assert_eq!(
+            f(AccessListMode::Allow, "192.0.2.1, 10.0.0.2"),
+            Ok(Some("192.0.2.1".into()))
+        );
+        assert_eq!(
+            f(AccessListMode::Allow, "192.0.2.2, 10.0.0.2"),
+            Err("192.0.2.2".into())
+        );
+        assert_eq!(
+            f(AccessListMode::Off, "192.0.2.1"),
+            Ok(Some("192.0.2.1".into()))
+        );
+        // Invalid header value
+        assert_eq!(f(AccessListMode::Allow, "foo"), Ok(None));
+    }
+}
@@ -1,35 +1,335 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use hashbrown::HashMap;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::SignatureScheme;
+use serde::{Deserialize, Serialize};
 
 pub type RustlsConfig = rustls::ServerConfig;
+pub type RustlsConfigArcSwap = ArcSwap<RustlsConfig>;
+
+/// Certificate to use for TLS connections where client requests hostname
+/// through SNI
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSniCertificateConfig {
+    pub hostname: String,
+    /// Path to TLS certificate (PEM-encoded X.509)
+    pub certificate_path: PathBuf,
+    /// Path to TLS private key (PEM-encoded PKCS#8, PKCS#1 or SEC1)
+    pub private_key_path: PathBuf,
+}
 
+/// Create TLS config
+///
+/// The certificate in `tls_certificate_path` is used when the client doesn't
+/// request a hostname or when the hostname doesn't match any entry in
+/// `sni_certificates`.
 pub fn create_rustls_config(
     tls_certificate_path: &Path,
     tls_private_key_path: &Path,
+    sni_certificates: &[TlsSniCertificateConfig],
 ) -> anyhow::Result<RustlsConfig> {
+    let default = load_certified_key(tls_certificate_path, tls_private_key_path)?;
+
+    let mut by_hostname = HashMap::new();
+
+    for sni_certificate in sni_certificates {
+        let certified_key = load_certified_key(
+            &sni_certificate.certificate_path,
+            &sni_certificate.private_key_path,
+        )
+        .with_context(|| format!("load certificate for {}", sni_certificate.hostname))?;
+
+        by_hostname.insert(sni_certificate.hostname.to_ascii_lowercase(), certified_key);
+    }
+
+    let tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniCertificateResolver {
+            default,
+            by_hostname,
+        }));
+
+    Ok(tls_config)
+}
+
+/// Replace TLS config with one created from current certificate and private
+/// key files. On failure, the old config is kept.
+///
+/// Only new connections use the new config.
+pub fn update_rustls_config(
+    tls_certificate_path: &Path,
+    tls_private_key_path: &Path,
+    sni_certificates: &[TlsSniCertificateConfig],
+    tls_config: &RustlsConfigArcSwap,
+) -> anyhow::Result<()> {
+    match create_rustls_config(tls_certificate_path, tls_private_key_path, sni_certificates) {
+        Ok(new_config) => {
+            tls_config.store(Arc::new(new_config));
+
+            ::log::info!("TLS config updated");
+
+            Ok(())
+        }
+        Err(err) => {
+            ::log::error!("Updating TLS config failed, keeping old one: {:#}", err);
+
+            Err(err)
+        }
+    }
+}
+
+/// Reload TLS config from a background thread when modification times of
+/// certificate or private key files change
+///
+/// Files are checked every `interval`. If creating the new config fails, for
+/// instance because the certificate has been replaced but the matching
+/// private key hasn't been written yet, it is retried on every following
+/// check until it succeeds.
+pub fn spawn_rustls_config_watcher(
+    tls_certificate_path: PathBuf,
+    tls_private_key_path: PathBuf,
+    sni_certificates: Vec<TlsSniCertificateConfig>,
+    interval: Duration,
+    tls_config: Arc<RustlsConfigArcSwap>,
+) -> anyhow::Result<()> {
+    let paths = [tls_certificate_path.clone(), tls_private_key_path.clone()]
+        .into_iter()
+        .chain(sni_certificates.iter().flat_map(|sni_certificate| {
+            [
+                sni_certificate.certificate_path.clone(),
+                sni_certificate.private_key_path.clone(),
+            ]
+        }))
+        .collect::<Vec<_>>();
+
+    let mut last_modified = get_modified(&paths);
+    let mut reload_pending = false;
+
+    ::std::thread::Builder::new()
+        .name("tls-watcher".into())
+        .spawn(move || loop {
+            ::std::thread::sleep(interval);
+
+            let modified = get_modified(&paths);
+
+            if modified.is_some() && modified != last_modified {
+                ::log::info!("TLS certificate or private key file changed, reloading");
+
+                last_modified = modified;
+                reload_pending = true;
+            }
+
+            if reload_pending {
+                reload_pending = update_rustls_config(
+                    &tls_certificate_path,
+                    &tls_private_key_path,
+                    &sni_certificates,
+                    &tls_config,
+                )
+                .is_err();
+            }
+        })
+        .with_context(|| "spawn tls config watcher")?;
+
+    Ok(())
+}
+
+fn get_modified(paths: &[PathBuf]) -> Option<Vec<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            ::std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Picks certificate by hostname requested through SNI, falling back to
+/// default certificate
+struct SniCertificateResolver {
+    default: Arc<CertifiedKey>,
+    by_hostname: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniCertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certified_key = client_hello
+            .server_name()
+            .and_then(|hostname| self.by_hostname.get(&hostname.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+
+        Some(certified_key.clone())
+    }
+}
+
+fn load_certified_key(
+    certificate_path: &Path,
+    private_key_path: &Path,
+) -> anyhow::Result<Arc<CertifiedKey>> {
     let certs = {
-        let f = File::open(tls_certificate_path)?;
+        let f = File::open(certificate_path)
+            .with_context(|| format!("open {}", certificate_path.display()))?;
         let mut f = BufReader::new(f);
 
         rustls_pemfile::certs(&mut f)?
             .into_iter()
-            .map(|bytes| rustls::Certificate(bytes))
-            .collect()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>()
     };
 
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificates in {}",
+            certificate_path.display()
+        ));
+    }
+
     let private_key = {
-        let f = File::open(tls_private_key_path)?;
+        let f = File::open(private_key_path)
+            .with_context(|| format!("open {}", private_key_path.display()))?;
         let mut f = BufReader::new(f);
 
-        rustls_pemfile::pkcs8_private_keys(&mut f)?
-            .first()
-            .map(|bytes| rustls::PrivateKey(bytes.clone()))
-            .ok_or(anyhow::anyhow!("No private keys in file"))?
+        read_private_key(&mut f)?
+            .ok_or_else(|| anyhow::anyhow!("No private keys in {}", private_key_path.display()))?
     };
 
-    let tls_config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, private_key)?;
+    let signing_key = rustls::sign::any_supported_type(&private_key)
+        .map_err(|_| anyhow::anyhow!("Unsupported private key type"))?;
 
-    Ok(tls_config)
+    check_key_matches_certificate(&certs[0], signing_key.as_ref()).with_context(|| {
+        format!(
+            "check that {} matches {}",
+            private_key_path.display(),
+            certificate_path.display()
+        )
+    })?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+/// Check that private key belongs to end-entity certificate by signing a
+/// message and verifying the signature with the certificate's public key
+fn check_key_matches_certificate(
+    certificate: &rustls::Certificate,
+    signing_key: &dyn SigningKey,
+) -> anyhow::Result<()> {
+    const MESSAGE: &[u8] = b"aquatic private key check";
+
+    let signer = signing_key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PSS_SHA256,
+        ])
+        .ok_or_else(|| anyhow::anyhow!("No supported signature scheme for private key"))?;
+
+    let algorithm: &webpki::SignatureAlgorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        scheme => return Err(anyhow::anyhow!("Unsupported signature scheme {:?}", scheme)),
+    };
+
+    let signature = signer
+        .sign(MESSAGE)
+        .map_err(|err| anyhow::anyhow!("Signing failed: {}", err))?;
+
+    let certificate = webpki::EndEntityCert::try_from(certificate.0.as_slice())
+        .map_err(|err| anyhow::anyhow!("Couldn't parse certificate: {:?}", err))?;
+
+    certificate
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| anyhow::anyhow!("Private key doesn't match certificate"))
+}
+
+/// Read first PKCS#8, PKCS#1 (RSA) or SEC1 (EC) private key
+fn read_private_key(
+    reader: &mut dyn std::io::BufRead,
+) -> anyhow::Result<Option<rustls::PrivateKey>> {
+    loop {
+        match rustls_pemfile::read_one(reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(bytes))
+            | Some(rustls_pemfile::Item::RSAKey(bytes))
+            | Some(rustls_pemfile::Item::ECKey(bytes)) => {
+                return Ok(Some(rustls::PrivateKey(bytes)));
+            }
+            Some(_) => (),
+            None => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_private_key() {
+        let pem = |label: &str, contents: &str| {
+            format!(
+                "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+                label, contents, label
+            )
+        };
+
+        // Base64-encoded "cert" and "key"
+        let certificate = pem("CERTIFICATE", "Y2VydA==");
+
+        for label in ["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"] {
+            let input = certificate.clone() + &pem(label, "a2V5");
+
+            assert_eq!(
+                read_private_key(&mut input.as_bytes()).unwrap(),
+                Some(rustls::PrivateKey(b"key".to_vec())),
+            );
+        }
+
+        assert_eq!(read_private_key(&mut certificate.as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_load_certified_key_checks_key() {
+        let dir = ::std::env::temp_dir().join(format!(
+            "aquatic-rustls-config-test-{}",
+            ::std::process::id()
+        ));
+
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        let write_pair = |name: &str| {
+            let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+            let certificate_path = dir.join(format!("{}.crt", name));
+            let private_key_path = dir.join(format!("{}.key", name));
+
+            ::std::fs::write(&certificate_path, certificate.serialize_pem().unwrap()).unwrap();
+            ::std::fs::write(&private_key_path, certificate.serialize_private_key_pem()).unwrap();
+
+            (certificate_path, private_key_path)
+        };
+
+        let (certificate_a, private_key_a) = write_pair("a");
+        let (certificate_b, private_key_b) = write_pair("b");
+
+        assert!(load_certified_key(&certificate_a, &private_key_a).is_ok());
+        assert!(load_certified_key(&certificate_b, &private_key_b).is_ok());
+
+        assert!(load_certified_key(&certificate_a, &private_key_b).is_err());
+        assert!(create_rustls_config(&certificate_b, &private_key_a, &[]).is_err());
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,233 @@
+//! Swarm workers shared between trackers running in the same process
+//!
+//! Swarm workers of one tracker own the torrent maps. Socket workers of
+//! other trackers convert their requests to the protocol-neutral types in
+//! this module, send them to the swarm workers and encode the responses
+//! according to their own protocol.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use futures_channel::oneshot;
+
+use crate::full_scrape::FullScrapeStatistics;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SharedAnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+    None,
+}
+
+#[derive(Clone, Debug)]
+pub struct SharedAnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub bytes_left: usize,
+    pub event: SharedAnnounceEvent,
+    /// Maximum number of peers to return per IP version
+    pub max_peers: usize,
+    /// Address to register peer with in IPv4 swarm, if any
+    pub opt_ipv4: Option<Ipv4Addr>,
+    /// Address to register peer with in IPv6 swarm, if any
+    pub opt_ipv6: Option<Ipv6Addr>,
+    /// Return statistics for IPv4 swarm (otherwise IPv6 swarm)
+    pub statistics_ipv4: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SharedAnnounceResponse {
+    pub statistics: FullScrapeStatistics,
+    pub peers_ipv4: Vec<(Ipv4Addr, u16)>,
+    pub peers_ipv6: Vec<(Ipv6Addr, u16)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SharedScrapeRequest {
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Return statistics for IPv4 swarms (otherwise IPv6 swarms)
+    pub ipv4: bool,
+}
+
+/// Statistics for torrents in request that swarm worker knows about
+pub type SharedScrapeResponse = Vec<([u8; 20], FullScrapeStatistics)>;
+
+#[derive(Debug)]
+pub enum SharedSwarmRequest {
+    Announce(SharedAnnounceRequest, oneshot::Sender<SharedAnnounceResponse>),
+    Scrape(SharedScrapeRequest, oneshot::Sender<SharedScrapeResponse>),
+}
+
+/// Swarm worker responsible for torrent
+///
+/// Matches the distribution of torrents among swarm workers in the tracker
+/// owning the torrent maps.
+pub fn calculate_swarm_worker_index(info_hash: &[u8; 20], num_swarm_workers: usize) -> usize {
+    info_hash[0] as usize % num_swarm_workers
+}
+
+/// Create channels to swarm workers. Receivers are to be passed on to
+/// swarm workers, one each.
+///
+/// A channel size of zero means that channels are unbounded.
+pub fn create_shared_swarm_channels(
+    num_swarm_workers: usize,
+    channel_size: usize,
+) -> (SharedSwarmSender, Vec<Receiver<SharedSwarmRequest>>) {
+    let (senders, receivers) = (0..num_swarm_workers)
+        .map(|_| {
+            if channel_size == 0 {
+                unbounded()
+            } else {
+                bounded(channel_size)
+            }
+        })
+        .unzip();
+
+    (SharedSwarmSender { senders }, receivers)
+}
+
+/// Sends requests to shared swarm workers from socket workers
+///
+/// Swarm workers stop handling shared requests once all senders have been
+/// dropped.
+#[derive(Clone)]
+pub struct SharedSwarmSender {
+    senders: Vec<Sender<SharedSwarmRequest>>,
+}
+
+impl SharedSwarmSender {
+    pub async fn announce(
+        &self,
+        request: SharedAnnounceRequest,
+    ) -> anyhow::Result<SharedAnnounceResponse> {
+        let index = calculate_swarm_worker_index(&request.info_hash, self.senders.len());
+        let (response_sender, response_receiver) = oneshot::channel();
+
+        self.try_send_to(
+            index,
+            SharedSwarmRequest::Announce(request, response_sender),
+        )?;
+
+        response_receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("shared swarm worker dropped announce request"))
+    }
+
+    /// Send parts of request to relevant swarm workers and combine
+    /// responses
+    pub async fn scrape(&self, request: SharedScrapeRequest) -> anyhow::Result<SharedScrapeResponse> {
+        let mut info_hashes_by_worker = vec![Vec::new(); self.senders.len()];
+
+        for info_hash in request.info_hashes {
+            info_hashes_by_worker[calculate_swarm_worker_index(&info_hash, self.senders.len())]
+                .push(info_hash);
+        }
+
+        let mut response_receivers = Vec::new();
+
+        for (index, info_hashes) in info_hashes_by_worker.into_iter().enumerate() {
+            if info_hashes.is_empty() {
+                continue;
+            }
+
+            let (response_sender, response_receiver) = oneshot::channel();
+
+            let request = SharedScrapeRequest {
+                info_hashes,
+                ipv4: request.ipv4,
+            };
+
+            self.try_send_to(index, SharedSwarmRequest::Scrape(request, response_sender))?;
+
+            response_receivers.push(response_receiver);
+        }
+
+        let mut response = Vec::new();
+
+        for response_receiver in response_receivers {
+            let partial_response = response_receiver
+                .await
+                .map_err(|_| anyhow::anyhow!("shared swarm worker dropped scrape request"))?;
+
+            response.extend(partial_response);
+        }
+
+        Ok(response)
+    }
+
+    fn try_send_to(&self, index: usize, request: SharedSwarmRequest) -> anyhow::Result<()> {
+        match self.senders[index].try_send(request) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(anyhow::anyhow!(
+                "shared swarm channel {} is full, dropping request",
+                index
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(anyhow::anyhow!(
+                "shared swarm channel {} is disconnected",
+                index
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_swarm_sender_scrape() {
+        let (sender, receivers) = create_shared_swarm_channels(2, 0);
+
+        let handles = receivers
+            .into_iter()
+            .map(|receiver| {
+                ::std::thread::spawn(move || {
+                    while let Ok(request) = receiver.recv() {
+                        if let SharedSwarmRequest::Scrape(request, response_sender) = request {
+                            let response = request
+                                .info_hashes
+                                .into_iter()
+                                .map(|info_hash| {
+                                    let statistics = FullScrapeStatistics {
+                                        seeders: info_hash[0] as usize,
+                                        ..Default::default()
+                                    };
+
+                                    (info_hash, statistics)
+                                })
+                                .collect();
+
+                            response_sender.send(response).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let request = SharedScrapeRequest {
+            info_hashes: vec![[1; 20], [2; 20], [3; 20]],
+            ipv4: true,
+        };
+
+        let mut response = futures_executor::block_on(sender.scrape(request)).unwrap();
+
+        response.sort_unstable_by_key(|(info_hash, _)| *info_hash);
+
+        assert_eq!(
+            response
+                .iter()
+                .map(|(info_hash, statistics)| (info_hash[0], statistics.seeders))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2), (3, 3)]
+        );
+
+        drop(sender);
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often async workers check if shutdown has been triggered
+#[cfg(feature = "glommio")]
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shared shutdown state
+///
+/// Triggered by main thread on SIGTERM. Workers then stop accepting new
+/// requests and try to finish pending work before the deadline.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<ShutdownInner>);
+
+#[derive(Default)]
+struct ShutdownInner {
+    triggered: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn trigger(&self, grace_period: Duration) -> Instant {
+        let deadline = Instant::now() + grace_period;
+
+        *self.0.deadline.lock().unwrap() = Some(deadline);
+        self.0.triggered.store(true, Ordering::SeqCst);
+
+        deadline
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.triggered.load(Ordering::Relaxed)
+    }
+
+    /// Returns None if shutdown hasn't been triggered
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.0.deadline.lock().unwrap()
+    }
+
+    /// Resolves once shutdown has been triggered
+    #[cfg(feature = "glommio")]
+    pub async fn wait_for_trigger(&self) {
+        while !self.is_triggered() {
+            glommio::timer::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Resolves once shutdown has been triggered and the grace period has
+    /// passed
+    #[cfg(feature = "glommio")]
+    pub async fn wait_for_deadline(&self) {
+        self.wait_for_trigger().await;
+
+        if let Some(deadline) = self.deadline() {
+            glommio::timer::sleep(deadline.saturating_duration_since(Instant::now())).await;
+        }
+    }
+}
+
+type WorkerResult = (String, anyhow::Result<()>);
+
+/// Collects results of worker threads
+pub struct WorkerWatcher {
+    sender: Sender<WorkerResult>,
+    receiver: Receiver<WorkerResult>,
+    num_workers: usize,
+}
+
+impl Default for WorkerWatcher {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+
+        Self {
+            sender,
+            receiver,
+            num_workers: 0,
+        }
+    }
+}
+
+impl WorkerWatcher {
+    /// Create guard to be moved into worker thread
+    pub fn register(&mut self, name: String) -> WorkerGuard {
+        self.num_workers += 1;
+
+        WorkerGuard {
+            name,
+            sender: self.sender.clone(),
+            reported: false,
+        }
+    }
+
+    /// Wait for all registered workers to finish, but not past deadline
+    ///
+    /// Worker errors are logged. The first one is returned.
+    pub fn wait(self, deadline: Instant) -> anyhow::Result<()> {
+        let Self {
+            sender,
+            receiver,
+            num_workers,
+        } = self;
+
+        drop(sender);
+
+        let mut num_finished = 0;
+        let mut first_error = None;
+
+        while num_finished < num_workers {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok((name, result)) => {
+                    num_finished += 1;
+
+                    if let Err(err) = result {
+                        ::log::error!("worker {} failed: {:#}", name, err);
+
+                        if first_error.is_none() {
+                            first_error = Some(err.context(format!("worker {} failed", name)));
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    ::log::warn!(
+                        "{} workers didn't finish within shutdown grace period",
+                        num_workers - num_finished
+                    );
+
+                    break;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reports worker result to WorkerWatcher
+///
+/// Workers returning errors cause SIGTERM to be raised, so that the whole
+/// program shuts down. If the guard is dropped without reporting, e.g.,
+/// because the worker panicked, an error is reported.
+pub struct WorkerGuard {
+    name: String,
+    sender: Sender<WorkerResult>,
+    reported: bool,
+}
+
+impl WorkerGuard {
+    pub fn report(mut self, result: anyhow::Result<()>) {
+        if result.is_err() {
+            crate::raise_sigterm();
+        }
+
+        self.send(result);
+    }
+
+    fn send(&mut self, result: anyhow::Result<()>) {
+        self.reported = true;
+
+        let _ = self.sender.send((self.name.clone(), result));
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if !self.reported {
+            self.send(Err(anyhow::anyhow!("worker panicked")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_watcher() {
+        let mut watcher = WorkerWatcher::default();
+
+        let a = watcher.register("a".into());
+        let b = watcher.register("b".into());
+        let c = watcher.register("c".into());
+
+        a.report(Ok(()));
+        drop(b);
+
+        let handle = ::std::thread::spawn(move || c.report(Ok(())));
+
+        let err = watcher
+            .wait(Instant::now() + Duration::from_secs(10))
+            .unwrap_err();
+
+        assert_eq!(format!("{}", err), "worker b failed");
+
+        handle.join().unwrap();
+
+        let mut watcher = WorkerWatcher::default();
+
+        let _a = watcher.register("a".into());
+
+        assert!(watcher.wait(Instant::now()).is_ok());
+    }
+}
@@ -0,0 +1,515 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"AQSWARM\0";
+/// Bump when changing the layout of snapshot contents
+const FORMAT_VERSION: u32 = 2;
+const FILE_EXTENSION: &str = "snapshot";
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Periodically save swarm state to disk and restore it on startup
+    pub active: bool,
+    /// Directory to save snapshot files in (one per swarm worker)
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub directory: PathBuf,
+    /// Save snapshots this often (seconds)
+    pub interval: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            directory: "tmp/snapshots".into(),
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerSnapshot<I> {
+    pub peer_id: [u8; 20],
+    /// Key supplied by peer in announce request, if any
+    pub key: Option<String>,
+    pub ip_address: I,
+    pub port: u16,
+    pub seeding: bool,
+    /// Number of seconds peer was still valid for when snapshot was taken
+    pub valid_for: u64,
+    /// Whether peer is included in seeder/leecher counts. False for peers
+    /// counted in the swarm of the other IP version.
+    pub counted: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentSnapshot<I> {
+    pub info_hash: [u8; 20],
+    pub num_completed: usize,
+    pub peers: Vec<PeerSnapshot<I>>,
+}
+
+/// Torrent maps of one or more swarm workers
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwarmSnapshot {
+    pub ipv4: Vec<TorrentSnapshot<Ipv4Addr>>,
+    pub ipv6: Vec<TorrentSnapshot<Ipv6Addr>>,
+}
+
+impl SwarmSnapshot {
+    /// Read snapshot files and split them into one snapshot per swarm worker
+    ///
+    /// Files of swarm workers beyond `num_workers`, left over from running
+    /// with more swarm workers, are removed afterwards, since they would
+    /// otherwise be restored again on every start.
+    pub fn load(
+        config: &SnapshotConfig,
+        kind: &str,
+        max_peer_age: u64,
+        num_workers: usize,
+    ) -> anyhow::Result<Vec<Self>> {
+        let snapshots = Self::read(config, kind, max_peer_age)?.split(num_workers);
+
+        remove_stale_files(config, kind, num_workers)?;
+
+        Ok(snapshots)
+    }
+
+    /// Read and merge all snapshot files for the given tracker kind
+    ///
+    /// Peer validity is reduced by time elapsed since each snapshot was
+    /// taken and capped to `max_peer_age`. Expired peers and torrents
+    /// without peers are dropped. Files that can't be parsed are skipped.
+    pub fn read(config: &SnapshotConfig, kind: &str, max_peer_age: u64) -> anyhow::Result<Self> {
+        let mut snapshot = Self::default();
+
+        let entries = match fs::read_dir(&config.directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ::std::io::ErrorKind::NotFound => {
+                return Ok(snapshot);
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("read snapshot directory {}", config.directory.display())
+                });
+            }
+        };
+
+        let prefix = format!("{}-", kind);
+        let now = unix_timestamp();
+
+        for entry in entries {
+            let path = entry?.path();
+
+            let is_match = path.extension() == Some(FILE_EXTENSION.as_ref())
+                && matches!(
+                    path.file_name().and_then(|name| name.to_str()),
+                    Some(name) if name.starts_with(&prefix)
+                );
+
+            if !is_match {
+                continue;
+            }
+
+            match read_file(&path) {
+                Ok((created_at, file_snapshot)) => {
+                    let elapsed = now.saturating_sub(created_at);
+
+                    ::log::info!(
+                        "loaded swarm snapshot {} ({} seconds old)",
+                        path.display(),
+                        elapsed
+                    );
+
+                    merge_torrents(
+                        &mut snapshot.ipv4,
+                        file_snapshot.ipv4,
+                        elapsed,
+                        max_peer_age,
+                    );
+                    merge_torrents(
+                        &mut snapshot.ipv6,
+                        file_snapshot.ipv6,
+                        elapsed,
+                        max_peer_age,
+                    );
+                }
+                Err(err) => {
+                    ::log::warn!("ignoring swarm snapshot {}: {:#}", path.display(), err);
+                }
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Atomically replace snapshot file of given swarm worker
+    pub fn write(
+        &self,
+        config: &SnapshotConfig,
+        kind: &str,
+        worker_index: usize,
+    ) -> anyhow::Result<()> {
+        fs::create_dir_all(&config.directory)
+            .with_context(|| format!("create snapshot directory {}", config.directory.display()))?;
+
+        let path = config.directory.join(file_name(kind, worker_index));
+        let tmp_path = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(
+            File::create(&tmp_path)
+                .with_context(|| format!("create file {}", tmp_path.display()))?,
+        );
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&unix_timestamp().to_le_bytes())?;
+
+        bincode::serialize_into(&mut writer, self).with_context(|| "serialize snapshot")?;
+
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("rename {} to {}", tmp_path.display(), path.display()))?;
+
+        Ok(())
+    }
+
+    /// Split into one snapshot per swarm worker
+    ///
+    /// Torrents are assigned to swarm workers by the first byte of the
+    /// info hash, the same way requests are.
+    pub fn split(self, num_workers: usize) -> Vec<Self> {
+        let mut snapshots = vec![Self::default(); num_workers];
+
+        for torrent in self.ipv4 {
+            snapshots[torrent.info_hash[0] as usize % num_workers]
+                .ipv4
+                .push(torrent);
+        }
+        for torrent in self.ipv6 {
+            snapshots[torrent.info_hash[0] as usize % num_workers]
+                .ipv6
+                .push(torrent);
+        }
+
+        snapshots
+    }
+}
+
+/// Writes snapshots of one swarm worker on a background thread, so that
+/// serialization and disk I/O don't hold up request handling
+pub struct SnapshotWriter {
+    config: SnapshotConfig,
+    kind: &'static str,
+    worker_index: usize,
+    opt_handle: Option<JoinHandle<()>>,
+}
+
+impl SnapshotWriter {
+    pub fn new(config: SnapshotConfig, kind: &'static str, worker_index: usize) -> Self {
+        Self {
+            config,
+            kind,
+            worker_index,
+            opt_handle: None,
+        }
+    }
+
+    /// Create snapshot on current thread and write it on a background thread
+    ///
+    /// Skipped if the previous snapshot is still being written.
+    pub fn write_in_background(&mut self, create_snapshot: impl FnOnce() -> SwarmSnapshot) {
+        if let Some(handle) = self.opt_handle.as_ref() {
+            if !handle.is_finished() {
+                ::log::warn!("Previous swarm snapshot is still being written, skipping");
+
+                return;
+            }
+        }
+
+        let snapshot = create_snapshot();
+        let config = self.config.clone();
+        let kind = self.kind;
+        let worker_index = self.worker_index;
+
+        let result = ::std::thread::Builder::new()
+            .name(format!("snapshot-{}-{}", kind, worker_index))
+            .spawn(move || {
+                if let Err(err) = snapshot.write(&config, kind, worker_index) {
+                    ::log::error!("Couldn't write swarm snapshot: {:#}", err);
+                }
+            });
+
+        match result {
+            Ok(handle) => {
+                self.opt_handle = Some(handle);
+            }
+            Err(err) => {
+                ::log::error!("Couldn't spawn swarm snapshot writer thread: {:#}", err);
+            }
+        }
+    }
+
+    /// Wait for any background write to finish, then write snapshot on
+    /// current thread
+    pub fn write_final(&mut self, snapshot: SwarmSnapshot) -> anyhow::Result<()> {
+        if let Some(handle) = self.opt_handle.take() {
+            let _ = handle.join();
+        }
+
+        snapshot.write(&self.config, self.kind, self.worker_index)
+    }
+}
+
+fn file_name(kind: &str, worker_index: usize) -> String {
+    format!("{}-{}.{}", kind, worker_index, FILE_EXTENSION)
+}
+
+fn remove_stale_files(
+    config: &SnapshotConfig,
+    kind: &str,
+    num_workers: usize,
+) -> anyhow::Result<()> {
+    let entries = match fs::read_dir(&config.directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ::std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!("read snapshot directory {}", config.directory.display())
+            });
+        }
+    };
+
+    let prefix = format!("{}-", kind);
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if path.extension() != Some(FILE_EXTENSION.as_ref()) {
+            continue;
+        }
+
+        let opt_worker_index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(&prefix))
+            .and_then(|worker_index| worker_index.parse::<usize>().ok());
+
+        if matches!(opt_worker_index, Some(worker_index) if worker_index >= num_workers) {
+            fs::remove_file(&path)
+                .with_context(|| format!("remove stale snapshot {}", path.display()))?;
+
+            ::log::info!("removed stale swarm snapshot {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn read_file(path: &Path) -> anyhow::Result<(u64, SwarmSnapshot)> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    let mut version = [0u8; 4];
+    let mut created_at = [0u8; 8];
+
+    reader.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(anyhow::anyhow!("not a swarm snapshot file"));
+    }
+
+    reader.read_exact(&mut version)?;
+
+    let version = u32::from_le_bytes(version);
+
+    if version != FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "unsupported format version {} (expected {})",
+            version,
+            FORMAT_VERSION
+        ));
+    }
+
+    reader.read_exact(&mut created_at)?;
+
+    let snapshot = bincode::deserialize_from(reader).with_context(|| "deserialize snapshot")?;
+
+    Ok((u64::from_le_bytes(created_at), snapshot))
+}
+
+fn merge_torrents<I>(
+    torrents: &mut Vec<TorrentSnapshot<I>>,
+    new_torrents: Vec<TorrentSnapshot<I>>,
+    elapsed: u64,
+    max_peer_age: u64,
+) {
+    for mut torrent in new_torrents {
+        torrent.peers.retain_mut(|peer| {
+            peer.valid_for = peer.valid_for.min(max_peer_age).saturating_sub(elapsed);
+
+            peer.valid_for != 0
+        });
+
+        if !torrent.peers.is_empty() {
+            torrents.push(torrent);
+        }
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gen_torrent<I: Copy>(
+        info_hash_byte: u8,
+        ip_address: I,
+        valid_for: &[u64],
+    ) -> TorrentSnapshot<I> {
+        TorrentSnapshot {
+            info_hash: [info_hash_byte; 20],
+            num_completed: 3,
+            peers: valid_for
+                .iter()
+                .enumerate()
+                .map(|(i, valid_for)| PeerSnapshot {
+                    peer_id: [i as u8; 20],
+                    key: None,
+                    ip_address,
+                    port: 1000 + i as u16,
+                    seeding: i % 2 == 0,
+                    valid_for: *valid_for,
+                    counted: true,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_merge_torrents() {
+        let mut torrents = Vec::new();
+
+        merge_torrents(
+            &mut torrents,
+            vec![
+                gen_torrent(0, Ipv4Addr::LOCALHOST, &[100, 5, 10_000]),
+                gen_torrent(1, Ipv4Addr::LOCALHOST, &[10]),
+            ],
+            10,
+            200,
+        );
+
+        // Expired peers and torrents without peers are dropped, validity is
+        // capped to max peer age
+        let mut expected = gen_torrent(0, Ipv4Addr::LOCALHOST, &[90, 190]);
+
+        expected.peers[1].peer_id = [2; 20];
+        expected.peers[1].port = 1002;
+        expected.peers[1].seeding = true;
+
+        assert_eq!(torrents, vec![expected]);
+    }
+
+    #[test]
+    fn test_write_read_snapshot() {
+        let config = SnapshotConfig {
+            active: true,
+            directory: ::std::env::temp_dir()
+                .join(format!("aquatic-snapshot-test-{}", ::std::process::id())),
+            interval: 1,
+        };
+
+        let snapshot = SwarmSnapshot {
+            ipv4: vec![gen_torrent(0, Ipv4Addr::LOCALHOST, &[100, 200])],
+            ipv6: vec![gen_torrent(1, Ipv6Addr::LOCALHOST, &[100])],
+        };
+
+        snapshot.write(&config, "test", 0).unwrap();
+        // Snapshots of other tracker kinds are ignored
+        snapshot.write(&config, "other", 0).unwrap();
+
+        let restored = SwarmSnapshot::read(&config, "test", 1000).unwrap();
+
+        fs::remove_dir_all(&config.directory).unwrap();
+
+        assert_eq!(restored.ipv4.len(), 1);
+        assert_eq!(restored.ipv6.len(), 1);
+        assert_eq!(restored.ipv4[0].peers.len(), 2);
+        assert_eq!(restored.ipv4[0].num_completed, 3);
+
+        let split = restored.split(2);
+
+        assert_eq!(split[0].ipv4.len(), 1);
+        assert_eq!(split[0].ipv6.len(), 0);
+        assert_eq!(split[1].ipv4.len(), 0);
+        assert_eq!(split[1].ipv6.len(), 1);
+    }
+
+    #[test]
+    fn test_load_removes_stale_files() {
+        let config = SnapshotConfig {
+            active: true,
+            directory: ::std::env::temp_dir().join(format!(
+                "aquatic-snapshot-stale-test-{}",
+                ::std::process::id()
+            )),
+            interval: 1,
+        };
+
+        let snapshot = SwarmSnapshot {
+            ipv4: vec![gen_torrent(0, Ipv4Addr::LOCALHOST, &[100])],
+            ipv6: vec![gen_torrent(1, Ipv6Addr::LOCALHOST, &[100])],
+        };
+
+        snapshot.write(&config, "test", 0).unwrap();
+        snapshot.write(&config, "test", 1).unwrap();
+        snapshot.write(&config, "test", 2).unwrap();
+        snapshot.write(&config, "other", 2).unwrap();
+
+        let loaded = SwarmSnapshot::load(&config, "test", 1000, 2).unwrap();
+
+        let exists = |kind, worker_index| {
+            config
+                .directory
+                .join(file_name(kind, worker_index))
+                .exists()
+        };
+
+        let remaining = (
+            exists("test", 0),
+            exists("test", 1),
+            exists("test", 2),
+            exists("other", 2),
+        );
+
+        fs::remove_dir_all(&config.directory).unwrap();
+
+        // Contents of all files are still restored
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].ipv4.len(), 3);
+        assert_eq!(loaded[1].ipv6.len(), 3);
+
+        assert_eq!(remaining, (true, true, false, true));
+    }
+}
@@ -0,0 +1,206 @@
+//! Sockets inherited from a service manager such as systemd
+//!
+//! Implements the `LISTEN_PID`/`LISTEN_FDS` protocol (see sd_listen_fds(3)).
+//! Inherited sockets are matched to listeners by socket type and local
+//! address. If exactly one matching socket was passed, it is shared by all
+//! socket workers. If several were passed (e.g., a SO_REUSEPORT group
+//! created by several `ListenDatagram` lines with `ReusePort=yes`), each
+//! socket worker takes one of them.
+
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::os::unix::prelude::{FromRawFd, RawFd};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use socket2::{Socket, Type};
+
+/// First file descriptor passed on by service manager
+const LISTEN_FDS_START: RawFd = 3;
+
+static INHERITED_SOCKETS: Lazy<Mutex<Result<Vec<InheritedSocket>, String>>> = Lazy::new(|| {
+    let sockets = read_inherited_sockets().map_err(|err| format!("{:#}", err));
+
+    Mutex::new(sockets)
+});
+
+struct InheritedSocket {
+    socket: Socket,
+    socket_type: Type,
+    address: SocketAddr,
+    taken: bool,
+}
+
+impl InheritedSocket {
+    fn matches(&self, socket_type: Type, address: SocketAddr) -> bool {
+        self.socket_type == socket_type && self.address == address
+    }
+}
+
+/// Take inherited socket of given type bound to address, if any was passed
+/// on by the service manager
+///
+/// Returns `Ok(None)` if no matching socket was inherited, in which case
+/// the caller should create its own socket. Returns an error if matching
+/// sockets were inherited but all have already been taken.
+pub fn take_inherited_socket(
+    socket_type: Type,
+    address: SocketAddr,
+) -> anyhow::Result<Option<Socket>> {
+    let mut sockets = INHERITED_SOCKETS.lock().unwrap();
+
+    let sockets = match sockets.as_mut() {
+        Ok(sockets) => sockets,
+        Err(err) => {
+            return Err(anyhow::anyhow!("read inherited sockets: {}", err));
+        }
+    };
+
+    let num_matching = sockets
+        .iter()
+        .filter(|socket| socket.matches(socket_type, address))
+        .count();
+
+    let opt_socket = match num_matching {
+        0 => return Ok(None),
+        // A single socket is shared by all socket workers
+        1 => sockets
+            .iter()
+            .find(|socket| socket.matches(socket_type, address)),
+        _ => sockets
+            .iter_mut()
+            .find(|socket| socket.matches(socket_type, address) && !socket.taken)
+            .map(|socket| {
+                socket.taken = true;
+
+                &*socket
+            }),
+    };
+
+    match opt_socket {
+        Some(socket) => {
+            let socket = socket
+                .socket
+                .try_clone()
+                .with_context(|| format!("duplicate inherited socket bound to {}", address))?;
+
+            Ok(Some(socket))
+        }
+        None => Err(anyhow::anyhow!(
+            "all inherited sockets bound to {} have already been taken. Pass on either one socket or one per socket worker",
+            address
+        )),
+    }
+}
+
+/// Returns whether the service manager passed on any file descriptors to
+/// this process
+pub fn sockets_inherited() -> anyhow::Result<bool> {
+    Ok(!listen_fds()?.is_empty())
+}
+
+fn read_inherited_sockets() -> anyhow::Result<Vec<InheritedSocket>> {
+    let fds = listen_fds()?;
+
+    let mut sockets = Vec::new();
+
+    for fd in fds {
+        // Safety: service manager passes on ownership of these file
+        // descriptors, and this function is only called once
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+
+        socket
+            .set_cloexec(true)
+            .with_context(|| format!("set close-on-exec for inherited fd {}", fd))?;
+
+        let socket_type = match socket.r#type() {
+            Ok(socket_type) => socket_type,
+            Err(err) => {
+                ::log::warn!("ignoring inherited fd {}: not a socket: {:#}", fd, err);
+
+                continue;
+            }
+        };
+
+        let address = match socket.local_addr().map(|address| address.as_socket()) {
+            Ok(Some(address)) => address,
+            _ => {
+                ::log::warn!("ignoring inherited fd {}: not an inet socket", fd);
+
+                continue;
+            }
+        };
+
+        ::log::info!("using inherited socket bound to {} (fd {})", address, fd);
+
+        sockets.push(InheritedSocket {
+            socket,
+            socket_type,
+            address,
+            taken: false,
+        });
+    }
+
+    Ok(sockets)
+}
+
+fn listen_fds() -> anyhow::Result<Range<RawFd>> {
+    parse_listen_fds(
+        ::std::env::var("LISTEN_PID").ok().as_deref(),
+        ::std::env::var("LISTEN_FDS").ok().as_deref(),
+        ::std::process::id(),
+    )
+}
+
+/// Get range of file descriptors passed on by service manager, if they
+/// were meant for this process
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> anyhow::Result<Range<RawFd>> {
+    let listen_pid = match listen_pid {
+        Some(listen_pid) => listen_pid,
+        None => return Ok(0..0),
+    };
+
+    let listen_pid: u32 = listen_pid
+        .parse()
+        .with_context(|| format!("parse LISTEN_PID: {}", listen_pid))?;
+
+    if listen_pid != pid {
+        return Ok(0..0);
+    }
+
+    let listen_fds =
+        listen_fds.ok_or_else(|| anyhow::anyhow!("LISTEN_PID set but not LISTEN_FDS"))?;
+
+    let num_fds: RawFd = listen_fds
+        .parse()
+        .with_context(|| format!("parse LISTEN_FDS: {}", listen_fds))?;
+
+    if num_fds < 0 {
+        return Err(anyhow::anyhow!("LISTEN_FDS is negative: {}", num_fds));
+    }
+
+    Ok(LISTEN_FDS_START..LISTEN_FDS_START + num_fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds(None, None, 100).unwrap(), 0..0);
+        assert_eq!(parse_listen_fds(None, Some("2"), 100).unwrap(), 0..0);
+        assert_eq!(parse_listen_fds(Some("99"), Some("2"), 100).unwrap(), 0..0);
+        assert_eq!(parse_listen_fds(Some("100"), Some("2"), 100).unwrap(), 3..5);
+        assert_eq!(parse_listen_fds(Some("100"), Some("0"), 100).unwrap(), 3..3);
+
+        assert!(parse_listen_fds(Some("100"), None, 100).is_err());
+        assert!(parse_listen_fds(Some("100"), Some("-1"), 100).is_err());
+        assert!(parse_listen_fds(Some("abc"), Some("2"), 100).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aquatic_toml_config::TomlConfig;
+use arc_swap::{ArcSwap, Cache};
+use hashbrown::HashSet;
+use serde::Deserialize;
+
+use crate::access_list::AccessListMode;
+
+/// URL path used for requests that don't include one, e.g., UDP announce
+/// requests without BEP 41 URL data
+pub const DEFAULT_URL_PATH: &[u8] = b"/";
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UrlAccessListConfig {
+    /// Access list mode. Available modes are allow, deny and off.
+    ///
+    /// In allow mode, only announce requests with URL paths matching an
+    /// entry in the file are served. In deny mode, matching requests are
+    /// rejected.
+    pub mode: AccessListMode,
+    /// Path to URL access list file consisting of newline-separated URL
+    /// paths including any query string, e.g., /announce or
+    /// /abc123/announce?passkey=def456. Requests that don't include a URL
+    /// path are matched as /. Empty lines and lines starting with # are
+    /// ignored.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+}
+
+impl Default for UrlAccessListConfig {
+    fn default() -> Self {
+        Self {
+            mode: AccessListMode::Off,
+            path: "".into(),
+        }
+    }
+}
+
+/// Set of exact URL paths (including query strings)
+#[derive(Default, Clone, Debug)]
+pub struct UrlAccessList(HashSet<Vec<u8>>);
+
+impl UrlAccessList {
+    pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+
+        Self::create_from_reader(BufReader::new(file))
+    }
+
+    fn create_from_reader(reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut new_list = Self::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let trimmed = line.trim();
+
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            new_list.0.insert(trimmed.as_bytes().to_vec());
+        }
+
+        Ok(new_list)
+    }
+
+    /// Check if URL path is allowed. Pass `None` if request didn't include
+    /// a URL path.
+    pub fn allows(&self, mode: AccessListMode, opt_url_path: Option<&[u8]>) -> bool {
+        let url_path = opt_url_path.unwrap_or(DEFAULT_URL_PATH);
+
+        match mode {
+            AccessListMode::Allow => self.0.contains(url_path),
+            AccessListMode::Deny => !self.0.contains(url_path),
+            AccessListMode::Off => true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+pub type UrlAccessListArcSwap = ArcSwap<UrlAccessList>;
+pub type UrlAccessListCache = Cache<Arc<UrlAccessListArcSwap>, Arc<UrlAccessList>>;
+
+pub fn create_url_access_list_cache(arc_swap: &Arc<UrlAccessListArcSwap>) -> UrlAccessListCache {
+    Cache::from(Arc::clone(arc_swap))
+}
+
+pub fn update_url_access_list(
+    config: &UrlAccessListConfig,
+    url_access_list: &Arc<UrlAccessListArcSwap>,
+) -> anyhow::Result<()> {
+    if config.mode.is_on() {
+        match UrlAccessList::create_from_path(&config.path) {
+            Ok(new_list) => {
+                url_access_list.store(Arc::new(new_list));
+
+                ::log::info!("URL access list updated")
+            }
+            Err(err) => {
+                ::log::error!("Updating URL access list failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_access_list() {
+        let input = "\
+# comment
+/
+
+/abc123/announce
+  /announce?passkey=def456
+";
+        let list = UrlAccessList::create_from_reader(input.as_bytes()).unwrap();
+
+        assert_eq!(list.len(), 3);
+
+        let f = |mode, url_path: Option<&str>| list.allows(mode, url_path.map(str::as_bytes));
+
+        assert!(f(AccessListMode::Allow, None));
+        assert!(f(AccessListMode::Allow, Some("/")));
+        assert!(f(AccessListMode::Allow, Some("/abc123/announce")));
+        assert!(f(AccessListMode::Allow, Some("/announce?passkey=def456")));
+
+        assert!(!f(AccessListMode::Allow, Some("/announce")));
+        assert!(!f(AccessListMode::Allow, Some("/abc123/announce?x=y")));
+        assert!(!f(AccessListMode::Allow, Some("/announce?passkey=def457")));
+
+        assert!(!f(AccessListMode::Deny, None));
+        assert!(f(AccessListMode::Deny, Some("/announce")));
+        assert!(f(AccessListMode::Off, Some("/announce")));
+    }
+}
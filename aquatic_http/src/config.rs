@@ -1,11 +1,14 @@
 use std::{net::SocketAddr, path::PathBuf};
 
 use aquatic_common::{
-    access_list::AccessListConfig, cpu_pinning::asc::CpuPinningConfigAsc,
-    privileges::PrivilegeConfig,
+    access_list::AccessListConfig, announce_events::AnnounceEventsConfig, control::ControlConfig,
+    cpu_pinning::asc::CpuPinningConfigAsc, deserialize_one_or_many, full_scrape::FullScrapeConfig,
+    ip_access_list::IpAccessListConfig, metrics::MetricsConfig, privileges::PrivilegeConfig,
+    reverse_proxy::ReverseProxyConfig, rustls_config::TlsSniCertificateConfig,
+    snapshot::SnapshotConfig,
 };
 use aquatic_toml_config::TomlConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 
@@ -21,11 +24,21 @@ pub struct Config {
     /// generate responses and send them back to the socket workers.
     pub swarm_workers: usize,
     pub log_level: LogLevel,
+    /// After receiving SIGTERM, let workers finish handling pending
+    /// requests for at most this many seconds before exiting
+    pub shutdown_grace_period: u64,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub cleaning: CleaningConfig,
+    pub metrics: MetricsConfig,
+    pub control: ControlConfig,
+    pub announce_events: AnnounceEventsConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub ip_access_list: IpAccessListConfig,
+    pub reverse_proxy: ReverseProxyConfig,
+    pub full_scrape: FullScrapeConfig,
+    pub snapshot: SnapshotConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
 }
 
@@ -35,11 +48,19 @@ impl Default for Config {
             socket_workers: 1,
             swarm_workers: 1,
             log_level: LogLevel::default(),
+            shutdown_grace_period: 10,
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             cleaning: CleaningConfig::default(),
+            metrics: MetricsConfig::default(),
+            control: ControlConfig::default(),
+            announce_events: AnnounceEventsConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            ip_access_list: IpAccessListConfig::default(),
+            reverse_proxy: ReverseProxyConfig::default(),
+            full_scrape: FullScrapeConfig::default(),
+            snapshot: SnapshotConfig::default(),
             cpu_pinning: Default::default(),
         }
     }
@@ -54,16 +75,49 @@ impl aquatic_common::cli::Config for Config {
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
-    /// Bind to this address
-    pub address: SocketAddr,
+    /// Accept TLS connections on `address`
+    pub enable_tls: bool,
+    /// Bind TLS listeners to these addresses
+    ///
+    /// Accepts a single address or a list, e.g.,
+    /// `["0.0.0.0:3000", "[::]:3000"]`. Every socket worker serves all of
+    /// them.
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub address: Vec<SocketAddr>,
+    /// Accept plaintext (non-TLS) connections on `plaintext_address`
+    ///
+    /// Useful when TLS is terminated by a reverse proxy or when serving
+    /// http:// announce URLs.
+    pub enable_plaintext: bool,
+    /// Bind plaintext listeners to these addresses (single address or list)
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub plaintext_address: Vec<SocketAddr>,
     /// Only allow access over IPv6
     pub only_ipv6: bool,
     /// Maximum number of pending TCP connections
     pub tcp_backlog: i32,
-    /// Path to TLS certificate (DER-encoded X.509)
+    /// Path to TLS certificate (PEM-encoded X.509)
     pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
+    /// Path to TLS private key (PEM-encoded PKCS#8, PKCS#1 or SEC1)
     pub tls_private_key_path: PathBuf,
+    /// Additional certificates to use when clients request matching
+    /// hostnames through SNI. The certificate in `tls_certificate_path` is
+    /// used when no hostname is requested or it doesn't match any entry.
+    ///
+    /// Example:
+    /// tls_sni_certificates = [{ hostname = "tracker.example.com", certificate_path = "cert.pem", private_key_path = "key.pem" }]
+    pub tls_sni_certificates: Vec<TlsSniCertificateConfig>,
+    /// Check TLS certificate and private key files for changes this often
+    /// (seconds) and reload them if they were modified. Set to zero to
+    /// disable. Files are also reloaded on SIGUSR1.
+    ///
+    /// Existing connections keep using the old certificate. If the new
+    /// files can't be loaded, e.g. because the private key doesn't match the
+    /// certificate, an error is logged, the old certificate is kept and
+    /// loading is retried on every check. If using chroot mode, paths must be
+    /// valid both before and after entering the new root for reloading to
+    /// work.
+    pub tls_reload_interval: u64,
     /// Keep connections alive after sending a response
     pub keep_alive: bool,
 }
@@ -71,9 +125,14 @@ pub struct NetworkConfig {
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
-            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            enable_tls: true,
+            address: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            enable_plaintext: false,
+            plaintext_address: vec![SocketAddr::from(([0, 0, 0, 0], 3080))],
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_sni_certificates: Vec::new(),
+            tls_reload_interval: 60,
             only_ipv6: false,
             tcp_backlog: 1024,
             keep_alive: true,
@@ -90,6 +149,16 @@ pub struct ProtocolConfig {
     pub max_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Use IP addresses sent by peers in announce requests (ip, ipv4 and
+    /// ipv6 parameters). Available values are never, private and always.
+    ///
+    /// In private mode, the addresses are only used if the request was
+    /// sent from a private, loopback or link-local address.
+    ///
+    /// Peers announcing both an IPv4 and an IPv6 address are added to the
+    /// swarms of both IP versions, but are only counted once in announce
+    /// and scrape statistics.
+    pub announced_ip_policy: AnnouncedIpPolicy,
 }
 
 impl Default for ProtocolConfig {
@@ -98,10 +167,20 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 100,
             max_peers: 50,
             peer_announce_interval: 120,
+            announced_ip_policy: AnnouncedIpPolicy::Never,
         }
     }
 }
 
+/// When to use IP addresses sent in announce requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnouncedIpPolicy {
+    Never,
+    Private,
+    Always,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct CleaningConfig {
@@ -1,20 +1,34 @@
+use anyhow::Context;
 use aquatic_common::{
     access_list::update_access_list,
+    announce_events::spawn_announce_record_writer,
+    control::{spawn_control_server, SwarmCommandRequest},
     cpu_pinning::{
         glommio::{get_worker_placement, set_affinity_for_util_worker},
         WorkerIndex,
     },
+    ip_access_list::update_ip_access_list,
+    metrics::spawn_metrics_server,
     privileges::PrivilegeDropper,
-    rustls_config::create_rustls_config,
+    rustls_config::{
+        create_rustls_config, spawn_rustls_config_watcher, update_rustls_config,
+        RustlsConfigArcSwap,
+    },
+    shared_swarm::SharedSwarmSender,
+    shutdown::WorkerWatcher,
+    snapshot::SwarmSnapshot,
     PanicSentinelWatcher,
 };
+use aquatic_http_protocol::common::InfoHash;
 use common::State;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
 use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
 use signal_hook::{
     consts::{SIGTERM, SIGUSR1},
     iterator::Signals,
 };
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::config::Config;
 
@@ -22,112 +36,347 @@ mod common;
 pub mod config;
 mod workers;
 
-pub const APP_NAME: &str = "aquatic_http: BitTorrent tracker (HTTP over TLS)";
+pub const APP_NAME: &str = "aquatic_http: BitTorrent tracker (HTTP)";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const SHARED_CHANNEL_SIZE: usize = 1024;
+const SNAPSHOT_KIND: &str = "http";
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
-    let state = State::default();
+    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
+    let tracker = Tracker::start(config, priv_dropper, None)?;
 
-    update_access_list(&config.access_list, &state.access_list)?;
+    for signal in &mut signals {
+        match signal {
+            SIGUSR1 => tracker.reload(),
+            SIGTERM => break,
+            _ => unreachable!(),
+        }
+    }
 
-    let num_peers = config.socket_workers + config.swarm_workers;
+    ::log::info!("shutting down");
 
-    let request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_CHANNEL_SIZE);
+    let deadline = tracker.trigger_shutdown();
 
-    let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
-    let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
+    tracker.wait(deadline)
+}
 
-    let tls_config = Arc::new(create_rustls_config(
-        &config.network.tls_certificate_path,
-        &config.network.tls_private_key_path,
-    )?);
-
-    let mut executors = Vec::new();
-
-    for i in 0..(config.socket_workers) {
-        let sentinel = sentinel.clone();
-        let config = config.clone();
-        let state = state.clone();
-        let tls_config = tls_config.clone();
-        let request_mesh_builder = request_mesh_builder.clone();
-        let priv_dropper = priv_dropper.clone();
-
-        let placement = get_worker_placement(
-            &config.cpu_pinning,
-            config.socket_workers,
-            config.swarm_workers,
-            WorkerIndex::SocketWorker(i),
-        )?;
-        let builder = LocalExecutorBuilder::new(placement).name("socket");
-
-        let executor = builder
-            .spawn(move || async move {
-                workers::socket::run_socket_worker(
-                    sentinel,
-                    config,
-                    state,
-                    tls_config,
-                    request_mesh_builder,
-                    priv_dropper,
-                )
-                .await
-            })
-            .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?;
-
-        executors.push(executor);
-    }
+/// Running tracker
+///
+/// Signal handling is left to the caller, so that several trackers can be
+/// run in the same process.
+pub struct Tracker {
+    config: Config,
+    state: State,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+    worker_watcher: WorkerWatcher,
+}
 
-    for i in 0..(config.swarm_workers) {
-        let sentinel = sentinel.clone();
-        let config = config.clone();
-        let state = state.clone();
-        let request_mesh_builder = request_mesh_builder.clone();
-
-        let placement = get_worker_placement(
-            &config.cpu_pinning,
-            config.socket_workers,
-            config.swarm_workers,
-            WorkerIndex::SwarmWorker(i),
-        )?;
-        let builder = LocalExecutorBuilder::new(placement).name("request");
-
-        let executor = builder
-            .spawn(move || async move {
-                workers::swarm::run_swarm_worker(sentinel, config, state, request_mesh_builder)
-                    .await
-            })
-            .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?;
-
-        executors.push(executor);
-    }
+impl Tracker {
+    /// Start workers
+    ///
+    /// Privileges are dropped by `priv_dropper` once all socket workers have
+    /// created their sockets.
+    ///
+    /// If `opt_shared_swarm` is set, requests are handled by swarm workers
+    /// of another tracker in the same process and no swarm workers are
+    /// started. Snapshots and full scrapes are not supported in that case.
+    pub fn start(
+        config: Config,
+        priv_dropper: PrivilegeDropper,
+        opt_shared_swarm: Option<SharedSwarmSender>,
+    ) -> ::anyhow::Result<Self> {
+        if opt_shared_swarm.is_some() && config.full_scrape.active {
+            return Err(anyhow::anyhow!(
+                "full_scrape.active can't be set when using shared swarm workers"
+            ));
+        }
+        // Announces are handled (and exported) by the other tracker
+        if opt_shared_swarm.is_some() && config.announce_events.active {
+            return Err(anyhow::anyhow!(
+                "announce_events.active can't be set when using shared swarm workers"
+            ));
+        }
 
-    if config.cpu_pinning.active {
-        set_affinity_for_util_worker(
-            &config.cpu_pinning,
-            config.socket_workers,
-            config.swarm_workers,
-        )?;
-    }
+        let mut state = State::new(config.swarm_workers);
 
-    for signal in &mut signals {
-        match signal {
-            SIGUSR1 => {
-                let _ = update_access_list(&config.access_list, &state.access_list);
+        state.trusted_proxies = Arc::new(
+            config
+                .reverse_proxy
+                .parse_trusted_proxies()
+                .with_context(|| "parse trusted proxies")?,
+        );
+
+        update_access_list(&config.access_list, &state.access_list)?;
+        update_ip_access_list(&config.ip_access_list, &state.ip_access_list)?;
+
+        if config.metrics.active {
+            spawn_metrics_server(&config.metrics, state.metrics_registry.clone())?;
+        }
+        if config.announce_events.active {
+            state.announce_records = Some(spawn_announce_record_writer(
+                &config.announce_events,
+                &state.metrics_registry,
+            )?);
+        }
+
+        // Swarm workers take the snapshot matching their request consumer index
+        let snapshots = if config.snapshot.active && opt_shared_swarm.is_none() {
+            SwarmSnapshot::load(
+                &config.snapshot,
+                SNAPSHOT_KIND,
+                config.cleaning.max_peer_age,
+                config.swarm_workers,
+            )?
+        } else {
+            vec![SwarmSnapshot::default(); config.swarm_workers]
+        };
+        let snapshots = Arc::new(Mutex::new(snapshots));
+
+        let num_peers = config.socket_workers + config.swarm_workers;
+
+        let request_mesh_builder = MeshBuilder::partial(num_peers, SHARED_CHANNEL_SIZE);
+
+        let (_, sentinel) = PanicSentinelWatcher::create_with_sentinel();
+        let mut worker_watcher = WorkerWatcher::default();
+
+        if !(config.network.enable_tls || config.network.enable_plaintext) {
+            return Err(anyhow::anyhow!(
+                "at least one of network.enable_tls and network.enable_plaintext must be set"
+            ));
+        }
+        if config.network.enable_tls && config.network.address.is_empty() {
+            return Err(anyhow::anyhow!(
+                "network.address must not be empty when network.enable_tls is set"
+            ));
+        }
+        if config.network.enable_plaintext && config.network.plaintext_address.is_empty() {
+            return Err(anyhow::anyhow!(
+                "network.plaintext_address must not be empty when network.enable_plaintext is set"
+            ));
+        }
+
+        let opt_tls_config = if config.network.enable_tls {
+            let tls_config = Arc::new(RustlsConfigArcSwap::from_pointee(create_rustls_config(
+                &config.network.tls_certificate_path,
+                &config.network.tls_private_key_path,
+                &config.network.tls_sni_certificates,
+            )?));
+
+            if config.network.tls_reload_interval != 0 {
+                spawn_rustls_config_watcher(
+                    config.network.tls_certificate_path.clone(),
+                    config.network.tls_private_key_path.clone(),
+                    config.network.tls_sni_certificates.clone(),
+                    Duration::from_secs(config.network.tls_reload_interval),
+                    tls_config.clone(),
+                )?;
             }
-            SIGTERM => {
-                if sentinel_watcher.panic_was_triggered() {
-                    return Err(anyhow::anyhow!("worker thread panicked"));
-                } else {
-                    return Ok(());
-                }
+
+            Some(tls_config)
+        } else {
+            None
+        };
+
+        let mut command_senders = Vec::new();
+        let mut command_receivers = Vec::new();
+
+        // In shared swarm mode, torrents are handled by the other tracker
+        if opt_shared_swarm.is_none() {
+            for _ in 0..config.swarm_workers {
+                let (command_sender, command_receiver) = unbounded();
+
+                command_senders.push(command_sender);
+                command_receivers.push(Some(command_receiver));
             }
-            _ => unreachable!(),
         }
+
+        let command_receivers = Arc::new(Mutex::new(command_receivers));
+
+        if config.control.active {
+            spawn_control_server_for_tracker(
+                &config,
+                &state,
+                opt_tls_config.clone(),
+                command_senders,
+            )?;
+        }
+
+        for i in 0..(config.socket_workers) {
+            let sentinel = sentinel.clone();
+            let config = config.clone();
+            let state = state.clone();
+            let opt_tls_config = opt_tls_config.clone();
+            let request_mesh_builder = request_mesh_builder.clone();
+            let priv_dropper = priv_dropper.clone();
+            let opt_shared_swarm = opt_shared_swarm.clone();
+
+            let placement = get_worker_placement(
+                &config.cpu_pinning,
+                config.socket_workers,
+                config.swarm_workers,
+                WorkerIndex::SocketWorker(i),
+            )?;
+            let builder = LocalExecutorBuilder::new(placement).name("socket");
+            let worker_guard = worker_watcher.register(format!("socket-{:02}", i + 1));
+
+            builder
+                .spawn(move || async move {
+                    worker_guard.report(
+                        workers::socket::run_socket_worker(
+                            sentinel,
+                            config,
+                            state,
+                            opt_tls_config,
+                            request_mesh_builder,
+                            priv_dropper,
+                            opt_shared_swarm,
+                        )
+                        .await,
+                    );
+                })
+                .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?;
+        }
+
+        let num_swarm_workers = if opt_shared_swarm.is_some() {
+            0
+        } else {
+            config.swarm_workers
+        };
+
+        // Don't keep shared swarm workers running after socket workers exit
+        drop(opt_shared_swarm);
+
+        for i in 0..num_swarm_workers {
+            let sentinel = sentinel.clone();
+            let config = config.clone();
+            let state = state.clone();
+            let request_mesh_builder = request_mesh_builder.clone();
+            let snapshots = snapshots.clone();
+            let command_receivers = command_receivers.clone();
+
+            let placement = get_worker_placement(
+                &config.cpu_pinning,
+                config.socket_workers,
+                config.swarm_workers,
+                WorkerIndex::SwarmWorker(i),
+            )?;
+            let builder = LocalExecutorBuilder::new(placement).name("request");
+            let worker_guard = worker_watcher.register(format!("swarm-{:02}", i + 1));
+
+            builder
+                .spawn(move || async move {
+                    worker_guard.report(
+                        workers::swarm::run_swarm_worker(
+                            sentinel,
+                            config,
+                            state,
+                            request_mesh_builder,
+                            snapshots,
+                            command_receivers,
+                        )
+                        .await,
+                    );
+                })
+                .map_err(|err| anyhow::anyhow!("Spawning executor failed: {:#}", err))?;
+        }
+
+        if config.cpu_pinning.active {
+            set_affinity_for_util_worker(
+                &config.cpu_pinning,
+                config.socket_workers,
+                config.swarm_workers,
+            )?;
+        }
+
+        Ok(Self {
+            config,
+            state,
+            opt_tls_config,
+            worker_watcher,
+        })
     }
 
-    Ok(())
+    /// Reload access lists and TLS config
+    pub fn reload(&self) {
+        let _ = reload(&self.config, &self.state, self.opt_tls_config.as_ref());
+    }
+
+    /// Tell workers to finish pending work within the grace period. Returns
+    /// deadline to pass to [`Tracker::wait`].
+    pub fn trigger_shutdown(&self) -> Instant {
+        self.state
+            .shutdown
+            .trigger(Duration::from_secs(self.config.shutdown_grace_period))
+    }
+
+    /// Wait for workers to exit, returning first worker error, if any
+    pub fn wait(self, deadline: Instant) -> ::anyhow::Result<()> {
+        self.worker_watcher.wait(deadline)
+    }
+}
+
+/// Reload access lists and TLS config, returning first error, if any
+fn reload(
+    config: &Config,
+    state: &State,
+    opt_tls_config: Option<&Arc<RustlsConfigArcSwap>>,
+) -> anyhow::Result<()> {
+    let mut results = vec![
+        update_access_list(&config.access_list, &state.access_list),
+        update_ip_access_list(&config.ip_access_list, &state.ip_access_list),
+    ];
+
+    if let Some(tls_config) = opt_tls_config {
+        results.push(update_rustls_config(
+            &config.network.tls_certificate_path,
+            &config.network.tls_private_key_path,
+            &config.network.tls_sni_certificates,
+            tls_config,
+        ));
+    }
+
+    results.into_iter().collect()
+}
+
+/// Pass on torrent commands to responsible swarm worker
+fn spawn_control_server_for_tracker(
+    config: &Config,
+    state: &State,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+    command_senders: Vec<UnboundedSender<SwarmCommandRequest>>,
+) -> anyhow::Result<()> {
+    let reload_config = config.clone();
+    let reload_state = state.clone();
+    let swarm_config = config.clone();
+
+    spawn_control_server(
+        &config.control,
+        state.metrics_registry.clone(),
+        move || reload(&reload_config, &reload_state, opt_tls_config.as_ref()),
+        move |command| {
+            if command_senders.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "torrents are handled by swarm workers of another tracker"
+                ));
+            }
+
+            let worker_index = workers::socket::calculate_request_consumer_index(
+                &swarm_config,
+                InfoHash(command.info_hash()),
+            );
+            let (response_sender, response_receiver) = ::std::sync::mpsc::channel();
+
+            command_senders[worker_index]
+                .unbounded_send(SwarmCommandRequest {
+                    command,
+                    response_sender,
+                })
+                .map_err(|_| anyhow::anyhow!("swarm worker has exited"))?;
+
+            Ok(response_receiver)
+        },
+    )
 }
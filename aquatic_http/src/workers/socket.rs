@@ -1,14 +1,25 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::access_list::{create_access_list_cache, AccessListCache};
+use aquatic_common::full_scrape::{FullScrapeRateLimiter, FullScrapeState, FullScrapeStatistics};
+use aquatic_common::ip_access_list::{
+    create_ip_access_list_cache, IpAccessList, IpAccessListArcSwap,
+};
 use aquatic_common::privileges::PrivilegeDropper;
-use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::reverse_proxy::{
+    allowed_client_ip_from_header, parse_proxy_protocol_header, proxy_protocol_bytes_needed,
+    ClientIpHeader,
+};
+use aquatic_common::rustls_config::RustlsConfigArcSwap;
+use aquatic_common::shared_swarm::{SharedScrapeRequest, SharedSwarmSender};
+use aquatic_common::socket_activation::take_inherited_socket;
 use aquatic_common::{CanonicalSocketAddr, PanicSentinel};
 use aquatic_http_protocol::common::InfoHash;
 use aquatic_http_protocol::request::{Request, RequestParseError, ScrapeRequest};
@@ -16,21 +27,24 @@ use aquatic_http_protocol::response::{
     FailureResponse, Response, ScrapeResponse, ScrapeStatistics,
 };
 use either::Either;
+use futures::future::{FutureExt, Shared};
 use futures::stream::FuturesUnordered;
-use futures_lite::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use futures_lite::future::race;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
 use futures_rustls::server::TlsStream;
 use futures_rustls::TlsAcceptor;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
 use glommio::channels::shared_channel::{self, SharedReceiver};
 use glommio::net::{TcpListener, TcpStream};
 use glommio::task::JoinHandle;
-use glommio::timer::TimerActionRepeat;
+use glommio::timer::{sleep, TimerActionRepeat};
 use glommio::{enclose, prelude::*};
 use once_cell::sync::Lazy;
 use slab::Slab;
 
 use crate::common::*;
 use crate::config::Config;
+use crate::workers::swarm::{create_announce_response_from_shared, create_shared_announce_request};
 
 const REQUEST_BUFFER_SIZE: usize = 2048;
 const RESPONSE_BUFFER_SIZE: usize = 4096;
@@ -47,6 +61,19 @@ struct PendingScrapeResponse {
     stats: BTreeMap<InfoHash, ScrapeStatistics>,
 }
 
+/// Resolves once shutdown has been triggered. Unlike
+/// `Shutdown::wait_for_trigger`, it is cheap to clone and poll.
+type ShutdownTriggered = Shared<JoinHandle<()>>;
+
+/// Senders of requests to swarm workers
+#[derive(Clone)]
+enum RequestSenders {
+    /// Swarm workers of this tracker
+    Local(Rc<Senders<ChannelRequest>>),
+    /// Swarm workers of another tracker running in the same process
+    Shared(Rc<SharedSwarmSender>),
+}
+
 struct ConnectionReference {
     task_handle: Option<JoinHandle<()>>,
     valid_until: ValidUntil,
@@ -56,19 +83,49 @@ pub async fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
-    tls_config: Arc<RustlsConfig>,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     priv_dropper: PrivilegeDropper,
-) {
+    opt_shared_swarm: Option<SharedSwarmSender>,
+) -> anyhow::Result<()> {
     let config = Rc::new(config);
-    let access_list = state.access_list;
 
-    let listener = create_tcp_listener(&config, priv_dropper).expect("create tcp listener");
+    let mut listeners = Vec::new();
+
+    if let Some(tls_config) = opt_tls_config {
+        for address in config.network.address.iter().copied() {
+            let listener = create_tcp_listener(&config, address)
+                .with_context(|| format!("create tls tcp listener bound to {}", address))?;
+
+            listeners.push((listener, Some(tls_config.clone())));
+        }
+    }
+    if config.network.enable_plaintext {
+        for address in config.network.plaintext_address.iter().copied() {
+            let listener = create_tcp_listener(&config, address)
+                .with_context(|| format!("create plaintext tcp listener bound to {}", address))?;
 
-    let (request_senders, _) = request_mesh_builder.join(Role::Producer).await.unwrap();
-    let request_senders = Rc::new(request_senders);
+            listeners.push((listener, None));
+        }
+    }
+
+    priv_dropper
+        .after_socket_creation()
+        .with_context(|| "drop privileges after socket creation")?;
+
+    let request_senders = if let Some(shared_swarm) = opt_shared_swarm {
+        RequestSenders::Shared(Rc::new(shared_swarm))
+    } else {
+        let (request_senders, _) = request_mesh_builder
+            .join(Role::Producer)
+            .await
+            .map_err(|err| anyhow::anyhow!("join request mesh: {:?}", err))?;
+
+        RequestSenders::Local(Rc::new(request_senders))
+    };
 
     let connection_slab = Rc::new(RefCell::new(Slab::new()));
+    let full_scrape_rate_limiter = Rc::new(RefCell::new(FullScrapeRateLimiter::default()));
 
     TimerActionRepeat::repeat(enclose!((config, connection_slab) move || {
         clean_connections(
@@ -77,27 +134,140 @@ pub async fn run_socket_worker(
         )
     }));
 
+    if config.full_scrape.active {
+        // Only first socket worker builds full scrape response
+        let build_response = matches!(
+            &request_senders,
+            RequestSenders::Local(senders) if senders.producer_id() == Some(0)
+        );
+
+        TimerActionRepeat::repeat(enclose!((config, state, full_scrape_rate_limiter) move || {
+            update_full_scrape(
+                config.clone(),
+                state.full_scrape.clone(),
+                full_scrape_rate_limiter.clone(),
+                build_response,
+            )
+        }));
+    }
+
+    let shutdown_triggered: ShutdownTriggered = spawn_local(enclose!((state) async move {
+        state.shutdown.wait_for_trigger().await
+    }))
+    .detach()
+    .shared();
+
+    let mut handles = Vec::new();
+
+    for (listener, opt_tls_config) in listeners {
+        // Stop accepting connections on shutdown
+        let handle = spawn_local(race(
+            accept_connections(
+                config.clone(),
+                state.clone(),
+                request_senders.clone(),
+                connection_slab.clone(),
+                full_scrape_rate_limiter.clone(),
+                shutdown_triggered.clone(),
+                listener,
+                opt_tls_config,
+            ),
+            shutdown_triggered.clone().map(|_| ()),
+        ))
+        .detach();
+
+        handles.push(handle);
+    }
+
+    for handle in handles {
+        handle.await;
+    }
+
+    // Let open connections finish handling requests
+    race(
+        wait_for_connections_to_close(connection_slab),
+        state.shutdown.wait_for_deadline(),
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Accept connections on listener, doing a TLS handshake if `opt_tls_config`
+/// is set
+async fn accept_connections(
+    config: Rc<Config>,
+    state: State,
+    request_senders: RequestSenders,
+    connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    shutdown_triggered: ShutdownTriggered,
+    listener: TcpListener,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+) {
+    let mut ip_access_list_cache = create_ip_access_list_cache(&state.ip_access_list);
+
     let mut incoming = listener.incoming();
 
     while let Some(stream) = incoming.next().await {
         match stream {
             Ok(stream) => {
+                if config.ip_access_list.mode.is_on() {
+                    let peer_addr = match stream.peer_addr() {
+                        Ok(peer_addr) => CanonicalSocketAddr::new(peer_addr),
+                        Err(err) => {
+                            ::log::info!(
+                                "could not extract peer address, closing connection: {:#}",
+                                err
+                            );
+
+                            continue;
+                        }
+                    };
+
+                    // Connections from trusted proxies are checked after
+                    // reading PROXY protocol header
+                    let check_later = config.reverse_proxy.proxy_protocol
+                        && state.trusted_proxies.contains(peer_addr.get().ip());
+
+                    if !check_later
+                        && !ip_access_list_cache
+                            .load()
+                            .allows(config.ip_access_list.mode, peer_addr.get().ip())
+                    {
+                        ::log::debug!(
+                            "closing connection from blocked address {}",
+                            peer_addr.get()
+                        );
+
+                        if config.metrics.active {
+                            state.metrics.connections_blocked.increment();
+                        }
+
+                        continue;
+                    }
+                }
+
                 let key = connection_slab.borrow_mut().insert(ConnectionReference {
                     task_handle: None,
                     valid_until: ValidUntil::new(config.cleaning.max_connection_idle),
                 });
 
-                let task_handle = spawn_local(enclose!((config, access_list, request_senders, tls_config, connection_slab) async move {
-                    if let Err(err) = Connection::run(
+                let task_handle = spawn_local(enclose!((config, state, request_senders, opt_tls_config, connection_slab, full_scrape_rate_limiter, shutdown_triggered) async move {
+                    let _connection_guard = config.metrics.active.then(|| state.metrics.connections.increment_scoped());
+
+                    if let Err(err) = run_connection(
                         config,
-                        access_list,
+                        state,
                         request_senders,
                         ConnectionId(key),
-                        tls_config,
+                        opt_tls_config,
                         connection_slab.clone(),
+                        full_scrape_rate_limiter,
+                        shutdown_triggered,
                         stream
                     ).await {
-                        ::log::debug!("Connection::run() error: {:?}", err);
+                        ::log::debug!("run_connection() error: {:?}", err);
                     }
 
                     connection_slab.borrow_mut().try_remove(key);
@@ -115,6 +285,12 @@ pub async fn run_socket_worker(
     }
 }
 
+async fn wait_for_connections_to_close(connection_slab: Rc<RefCell<Slab<ConnectionReference>>>) {
+    while !connection_slab.borrow().is_empty() {
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
 async fn clean_connections(
     config: Rc<Config>,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
@@ -140,75 +316,277 @@ async fn clean_connections(
     ))
 }
 
-struct Connection {
+async fn update_full_scrape(
+    config: Rc<Config>,
+    full_scrape: Arc<FullScrapeState<Vec<u8>>>,
+    rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    build_response: bool,
+) -> Option<Duration> {
+    rate_limiter
+        .borrow_mut()
+        .clean(&config.full_scrape, Instant::now());
+
+    if build_response {
+        match create_full_scrape_response(&full_scrape) {
+            Ok(response) => full_scrape.set_response(response),
+            Err(err) => ::log::error!("Couldn't create full scrape response: {:#}", err),
+        }
+    }
+
+    Some(Duration::from_secs(config.full_scrape.update_interval))
+}
+
+/// Create full scrape HTTP response, including headers, from statistics
+/// contributed by swarm workers
+fn create_full_scrape_response(full_scrape: &FullScrapeState<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    Response::Scrape(create_scrape_response(full_scrape.collect_statistics())).write(&mut body)?;
+
+    body.extend_from_slice(b"\r\n");
+
+    let mut buf = ::itoa::Buffer::new();
+    let content_len_bytes = buf.format(body.len()).as_bytes();
+
+    let mut response = Vec::with_capacity(
+        RESPONSE_HEADER_A.len() + content_len_bytes.len() + RESPONSE_HEADER_C.len() + body.len(),
+    );
+
+    response.extend_from_slice(RESPONSE_HEADER_A);
+    response.extend_from_slice(content_len_bytes);
+    response.extend_from_slice(RESPONSE_HEADER_C);
+    response.extend_from_slice(&body);
+
+    Ok(response)
+}
+
+/// Stream that connections are served over, with or without TLS
+trait ConnectionStream: AsyncRead + AsyncWrite + Unpin {
+    fn tcp_stream(&self) -> &TcpStream;
+}
+
+impl ConnectionStream for TcpStream {
+    fn tcp_stream(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl ConnectionStream for TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+}
+
+/// Read PROXY protocol header if connection is from trusted proxy, check IP
+/// access list, do TLS handshake if `opt_tls_config` is set and then serve
+/// requests
+async fn run_connection(
+    config: Rc<Config>,
+    state: State,
+    request_senders: RequestSenders,
+    connection_id: ConnectionId,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+    connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    shutdown_triggered: ShutdownTriggered,
+    mut stream: TcpStream,
+) -> anyhow::Result<()> {
+    let peer_addr = stream
+        .peer_addr()
+        .map_err(|err| anyhow::anyhow!("Couldn't get peer addr: {:?}", err))?;
+    let mut peer_addr = CanonicalSocketAddr::new(peer_addr);
+
+    let is_trusted_proxy =
+        config.reverse_proxy.is_on() && state.trusted_proxies.contains(peer_addr.get().ip());
+
+    if is_trusted_proxy && config.reverse_proxy.proxy_protocol {
+        if let Some(client_addr) = read_proxy_protocol_header(&mut stream).await? {
+            peer_addr = CanonicalSocketAddr::new(client_addr);
+        }
+
+        if !state
+            .ip_access_list
+            .load()
+            .allows(config.ip_access_list.mode, peer_addr.get().ip())
+        {
+            if config.metrics.active {
+                state.metrics.connections_blocked.increment();
+            }
+
+            return Err(anyhow::anyhow!(
+                "connection from blocked address {}",
+                peer_addr.get()
+            ));
+        }
+    }
+
+    let opt_client_ip_header = (is_trusted_proxy && config.reverse_proxy.client_ip_header.is_on())
+        .then_some(config.reverse_proxy.client_ip_header);
+
+    let connection_state = ConnectionState {
+        config,
+        state,
+        request_senders,
+        connection_id,
+        connection_slab,
+        full_scrape_rate_limiter,
+        shutdown_triggered,
+        peer_addr,
+        opt_client_ip_header,
+    };
+
+    match opt_tls_config {
+        Some(tls_config) => {
+            let tls_acceptor: TlsAcceptor = tls_config.load_full().into();
+            let stream = tls_acceptor.accept(stream).await?;
+
+            Connection::new(connection_state, stream)
+                .run_request_response_loop()
+                .await
+        }
+        None => {
+            Connection::new(connection_state, stream)
+                .run_request_response_loop()
+                .await
+        }
+    }
+}
+
+/// Connection state that doesn't depend on stream type
+struct ConnectionState {
+    config: Rc<Config>,
+    state: State,
+    request_senders: RequestSenders,
+    connection_id: ConnectionId,
+    connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    shutdown_triggered: ShutdownTriggered,
+    peer_addr: CanonicalSocketAddr,
+    opt_client_ip_header: Option<ClientIpHeader>,
+}
+
+struct Connection<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
-    request_senders: Rc<Senders<ChannelRequest>>,
+    metrics: Arc<Metrics>,
+    request_senders: RequestSenders,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
-    stream: TlsStream<TcpStream>,
+    stream: S,
     peer_addr: CanonicalSocketAddr,
+    trusted_proxies: Arc<IpAccessList>,
+    ip_access_list: Arc<IpAccessListArcSwap>,
+    full_scrape: Arc<FullScrapeState<Vec<u8>>>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    shutdown_triggered: ShutdownTriggered,
+    /// Set if connection is from trusted proxy and client IP header is
+    /// enabled
+    opt_client_ip_header: Option<ClientIpHeader>,
     connection_id: ConnectionId,
     request_buffer: [u8; REQUEST_BUFFER_SIZE],
     request_buffer_position: usize,
     response_buffer: [u8; RESPONSE_BUFFER_SIZE],
 }
 
-impl Connection {
-    async fn run(
-        config: Rc<Config>,
-        access_list: Arc<AccessListArcSwap>,
-        request_senders: Rc<Senders<ChannelRequest>>,
-        connection_id: ConnectionId,
-        tls_config: Arc<RustlsConfig>,
-        connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
-        stream: TcpStream,
-    ) -> anyhow::Result<()> {
-        let peer_addr = stream
-            .peer_addr()
-            .map_err(|err| anyhow::anyhow!("Couldn't get peer addr: {:?}", err))?;
-        let peer_addr = CanonicalSocketAddr::new(peer_addr);
-
-        let tls_acceptor: TlsAcceptor = tls_config.into();
-        let stream = tls_acceptor.accept(stream).await?;
+impl<S: ConnectionStream> Connection<S> {
+    fn new(connection_state: ConnectionState, stream: S) -> Self {
+        let ConnectionState {
+            config,
+            state,
+            request_senders,
+            connection_id,
+            connection_slab,
+            full_scrape_rate_limiter,
+            shutdown_triggered,
+            peer_addr,
+            opt_client_ip_header,
+        } = connection_state;
 
         let mut response_buffer = [0; RESPONSE_BUFFER_SIZE];
 
         response_buffer[..RESPONSE_HEADER.len()].copy_from_slice(&RESPONSE_HEADER);
 
-        let mut conn = Connection {
-            config: config.clone(),
-            access_list_cache: create_access_list_cache(&access_list),
-            request_senders: request_senders.clone(),
+        Self {
+            config,
+            access_list_cache: create_access_list_cache(&state.access_list),
+            metrics: state.metrics,
+            request_senders,
             connection_slab,
             stream,
             peer_addr,
+            trusted_proxies: state.trusted_proxies,
+            ip_access_list: state.ip_access_list,
+            full_scrape: state.full_scrape,
+            full_scrape_rate_limiter,
+            shutdown_triggered,
+            opt_client_ip_header,
             connection_id,
             request_buffer: [0; REQUEST_BUFFER_SIZE],
             request_buffer_position: 0,
             response_buffer,
-        };
-
-        conn.run_request_response_loop().await?;
-
-        Ok(())
+        }
     }
 
     async fn run_request_response_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            let response = match self.read_request().await? {
+            let shutdown_triggered = self.shutdown_triggered.clone();
+
+            // Close idle connections on shutdown
+            let opt_request = race(
+                async { Some(self.read_request().await) },
+                shutdown_triggered.map(|_| None),
+            )
+            .await;
+
+            let request = match opt_request {
+                Some(request) => request?,
+                None => {
+                    self.close().await;
+
+                    break;
+                }
+            };
+
+            let response = match request {
                 Either::Left(response) => Response::Failure(response),
-                Either::Right(request) => self.handle_request(request).await?,
+                Either::Right((Request::Scrape(ref request), peer_addr))
+                    if request.info_hashes.is_empty() =>
+                {
+                    match self.get_full_scrape_response(peer_addr) {
+                        Ok(response_bytes) => {
+                            self.write_bytes(&response_bytes).await?;
+
+                            if self.config.metrics.active {
+                                self.metrics.responses_sent_scrape.increment();
+                            }
+
+                            if !self.keep_alive() {
+                                self.close().await;
+
+                                break;
+                            }
+
+                            continue;
+                        }
+                        Err(response) => Response::Failure(response),
+                    }
+                }
+                Either::Right((request, peer_addr)) => {
+                    self.handle_request(request, peer_addr).await?
+                }
             };
 
             self.write_response(&response).await?;
 
-            if matches!(response, Response::Failure(_)) || !self.config.network.keep_alive {
-                let _ = self
-                    .stream
-                    .get_ref()
-                    .0
-                    .shutdown(std::net::Shutdown::Both)
-                    .await;
+            if self.config.metrics.active {
+                match response {
+                    Response::Announce(_) => self.metrics.responses_sent_announce.increment(),
+                    Response::Scrape(_) => self.metrics.responses_sent_scrape.increment(),
+                    Response::Failure(_) => self.metrics.responses_sent_failure.increment(),
+                }
+            }
+
+            if matches!(response, Response::Failure(_)) || !self.keep_alive() {
+                self.close().await;
 
                 break;
             }
@@ -217,7 +595,22 @@ impl Connection {
         Ok(())
     }
 
-    async fn read_request(&mut self) -> anyhow::Result<Either<FailureResponse, Request>> {
+    /// Keep connection open after response unless disabled or shutting down
+    fn keep_alive(&self) -> bool {
+        self.config.network.keep_alive && self.shutdown_triggered.peek().is_none()
+    }
+
+    async fn close(&mut self) {
+        let _ = self
+            .stream
+            .tcp_stream()
+            .shutdown(std::net::Shutdown::Both)
+            .await;
+    }
+
+    async fn read_request(
+        &mut self,
+    ) -> anyhow::Result<Either<FailureResponse, (Request, CanonicalSocketAddr)>> {
         self.request_buffer_position = 0;
 
         loop {
@@ -234,17 +627,82 @@ impl Connection {
                 return Err(anyhow::anyhow!("peer closed connection"));
             }
 
+            if self.config.metrics.active {
+                self.metrics.bytes_received.add(bytes_read as u64);
+            }
+
             self.request_buffer_position += bytes_read;
 
-            match Request::from_bytes(&self.request_buffer[..self.request_buffer_position]) {
-                Ok(request) => {
+            let bytes = &self.request_buffer[..self.request_buffer_position];
+
+            let res_request = match self.opt_client_ip_header {
+                Some(header) => {
+                    // Header name is always set for enabled header types
+                    let header_name = header.header_name().unwrap_or_default();
+
+                    Request::from_bytes_with_header(bytes, header_name).map(
+                        |(request, opt_value)| {
+                            let res_client_ip = match opt_value {
+                                Some(value) => allowed_client_ip_from_header(
+                                    header,
+                                    &self.trusted_proxies,
+                                    &self.ip_access_list.load(),
+                                    self.config.ip_access_list.mode,
+                                    value,
+                                ),
+                                None => Ok(None),
+                            };
+
+                            (request, res_client_ip)
+                        },
+                    )
+                }
+                None => Request::from_bytes(bytes).map(|request| (request, Ok(None))),
+            };
+
+            match res_request {
+                Ok((_, Err(client_ip))) => {
+                    ::log::debug!("request from blocked address {}", client_ip);
+
+                    if self.config.metrics.active {
+                        self.metrics.connections_blocked.increment();
+                    }
+
+                    let response = FailureResponse {
+                        failure_reason: "Address not allowed".into(),
+                    };
+
+                    return Ok(Either::Left(response));
+                }
+                Ok((request, Ok(opt_client_ip))) => {
                     ::log::debug!("received request: {:?}", request);
 
-                    return Ok(Either::Right(request));
+                    if self.config.metrics.active {
+                        match request {
+                            Request::Announce(_) => {
+                                self.metrics.requests_received_announce.increment()
+                            }
+                            Request::Scrape(_) => self.metrics.requests_received_scrape.increment(),
+                        }
+                    }
+
+                    let peer_addr = match opt_client_ip {
+                        Some(ip) => CanonicalSocketAddr::new(SocketAddr::new(
+                            ip,
+                            self.peer_addr.get().port(),
+                        )),
+                        None => self.peer_addr,
+                    };
+
+                    return Ok(Either::Right((request, peer_addr)));
                 }
                 Err(RequestParseError::Invalid(err)) => {
                     ::log::debug!("invalid request: {:?}", err);
 
+                    if self.config.metrics.active {
+                        self.metrics.invalid_requests.increment();
+                    }
+
                     let response = FailureResponse {
                         failure_reason: "Invalid request".into(),
                     };
@@ -268,13 +726,28 @@ impl Connection {
     ///   response
     /// - If it is a scrape requests, split it up, pass on the parts to
     ///   relevant swarm workers and await a response
-    async fn handle_request(&mut self, request: Request) -> anyhow::Result<Response> {
+    async fn handle_request(
+        &mut self,
+        request: Request,
+        peer_addr: CanonicalSocketAddr,
+    ) -> anyhow::Result<Response> {
         if let Ok(mut slab) = self.connection_slab.try_borrow_mut() {
             if let Some(reference) = slab.get_mut(self.connection_id.0) {
                 reference.valid_until = ValidUntil::new(self.config.cleaning.max_connection_idle);
             }
         }
 
+        let request_senders = match &self.request_senders {
+            RequestSenders::Local(request_senders) => request_senders.clone(),
+            RequestSenders::Shared(shared_swarm) => {
+                let shared_swarm = shared_swarm.clone();
+
+                return self
+                    .handle_request_with_shared_swarm(&shared_swarm, request, peer_addr)
+                    .await;
+            }
+        };
+
         match request {
             Request::Announce(request) => {
                 let info_hash = request.info_hash;
@@ -288,14 +761,14 @@ impl Connection {
 
                     let request = ChannelRequest::Announce {
                         request,
-                        peer_addr: self.peer_addr,
+                        peer_addr,
                         response_sender,
                     };
 
                     let consumer_index = calculate_request_consumer_index(&self.config, info_hash);
 
                     // Only fails when receiver is closed
-                    self.request_senders
+                    request_senders
                         .send_to(consumer_index, request)
                         .await
                         .unwrap();
@@ -336,12 +809,11 @@ impl Connection {
 
                     let request = ChannelRequest::Scrape {
                         request: ScrapeRequest { info_hashes },
-                        peer_addr: self.peer_addr,
                         response_sender,
                     };
 
                     // Only fails when receiver is closed
-                    self.request_senders
+                    request_senders
                         .send_to(consumer_index, request)
                         .await
                         .unwrap();
@@ -358,6 +830,90 @@ impl Connection {
         }
     }
 
+    /// Pass on request to swarm workers of another tracker running in the
+    /// same process and await a response
+    async fn handle_request_with_shared_swarm(
+        &mut self,
+        shared_swarm: &SharedSwarmSender,
+        request: Request,
+        peer_addr: CanonicalSocketAddr,
+    ) -> anyhow::Result<Response> {
+        match request {
+            Request::Announce(request) => {
+                if self
+                    .access_list_cache
+                    .load()
+                    .allows(self.config.access_list.mode, &request.info_hash.0)
+                {
+                    let request = create_shared_announce_request(&self.config, peer_addr, request);
+
+                    shared_swarm
+                        .announce(request)
+                        .await
+                        .map(|response| {
+                            create_announce_response_from_shared(&self.config, response)
+                        })
+                        .map(Response::Announce)
+                } else {
+                    let response = Response::Failure(FailureResponse {
+                        failure_reason: "Info hash not allowed".into(),
+                    });
+
+                    Ok(response)
+                }
+            }
+            Request::Scrape(ScrapeRequest { info_hashes }) => {
+                let request = SharedScrapeRequest {
+                    info_hashes: info_hashes
+                        .into_iter()
+                        .take(self.config.protocol.max_scrape_torrents)
+                        .map(|info_hash| info_hash.0)
+                        .collect(),
+                    ipv4: peer_addr.is_ipv4(),
+                };
+
+                let statistics = shared_swarm.scrape(request).await?;
+
+                Ok(Response::Scrape(create_scrape_response(statistics)))
+            }
+        }
+    }
+
+    /// Get cached full scrape response if full scrapes are enabled and
+    /// client isn't rate limited
+    fn get_full_scrape_response(
+        &self,
+        peer_addr: CanonicalSocketAddr,
+    ) -> Result<Arc<Vec<u8>>, FailureResponse> {
+        if !self.config.full_scrape.active {
+            return Err(FailureResponse {
+                failure_reason: "Full scrapes are not allowed".into(),
+            });
+        }
+
+        if let Ok(mut slab) = self.connection_slab.try_borrow_mut() {
+            if let Some(reference) = slab.get_mut(self.connection_id.0) {
+                reference.valid_until = ValidUntil::new(self.config.cleaning.max_connection_idle);
+            }
+        }
+
+        if !self.full_scrape_rate_limiter.borrow_mut().check(
+            &self.config.full_scrape,
+            peer_addr.get().ip(),
+            Instant::now(),
+        ) {
+            return Err(FailureResponse {
+                failure_reason: "Full scrape rate limit exceeded".into(),
+            });
+        }
+
+        self.full_scrape
+            .load_response()
+            .ok_or_else(|| FailureResponse {
+                failure_reason: "Full scrape not available yet".into(),
+            })
+    }
+
     /// Wait for partial scrape responses to arrive,
     /// return full response
     async fn wait_for_scrape_responses(
@@ -443,19 +999,74 @@ impl Connection {
         self.stream.write(&self.response_buffer[..position]).await?;
         self.stream.flush().await?;
 
+        if self.config.metrics.active {
+            self.metrics.bytes_sent.add(position as u64);
+        }
+
         Ok(())
     }
+
+    /// Write complete response, including headers, to stream
+    async fn write_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(bytes).await?;
+        self.stream.flush().await?;
+
+        if self.config.metrics.active {
+            self.metrics.bytes_sent.add(bytes.len() as u64);
+        }
+
+        Ok(())
+    }
+}
+
+/// Create scrape response from statistics collected by swarm workers
+fn create_scrape_response(statistics: Vec<([u8; 20], FullScrapeStatistics)>) -> ScrapeResponse {
+    let files = statistics
+        .into_iter()
+        .map(|(info_hash, statistics)| {
+            let statistics = ScrapeStatistics {
+                complete: statistics.seeders,
+                incomplete: statistics.leechers,
+                downloaded: statistics.completed,
+            };
+
+            (InfoHash(info_hash), statistics)
+        })
+        .collect();
+
+    ScrapeResponse { files }
+}
+
+/// Read PROXY protocol header without consuming any data after it
+async fn read_proxy_protocol_header(stream: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    let mut buffer = Vec::new();
+
+    loop {
+        match proxy_protocol_bytes_needed(&buffer)? {
+            0 => return parse_proxy_protocol_header(&buffer),
+            bytes_needed => {
+                let start = buffer.len();
+
+                buffer.resize(start + bytes_needed, 0);
+
+                stream.read_exact(&mut buffer[start..]).await?;
+            }
+        }
+    }
 }
 
-fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+pub fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
     (info_hash.0[0] as usize) % config.swarm_workers
 }
 
-fn create_tcp_listener(
-    config: &Config,
-    priv_dropper: PrivilegeDropper,
-) -> anyhow::Result<TcpListener> {
-    let domain = if config.network.address.is_ipv4() {
+fn create_tcp_listener(config: &Config, address: SocketAddr) -> anyhow::Result<TcpListener> {
+    if let Some(socket) = take_inherited_socket(socket2::Type::STREAM, address)
+        .with_context(|| "take inherited socket")?
+    {
+        return Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) });
+    }
+
+    let domain = if address.is_ipv4() {
         socket2::Domain::IPV4
     } else {
         socket2::Domain::IPV6
@@ -474,14 +1085,12 @@ fn create_tcp_listener(
         .with_context(|| "socket: set reuse port")?;
 
     socket
-        .bind(&config.network.address.into())
-        .with_context(|| format!("socket: bind to {}", config.network.address))?;
+        .bind(&address.into())
+        .with_context(|| format!("socket: bind to {}", address))?;
 
     socket
         .listen(config.network.tcp_backlog)
-        .with_context(|| format!("socket: listen on {}", config.network.address))?;
-
-    priv_dropper.after_socket_creation()?;
+        .with_context(|| format!("socket: listen on {}", address))?;
 
     Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
 }
@@ -2,11 +2,13 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::time::Instant;
 
+use anyhow::Context;
 use either::Either;
+use futures::channel::mpsc::UnboundedReceiver;
 use futures_lite::{Stream, StreamExt};
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
 use glommio::timer::TimerActionRepeat;
@@ -17,6 +19,15 @@ use rand::SeedableRng;
 use smartstring::{LazyCompact, SmartString};
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::announce_events::{AnnounceRecord, AnnounceRecordEvent, AnnounceRecordSender};
+use aquatic_common::control::{
+    ControlResponse, PeerInfo, SwarmCommand, SwarmCommandRequest, TorrentInfo,
+};
+use aquatic_common::full_scrape::FullScrapeStatistics;
+use aquatic_common::shared_swarm::{
+    SharedAnnounceEvent, SharedAnnounceRequest, SharedAnnounceResponse,
+};
+use aquatic_common::snapshot::{PeerSnapshot, SnapshotWriter, SwarmSnapshot, TorrentSnapshot};
 use aquatic_common::ValidUntil;
 use aquatic_common::{extract_response_peers, PanicSentinel};
 use aquatic_common::{AmortizedIndexMap, CanonicalSocketAddr};
@@ -26,7 +37,8 @@ use aquatic_http_protocol::response::ResponsePeer;
 use aquatic_http_protocol::response::*;
 
 use crate::common::*;
-use crate::config::Config;
+use crate::config::{AnnouncedIpPolicy, Config};
+use crate::SNAPSHOT_KIND;
 
 pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash {}
 
@@ -62,6 +74,10 @@ pub struct Peer<I: Ip> {
     pub port: u16,
     pub status: PeerStatus,
     pub valid_until: ValidUntil,
+    /// Included in seeder/leecher counts and completions. Peers announcing
+    /// addresses of both IP versions are only counted in the swarm of the
+    /// IP version they connected with.
+    pub counted: bool,
 }
 
 impl<I: Ip> Peer<I> {
@@ -83,8 +99,11 @@ pub type PeerMap<I> = AmortizedIndexMap<PeerMapKey<I>, Peer<I>>;
 
 pub struct TorrentData<I: Ip> {
     pub peers: PeerMap<I>,
+    /// Number of counted seeding peers
     pub num_seeders: usize,
+    /// Number of counted leeching peers
     pub num_leechers: usize,
+    pub num_completed: usize,
 }
 
 impl<I: Ip> Default for TorrentData<I> {
@@ -94,10 +113,74 @@ impl<I: Ip> Default for TorrentData<I> {
             peers: Default::default(),
             num_seeders: 0,
             num_leechers: 0,
+            num_completed: 0,
         }
     }
 }
 
+impl<I: Ip> TorrentData<I> {
+    fn remove_from_counts(&mut self, peer: &Peer<I>) {
+        match peer.status {
+            PeerStatus::Seeding if peer.counted => {
+                self.num_seeders -= 1;
+            }
+            PeerStatus::Leeching if peer.counted => {
+                self.num_leechers -= 1;
+            }
+            _ => (),
+        }
+    }
+
+    /// Remove all peers with given peer id, returning whether any were found
+    pub fn remove_peers(&mut self, peer_id: PeerId) -> bool {
+        let num_peers = self.peers.len();
+        let num_seeders = &mut self.num_seeders;
+        let num_leechers = &mut self.num_leechers;
+
+        self.peers.retain(|key, peer| {
+            if key.peer_id != peer_id {
+                return true;
+            }
+
+            match peer.status {
+                PeerStatus::Seeding if peer.counted => {
+                    *num_seeders -= 1;
+                }
+                PeerStatus::Leeching if peer.counted => {
+                    *num_leechers -= 1;
+                }
+                _ => (),
+            }
+
+            false
+        });
+
+        self.peers.len() != num_peers
+    }
+
+    fn counts(&self) -> (usize, usize, usize) {
+        (self.num_seeders, self.num_leechers, self.num_completed)
+    }
+
+    fn add_to_torrent_info(&self, torrent_info: &mut TorrentInfo, now: Instant)
+    where
+        I: Into<IpAddr>,
+    {
+        torrent_info.seeders += self.num_seeders;
+        torrent_info.leechers += self.num_leechers;
+        torrent_info.completed += self.num_completed;
+        torrent_info
+            .peers
+            .extend(self.peers.iter().map(|(key, peer)| PeerInfo {
+                peer_id: key.peer_id.0,
+                ip_address: peer.ip_address.into(),
+                port: peer.port,
+                seeding: peer.status == PeerStatus::Seeding,
+                valid_for: peer.valid_until.seconds_left(now),
+            }));
+    }
+}
+
 pub type TorrentMap<I> = AmortizedIndexMap<InfoHash, TorrentData<I>>;
 
 #[derive(Default)]
@@ -114,6 +197,20 @@ impl TorrentMaps {
         Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6);
     }
 
+    pub fn update_metrics(&self, metrics: &Metrics, worker_index: usize) {
+        fn num_peers<I: Ip>(torrent_map: &TorrentMap<I>) -> i64 {
+            torrent_map
+                .values()
+                .map(|torrent_data| torrent_data.peers.len() as i64)
+                .sum()
+        }
+
+        metrics.torrents_ipv4[worker_index].set(self.ipv4.len() as i64);
+        metrics.torrents_ipv6[worker_index].set(self.ipv6.len() as i64);
+        metrics.peers_ipv4[worker_index].set(num_peers(&self.ipv4));
+        metrics.peers_ipv6[worker_index].set(num_peers(&self.ipv6));
+    }
+
     fn clean_torrent_map<I: Ip>(
         config: &Config,
         access_list_cache: &mut AccessListCache,
@@ -137,10 +234,10 @@ impl TorrentMaps {
 
                 if !keep {
                     match peer.status {
-                        PeerStatus::Seeding => {
+                        PeerStatus::Seeding if peer.counted => {
                             *num_seeders -= 1;
                         }
-                        PeerStatus::Leeching => {
+                        PeerStatus::Leeching if peer.counted => {
                             *num_leechers -= 1;
                         }
                         _ => (),
@@ -155,6 +252,186 @@ impl TorrentMaps {
 
         torrent_map.shrink_to_fit();
     }
+
+    /// Statistics for torrent, with IPv4 and IPv6 swarms combined
+    pub fn statistics(&self, info_hash: &InfoHash) -> Option<ScrapeStatistics> {
+        let counts = [
+            self.ipv4.get(info_hash).map(TorrentData::counts),
+            self.ipv6.get(info_hash).map(TorrentData::counts),
+        ];
+
+        if counts.iter().all(Option::is_none) {
+            return None;
+        }
+
+        let mut statistics = ScrapeStatistics {
+            complete: 0,
+            incomplete: 0,
+            downloaded: 0,
+        };
+
+        for (num_seeders, num_leechers, num_completed) in counts.into_iter().flatten() {
+            statistics.complete += num_seeders;
+            statistics.incomplete += num_leechers;
+            statistics.downloaded += num_completed;
+        }
+
+        Some(statistics)
+    }
+
+    /// Statistics for all torrents, with IPv4 and IPv6 swarms combined
+    pub fn full_scrape_statistics(&self) -> Vec<([u8; 20], FullScrapeStatistics)> {
+        fn add_statistics<I: Ip>(
+            statistics: &mut BTreeMap<[u8; 20], FullScrapeStatistics>,
+            torrent_map: &TorrentMap<I>,
+        ) {
+            for (info_hash, torrent_data) in torrent_map.iter() {
+                let entry = statistics.entry(info_hash.0).or_default();
+
+                entry.seeders += torrent_data.num_seeders;
+                entry.leechers += torrent_data.num_leechers;
+                entry.completed += torrent_data.num_completed;
+            }
+        }
+
+        let mut statistics = BTreeMap::new();
+
+        add_statistics(&mut statistics, &self.ipv4);
+        add_statistics(&mut statistics, &self.ipv6);
+
+        statistics.into_iter().collect()
+    }
+
+    /// Statistics and peers for torrent, with IPv4 and IPv6 swarms combined
+    pub fn torrent_info(&self, info_hash: InfoHash) -> Option<TorrentInfo> {
+        let now = Instant::now();
+        let mut opt_torrent_info: Option<TorrentInfo> = None;
+
+        if let Some(torrent_data) = self.ipv4.get(&info_hash) {
+            torrent_data
+                .add_to_torrent_info(opt_torrent_info.get_or_insert_with(Default::default), now);
+        }
+        if let Some(torrent_data) = self.ipv6.get(&info_hash) {
+            torrent_data
+                .add_to_torrent_info(opt_torrent_info.get_or_insert_with(Default::default), now);
+        }
+
+        opt_torrent_info
+    }
+
+    /// Remove torrent, returning whether it was present
+    pub fn remove_torrent(&mut self, info_hash: InfoHash) -> bool {
+        let removed_ipv4 = self.ipv4.remove(&info_hash).is_some();
+        let removed_ipv6 = self.ipv6.remove(&info_hash).is_some();
+
+        removed_ipv4 || removed_ipv6
+    }
+
+    /// Remove peers with given id from torrent, returning whether any were
+    /// present
+    pub fn remove_peer(&mut self, info_hash: InfoHash, peer_id: PeerId) -> bool {
+        let removed_ipv4 = self
+            .ipv4
+            .get_mut(&info_hash)
+            .map_or(false, |torrent_data| torrent_data.remove_peers(peer_id));
+        let removed_ipv6 = self
+            .ipv6
+            .get_mut(&info_hash)
+            .map_or(false, |torrent_data| torrent_data.remove_peers(peer_id));
+
+        removed_ipv4 || removed_ipv6
+    }
+
+    pub fn to_snapshot(&self) -> SwarmSnapshot {
+        let now = Instant::now();
+
+        SwarmSnapshot {
+            ipv4: Self::torrent_map_to_snapshot(&self.ipv4, now),
+            ipv6: Self::torrent_map_to_snapshot(&self.ipv6, now),
+        }
+    }
+
+    pub fn restore_from_snapshot(&mut self, snapshot: SwarmSnapshot) {
+        let now = Instant::now();
+
+        Self::restore_torrent_map(&mut self.ipv4, snapshot.ipv4, now);
+        Self::restore_torrent_map(&mut self.ipv6, snapshot.ipv6, now);
+    }
+
+    fn torrent_map_to_snapshot<I: Ip>(
+        torrent_map: &TorrentMap<I>,
+        now: Instant,
+    ) -> Vec<TorrentSnapshot<I>> {
+        torrent_map
+            .iter()
+            .map(|(info_hash, torrent_data)| TorrentSnapshot {
+                info_hash: info_hash.0,
+                num_completed: torrent_data.num_completed,
+                peers: torrent_data
+                    .peers
+                    .iter()
+                    .map(|(key, peer)| PeerSnapshot {
+                        peer_id: key.peer_id.0,
+                        key: key.ip_or_key.as_ref().right().map(|key| key.to_string()),
+                        ip_address: peer.ip_address,
+                        port: peer.port,
+                        seeding: peer.status == PeerStatus::Seeding,
+                        valid_for: peer.valid_until.seconds_left(now),
+                        counted: peer.counted,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn restore_torrent_map<I: Ip>(
+        torrent_map: &mut TorrentMap<I>,
+        torrents: Vec<TorrentSnapshot<I>>,
+        now: Instant,
+    ) {
+        for torrent in torrents {
+            let torrent_data = torrent_map.entry(InfoHash(torrent.info_hash)).or_default();
+
+            for peer in torrent.peers {
+                let status = if peer.seeding {
+                    PeerStatus::Seeding
+                } else {
+                    PeerStatus::Leeching
+                };
+
+                if peer.counted {
+                    match status {
+                        PeerStatus::Seeding => torrent_data.num_seeders += 1,
+                        _ => torrent_data.num_leechers += 1,
+                    }
+                }
+
+                let key = PeerMapKey {
+                    peer_id: PeerId(peer.peer_id),
+                    ip_or_key: peer.key.map_or(Either::Left(peer.ip_address), |key| {
+                        Either::Right(key.into())
+                    }),
+                };
+
+                let opt_removed_peer = torrent_data.peers.insert(
+                    key,
+                    Peer {
+                        ip_address: peer.ip_address,
+                        port: peer.port,
+                        status,
+                        valid_until: ValidUntil::new_with_now(now, peer.valid_for),
+                        counted: peer.counted,
+                    },
+                );
+
+                if let Some(removed_peer) = opt_removed_peer {
+                    torrent_data.remove_from_counts(&removed_peer);
+                }
+            }
+
+            torrent_data.num_completed = torrent.num_completed;
+        }
+    }
 }
 
 pub async fn run_swarm_worker(
@@ -162,21 +439,76 @@ pub async fn run_swarm_worker(
     config: Config,
     state: State,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
-) {
-    let (_, mut request_receivers) = request_mesh_builder.join(Role::Consumer).await.unwrap();
+    snapshots: Arc<Mutex<Vec<SwarmSnapshot>>>,
+    command_receivers: Arc<Mutex<Vec<Option<UnboundedReceiver<SwarmCommandRequest>>>>>,
+) -> anyhow::Result<()> {
+    let (_, mut request_receivers) = request_mesh_builder
+        .join(Role::Consumer)
+        .await
+        .map_err(|err| anyhow::anyhow!("join request mesh: {:?}", err))?;
+
+    let consumer_index = request_receivers
+        .consumer_id()
+        .ok_or_else(|| anyhow::anyhow!("no consumer id"))?;
 
     let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
     let access_list = state.access_list;
+    let metrics = state.metrics;
+
+    {
+        let snapshot = ::std::mem::take(&mut snapshots.lock().unwrap()[consumer_index]);
+
+        torrents.borrow_mut().restore_from_snapshot(snapshot);
+    }
 
     // Periodically clean torrents
-    TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
-        enclose!((config, torrents, access_list) move || async move {
+    TimerActionRepeat::repeat(enclose!((config, torrents, access_list, metrics) move || {
+        enclose!((config, torrents, access_list, metrics) move || async move {
             torrents.borrow_mut().clean(&config, &access_list);
 
+            if config.metrics.active {
+                torrents.borrow().update_metrics(&metrics, consumer_index);
+            }
+
             Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
         })()
     }));
 
+    let snapshot_writer = Rc::new(RefCell::new(SnapshotWriter::new(
+        config.snapshot.clone(),
+        SNAPSHOT_KIND,
+        consumer_index,
+    )));
+
+    // Periodically save snapshot of torrents. Only copying the torrents is
+    // done on the executor.
+    if config.snapshot.active {
+        TimerActionRepeat::repeat(enclose!((config, torrents, snapshot_writer) move || {
+            enclose!((config, torrents, snapshot_writer) move || async move {
+                snapshot_writer
+                    .borrow_mut()
+                    .write_in_background(|| torrents.borrow().to_snapshot());
+
+                Some(Duration::from_secs(config.snapshot.interval))
+            })()
+        }));
+    }
+
+    // Periodically contribute torrent statistics to full scrape response
+    if config.full_scrape.active {
+        let full_scrape = state.full_scrape;
+
+        TimerActionRepeat::repeat(enclose!((config, torrents, full_scrape) move || {
+            enclose!((config, torrents, full_scrape) move || async move {
+                let statistics = torrents.borrow().full_scrape_statistics();
+
+                full_scrape.set_worker_statistics(consumer_index, statistics);
+
+                Some(Duration::from_secs(config.full_scrape.update_interval))
+            })()
+        }));
+    }
+
     let max_peer_age = config.cleaning.max_peer_age;
     let peer_valid_until = Rc::new(RefCell::new(ValidUntil::new(max_peer_age)));
 
@@ -189,6 +521,12 @@ pub async fn run_swarm_worker(
         })()
     }));
 
+    // Control server commands. Not awaited, since the control server
+    // doesn't keep workers running.
+    if let Some(command_receiver) = command_receivers.lock().unwrap()[consumer_index].take() {
+        spawn_local(handle_command_stream(torrents.clone(), command_receiver)).detach();
+    }
+
     let mut handles = Vec::new();
 
     for (_, receiver) in request_receivers.streams() {
@@ -196,6 +534,7 @@ pub async fn run_swarm_worker(
             config.clone(),
             torrents.clone(),
             peer_valid_until.clone(),
+            state.announce_records.clone(),
             receiver,
         ))
         .detach();
@@ -203,15 +542,26 @@ pub async fn run_swarm_worker(
         handles.push(handle);
     }
 
+    // Request streams end when all socket workers have exited
     for handle in handles {
         handle.await;
     }
+
+    if config.snapshot.active {
+        snapshot_writer
+            .borrow_mut()
+            .write_final(torrents.borrow().to_snapshot())
+            .with_context(|| "write swarm snapshot on shutdown")?;
+    }
+
+    Ok(())
 }
 
 async fn handle_request_stream<S>(
     config: Config,
     torrents: Rc<RefCell<TorrentMaps>>,
     peer_valid_until: Rc<RefCell<ValidUntil>>,
+    opt_announce_records: Option<AnnounceRecordSender>,
     mut stream: S,
 ) where
     S: Stream<Item = ChannelRequest> + ::std::marker::Unpin,
@@ -225,6 +575,10 @@ async fn handle_request_stream<S>(
                 peer_addr,
                 response_sender,
             } => {
+                if let Some(announce_records) = opt_announce_records.as_ref() {
+                    announce_records.send(|| create_announce_record(&request, peer_addr));
+                }
+
                 let response = handle_announce_request(
                     &config,
                     &mut rng,
@@ -240,11 +594,9 @@ async fn handle_request_stream<S>(
             }
             ChannelRequest::Scrape {
                 request,
-                peer_addr,
                 response_sender,
             } => {
-                let response =
-                    handle_scrape_request(&config, &mut torrents.borrow_mut(), peer_addr, request);
+                let response = handle_scrape_request(&config, &mut torrents.borrow_mut(), request);
 
                 if let Err(err) = response_sender.connect().await.send(response).await {
                     ::log::error!("swarm worker could not send scrape response: {:#}", err);
@@ -254,6 +606,30 @@ async fn handle_request_stream<S>(
     }
 }
 
+async fn handle_command_stream(
+    torrents: Rc<RefCell<TorrentMaps>>,
+    mut stream: UnboundedReceiver<SwarmCommandRequest>,
+) {
+    while let Some(request) = stream.next().await {
+        let mut torrent_maps = torrents.borrow_mut();
+
+        let response = match request.command {
+            SwarmCommand::Torrent { info_hash } => ControlResponse::Torrent {
+                torrent: torrent_maps.torrent_info(InfoHash(info_hash)),
+            },
+            SwarmCommand::RemoveTorrent { info_hash } => ControlResponse::Removed {
+                removed: torrent_maps.remove_torrent(InfoHash(info_hash)),
+            },
+            SwarmCommand::RemovePeer { info_hash, peer_id } => ControlResponse::Removed {
+                removed: torrent_maps.remove_peer(InfoHash(info_hash), PeerId(peer_id)),
+            },
+        };
+
+        // Control server might have stopped waiting
+        let _ = request.response_sender.send(response);
+    }
+}
+
 pub fn handle_announce_request(
     config: &Config,
     rng: &mut impl Rng,
@@ -262,67 +638,153 @@ pub fn handle_announce_request(
     peer_addr: CanonicalSocketAddr,
     request: AnnounceRequest,
 ) -> AnnounceResponse {
-    match peer_addr.get().ip() {
-        IpAddr::V4(peer_ip_address) => {
-            let torrent_data: &mut TorrentData<Ipv4Addr> =
-                torrent_maps.ipv4.entry(request.info_hash).or_default();
-
-            let (seeders, leechers, response_peers) = upsert_peer_and_get_response_peers(
-                config,
-                rng,
-                peer_ip_address,
-                torrent_data,
-                request,
-                valid_until,
-            );
+    let (opt_ipv4, opt_ipv6) = get_peer_ip_addresses(config, peer_addr, &request);
+
+    let mut response = AnnounceResponse {
+        complete: 0,
+        incomplete: 0,
+        downloaded: 0,
+        announce_interval: config.protocol.peer_announce_interval,
+        peers: ResponsePeerListV4(vec![]),
+        peers6: ResponsePeerListV6(vec![]),
+        warning_message: None,
+    };
 
-            let response = AnnounceResponse {
-                complete: seeders,
-                incomplete: leechers,
-                announce_interval: config.protocol.peer_announce_interval,
-                peers: ResponsePeerListV4(response_peers),
-                peers6: ResponsePeerListV6(vec![]),
-                warning_message: None,
-            };
+    // Peers are counted once, in the swarm of the request IP version, even
+    // if they announce addresses of both IP versions
+    if let Some(peer_ip_address) = opt_ipv4 {
+        let torrent_data: &mut TorrentData<Ipv4Addr> =
+            torrent_maps.ipv4.entry(request.info_hash).or_default();
+
+        let response_peers = upsert_peer_and_get_response_peers(
+            config,
+            rng,
+            peer_ip_address,
+            peer_addr.is_ipv4(),
+            torrent_data,
+            &request,
+            valid_until,
+        );
+
+        response.peers = ResponsePeerListV4(response_peers);
+    }
 
-            response
+    if let Some(peer_ip_address) = opt_ipv6 {
+        let torrent_data: &mut TorrentData<Ipv6Addr> =
+            torrent_maps.ipv6.entry(request.info_hash).or_default();
+
+        let response_peers = upsert_peer_and_get_response_peers(
+            config,
+            rng,
+            peer_ip_address,
+            !peer_addr.is_ipv4(),
+            torrent_data,
+            &request,
+            valid_until,
+        );
+
+        response.peers6 = ResponsePeerListV6(response_peers);
+    }
+
+    if let Some(statistics) = torrent_maps.statistics(&request.info_hash) {
+        response.complete = statistics.complete;
+        response.incomplete = statistics.incomplete;
+        response.downloaded = statistics.downloaded;
+    }
+
+    response
+}
+
+fn create_announce_record(
+    request: &AnnounceRequest,
+    peer_addr: CanonicalSocketAddr,
+) -> AnnounceRecord {
+    let event = match request.event {
+        AnnounceEvent::Started => AnnounceRecordEvent::Started,
+        AnnounceEvent::Stopped => AnnounceRecordEvent::Stopped,
+        AnnounceEvent::Completed => AnnounceRecordEvent::Completed,
+        AnnounceEvent::Empty => AnnounceRecordEvent::None,
+    };
+
+    AnnounceRecord {
+        info_hash: request.info_hash.0,
+        peer_id: request.peer_id.0,
+        event,
+        bytes_left: Some(request.bytes_left as u64),
+        bytes_uploaded: Some(request.bytes_uploaded as u64),
+        bytes_downloaded: Some(request.bytes_downloaded as u64),
+        ipv4: peer_addr.is_ipv4(),
+    }
+}
+
+/// Get addresses to register peer with in IPv4 and IPv6 swarms
+///
+/// The source address is used unless announced addresses are trusted.
+/// Announced addresses replace the source address if they are of the same
+/// IP version, and are used in addition to it otherwise.
+fn get_peer_ip_addresses(
+    config: &Config,
+    peer_addr: CanonicalSocketAddr,
+    request: &AnnounceRequest,
+) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let source_ip = peer_addr.get().ip();
+
+    let (mut opt_ipv4, mut opt_ipv6) = match source_ip {
+        IpAddr::V4(ip) => (Some(ip), None),
+        IpAddr::V6(ip) => (None, Some(ip)),
+    };
+
+    let use_announced = match config.protocol.announced_ip_policy {
+        AnnouncedIpPolicy::Never => false,
+        AnnouncedIpPolicy::Private => is_private_ip(source_ip),
+        AnnouncedIpPolicy::Always => true,
+    };
+
+    if use_announced {
+        match request.ip {
+            Some(IpAddr::V4(ip)) => opt_ipv4 = Some(ip),
+            Some(IpAddr::V6(ip)) => opt_ipv6 = Some(ip),
+            None => (),
         }
-        IpAddr::V6(peer_ip_address) => {
-            let torrent_data: &mut TorrentData<Ipv6Addr> =
-                torrent_maps.ipv6.entry(request.info_hash).or_default();
-
-            let (seeders, leechers, response_peers) = upsert_peer_and_get_response_peers(
-                config,
-                rng,
-                peer_ip_address,
-                torrent_data,
-                request,
-                valid_until,
-            );
 
-            let response = AnnounceResponse {
-                complete: seeders,
-                incomplete: leechers,
-                announce_interval: config.protocol.peer_announce_interval,
-                peers: ResponsePeerListV4(vec![]),
-                peers6: ResponsePeerListV6(response_peers),
-                warning_message: None,
-            };
+        // BEP 7 parameters take precedence
+        opt_ipv4 = request.ipv4.or(opt_ipv4);
+        opt_ipv6 = request.ipv6.or(opt_ipv6);
+    }
 
-            response
+    (opt_ipv4, opt_ipv6)
+}
+
+/// Private, loopback or link-local address
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+
+            // Unique local (fc00::/7) and link-local (fe80::/10) checks are
+            // not available in stable std
+            ip.is_loopback()
+                || (first_segment & 0xfe00) == 0xfc00
+                || (first_segment & 0xffc0) == 0xfe80
         }
     }
 }
 
-/// Insert/update peer. Return num_seeders, num_leechers and response peers
+/// Insert/update peer and return response peers
+///
+/// If `counted` is false, the peer is registered without being included in
+/// seeder/leecher counts and completions, since it is counted in the swarm
+/// of the other IP version.
 pub fn upsert_peer_and_get_response_peers<I: Ip>(
     config: &Config,
     rng: &mut impl Rng,
     peer_ip_address: I,
+    counted: bool,
     torrent_data: &mut TorrentData<I>,
-    request: AnnounceRequest,
+    request: &AnnounceRequest,
     valid_until: ValidUntil,
-) -> (usize, usize, Vec<ResponsePeer<I>>) {
+) -> Vec<ResponsePeer<I>> {
     // Insert/update/remove peer who sent this request
 
     let peer_status =
@@ -333,12 +795,14 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         port: request.port,
         status: peer_status,
         valid_until,
+        counted,
     };
 
     ::log::debug!("peer: {:?}", peer);
 
     let ip_or_key = request
         .key
+        .clone()
         .map(Either::Right)
         .unwrap_or_else(|| Either::Left(peer_ip_address));
 
@@ -351,12 +815,16 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
 
     let opt_removed_peer = match peer_status {
         PeerStatus::Leeching => {
-            torrent_data.num_leechers += 1;
+            if counted {
+                torrent_data.num_leechers += 1;
+            }
 
             torrent_data.peers.insert(peer_map_key.clone(), peer)
         }
         PeerStatus::Seeding => {
-            torrent_data.num_seeders += 1;
+            if counted {
+                torrent_data.num_seeders += 1;
+            }
 
             torrent_data.peers.insert(peer_map_key.clone(), peer)
         }
@@ -365,14 +833,16 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
 
     ::log::debug!("opt_removed_peer: {:?}", opt_removed_peer);
 
-    match opt_removed_peer.map(|peer| peer.status) {
-        Some(PeerStatus::Leeching) => {
-            torrent_data.num_leechers -= 1;
-        }
-        Some(PeerStatus::Seeding) => {
-            torrent_data.num_seeders -= 1;
-        }
-        _ => {}
+    // Peers still registered as seeding have already been counted
+    if counted
+        && request.event == AnnounceEvent::Completed
+        && opt_removed_peer.map(|peer| peer.status) != Some(PeerStatus::Seeding)
+    {
+        torrent_data.num_completed += 1;
+    }
+
+    if let Some(removed_peer) = opt_removed_peer {
+        torrent_data.remove_from_counts(&removed_peer);
     }
 
     ::log::debug!("peer request numwant: {:?}", request.numwant);
@@ -382,25 +852,18 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         Some(numwant) => numwant.min(config.protocol.max_peers),
     };
 
-    let response_peers: Vec<ResponsePeer<I>> = extract_response_peers(
+    extract_response_peers(
         rng,
         &torrent_data.peers,
         max_num_peers_to_take,
         peer_map_key,
         Peer::to_response_peer,
-    );
-
-    (
-        torrent_data.num_seeders,
-        torrent_data.num_leechers,
-        response_peers,
     )
 }
 
 pub fn handle_scrape_request(
     config: &Config,
     torrent_maps: &mut TorrentMaps,
-    peer_addr: CanonicalSocketAddr,
     request: ScrapeRequest,
 ) -> ScrapeResponse {
     let num_to_take = request
@@ -412,35 +875,318 @@ pub fn handle_scrape_request(
         files: BTreeMap::new(),
     };
 
-    let peer_ip = peer_addr.get().ip();
-
-    // If request.info_hashes is empty, don't return scrape for all
-    // torrents, even though reference server does it. It is too expensive.
-    if peer_ip.is_ipv4() {
-        for info_hash in request.info_hashes.into_iter().take(num_to_take) {
-            if let Some(torrent_data) = torrent_maps.ipv4.get(&info_hash) {
-                let stats = ScrapeStatistics {
-                    complete: torrent_data.num_seeders,
-                    downloaded: 0, // No implementation planned
-                    incomplete: torrent_data.num_leechers,
-                };
-
-                response.files.insert(info_hash, stats);
-            }
+    // Full scrape requests (without info hashes) are served by socket
+    // workers from a periodically rebuilt cache
+    for info_hash in request.info_hashes.into_iter().take(num_to_take) {
+        if let Some(statistics) = torrent_maps.statistics(&info_hash) {
+            response.files.insert(info_hash, statistics);
         }
-    } else {
-        for info_hash in request.info_hashes.into_iter().take(num_to_take) {
-            if let Some(torrent_data) = torrent_maps.ipv6.get(&info_hash) {
-                let stats = ScrapeStatistics {
-                    complete: torrent_data.num_seeders,
-                    downloaded: 0, // No implementation planned
-                    incomplete: torrent_data.num_leechers,
-                };
+    }
 
-                response.files.insert(info_hash, stats);
-            }
-        }
+    response
+}
+
+/// Convert announce request for handling by swarm workers of another
+/// tracker running in the same process
+///
+/// Peers are identified by peer ID only, since the shared swarm workers
+/// don't know about announce keys.
+pub fn create_shared_announce_request(
+    config: &Config,
+    peer_addr: CanonicalSocketAddr,
+    request: AnnounceRequest,
+) -> SharedAnnounceRequest {
+    let (opt_ipv4, opt_ipv6) = get_peer_ip_addresses(config, peer_addr, &request);
+
+    let max_peers = match request.numwant {
+        Some(0) | None => config.protocol.max_peers,
+        Some(numwant) => numwant.min(config.protocol.max_peers),
     };
 
-    response
+    let event = match request.event {
+        AnnounceEvent::Started => SharedAnnounceEvent::Started,
+        AnnounceEvent::Stopped => SharedAnnounceEvent::Stopped,
+        AnnounceEvent::Completed => SharedAnnounceEvent::Completed,
+        AnnounceEvent::Empty => SharedAnnounceEvent::None,
+    };
+
+    SharedAnnounceRequest {
+        info_hash: request.info_hash.0,
+        peer_id: request.peer_id.0,
+        port: request.port,
+        bytes_left: request.bytes_left,
+        event,
+        max_peers,
+        opt_ipv4,
+        opt_ipv6,
+        statistics_ipv4: peer_addr.is_ipv4(),
+    }
+}
+
+pub fn create_announce_response_from_shared(
+    config: &Config,
+    response: SharedAnnounceResponse,
+) -> AnnounceResponse {
+    AnnounceResponse {
+        complete: response.statistics.seeders,
+        incomplete: response.statistics.leechers,
+        downloaded: response.statistics.completed,
+        announce_interval: config.protocol.peer_announce_interval,
+        peers: ResponsePeerListV4(
+            response
+                .peers_ipv4
+                .into_iter()
+                .map(|(ip_address, port)| ResponsePeer { ip_address, port })
+                .collect(),
+        ),
+        peers6: ResponsePeerListV6(
+            response
+                .peers_ipv6
+                .into_iter()
+                .map(|(ip_address, port)| ResponsePeer { ip_address, port })
+                .collect(),
+        ),
+        warning_message: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_peer_ip_addresses() {
+        let mut config = Config::default();
+
+        let mut request = AnnounceRequest {
+            info_hash: InfoHash([0; 20]),
+            peer_id: PeerId([0; 20]),
+            port: 1000,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left: 0,
+            event: AnnounceEvent::Started,
+            numwant: None,
+            key: None,
+            ip: Some("192.0.2.2".parse().unwrap()),
+            ipv4: None,
+            ipv6: Some("2001:db8::2".parse().unwrap()),
+        };
+
+        let public_addr = CanonicalSocketAddr::new("192.0.2.1:1000".parse().unwrap());
+        let private_addr = CanonicalSocketAddr::new("[fd00::1]:1000".parse().unwrap());
+
+        let f = |config: &Config, peer_addr, request: &AnnounceRequest| {
+            let (opt_ipv4, opt_ipv6) = get_peer_ip_addresses(config, peer_addr, request);
+
+            (
+                opt_ipv4.map(|ip| ip.to_string()),
+                opt_ipv6.map(|ip| ip.to_string()),
+            )
+        };
+
+        assert_eq!(
+            f(&config, public_addr, &request),
+            (Some("192.0.2.1".into()), None)
+        );
+
+        config.protocol.announced_ip_policy = AnnouncedIpPolicy::Private;
+
+        assert_eq!(
+            f(&config, public_addr, &request),
+            (Some("192.0.2.1".into()), None)
+        );
+        assert_eq!(
+            f(&config, private_addr, &request),
+            (Some("192.0.2.2".into()), Some("2001:db8::2".into()))
+        );
+
+        config.protocol.announced_ip_policy = AnnouncedIpPolicy::Always;
+        request.ipv4 = Some("192.0.2.3".parse().unwrap());
+
+        assert_eq!(
+            f(&config, public_addr, &request),
+            (Some("192.0.2.3".into()), Some("2001:db8::2".into()))
+        );
+    }
+
+    #[test]
+    fn test_create_shared_announce_request() {
+        let config = Config::default();
+
+        let request = AnnounceRequest {
+            info_hash: InfoHash([1; 20]),
+            peer_id: PeerId([2; 20]),
+            port: 1000,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left: 10,
+            event: AnnounceEvent::Empty,
+            numwant: Some(config.protocol.max_peers + 1),
+            key: None,
+            ip: None,
+            ipv4: None,
+            ipv6: None,
+        };
+
+        let peer_addr = CanonicalSocketAddr::new("192.0.2.1:1000".parse().unwrap());
+
+        let shared_request = create_shared_announce_request(&config, peer_addr, request);
+
+        assert_eq!(shared_request.info_hash, [1; 20]);
+        assert_eq!(shared_request.peer_id, [2; 20]);
+        assert_eq!(shared_request.bytes_left, 10);
+        assert_eq!(shared_request.event, SharedAnnounceEvent::None);
+        assert_eq!(shared_request.max_peers, config.protocol.max_peers);
+        assert_eq!(shared_request.opt_ipv4, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(shared_request.opt_ipv6, None);
+        assert!(shared_request.statistics_ipv4);
+    }
+
+    #[test]
+    fn test_num_completed() {
+        let config = Config::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut torrent_data = TorrentData::<Ipv4Addr>::default();
+        let ip_address = Ipv4Addr::new(192, 0, 2, 1);
+        let valid_until = ValidUntil::new(60);
+
+        let mut request = AnnounceRequest {
+            info_hash: InfoHash([0; 20]),
+            peer_id: PeerId([1; 20]),
+            port: 1000,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left: 10,
+            event: AnnounceEvent::Started,
+            numwant: None,
+            key: None,
+            ip: None,
+            ipv4: None,
+            ipv6: None,
+        };
+
+        let mut announce = |torrent_data: &mut TorrentData<Ipv4Addr>, request: &AnnounceRequest| {
+            upsert_peer_and_get_response_peers(
+                &config,
+                &mut rng,
+                ip_address,
+                true,
+                torrent_data,
+                request,
+                valid_until,
+            );
+
+            torrent_data.num_completed
+        };
+
+        assert_eq!(announce(&mut torrent_data, &request), 0);
+
+        request.bytes_left = 0;
+        request.event = AnnounceEvent::Completed;
+
+        assert_eq!(announce(&mut torrent_data, &request), 1);
+
+        // Repeated completion from peer that is still seeding
+        assert_eq!(announce(&mut torrent_data, &request), 1);
+
+        request.peer_id = PeerId([2; 20]);
+
+        assert_eq!(announce(&mut torrent_data, &request), 2);
+
+        // Removed peers are not remembered, so completion is counted again
+        torrent_data.remove_peers(PeerId([1; 20]));
+        request.peer_id = PeerId([1; 20]);
+
+        assert_eq!(announce(&mut torrent_data, &request), 3);
+    }
+
+    #[test]
+    fn test_dual_stack_peer_counted_once() {
+        let mut config = Config::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut torrent_maps = TorrentMaps::default();
+        let valid_until = ValidUntil::new(60);
+        let info_hash = InfoHash([0; 20]);
+
+        config.protocol.announced_ip_policy = AnnouncedIpPolicy::Always;
+
+        let request = AnnounceRequest {
+            info_hash,
+            peer_id: PeerId([1; 20]),
+            port: 1000,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            bytes_left: 0,
+            event: AnnounceEvent::Completed,
+            numwant: None,
+            key: None,
+            ip: None,
+            ipv4: Some("192.0.2.2".parse().unwrap()),
+            ipv6: Some("2001:db8::2".parse().unwrap()),
+        };
+
+        let peer_addr = CanonicalSocketAddr::new("192.0.2.1:1000".parse().unwrap());
+
+        let response = handle_announce_request(
+            &config,
+            &mut rng,
+            &mut torrent_maps,
+            valid_until,
+            peer_addr,
+            request,
+        );
+
+        assert_eq!(response.complete, 1);
+        assert_eq!(response.incomplete, 0);
+        assert_eq!(response.downloaded, 1);
+
+        // Peer is registered in both swarms
+        assert_eq!(torrent_maps.ipv4.get(&info_hash).unwrap().peers.len(), 1);
+        assert_eq!(torrent_maps.ipv6.get(&info_hash).unwrap().peers.len(), 1);
+
+        let response = handle_scrape_request(
+            &config,
+            &mut torrent_maps,
+            ScrapeRequest {
+                info_hashes: vec![info_hash],
+            },
+        );
+
+        let statistics = &response.files[&info_hash];
+
+        assert_eq!(statistics.complete, 1);
+        assert_eq!(statistics.incomplete, 0);
+        assert_eq!(statistics.downloaded, 1);
+
+        let full_scrape_statistics = torrent_maps.full_scrape_statistics();
+
+        assert_eq!(full_scrape_statistics.len(), 1);
+        assert_eq!(full_scrape_statistics[0].1.seeders, 1);
+        assert_eq!(full_scrape_statistics[0].1.completed, 1);
+
+        let torrent_info = torrent_maps.torrent_info(info_hash).unwrap();
+
+        assert_eq!(torrent_info.seeders, 1);
+        assert_eq!(torrent_info.completed, 1);
+        assert_eq!(torrent_info.peers.len(), 2);
+
+        // Counts survive snapshot round trip
+        let mut restored = TorrentMaps::default();
+
+        restored.restore_from_snapshot(torrent_maps.to_snapshot());
+
+        let statistics = restored.statistics(&info_hash).unwrap();
+
+        assert_eq!(statistics.complete, 1);
+        assert_eq!(statistics.downloaded, 1);
+
+        // Removing peer updates counts of both swarms
+        assert!(torrent_maps.remove_peer(info_hash, PeerId([1; 20])));
+
+        let statistics = torrent_maps.statistics(&info_hash).unwrap();
+
+        assert_eq!(statistics.complete, 0);
+        assert_eq!(statistics.downloaded, 1);
+    }
 }
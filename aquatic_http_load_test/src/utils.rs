@@ -49,6 +49,9 @@ fn create_announce_request(config: &Config, state: &LoadTestState, rng: &mut imp
         port: rng.gen(),
         bytes_uploaded: 0,
         bytes_downloaded: 0,
+        ip: None,
+        ipv4: None,
+        ipv6: None,
     })
 }
 
@@ -1,5 +1,6 @@
 use tokio::sync::{mpsc, oneshot};
 
+use aquatic_common::metrics::{Counter, Gauge, MetricsRegistry};
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_http_protocol::{common::InfoHash, response::Response};
 
@@ -12,6 +13,68 @@ pub struct ChannelAnnounceRequest {
     pub response_sender: oneshot::Sender<Response>,
 }
 
+/// Metrics exposed in OpenMetrics format
+pub struct Metrics {
+    pub requests_received_announce: Counter,
+    pub invalid_requests: Counter,
+    pub responses_sent_announce: Counter,
+    pub responses_sent_failure: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    pub connections: Gauge,
+    pub torrents_ipv4: Vec<Gauge>,
+    pub torrents_ipv6: Vec<Gauge>,
+    pub peers_ipv4: Vec<Gauge>,
+    pub peers_ipv6: Vec<Gauge>,
+}
+
+impl Metrics {
+    pub fn new(registry: &MetricsRegistry, num_swarm_workers: usize) -> Self {
+        let response_counter = |response_type| {
+            registry.counter(
+                "responses_sent",
+                "Responses sent",
+                &[("type", response_type)],
+            )
+        };
+        let worker_gauges = |name, help, ip_version| -> Vec<Gauge> {
+            (0..num_swarm_workers)
+                .map(|i| {
+                    let worker = i.to_string();
+
+                    registry.gauge(
+                        name,
+                        help,
+                        &[("ip_version", ip_version), ("worker", worker.as_str())],
+                    )
+                })
+                .collect()
+        };
+
+        Self {
+            requests_received_announce: registry.counter(
+                "requests_received",
+                "Valid requests received",
+                &[("type", "announce")],
+            ),
+            invalid_requests: registry.counter(
+                "invalid_requests",
+                "Requests that could not be parsed",
+                &[],
+            ),
+            responses_sent_announce: response_counter("announce"),
+            responses_sent_failure: response_counter("failure"),
+            bytes_received: registry.counter("bytes_received", "Bytes received", &[]),
+            bytes_sent: registry.counter("bytes_sent", "Bytes sent", &[]),
+            connections: registry.gauge("connections", "Open connections", &[]),
+            torrents_ipv4: worker_gauges("torrents", "Torrents per swarm worker", "4"),
+            torrents_ipv6: worker_gauges("torrents", "Torrents per swarm worker", "6"),
+            peers_ipv4: worker_gauges("peers", "Peers per swarm worker", "4"),
+            peers_ipv6: worker_gauges("peers", "Peers per swarm worker", "6"),
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct RequestWorkerIndex(pub usize);
 
@@ -1,6 +1,6 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::privileges::PrivilegeConfig;
+use aquatic_common::{metrics::MetricsConfig, privileges::PrivilegeConfig};
 use aquatic_toml_config::TomlConfig;
 use serde::Deserialize;
 
@@ -24,6 +24,7 @@ pub struct Config {
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub cleaning: CleaningConfig,
+    pub metrics: MetricsConfig,
     pub privileges: PrivilegeConfig,
 }
 
@@ -38,6 +39,7 @@ impl Default for Config {
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             cleaning: CleaningConfig::default(),
+            metrics: MetricsConfig::default(),
             privileges: PrivilegeConfig::default(),
         }
     }
@@ -54,9 +56,9 @@ impl aquatic_common::cli::Config for Config {
 pub struct NetworkConfig {
     /// Bind to this address
     pub address: SocketAddr,
-    /// Path to TLS certificate (DER-encoded X.509)
+    /// Path to TLS certificate (PEM-encoded X.509)
     pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
+    /// Path to TLS private key (PEM-encoded PKCS#8, PKCS#1 or SEC1)
     pub tls_private_key_path: PathBuf,
     pub keep_alive: bool,
 }
@@ -5,9 +5,12 @@ mod workers;
 use std::{collections::VecDeque, sync::Arc};
 
 use aquatic_common::{
-    privileges::PrivilegeDropper, rustls_config::create_rustls_config, PanicSentinelWatcher,
+    metrics::{spawn_metrics_server, MetricsRegistry},
+    privileges::PrivilegeDropper,
+    rustls_config::create_rustls_config,
+    PanicSentinelWatcher,
 };
-use common::ChannelRequestSender;
+use common::{ChannelRequestSender, Metrics};
 use dotenv::dotenv;
 use signal_hook::{consts::SIGTERM, iterator::Signals};
 use tokio::sync::mpsc::channel;
@@ -22,9 +25,17 @@ pub fn run(config: Config) -> anyhow::Result<()> {
 
     dotenv().ok();
 
+    let metrics_registry = MetricsRegistry::new("aquatic_http_private");
+    let metrics = Arc::new(Metrics::new(&metrics_registry, config.swarm_workers));
+
+    if config.metrics.active {
+        spawn_metrics_server(&config.metrics, metrics_registry)?;
+    }
+
     let tls_config = Arc::new(create_rustls_config(
         &config.network.tls_certificate_path,
         &config.network.tls_private_key_path,
+        &[],
     )?);
 
     let mut request_senders = Vec::new();
@@ -46,6 +57,7 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         let sentinel = sentinel.clone();
         let config = config.clone();
         let tls_config = tls_config.clone();
+        let metrics = metrics.clone();
         let request_sender = ChannelRequestSender::new(request_senders.clone());
         let priv_dropper = priv_dropper.clone();
 
@@ -56,6 +68,7 @@ pub fn run(config: Config) -> anyhow::Result<()> {
                     sentinel,
                     config,
                     tls_config,
+                    metrics,
                     request_sender,
                     priv_dropper,
                 )
@@ -64,14 +77,17 @@ pub fn run(config: Config) -> anyhow::Result<()> {
         handles.push(handle);
     }
 
-    for _ in 0..config.swarm_workers {
+    for i in 0..config.swarm_workers {
         let sentinel = sentinel.clone();
         let config = config.clone();
+        let metrics = metrics.clone();
         let request_receiver = request_receivers.pop_front().unwrap();
 
         let handle = ::std::thread::Builder::new()
             .name("request".into())
-            .spawn(move || workers::swarm::run_swarm_worker(sentinel, config, request_receiver))?;
+            .spawn(move || {
+                workers::swarm::run_swarm_worker(sentinel, config, metrics, i, request_receiver)
+            })?;
 
         handles.push(handle);
     }
@@ -8,13 +8,19 @@ use std::{
 };
 
 use anyhow::Context;
-use aquatic_common::{privileges::PrivilegeDropper, rustls_config::RustlsConfig, PanicSentinel};
+use aquatic_common::{
+    privileges::PrivilegeDropper, rustls_config::RustlsConfig,
+    socket_activation::take_inherited_socket, PanicSentinel,
+};
 use axum::{extract::connect_info::Connected, routing::get, Extension, Router};
 use hyper::server::conn::AddrIncoming;
 use sqlx::mysql::MySqlPoolOptions;
 
 use self::tls::{TlsAcceptor, TlsStream};
-use crate::{common::ChannelRequestSender, config::Config};
+use crate::{
+    common::{ChannelRequestSender, Metrics},
+    config::Config,
+};
 
 impl<'a> Connected<&'a tls::TlsStream> for SocketAddr {
     fn connect_info(target: &'a TlsStream) -> Self {
@@ -26,6 +32,7 @@ pub fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
     tls_config: Arc<RustlsConfig>,
+    metrics: Arc<Metrics>,
     request_sender: ChannelRequestSender,
     priv_dropper: PrivilegeDropper,
 ) -> anyhow::Result<()> {
@@ -35,7 +42,13 @@ pub fn run_socket_worker(
         .enable_all()
         .build()?;
 
-    runtime.block_on(run_app(config, tls_config, tcp_listener, request_sender))?;
+    runtime.block_on(run_app(
+        config,
+        tls_config,
+        metrics,
+        tcp_listener,
+        request_sender,
+    ))?;
 
     Ok(())
 }
@@ -43,6 +56,7 @@ pub fn run_socket_worker(
 async fn run_app(
     config: Config,
     tls_config: Arc<RustlsConfig>,
+    metrics: Arc<Metrics>,
     tcp_listener: TcpListener,
     request_sender: ChannelRequestSender,
 ) -> anyhow::Result<()> {
@@ -52,6 +66,7 @@ async fn run_app(
     let tls_acceptor = TlsAcceptor::new(
         tls_config,
         AddrIncoming::from_listener(tokio::net::TcpListener::from_std(tcp_listener)?)?,
+        config.metrics.active.then(|| metrics.clone()),
     );
 
     let pool = MySqlPoolOptions::new()
@@ -63,6 +78,7 @@ async fn run_app(
         .route("/announce/:user_token/", get(routes::announce))
         .layer(Extension(Arc::new(config.clone())))
         .layer(Extension(pool))
+        .layer(Extension(metrics))
         .layer(Extension(Arc::new(request_sender)));
 
     axum::Server::builder(tls_acceptor)
@@ -83,20 +99,30 @@ fn create_tcp_listener(
         socket2::Domain::IPV6
     };
 
-    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    let socket = if let Some(socket) = take_inherited_socket(socket2::Type::STREAM, addr)
+        .with_context(|| "take inherited socket")?
+    {
+        socket
+    } else {
+        let socket =
+            socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+
+        socket
+            .set_reuse_port(true)
+            .with_context(|| "set_reuse_port")?;
+        socket
+            .bind(&addr.into())
+            .with_context(|| format!("bind to {}", addr))?;
+        socket
+            .listen(1024)
+            .with_context(|| format!("listen on {}", addr))?;
+
+        socket
+    };
 
-    socket
-        .set_reuse_port(true)
-        .with_context(|| "set_reuse_port")?;
     socket
         .set_nonblocking(true)
         .with_context(|| "set_nonblocking")?;
-    socket
-        .bind(&addr.into())
-        .with_context(|| format!("bind to {}", addr))?;
-    socket
-        .listen(1024)
-        .with_context(|| format!("listen on {}", addr))?;
 
     priv_dropper.after_socket_creation()?;
 
@@ -13,7 +13,7 @@ use aquatic_http_protocol::{
 };
 
 use crate::{
-    common::{ChannelRequestSender, RequestWorkerIndex},
+    common::{ChannelRequestSender, Metrics, RequestWorkerIndex},
     config::Config,
 };
 
@@ -23,39 +23,68 @@ pub async fn announce(
     Extension(config): Extension<Arc<Config>>,
     Extension(pool): Extension<MySqlPool>,
     Extension(request_sender): Extension<Arc<ChannelRequestSender>>,
+    Extension(metrics): Extension<Arc<Metrics>>,
     ConnectInfo(source_addr): ConnectInfo<SocketAddr>,
     opt_user_agent: Option<TypedHeader<UserAgent>>,
     Path(user_token): Path<String>,
     RawQuery(query): RawQuery,
 ) -> Result<Response, FailureResponse> {
-    let query = query.ok_or_else(|| FailureResponse::new("Empty query string"))?;
+    let result = async {
+        let query = query.ok_or_else(|| FailureResponse::new("Empty query string"))?;
 
-    let request = AnnounceRequest::from_query_string(&query)
-        .map_err(|_| FailureResponse::new("Malformed request"))?;
+        let request = match AnnounceRequest::from_query_string(&query) {
+            Ok(request) => {
+                if config.metrics.active {
+                    metrics.requests_received_announce.increment();
+                }
 
-    let swarm_worker_index = RequestWorkerIndex::from_info_hash(&config, request.info_hash);
-    let opt_user_agent = opt_user_agent.map(|header| header.as_str().to_owned());
+                request
+            }
+            Err(_) => {
+                if config.metrics.active {
+                    metrics.invalid_requests.increment();
+                }
 
-    let source_addr = CanonicalSocketAddr::new(source_addr);
+                return Err(FailureResponse::new("Malformed request"));
+            }
+        };
 
-    let (validated_request, opt_warning_message) =
-        db::validate_announce_request(&pool, source_addr, opt_user_agent, user_token, request)
-            .await?;
+        let swarm_worker_index = RequestWorkerIndex::from_info_hash(&config, request.info_hash);
+        let opt_user_agent = opt_user_agent.map(|header| header.as_str().to_owned());
 
-    let response_receiver = request_sender
-        .send_to(swarm_worker_index, validated_request, source_addr)
-        .await
-        .map_err(|err| internal_error(format!("Sending request over channel failed: {:#}", err)))?;
+        let source_addr = CanonicalSocketAddr::new(source_addr);
 
-    let mut response = response_receiver.await.map_err(|err| {
-        internal_error(format!("Receiving response over channel failed: {:#}", err))
-    })?;
+        let (validated_request, opt_warning_message) =
+            db::validate_announce_request(&pool, source_addr, opt_user_agent, user_token, request)
+                .await?;
 
-    if let Response::Announce(ref mut r) = response {
-        r.warning_message = opt_warning_message;
+        let response_receiver = request_sender
+            .send_to(swarm_worker_index, validated_request, source_addr)
+            .await
+            .map_err(|err| {
+                internal_error(format!("Sending request over channel failed: {:#}", err))
+            })?;
+
+        let mut response = response_receiver.await.map_err(|err| {
+            internal_error(format!("Receiving response over channel failed: {:#}", err))
+        })?;
+
+        if let Response::Announce(ref mut r) = response {
+            r.warning_message = opt_warning_message;
+        }
+
+        Ok(response)
+    }
+    .await;
+
+    if config.metrics.active {
+        match result {
+            Ok(_) => metrics.responses_sent_announce.increment(),
+            Err(_) => metrics.responses_sent_failure.increment(),
+        }
     }
 
-    Ok(response)
+    result
 }
 
 fn internal_error(error: String) -> FailureResponse {
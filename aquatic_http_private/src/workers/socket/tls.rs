@@ -21,6 +21,7 @@
 // ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE OF
 // THIS SOFTWARE.
 
+use aquatic_common::metrics::GaugeGuard;
 use core::task::{Context, Poll};
 use futures_util::ready;
 use hyper::server::accept::Accept;
@@ -33,6 +34,8 @@ use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_rustls::rustls::ServerConfig;
 
+use crate::common::Metrics;
+
 enum State {
     Handshaking(tokio_rustls::Accept<AddrStream>, SocketAddr),
     Streaming(tokio_rustls::server::TlsStream<AddrStream>),
@@ -43,15 +46,28 @@ enum State {
 // TlsStream implements AsyncRead/AsyncWrite handshaking tokio_rustls::Accept first
 pub struct TlsStream {
     state: State,
+    /// Set when metrics are active
+    metrics: Option<(Arc<Metrics>, GaugeGuard)>,
 }
 
 impl TlsStream {
-    fn new(stream: AddrStream, config: Arc<ServerConfig>) -> TlsStream {
+    fn new(
+        stream: AddrStream,
+        config: Arc<ServerConfig>,
+        opt_metrics: Option<Arc<Metrics>>,
+    ) -> TlsStream {
         let remote_addr = stream.remote_addr();
         let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
 
+        let metrics = opt_metrics.map(|metrics| {
+            let guard = metrics.connections.increment_scoped();
+
+            (metrics, guard)
+        });
+
         TlsStream {
             state: State::Handshaking(accept, remote_addr),
+            metrics,
         }
     }
 
@@ -70,7 +86,9 @@ impl AsyncRead for TlsStream {
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
         let pin = self.get_mut();
-        match pin.state {
+        let filled_before = buf.filled().len();
+
+        let result = match pin.state {
             State::Handshaking(ref mut accept, ref mut socket_addr) => {
                 match ready!(Pin::new(accept).poll(cx)) {
                     Ok(mut stream) => {
@@ -83,7 +101,15 @@ impl AsyncRead for TlsStream {
                 }
             }
             State::Streaming(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+        };
+
+        if let (Some((metrics, _)), Poll::Ready(Ok(()))) = (&pin.metrics, &result) {
+            metrics
+                .bytes_received
+                .add((buf.filled().len() - filled_before) as u64);
         }
+
+        result
     }
 }
 
@@ -94,7 +120,8 @@ impl AsyncWrite for TlsStream {
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let pin = self.get_mut();
-        match pin.state {
+
+        let result = match pin.state {
             State::Handshaking(ref mut accept, _) => match ready!(Pin::new(accept).poll(cx)) {
                 Ok(mut stream) => {
                     let result = Pin::new(&mut stream).poll_write(cx, buf);
@@ -104,7 +131,13 @@ impl AsyncWrite for TlsStream {
                 Err(err) => Poll::Ready(Err(err)),
             },
             State::Streaming(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+        };
+
+        if let (Some((metrics, _)), Poll::Ready(Ok(bytes_written))) = (&pin.metrics, &result) {
+            metrics.bytes_sent.add(*bytes_written as u64);
         }
+
+        result
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -125,11 +158,20 @@ impl AsyncWrite for TlsStream {
 pub struct TlsAcceptor {
     config: Arc<ServerConfig>,
     incoming: AddrIncoming,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl TlsAcceptor {
-    pub fn new(config: Arc<ServerConfig>, incoming: AddrIncoming) -> TlsAcceptor {
-        TlsAcceptor { config, incoming }
+    pub fn new(
+        config: Arc<ServerConfig>,
+        incoming: AddrIncoming,
+        metrics: Option<Arc<Metrics>>,
+    ) -> TlsAcceptor {
+        TlsAcceptor {
+            config,
+            incoming,
+            metrics,
+        }
     }
 }
 
@@ -143,7 +185,11 @@ impl Accept for TlsAcceptor {
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         let pin = self.get_mut();
         match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
+            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(
+                sock,
+                pin.config.clone(),
+                pin.metrics.clone(),
+            )))),
             Some(Err(e)) => Poll::Ready(Some(Err(e))),
             None => Poll::Ready(None),
         }
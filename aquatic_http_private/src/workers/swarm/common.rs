@@ -5,6 +5,8 @@ use aquatic_common::{AmortizedIndexMap, ValidUntil};
 use aquatic_http_protocol::common::{AnnounceEvent, InfoHash, PeerId};
 use aquatic_http_protocol::response::ResponsePeer;
 
+use crate::common::Metrics;
+
 pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash {}
 
 impl Ip for Ipv4Addr {}
@@ -62,6 +64,7 @@ pub struct TorrentData<I: Ip> {
     pub peers: PeerMap<I>,
     pub num_seeders: usize,
     pub num_leechers: usize,
+    pub num_completed: usize,
 }
 
 impl<I: Ip> Default for TorrentData<I> {
@@ -71,6 +74,7 @@ impl<I: Ip> Default for TorrentData<I> {
             peers: Default::default(),
             num_seeders: 0,
             num_leechers: 0,
+            num_completed: 0,
         }
     }
 }
@@ -119,4 +123,18 @@ impl TorrentMaps {
 
         torrent_map.shrink_to_fit();
     }
+
+    pub fn update_metrics(&self, metrics: &Metrics, worker_index: usize) {
+        fn num_peers<I: Ip>(torrent_map: &TorrentMap<I>) -> i64 {
+            torrent_map
+                .values()
+                .map(|torrent_data| torrent_data.peers.len() as i64)
+                .sum()
+        }
+
+        metrics.torrents_ipv4[worker_index].set(self.ipv4.len() as i64);
+        metrics.torrents_ipv6[worker_index].set(self.ipv6.len() as i64);
+        metrics.peers_ipv4[worker_index].set(num_peers(&self.ipv4));
+        metrics.peers_ipv6[worker_index].set(num_peers(&self.ipv6));
+    }
 }
@@ -3,7 +3,9 @@ mod common;
 use std::cell::RefCell;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::rc::Rc;
+use std::sync::Arc;
 
+use aquatic_http_protocol::common::AnnounceEvent;
 use aquatic_http_protocol::request::AnnounceRequest;
 use rand::prelude::SmallRng;
 use rand::SeedableRng;
@@ -16,7 +18,7 @@ use aquatic_http_protocol::response::{
     AnnounceResponse, Response, ResponsePeer, ResponsePeerListV4, ResponsePeerListV6,
 };
 
-use crate::common::ChannelAnnounceRequest;
+use crate::common::{ChannelAnnounceRequest, Metrics};
 use crate::config::Config;
 
 use common::*;
@@ -24,27 +26,38 @@ use common::*;
 pub fn run_swarm_worker(
     _sentinel: PanicSentinel,
     config: Config,
+    metrics: Arc<Metrics>,
+    worker_index: usize,
     request_receiver: Receiver<ChannelAnnounceRequest>,
 ) -> anyhow::Result<()> {
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
-    runtime.block_on(run_inner(config, request_receiver))?;
+    let local_set = LocalSet::new();
+
+    local_set.block_on(
+        &runtime,
+        run_inner(config, metrics, worker_index, request_receiver),
+    )?;
 
     Ok(())
 }
 
 async fn run_inner(
     config: Config,
+    metrics: Arc<Metrics>,
+    worker_index: usize,
     mut request_receiver: Receiver<ChannelAnnounceRequest>,
 ) -> anyhow::Result<()> {
     let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
     let mut rng = SmallRng::from_entropy();
 
-    LocalSet::new().spawn_local(periodically_clean_torrents(
+    tokio::task::spawn_local(periodically_clean_torrents(
         config.clone(),
         torrents.clone(),
+        metrics,
+        worker_index,
     ));
 
     loop {
@@ -68,7 +81,12 @@ async fn run_inner(
     }
 }
 
-async fn periodically_clean_torrents(config: Config, torrents: Rc<RefCell<TorrentMaps>>) {
+async fn periodically_clean_torrents(
+    config: Config,
+    torrents: Rc<RefCell<TorrentMaps>>,
+    metrics: Arc<Metrics>,
+    worker_index: usize,
+) {
     let mut interval = time::interval(time::Duration::from_secs(
         config.cleaning.torrent_cleaning_interval,
     ));
@@ -77,6 +95,10 @@ async fn periodically_clean_torrents(config: Config, torrents: Rc<RefCell<Torren
         interval.tick().await;
 
         torrents.borrow_mut().clean();
+
+        if config.metrics.active {
+            torrents.borrow().update_metrics(&metrics, worker_index);
+        }
     }
 }
 
@@ -93,7 +115,7 @@ fn handle_announce_request(
             let torrent_data: &mut TorrentData<Ipv4Addr> =
                 torrent_maps.ipv4.entry(request.info_hash).or_default();
 
-            let (seeders, leechers, response_peers) = upsert_peer_and_get_response_peers(
+            let (seeders, leechers, completed, response_peers) = upsert_peer_and_get_response_peers(
                 config,
                 rng,
                 torrent_data,
@@ -105,6 +127,7 @@ fn handle_announce_request(
             let response = AnnounceResponse {
                 complete: seeders,
                 incomplete: leechers,
+                downloaded: completed,
                 announce_interval: config.protocol.peer_announce_interval,
                 peers: ResponsePeerListV4(response_peers),
                 peers6: ResponsePeerListV6(vec![]),
@@ -117,7 +140,7 @@ fn handle_announce_request(
             let torrent_data: &mut TorrentData<Ipv6Addr> =
                 torrent_maps.ipv6.entry(request.info_hash).or_default();
 
-            let (seeders, leechers, response_peers) = upsert_peer_and_get_response_peers(
+            let (seeders, leechers, completed, response_peers) = upsert_peer_and_get_response_peers(
                 config,
                 rng,
                 torrent_data,
@@ -129,6 +152,7 @@ fn handle_announce_request(
             let response = AnnounceResponse {
                 complete: seeders,
                 incomplete: leechers,
+                downloaded: completed,
                 announce_interval: config.protocol.peer_announce_interval,
                 peers: ResponsePeerListV4(vec![]),
                 peers6: ResponsePeerListV6(response_peers),
@@ -140,7 +164,8 @@ fn handle_announce_request(
     }
 }
 
-/// Insert/update peer. Return num_seeders, num_leechers and response peers
+/// Insert/update peer. Return num_seeders, num_leechers, num_completed and
+/// response peers
 pub fn upsert_peer_and_get_response_peers<I: Ip>(
     config: &Config,
     rng: &mut SmallRng,
@@ -148,7 +173,7 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
     source_ip: I,
     request: AnnounceRequest,
     valid_until: ValidUntil,
-) -> (usize, usize, Vec<ResponsePeer<I>>) {
+) -> (usize, usize, usize, Vec<ResponsePeer<I>>) {
     // Insert/update/remove peer who sent this request
 
     let peer_status =
@@ -180,7 +205,15 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
         PeerStatus::Stopped => torrent_data.peers.remove(&peer_map_key),
     };
 
-    match opt_removed_peer.map(|peer| peer.status) {
+    let opt_removed_status = opt_removed_peer.map(|peer| peer.status);
+
+    // A peer that is still seeding has already had its completion counted
+    if request.event == AnnounceEvent::Completed && opt_removed_status != Some(PeerStatus::Seeding)
+    {
+        torrent_data.num_completed += 1;
+    }
+
+    match opt_removed_status {
         Some(PeerStatus::Leeching) => {
             torrent_data.num_leechers -= 1;
         }
@@ -206,6 +239,68 @@ pub fn upsert_peer_and_get_response_peers<I: Ip>(
     (
         torrent_data.num_seeders,
         torrent_data.num_leechers,
+        torrent_data.num_completed,
         response_peers,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use aquatic_http_protocol::common::{InfoHash, PeerId};
+
+    use super::*;
+
+    #[test]
+    fn test_num_completed() {
+        let config = Config::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut torrent_maps = TorrentMaps::default();
+        let ip_address = Ipv4Addr::new(192, 0, 2, 1);
+
+        let mut announce =
+            |torrent_maps: &mut TorrentMaps, peer_id: u8, valid_until: ValidUntil| {
+                let request = AnnounceRequest {
+                    info_hash: InfoHash([0; 20]),
+                    peer_id: PeerId([peer_id; 20]),
+                    port: 1000,
+                    bytes_uploaded: 0,
+                    bytes_downloaded: 0,
+                    bytes_left: 0,
+                    event: AnnounceEvent::Completed,
+                    numwant: None,
+                    key: None,
+                    ip: None,
+                    ipv4: None,
+                    ipv6: None,
+                };
+
+                let torrent_data = torrent_maps.ipv4.entry(request.info_hash).or_default();
+
+                let (_, _, num_completed, _) = upsert_peer_and_get_response_peers(
+                    &config,
+                    &mut rng,
+                    torrent_data,
+                    ip_address,
+                    request,
+                    valid_until,
+                );
+
+                num_completed
+            };
+
+        let valid_until = ValidUntil::new(60);
+        let expired = ValidUntil::new_with_now(Instant::now() - Duration::from_secs(1), 0);
+
+        assert_eq!(announce(&mut torrent_maps, 1, valid_until), 1);
+        // Peer is still seeding
+        assert_eq!(announce(&mut torrent_maps, 1, valid_until), 1);
+        assert_eq!(announce(&mut torrent_maps, 2, expired), 2);
+
+        torrent_maps.clean();
+
+        // Expired peers are not remembered, so completion is counted again
+        assert_eq!(announce(&mut torrent_maps, 2, valid_until), 3);
+    }
+}
@@ -19,6 +19,7 @@ pub fn bench(c: &mut Criterion) {
         announce_interval: 120,
         complete: 100,
         incomplete: 500,
+        downloaded: 50,
         peers: ResponsePeerListV4(peers),
         peers6: ResponsePeerListV6(Vec::new()),
         warning_message: None,
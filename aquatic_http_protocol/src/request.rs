@@ -1,4 +1,5 @@
 use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::Context;
 use smartstring::{LazyCompact, SmartString};
@@ -18,6 +19,12 @@ pub struct AnnounceRequest {
     /// Number of response peers wanted
     pub numwant: Option<usize>,
     pub key: Option<SmartString<LazyCompact>>,
+    /// Address sent in `ip` parameter. DNS names are not supported.
+    pub ip: Option<IpAddr>,
+    /// Address sent in `ipv4` parameter (BEP 7). Any port is ignored.
+    pub ipv4: Option<Ipv4Addr>,
+    /// Address sent in `ipv6` parameter (BEP 7). Any port is ignored.
+    pub ipv6: Option<Ipv6Addr>,
 }
 
 impl AnnounceRequest {
@@ -59,6 +66,21 @@ impl AnnounceRequest {
             output.write_all(::urlencoding::encode(key.as_str()).as_bytes())?;
         }
 
+        if let Some(ip) = self.ip {
+            output.write_all(b"&ip=")?;
+            output.write_all(::urlencoding::encode(&ip.to_string()).as_bytes())?;
+        }
+
+        if let Some(ipv4) = self.ipv4 {
+            output.write_all(b"&ipv4=")?;
+            output.write_all(ipv4.to_string().as_bytes())?;
+        }
+
+        if let Some(ipv6) = self.ipv6 {
+            output.write_all(b"&ipv6=")?;
+            output.write_all(::urlencoding::encode(&ipv6.to_string()).as_bytes())?;
+        }
+
         // Always ask for compact responses to ease load testing of non-aquatic trackers
         output.write_all(b"&compact=1")?;
 
@@ -79,6 +101,9 @@ impl AnnounceRequest {
         let mut event = AnnounceEvent::default();
         let mut opt_numwant = None;
         let mut opt_key = None;
+        let mut opt_ip = None;
+        let mut opt_ipv4 = None;
+        let mut opt_ipv6 = None;
 
         let query_string_bytes = query_string.as_bytes();
 
@@ -141,6 +166,23 @@ impl AnnounceRequest {
                     }
                     opt_key = Some(::urlencoding::decode(value)?.into());
                 }
+                // Invalid addresses are ignored, since `ip` may contain DNS
+                // names and clients might send empty values
+                "ip" => {
+                    opt_ip = parse_ip_parameter(value);
+                }
+                "ipv4" => {
+                    opt_ipv4 = match parse_ip_parameter(value) {
+                        Some(IpAddr::V4(ip)) => Some(ip),
+                        _ => None,
+                    };
+                }
+                "ipv6" => {
+                    opt_ipv6 = match parse_ip_parameter(value) {
+                        Some(IpAddr::V6(ip)) => Some(ip),
+                        _ => None,
+                    };
+                }
                 k => {
                     ::log::debug!("ignored unrecognized key: {}", k)
                 }
@@ -163,12 +205,26 @@ impl AnnounceRequest {
             event,
             numwant: opt_numwant,
             key: opt_key,
+            ip: opt_ip,
+            ipv4: opt_ipv4,
+            ipv6: opt_ipv6,
         })
     }
 }
 
+/// Parse url-encoded IP address, optionally including a port
+fn parse_ip_parameter(value: &str) -> Option<IpAddr> {
+    let value = ::urlencoding::decode(value).ok()?;
+
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ScrapeRequest {
+    /// Empty for full scrape requests
     pub info_hashes: Vec<InfoHash>,
 }
 
@@ -236,10 +292,6 @@ impl ScrapeRequest {
             }
         }
 
-        if info_hashes.is_empty() {
-            return Err(anyhow::anyhow!("No info hashes sent"));
-        }
-
         Ok(ScrapeRequest { info_hashes })
     }
 }
@@ -283,6 +335,39 @@ impl Request {
         Self::from_http_get_path(path).map_err(RequestParseError::Invalid)
     }
 
+    /// Parse Request from HTTP request bytes, also returning value of the
+    /// last header with given name (case-insensitive), if any
+    ///
+    /// Unlike `from_bytes`, this waits for all headers to arrive.
+    pub fn from_bytes_with_header<'a>(
+        bytes: &'a [u8],
+        header_name: &str,
+    ) -> Result<(Self, Option<&'a [u8]>), RequestParseError> {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut http_request = httparse::Request::new(&mut headers);
+
+        match http_request.parse(bytes) {
+            Ok(httparse::Status::Complete(_)) => {
+                let path = http_request
+                    .path
+                    .ok_or_else(|| RequestParseError::Invalid(anyhow::anyhow!("no http path")))?;
+
+                let opt_header_value = http_request
+                    .headers
+                    .iter()
+                    .rev()
+                    .find(|header| header.name.eq_ignore_ascii_case(header_name))
+                    .map(|header| header.value);
+
+                let request = Self::from_http_get_path(path).map_err(RequestParseError::Invalid)?;
+
+                Ok((request, opt_header_value))
+            }
+            Ok(httparse::Status::Partial) => Err(RequestParseError::NeedMoreData),
+            Err(err) => Err(RequestParseError::Invalid(anyhow::Error::from(err))),
+        }
+    }
+
     /// Parse Request from http path (GET `/announce?info_hash=...`)
     ///
     /// Existing serde-url decode crates were insufficient, so the decision was
@@ -302,15 +387,16 @@ impl Request {
         let mut split_parts = path.splitn(2, '?');
 
         let location = split_parts.next().with_context(|| "no location")?;
-        let query_string = split_parts.next().with_context(|| "no query string")?;
+        let opt_query_string = split_parts.next();
 
         if location == "/announce" {
             Ok(Request::Announce(AnnounceRequest::from_query_string(
-                query_string,
+                opt_query_string.with_context(|| "no query string")?,
             )?))
         } else {
+            // Full scrape requests don't need to include a query string
             Ok(Request::Scrape(ScrapeRequest::from_query_string(
-                query_string,
+                opt_query_string.unwrap_or_default(),
             )?))
         }
     }
@@ -352,6 +438,9 @@ mod tests {
             event: AnnounceEvent::Started,
             numwant: Some(0),
             key: Some("4ab4b877".into()),
+            ip: None,
+            ipv4: None,
+            ipv6: None,
         })
     }
 
@@ -369,6 +458,57 @@ mod tests {
         assert_eq!(parsed_request, reference_request);
     }
 
+    #[test]
+    fn test_request_from_bytes_with_header() {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(b"GET ");
+        bytes.extend_from_slice(&ANNOUNCE_REQUEST_PATH.as_bytes());
+        bytes.extend_from_slice(b" HTTP/1.1\r\nX-Forwarded-For: 192.0.2.1\r\n");
+
+        assert!(matches!(
+            Request::from_bytes_with_header(&bytes, "x-forwarded-for"),
+            Err(RequestParseError::NeedMoreData)
+        ));
+
+        bytes.extend_from_slice(b"x-forwarded-for: 192.0.2.2\r\n\r\n");
+
+        let (request, opt_value) =
+            Request::from_bytes_with_header(&bytes, "X-Forwarded-For").unwrap();
+
+        assert_eq!(request, get_reference_announce_request());
+        assert_eq!(opt_value, Some(&b"192.0.2.2"[..]));
+
+        let (_, opt_value) = Request::from_bytes_with_header(&bytes, "X-Real-IP").unwrap();
+
+        assert_eq!(opt_value, None);
+    }
+
+    #[test]
+    fn test_announce_request_ip_parameters() {
+        let query_string = format!(
+            "{}&ip=example.com&ipv4=192.0.2.1:6881&ipv6=%5B2001%3Adb8%3A%3A1%5D%3A6881",
+            ANNOUNCE_REQUEST_PATH.split_once('?').unwrap().1
+        );
+
+        let request = AnnounceRequest::from_query_string(&query_string).unwrap();
+
+        assert_eq!(request.ip, None);
+        assert_eq!(request.ipv4, Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(request.ipv6, Some("2001:db8::1".parse().unwrap()));
+
+        let query_string = format!(
+            "{}&ip=2001%3Adb8%3A%3A2&ipv4=2001%3Adb8%3A%3A3&ipv6=192.0.2.1",
+            ANNOUNCE_REQUEST_PATH.split_once('?').unwrap().1
+        );
+
+        let request = AnnounceRequest::from_query_string(&query_string).unwrap();
+
+        assert_eq!(request.ip, Some("2001:db8::2".parse().unwrap()));
+        assert_eq!(request.ipv4, None);
+        assert_eq!(request.ipv6, None);
+    }
+
     #[test]
     fn test_scrape_request_from_bytes() {
         let mut bytes = Vec::new();
@@ -385,6 +525,23 @@ mod tests {
         assert_eq!(parsed_request, reference_request);
     }
 
+    #[test]
+    fn test_full_scrape_request_from_bytes() {
+        let reference_request = Request::Scrape(ScrapeRequest {
+            info_hashes: Vec::new(),
+        });
+
+        for path in ["/scrape", "/scrape?", "/scrape?compact=1"] {
+            let bytes = format!("GET {} HTTP/1.1\r\n\r\n", path);
+
+            let parsed_request = Request::from_bytes(bytes.as_bytes()).unwrap();
+
+            assert_eq!(parsed_request, reference_request);
+        }
+
+        assert!(Request::from_bytes(b"GET /announce HTTP/1.1\r\n\r\n").is_err());
+    }
+
     impl Arbitrary for AnnounceRequest {
         fn arbitrary(g: &mut Gen) -> Self {
             let key: Option<String> = Arbitrary::arbitrary(g);
@@ -399,6 +556,9 @@ mod tests {
                 event: Arbitrary::arbitrary(g),
                 numwant: Arbitrary::arbitrary(g),
                 key: key.map(|key| key.into()),
+                ip: Arbitrary::arbitrary(g),
+                ipv4: Arbitrary::arbitrary(g),
+                ipv6: Arbitrary::arbitrary(g),
             }
         }
     }
@@ -432,11 +592,6 @@ mod tests {
                         return TestResult::discard();
                     }
                 }
-                Request::Scrape(ScrapeRequest { ref info_hashes }) => {
-                    if info_hashes.is_empty() {
-                        return TestResult::discard();
-                    }
-                }
                 _ => {}
             }
 
@@ -47,6 +47,9 @@ pub struct AnnounceResponse {
     pub announce_interval: usize,
     pub complete: usize,
     pub incomplete: usize,
+    /// Number of completed downloads
+    #[serde(default)]
+    pub downloaded: usize,
     #[serde(default)]
     pub peers: ResponsePeerListV4,
     #[serde(default)]
@@ -67,6 +70,9 @@ impl AnnounceResponse {
         bytes_written += output.write(b"d8:completei")?;
         bytes_written += output.write(itoa::Buffer::new().format(self.complete).as_bytes())?;
 
+        bytes_written += output.write(b"e10:downloadedi")?;
+        bytes_written += output.write(itoa::Buffer::new().format(self.downloaded).as_bytes())?;
+
         bytes_written += output.write(b"e10:incompletei")?;
         bytes_written += output.write(itoa::Buffer::new().format(self.incomplete).as_bytes())?;
 
@@ -146,7 +152,10 @@ impl ScrapeResponse {
             bytes_written += output.write(b"d8:completei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.complete).as_bytes())?;
-            bytes_written += output.write(b"e10:downloadedi0e10:incompletei")?;
+            bytes_written += output.write(b"e10:downloadedi")?;
+            bytes_written +=
+                output.write(itoa::Buffer::new().format(statistics.downloaded).as_bytes())?;
+            bytes_written += output.write(b"e10:incompletei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.incomplete).as_bytes())?;
             bytes_written += output.write(b"ee")?;
@@ -280,7 +289,7 @@ impl quickcheck::Arbitrary for ScrapeStatistics {
         Self {
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
-            downloaded: 0,
+            downloaded: usize::arbitrary(g),
         }
     }
 }
@@ -292,6 +301,7 @@ impl quickcheck::Arbitrary for AnnounceResponse {
             announce_interval: usize::arbitrary(g),
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
+            downloaded: usize::arbitrary(g),
             peers: ResponsePeerListV4::arbitrary(g),
             peers6: ResponsePeerListV6::arbitrary(g),
             warning_message: quickcheck::Arbitrary::arbitrary(g),
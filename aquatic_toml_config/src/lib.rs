@@ -19,8 +19,8 @@ macro_rules! gen_serialize_deserialize_test {
 
 /// Export structs to toml, converting Rust doc strings to comments.
 ///
-/// Supports one level of nesting. Fields containing structs must come
-/// after regular fields.
+/// Supports nesting. Fields containing structs must come after regular
+/// fields.
 ///
 /// Usage:
 /// ```
@@ -80,6 +80,17 @@ pub mod __private {
 
     pub trait Private {
         fn __to_string(&self, comment: Option<String>, field_name: String) -> String;
+
+        /// Tables use `prefix` to build their full dotted name, while other
+        /// values ignore it
+        fn __to_string_with_prefix(
+            &self,
+            comment: Option<String>,
+            _prefix: &str,
+            field_name: String,
+        ) -> String {
+            self.__to_string(comment, field_name)
+        }
     }
 
     macro_rules! impl_trait {
@@ -123,4 +134,22 @@ pub mod __private {
 
     impl_trait!(PathBuf);
     impl_trait!(SocketAddr);
+
+    impl<T: serde::Serialize> Private for Vec<T> {
+        fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+            let mut output = String::new();
+
+            if let Some(comment) = comment {
+                output.push_str(&comment);
+            }
+
+            // Arrays can't be serialized as toml documents on
+            // their own, so go through Value
+            let value = crate::toml::Value::try_from(self).unwrap();
+
+            output.push_str(&format!("{} = {}\n", field_name, value));
+
+            output
+        }
+    }
 }
@@ -19,6 +19,26 @@ impl Default for TestConfigInnerA {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, TomlConfig, Deserialize)]
+struct TestConfigInnerB {
+    /// Comment for c
+    c: bool,
+    /// Comment for nested TestConfigInnerA
+    inner_a: TestConfigInnerA,
+}
+
+impl Default for TestConfigInnerB {
+    fn default() -> Self {
+        Self {
+            c: false,
+            inner_a: TestConfigInnerA {
+                a: "Nested hello world".into(),
+                b: 200,
+            },
+        }
+    }
+}
+
 /// Comment for TestConfig
 #[derive(Clone, Debug, PartialEq, Eq, TomlConfig, Deserialize)]
 struct TestConfig {
@@ -28,8 +48,12 @@ struct TestConfig {
     /// Comment for b
     b: usize,
     c: bool,
+    /// Comment for d
+    d: Vec<String>,
     /// Comment for TestConfigInnerA
     inner_a: TestConfigInnerA,
+    /// Comment for TestConfigInnerB
+    inner_b: TestConfigInnerB,
 }
 
 impl Default for TestConfig {
@@ -38,7 +62,9 @@ impl Default for TestConfig {
             a: "Hello, world!".into(),
             b: 100,
             c: true,
+            d: vec!["a".into(), "b".into()],
             inner_a: Default::default(),
+            inner_b: Default::default(),
         }
     }
 }
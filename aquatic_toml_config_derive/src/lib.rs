@@ -1,6 +1,6 @@
 use proc_macro2::{TokenStream, TokenTree};
 use quote::quote;
-use syn::{parse_macro_input, Attribute, Data, DataStruct, DeriveInput, Fields, Ident, Type};
+use syn::{parse_macro_input, Attribute, Data, DataStruct, DeriveInput, Fields, Type};
 
 #[proc_macro_derive(TomlConfig)]
 pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -15,7 +15,7 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 let mut output = String::new();
             };
 
-            extract_from_struct(ident.clone(), struct_data, &mut output_stream);
+            extract_from_struct(struct_data, &mut output_stream);
 
             proc_macro::TokenStream::from(quote! {
                 impl ::aquatic_toml_config::TomlConfig for #ident {
@@ -30,6 +30,9 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                         }
 
                         let body = {
+                            let struct_default = &#ident::default();
+                            let table_prefix = String::new();
+
                             #output_stream
 
                             output
@@ -42,6 +45,20 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                 }
                 impl ::aquatic_toml_config::__private::Private for #ident {
                     fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+                        ::aquatic_toml_config::__private::Private::__to_string_with_prefix(
+                            self,
+                            comment,
+                            "",
+                            field_name,
+                        )
+                    }
+
+                    fn __to_string_with_prefix(
+                        &self,
+                        comment: Option<String>,
+                        prefix: &str,
+                        field_name: String,
+                    ) -> String {
                         let mut output = String::new();
 
                         output.push('\n');
@@ -49,9 +66,14 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                         if let Some(comment) = comment {
                             output.push_str(&comment);
                         }
-                        output.push_str(&format!("[{}]\n", field_name));
+                        output.push_str(&format!("[{}{}]\n", prefix, field_name));
 
                         let body = {
+                            // Use actual value, since it might differ
+                            // from default of type when nested
+                            let struct_default = self;
+                            let table_prefix = format!("{}{}.", prefix, field_name);
+
                             #output_stream
 
                             output
@@ -93,21 +115,14 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     }
 }
 
-fn extract_from_struct(
-    struct_ty_ident: Ident,
-    struct_data: DataStruct,
-    output_stream: &mut TokenStream,
-) {
+/// Generate code pushing fields of `struct_default` to `output`
+fn extract_from_struct(struct_data: DataStruct, output_stream: &mut TokenStream) {
     let fields = if let Fields::Named(fields) = struct_data.fields {
         fields
     } else {
         panic!("Fields are not named");
     };
 
-    output_stream.extend(::std::iter::once(quote! {
-        let struct_default = #struct_ty_ident::default();
-    }));
-
     for field in fields.named.into_iter() {
         let ident = field.ident.expect("Encountered unnamed field");
         let ident_string = format!("{}", ident);
@@ -117,11 +132,12 @@ fn extract_from_struct(
             output_stream.extend(::std::iter::once(quote! {
                 {
                     let comment: Option<String> = #comment;
-                    let field_default: #path = struct_default.#ident;
+                    let field_default: &#path = &struct_default.#ident;
 
-                    let s: String = ::aquatic_toml_config::__private::Private::__to_string(
-                        &field_default,
+                    let s: String = ::aquatic_toml_config::__private::Private::__to_string_with_prefix(
+                        field_default,
                         comment,
+                        &table_prefix,
                         #ident_string.to_string()
                     );
                     output.push_str(&s);
@@ -1,16 +1,20 @@
 use std::collections::BTreeMap;
 use std::hash::Hash;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::sync::atomic::AtomicUsize;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use crossbeam_channel::{Sender, TrySendError};
-
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::announce_events::AnnounceRecordSender;
+use aquatic_common::ip_access_list::{IpAccessList, IpAccessListArcSwap};
+use aquatic_common::metrics::{Counter, Gauge, MetricsRegistry};
+use aquatic_common::shutdown::Shutdown;
+use aquatic_common::url_access_list::UrlAccessListArcSwap;
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::*;
 
 use crate::config::Config;
+use crate::queue::{BatchReceiver, BatchSender};
 
 pub const BUFFER_SIZE: usize = 8192;
 
@@ -42,6 +46,12 @@ pub enum ConnectedResponse {
 #[derive(Clone, Copy, Debug)]
 pub struct SocketWorkerIndex(pub usize);
 
+/// Index of listener (socket bound to one of the configured addresses)
+/// that a request was received on, and that the response should be sent
+/// from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ListenerIndex(pub usize);
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct SwarmWorkerIndex(pub usize);
 
@@ -51,60 +61,139 @@ impl SwarmWorkerIndex {
     }
 }
 
+pub type ConnectedRequestItem = (
+    SocketWorkerIndex,
+    ListenerIndex,
+    ConnectedRequest,
+    CanonicalSocketAddr,
+);
+pub type ConnectedResponseItem = (ConnectedResponse, ListenerIndex, CanonicalSocketAddr);
+
+pub type ConnectedRequestReceiver = BatchReceiver<ConnectedRequestItem>;
+pub type ConnectedResponseReceiver = BatchReceiver<ConnectedResponseItem>;
+
+/// Passes on requests from a socket worker to swarm workers in batches
+///
+/// Call [`ConnectedRequestSender::flush`] after handling a number of
+/// incoming datagrams.
 pub struct ConnectedRequestSender {
     index: SocketWorkerIndex,
-    senders: Vec<Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>>,
+    sender: BatchSender<ConnectedRequestItem>,
+    counters: QueueCounters,
 }
 
 impl ConnectedRequestSender {
     pub fn new(
         index: SocketWorkerIndex,
-        senders: Vec<Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>>,
+        sender: BatchSender<ConnectedRequestItem>,
+        state: &State,
     ) -> Self {
-        Self { index, senders }
+        Self {
+            index,
+            sender,
+            counters: QueueCounters {
+                statistics_ipv4: state.statistics_ipv4.clone(),
+                statistics_ipv6: state.statistics_ipv6.clone(),
+                dropped: state.metrics.request_channel_dropped.clone(),
+                queue_full: state.metrics.request_queue_full.clone(),
+            },
+        }
     }
 
     pub fn try_send_to(
-        &self,
+        &mut self,
         index: SwarmWorkerIndex,
+        listener_index: ListenerIndex,
         request: ConnectedRequest,
         addr: CanonicalSocketAddr,
     ) {
-        match self.senders[index.0].try_send((self.index, request, addr)) {
-            Ok(()) => {}
-            Err(TrySendError::Full(_)) => {
-                ::log::error!("Request channel {} is full, dropping request. Try increasing number of swarm workers or raising config.worker_channel_size.", index.0)
-            }
-            Err(TrySendError::Disconnected(_)) => {
-                panic!("Request channel {} is disconnected", index.0);
-            }
+        if self
+            .sender
+            .send(index.0, (self.index, listener_index, request, addr))
+            .is_err()
+        {
+            self.counters
+                .record_dropped(addr, |statistics| &statistics.requests_dropped);
         }
     }
+
+    pub fn flush(&mut self) {
+        self.counters.record_queue_full(self.sender.flush());
+    }
 }
 
+/// Passes on responses from a swarm worker to socket workers in batches
+///
+/// Call [`ConnectedResponseSender::flush`] after handling a number of
+/// requests.
 pub struct ConnectedResponseSender {
-    senders: Vec<Sender<(ConnectedResponse, CanonicalSocketAddr)>>,
+    sender: BatchSender<ConnectedResponseItem>,
+    counters: QueueCounters,
 }
 
 impl ConnectedResponseSender {
-    pub fn new(senders: Vec<Sender<(ConnectedResponse, CanonicalSocketAddr)>>) -> Self {
-        Self { senders }
+    pub fn new(sender: BatchSender<ConnectedResponseItem>, state: &State) -> Self {
+        Self {
+            sender,
+            counters: QueueCounters {
+                statistics_ipv4: state.statistics_ipv4.clone(),
+                statistics_ipv6: state.statistics_ipv6.clone(),
+                dropped: state.metrics.response_channel_dropped.clone(),
+                queue_full: state.metrics.response_queue_full.clone(),
+            },
+        }
     }
 
     pub fn try_send_to(
-        &self,
+        &mut self,
         index: SocketWorkerIndex,
+        listener_index: ListenerIndex,
         response: ConnectedResponse,
         addr: CanonicalSocketAddr,
     ) {
-        match self.senders[index.0].try_send((response, addr)) {
-            Ok(()) => {}
-            Err(TrySendError::Full(_)) => {
-                ::log::error!("Response channel {} is full, dropping response. Try increasing number of socket workers or raising config.worker_channel_size.", index.0)
-            }
-            Err(TrySendError::Disconnected(_)) => {
-                panic!("Response channel {} is disconnected", index.0);
-            }
+        if self
+            .sender
+            .send(index.0, (response, listener_index, addr))
+            .is_err()
+        {
+            self.counters
+                .record_dropped(addr, |statistics| &statistics.responses_dropped);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.counters.record_queue_full(self.sender.flush());
+    }
+}
+
+/// Accounting for messages that couldn't be passed on right away
+struct QueueCounters {
+    statistics_ipv4: Arc<Statistics>,
+    statistics_ipv6: Arc<Statistics>,
+    dropped: Counter,
+    /// Number of times a batch was kept back, because the queue was full
+    queue_full: Counter,
+}
+
+impl QueueCounters {
+    fn record_dropped(
+        &self,
+        addr: CanonicalSocketAddr,
+        get_counter: impl Fn(&Statistics) -> &AtomicUsize,
+    ) {
+        let statistics = if addr.is_ipv4() {
+            &self.statistics_ipv4
+        } else {
+            &self.statistics_ipv6
+        };
+
+        get_counter(statistics).fetch_add(1, Ordering::Relaxed);
+        self.dropped.increment();
+    }
+
+    fn record_queue_full(&self, num_batches_kept: usize) {
+        if num_batches_kept > 0 {
+            self.queue_full.add(num_batches_kept as u64);
         }
     }
 }
@@ -134,12 +223,17 @@ impl PeerStatus {
 
 pub struct Statistics {
     pub requests_received: AtomicUsize,
+    pub requests_blocked: AtomicUsize,
     pub responses_sent_connect: AtomicUsize,
     pub responses_sent_announce: AtomicUsize,
     pub responses_sent_scrape: AtomicUsize,
     pub responses_sent_error: AtomicUsize,
     pub bytes_received: AtomicUsize,
     pub bytes_sent: AtomicUsize,
+    /// Requests dropped because queue to swarm worker was full
+    pub requests_dropped: AtomicUsize,
+    /// Responses dropped because queue to socket worker was full
+    pub responses_dropped: AtomicUsize,
     pub torrents: Vec<AtomicUsize>,
     pub peers: Vec<AtomicUsize>,
 }
@@ -148,12 +242,15 @@ impl Statistics {
     pub fn new(num_swarm_workers: usize) -> Self {
         Self {
             requests_received: Default::default(),
+            requests_blocked: Default::default(),
             responses_sent_connect: Default::default(),
             responses_sent_announce: Default::default(),
             responses_sent_scrape: Default::default(),
             responses_sent_error: Default::default(),
             bytes_received: Default::default(),
             bytes_sent: Default::default(),
+            requests_dropped: Default::default(),
+            responses_dropped: Default::default(),
             torrents: Self::create_atomic_usize_vec(num_swarm_workers),
             peers: Self::create_atomic_usize_vec(num_swarm_workers),
         }
@@ -166,19 +263,224 @@ impl Statistics {
     }
 }
 
+/// Statistics for one listener, covering both IP versions
+pub struct ListenerStatistics {
+    pub address: SocketAddr,
+    pub requests_received: AtomicUsize,
+    pub responses_sent: AtomicUsize,
+    pub bytes_received: AtomicUsize,
+    pub bytes_sent: AtomicUsize,
+}
+
+impl ListenerStatistics {
+    fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            requests_received: Default::default(),
+            responses_sent: Default::default(),
+            bytes_received: Default::default(),
+            bytes_sent: Default::default(),
+        }
+    }
+}
+
+/// Metrics for one IP version, exposed in OpenMetrics format
+pub struct IpVersionMetrics {
+    pub requests_received: Counter,
+    pub invalid_requests: Counter,
+    pub requests_blocked: Counter,
+    pub requests_rate_limited: Counter,
+    pub responses_sent_connect: Counter,
+    pub responses_sent_announce: Counter,
+    pub responses_sent_scrape: Counter,
+    pub responses_sent_error: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    pub torrents: Vec<Gauge>,
+    pub peers: Vec<Gauge>,
+}
+
+impl IpVersionMetrics {
+    fn new(registry: &MetricsRegistry, ip_version: &str, num_swarm_workers: usize) -> Self {
+        let labels = [("ip_version", ip_version)];
+        let response_counter = |response_type| {
+            registry.counter(
+                "responses_sent",
+                "Responses sent",
+                &[("ip_version", ip_version), ("type", response_type)],
+            )
+        };
+        let worker_gauges = |name, help| -> Vec<Gauge> {
+            (0..num_swarm_workers)
+                .map(|i| {
+                    let worker = i.to_string();
+
+                    registry.gauge(
+                        name,
+                        help,
+                        &[("ip_version", ip_version), ("worker", worker.as_str())],
+                    )
+                })
+                .collect()
+        };
+
+        Self {
+            requests_received: registry.counter(
+                "requests_received",
+                "Valid requests received",
+                &labels,
+            ),
+            invalid_requests: registry.counter(
+                "invalid_requests",
+                "Requests that could not be parsed",
+                &labels,
+            ),
+            requests_blocked: registry.counter(
+                "requests_blocked",
+                "Requests ignored because of IP access list",
+                &labels,
+            ),
+            requests_rate_limited: registry.counter(
+                "requests_rate_limited",
+                "Requests exceeding per-IP rate limit",
+                &labels,
+            ),
+            responses_sent_connect: response_counter("connect"),
+            responses_sent_announce: response_counter("announce"),
+            responses_sent_scrape: response_counter("scrape"),
+            responses_sent_error: response_counter("error"),
+            bytes_received: registry.counter("bytes_received", "Bytes received", &labels),
+            bytes_sent: registry.counter("bytes_sent", "Bytes sent", &labels),
+            torrents: worker_gauges("torrents", "Torrents per swarm worker"),
+            peers: worker_gauges("peers", "Peers per swarm worker"),
+        }
+    }
+}
+
+/// Metrics for one listener, covering both IP versions
+pub struct ListenerMetrics {
+    pub requests_received: Counter,
+    pub responses_sent: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+}
+
+impl ListenerMetrics {
+    fn new(registry: &MetricsRegistry, address: SocketAddr) -> Self {
+        let address = address.to_string();
+        let labels = [("listener", address.as_str())];
+
+        Self {
+            requests_received: registry.counter(
+                "listener_requests_received",
+                "Valid requests received per listener",
+                &labels,
+            ),
+            responses_sent: registry.counter(
+                "listener_responses_sent",
+                "Responses sent per listener",
+                &labels,
+            ),
+            bytes_received: registry.counter(
+                "listener_bytes_received",
+                "Bytes received per listener",
+                &labels,
+            ),
+            bytes_sent: registry.counter("listener_bytes_sent", "Bytes sent per listener", &labels),
+        }
+    }
+}
+
+pub struct Metrics {
+    pub ipv4: IpVersionMetrics,
+    pub ipv6: IpVersionMetrics,
+    /// Indexed by `ListenerIndex`
+    pub listeners: Vec<ListenerMetrics>,
+    pub request_channel_dropped: Counter,
+    pub response_channel_dropped: Counter,
+    pub request_queue_full: Counter,
+    pub response_queue_full: Counter,
+}
+
+impl Metrics {
+    pub fn new(
+        registry: &MetricsRegistry,
+        num_swarm_workers: usize,
+        listener_addresses: &[SocketAddr],
+    ) -> Self {
+        let dropped_counter = |channel| {
+            registry.counter(
+                "channel_messages_dropped",
+                "Messages dropped because worker queue was full",
+                &[("channel", channel)],
+            )
+        };
+        let queue_full_counter = |channel| {
+            registry.counter(
+                "worker_queue_full",
+                "Batches kept back by sender because worker queue was full",
+                &[("channel", channel)],
+            )
+        };
+
+        Self {
+            ipv4: IpVersionMetrics::new(registry, "4", num_swarm_workers),
+            ipv6: IpVersionMetrics::new(registry, "6", num_swarm_workers),
+            listeners: listener_addresses
+                .iter()
+                .map(|address| ListenerMetrics::new(registry, *address))
+                .collect(),
+            request_channel_dropped: dropped_counter("request"),
+            response_channel_dropped: dropped_counter("response"),
+            request_queue_full: queue_full_counter("request"),
+            response_queue_full: queue_full_counter("response"),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub ip_access_list: Arc<IpAccessListArcSwap>,
+    pub url_access_list: Arc<UrlAccessListArcSwap>,
+    pub announced_ip_trusted_networks: Arc<IpAccessList>,
     pub statistics_ipv4: Arc<Statistics>,
     pub statistics_ipv6: Arc<Statistics>,
+    /// Indexed by `ListenerIndex`
+    pub listener_statistics: Arc<Vec<ListenerStatistics>>,
+    pub metrics_registry: MetricsRegistry,
+    pub metrics: Arc<Metrics>,
+    /// Set if announce event export is active
+    pub announce_records: Option<AnnounceRecordSender>,
+    pub shutdown: Shutdown,
 }
 
 impl State {
-    pub fn new(num_swarm_workers: usize) -> Self {
+    pub fn new(num_swarm_workers: usize, listener_addresses: &[SocketAddr]) -> Self {
+        let metrics_registry = MetricsRegistry::new("aquatic_udp");
+        let metrics = Arc::new(Metrics::new(
+            &metrics_registry,
+            num_swarm_workers,
+            listener_addresses,
+        ));
+
         Self {
             access_list: Arc::new(AccessListArcSwap::default()),
+            ip_access_list: Arc::new(IpAccessListArcSwap::default()),
+            url_access_list: Arc::new(UrlAccessListArcSwap::default()),
+            announced_ip_trusted_networks: Default::default(),
             statistics_ipv4: Arc::new(Statistics::new(num_swarm_workers)),
             statistics_ipv6: Arc::new(Statistics::new(num_swarm_workers)),
+            listener_statistics: Arc::new(
+                listener_addresses
+                    .iter()
+                    .map(|address| ListenerStatistics::new(*address))
+                    .collect(),
+            ),
+            metrics_registry,
+            metrics,
+            announce_records: None,
+            shutdown: Default::default(),
         }
     }
 }
@@ -1,7 +1,17 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
-use serde::Deserialize;
+use aquatic_common::{
+    access_list::AccessListConfig,
+    announce_events::AnnounceEventsConfig,
+    control::ControlConfig,
+    deserialize_one_or_many,
+    ip_access_list::{IpAccessList, IpAccessListConfig},
+    metrics::MetricsConfig,
+    privileges::PrivilegeConfig,
+    snapshot::SnapshotConfig,
+    url_access_list::UrlAccessListConfig,
+};
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 use aquatic_toml_config::TomlConfig;
@@ -17,9 +27,32 @@ pub struct Config {
     /// Swarm workers receive a number of requests from socket workers,
     /// generate responses and send them back to the socket workers.
     pub swarm_workers: usize,
+    /// Let each socket worker also act as swarm worker for its own shard of
+    /// torrents (Linux only). A BPF program attached to the sockets routes
+    /// announce and scrape requests to the socket worker owning the info
+    /// hash, so that they can be answered without passing through
+    /// channels. swarm_workers is ignored, since there is one shard per
+    /// socket worker. Other processes must not bind sockets to the same
+    /// addresses. Can't be combined with network.use_io_uring, with
+    /// systemd socket activation or with a shared swarm in the combined
+    /// binary.
+    pub shard_per_core: bool,
     pub log_level: LogLevel,
-    /// Maximum number of items in each channel passing requests/responses
-    /// between workers. A value of zero means that the channel will be of
+    /// After receiving SIGTERM, let workers finish handling pending
+    /// requests for at most this many seconds before exiting
+    pub shutdown_grace_period: u64,
+    /// Maximum number of batches waiting in each queue passing requests or
+    /// responses between a socket worker and a swarm worker. Rounded up to
+    /// a power of two. When a queue is full, one more batch is kept by the
+    /// sending worker, after which requests or responses are dropped.
+    pub worker_queue_capacity: usize,
+    /// Maximum number of requests or responses in each batch passed between
+    /// workers. Batches are also passed on when the sending worker has
+    /// handled what it has received so far.
+    pub worker_batch_size: usize,
+    /// Maximum number of items in each channel passing requests from other
+    /// trackers to swarm workers when shared_swarm is enabled in the
+    /// combined binary. A value of zero means that the channel will be of
     /// unbounded size.
     pub worker_channel_size: usize,
     /// How long to block waiting for requests in swarm workers. Higher
@@ -30,10 +63,21 @@ pub struct Config {
     pub request_channel_recv_timeout_ms: u64,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
+    pub rate_limit: RateLimitConfig,
+    pub connection_id: ConnectionIdConfig,
     pub statistics: StatisticsConfig,
+    pub metrics: MetricsConfig,
+    pub control: ControlConfig,
+    pub announce_events: AnnounceEventsConfig,
     pub cleaning: CleaningConfig,
     pub privileges: PrivilegeConfig,
     pub access_list: AccessListConfig,
+    pub ip_access_list: IpAccessListConfig,
+    /// Access list for announce request URL paths, sent by clients
+    /// supporting BEP 41. Can be used to serve multiple logical trackers
+    /// on one port.
+    pub url_access_list: UrlAccessListConfig,
+    pub snapshot: SnapshotConfig,
     #[cfg(feature = "cpu-pinning")]
     pub cpu_pinning: aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc,
 }
@@ -43,15 +87,27 @@ impl Default for Config {
         Self {
             socket_workers: 1,
             swarm_workers: 1,
+            shard_per_core: false,
             log_level: LogLevel::Error,
+            shutdown_grace_period: 10,
+            worker_queue_capacity: 256,
+            worker_batch_size: 64,
             worker_channel_size: 0,
             request_channel_recv_timeout_ms: 100,
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            connection_id: ConnectionIdConfig::default(),
             statistics: StatisticsConfig::default(),
+            metrics: MetricsConfig::default(),
+            control: ControlConfig::default(),
+            announce_events: AnnounceEventsConfig::default(),
             cleaning: CleaningConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            ip_access_list: IpAccessListConfig::default(),
+            url_access_list: UrlAccessListConfig::default(),
+            snapshot: SnapshotConfig::default(),
             #[cfg(feature = "cpu-pinning")]
             cpu_pinning: Default::default(),
         }
@@ -67,8 +123,13 @@ impl aquatic_common::cli::Config for Config {
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
-    /// Bind to this address
-    pub address: SocketAddr,
+    /// Bind to these addresses
+    ///
+    /// Accepts a single address or a list, e.g.,
+    /// `["0.0.0.0:6969", "[::]:6969"]`. Every socket worker serves all of
+    /// them.
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub address: Vec<SocketAddr>,
     /// Only allow access over IPv6
     pub only_ipv6: bool,
     /// Size of socket recv buffer. Use 0 for OS default.
@@ -92,26 +153,58 @@ pub struct NetworkConfig {
     /// such as FreeBSD. Setting the value to zero disables resending
     /// functionality.
     pub resend_buffer_max_len: usize,
+    /// Maximum number of datagrams to receive or send with a single system
+    /// call
+    ///
+    /// Values larger than one enable recvmmsg and sendmmsg on Linux, which
+    /// significantly reduces CPU usage at high request rates. Other
+    /// operating systems always handle one datagram at a time. Responses
+    /// are sent at the latest after each poll iteration, so this doesn't
+    /// add latency.
+    pub batch_size: usize,
+    /// Use io_uring instead of mio for socket I/O (requires Linux 6.0 or
+    /// later)
+    ///
+    /// Requests are received with multishot recvmsg into buffers shared
+    /// with the kernel and responses are sent asynchronously. batch_size,
+    /// poll_event_capacity and resend_buffer_max_len are not used.
+    #[cfg(feature = "io-uring")]
+    pub use_io_uring: bool,
+    /// Number of receive buffers and maximum number of responses being
+    /// sent at a time in each socket worker when using io_uring. Must be a
+    /// power of two.
+    ///
+    /// At most this many further responses to connect and error requests
+    /// wait for a free send slot. Others are dropped.
+    #[cfg(feature = "io-uring")]
+    pub ring_size: u16,
 }
 
 impl NetworkConfig {
     pub fn ipv4_active(&self) -> bool {
-        self.address.is_ipv4() || !self.only_ipv6
+        self.address
+            .iter()
+            .any(|address| address.is_ipv4() || !self.only_ipv6)
     }
     pub fn ipv6_active(&self) -> bool {
-        self.address.is_ipv6()
+        self.address.iter().any(|address| address.is_ipv6())
     }
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
-            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            address: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
             only_ipv6: false,
             socket_recv_buffer_size: 4096 * 128,
             poll_event_capacity: 4096,
             poll_timeout_ms: 50,
             resend_buffer_max_len: 0,
+            batch_size: 32,
+            #[cfg(feature = "io-uring")]
+            use_io_uring: false,
+            #[cfg(feature = "io-uring")]
+            ring_size: 1024,
         }
     }
 }
@@ -125,6 +218,27 @@ pub struct ProtocolConfig {
     pub max_response_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: i32,
+    /// Use IPv4 addresses sent by peers in the ip address field of announce
+    /// requests. Available values are ignore, trusted and always.
+    ///
+    /// In trusted mode, the addresses are only used if the request was sent
+    /// from an address in announced_ip_trusted_networks.
+    ///
+    /// Only applies to requests sent over IPv4.
+    pub announced_ip_policy: AnnouncedIpPolicy,
+    /// IP addresses or CIDR ranges, e.g., 192.168.0.0/16, allowed to set
+    /// the ip address field in trusted mode
+    pub announced_ip_trusted_networks: Vec<String>,
+}
+
+impl ProtocolConfig {
+    pub fn parse_announced_ip_trusted_networks(&self) -> anyhow::Result<IpAccessList> {
+        IpAccessList::create_from_entries(
+            self.announced_ip_trusted_networks
+                .iter()
+                .map(|s| s.as_str()),
+        )
+    }
 }
 
 impl Default for ProtocolConfig {
@@ -133,6 +247,108 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 70,
             max_response_peers: 50,
             peer_announce_interval: 60 * 15,
+            announced_ip_policy: AnnouncedIpPolicy::Ignore,
+            announced_ip_trusted_networks: Vec::new(),
+        }
+    }
+}
+
+/// Policy for using IP addresses sent in announce requests. Available
+/// values are ignore, trusted and always.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnouncedIpPolicy {
+    /// Always use source address of request
+    Ignore,
+    /// Use announced address if request was sent from a trusted network
+    Trusted,
+    /// Always use announced address if present
+    Always,
+}
+
+/// What to do with requests exceeding the rate limit. Available actions
+/// are drop and error.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    /// Silently drop requests
+    Drop,
+    /// Send error response to announce and scrape requests with valid
+    /// connection IDs. Other requests are dropped.
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit number of requests per client IP address
+    ///
+    /// Limits are enforced separately by each socket worker.
+    pub active: bool,
+    /// Number of requests per second to allow on average
+    pub requests_per_second: u32,
+    /// Maximum number of requests to allow in a burst
+    pub burst: u32,
+    /// Group IPv6 addresses by this prefix length, since clients are often
+    /// assigned whole networks. Set to 128 to limit each address separately.
+    pub ipv6_prefix_len: u8,
+    /// Maximum number of addresses to track in each socket worker
+    ///
+    /// When the table is full, addresses with refilled buckets are evicted.
+    /// If no space is freed, requests from untracked addresses share a
+    /// single bucket with the limits above. This means that flooding the
+    /// table with spoofed addresses limits new clients more strictly, rather
+    /// than disabling rate limiting.
+    pub max_tracked_addresses: usize,
+    pub action: RateLimitAction,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            active: false,
+            requests_per_second: 10,
+            burst: 50,
+            ipv6_prefix_len: 64,
+            max_tracked_addresses: 100_000,
+            action: RateLimitAction::Drop,
+        }
+    }
+}
+
+/// Keys used for creating and validating connection IDs
+///
+/// By default, each process generates a random secret, so connection IDs
+/// are only accepted by the process that created them. Trackers using the
+/// same key file or secret, e.g., behind an anycast address, accept each
+/// other's connection IDs as long as their clocks are roughly in sync and
+/// they use the same key_rotation_interval and max_connection_age. This also
+/// keeps connection IDs valid across restarts.
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionIdConfig {
+    /// Path to file containing secret to derive keys from (at least 16
+    /// bytes, trailing newlines are ignored). Leave empty to not use a key
+    /// file.
+    ///
+    /// A suitable file can be created with, e.g.,
+    /// `head -c 32 /dev/urandom | xxd -p -c 64 > connection-id-secret`
+    pub key_file: PathBuf,
+    /// Secret to derive keys from (at least 16 bytes), as an alternative to
+    /// key_file. Leave empty to not use.
+    pub secret: String,
+    /// Derive a new key this often (seconds). Connection IDs created with
+    /// the previous key are still accepted. Must be at least as large as
+    /// max_connection_age. Set to 0 to disable rotation.
+    pub key_rotation_interval: u64,
+}
+
+impl Default for ConnectionIdConfig {
+    fn default() -> Self {
+        Self {
+            key_file: PathBuf::new(),
+            secret: String::new(),
+            key_rotation_interval: 60 * 60 * 24,
         }
     }
 }
@@ -1,177 +1,437 @@
 pub mod common;
 pub mod config;
+pub mod queue;
 pub mod workers;
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::thread::Builder;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use crossbeam_channel::{bounded, unbounded};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use signal_hook::consts::{SIGTERM, SIGUSR1};
 use signal_hook::iterator::Signals;
 
 use aquatic_common::access_list::update_access_list;
+use aquatic_common::announce_events::spawn_announce_record_writer;
+use aquatic_common::control::{spawn_control_server, SwarmCommandRequest};
 #[cfg(feature = "cpu-pinning")]
 use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+use aquatic_common::ip_access_list::update_ip_access_list;
+use aquatic_common::metrics::spawn_metrics_server;
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::shared_swarm::SharedSwarmRequest;
+use aquatic_common::shutdown::WorkerWatcher;
+use aquatic_common::snapshot::SwarmSnapshot;
+use aquatic_common::socket_activation::sockets_inherited;
+use aquatic_common::url_access_list::update_url_access_list;
 use aquatic_common::PanicSentinelWatcher;
+use aquatic_udp_protocol::InfoHash;
 
 use common::{
     ConnectedRequestSender, ConnectedResponseSender, SocketWorkerIndex, State, SwarmWorkerIndex,
 };
 use config::Config;
+use queue::batch_queues;
+use workers::socket::shard::Shard;
 use workers::socket::validator::ConnectionValidator;
+use workers::swarm::SwarmWorker;
 
 pub const APP_NAME: &str = "aquatic_udp: UDP BitTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const SNAPSHOT_KIND: &str = "udp";
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
-    let state = State::new(config.swarm_workers);
-    let connection_validator = ConnectionValidator::new(&config)?;
-    let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
+    let tracker = Tracker::start(config, priv_dropper, None)?;
 
-    update_access_list(&config.access_list, &state.access_list)?;
+    for signal in &mut signals {
+        match signal {
+            SIGUSR1 => tracker.reload(),
+            SIGTERM => break,
+            _ => unreachable!(),
+        }
+    }
+
+    ::log::info!("shutting down");
+
+    let deadline = tracker.trigger_shutdown();
+
+    tracker.wait(deadline)
+}
+
+/// Running tracker
+///
+/// Signal handling is left to the caller, so that several trackers can be
+/// run in the same process.
+pub struct Tracker {
+    config: Config,
+    state: State,
+    worker_watcher: WorkerWatcher,
+}
+
+impl Tracker {
+    /// Start workers
+    ///
+    /// Privileges are dropped by `priv_dropper` once all socket workers have
+    /// created their sockets.
+    ///
+    /// If `opt_shared_request_receivers` is set, swarm workers also handle
+    /// requests from socket workers of other trackers in the same process.
+    /// It must contain one receiver per swarm worker.
+    ///
+    /// In shard-per-core mode, swarm workers are run by socket workers
+    /// instead of in threads of their own.
+    pub fn start(
+        mut config: Config,
+        priv_dropper: PrivilegeDropper,
+        opt_shared_request_receivers: Option<Vec<Receiver<SharedSwarmRequest>>>,
+    ) -> ::anyhow::Result<Self> {
+        if config.network.address.is_empty() {
+            return Err(anyhow::anyhow!("network.address must not be empty"));
+        }
+
+        if config.shard_per_core {
+            check_shard_per_core_config(
+                &config,
+                opt_shared_request_receivers.is_some(),
+                sockets_inherited().with_context(|| "check for inherited sockets")?,
+            )?;
+
+            config.swarm_workers = config.socket_workers;
+        }
+
+        let mut state = State::new(config.swarm_workers, &config.network.address);
+
+        state.announced_ip_trusted_networks = Arc::new(
+            config
+                .protocol
+                .parse_announced_ip_trusted_networks()
+                .with_context(|| "parse announced ip trusted networks")?,
+        );
 
-    let mut request_senders = Vec::new();
-    let mut request_receivers = BTreeMap::new();
+        let connection_validator = ConnectionValidator::new(&config)?;
+        let (_, sentinel) = PanicSentinelWatcher::create_with_sentinel();
+        let mut worker_watcher = WorkerWatcher::default();
 
-    let mut response_senders = Vec::new();
-    let mut response_receivers = BTreeMap::new();
+        update_access_list(&config.access_list, &state.access_list)?;
+        update_ip_access_list(&config.ip_access_list, &state.ip_access_list)?;
+        update_url_access_list(&config.url_access_list, &state.url_access_list)?;
 
-    for i in 0..config.swarm_workers {
-        let (request_sender, request_receiver) = if config.worker_channel_size == 0 {
-            unbounded()
+        if config.metrics.active {
+            spawn_metrics_server(&config.metrics, state.metrics_registry.clone())?;
+        }
+        if config.announce_events.active {
+            state.announce_records = Some(spawn_announce_record_writer(
+                &config.announce_events,
+                &state.metrics_registry,
+            )?);
+        }
+
+        let mut command_senders = Vec::new();
+        let mut command_receivers = BTreeMap::new();
+
+        for i in 0..config.swarm_workers {
+            let (command_sender, command_receiver) = unbounded();
+
+            command_senders.push(command_sender);
+            command_receivers.insert(i, command_receiver);
+        }
+
+        if config.control.active {
+            spawn_control_server_for_tracker(&config, &state, command_senders)?;
+        }
+
+        let mut snapshots = if config.snapshot.active {
+            SwarmSnapshot::load(
+                &config.snapshot,
+                SNAPSHOT_KIND,
+                config.cleaning.max_peer_age,
+                config.swarm_workers,
+            )?
         } else {
-            bounded(config.worker_channel_size)
+            vec![SwarmSnapshot::default(); config.swarm_workers]
         };
 
-        request_senders.push(request_sender);
-        request_receivers.insert(i, request_receiver);
-    }
+        let mut shared_request_receivers = opt_shared_request_receivers
+            .into_iter()
+            .flatten()
+            .map(Some)
+            .collect::<Vec<_>>();
+
+        shared_request_receivers.resize_with(config.swarm_workers, || None);
+
+        // Workers detect shutdown through all senders of their queues being
+        // dropped, so only they may hold them
+        let (request_senders, request_receivers) = batch_queues(
+            config.socket_workers,
+            config.swarm_workers,
+            config.worker_queue_capacity,
+            config.worker_batch_size,
+        );
+        let (response_senders, response_receivers) = batch_queues(
+            config.swarm_workers,
+            config.socket_workers,
+            config.worker_queue_capacity,
+            config.worker_batch_size,
+        );
 
-    for i in 0..config.socket_workers {
-        let (response_sender, response_receiver) = if config.worker_channel_size == 0 {
-            unbounded()
+        let mut request_receivers = request_receivers.into_iter();
+        let mut response_senders = response_senders.into_iter();
+
+        // Sockets must be created in worker order for steering to work, so
+        // this is done before spawning any socket workers
+        let mut shard_sockets = if config.shard_per_core {
+            workers::socket::shard::create_shard_sockets(&config)?
         } else {
-            bounded(config.worker_channel_size)
+            Vec::new()
         };
 
-        response_senders.push(response_sender);
-        response_receivers.insert(i, response_receiver);
-    }
+        let mut shards = Vec::new();
+
+        for i in 0..config.swarm_workers {
+            let sentinel = sentinel.clone();
+            let config = config.clone();
+            let state = state.clone();
+            let request_receiver = request_receivers.next().unwrap();
+            let response_sender =
+                ConnectedResponseSender::new(response_senders.next().unwrap(), &state);
+            let snapshot = ::std::mem::take(&mut snapshots[i]);
+            let opt_shared_request_receiver = shared_request_receivers[i].take();
+            let command_receiver = command_receivers.remove(&i).unwrap();
 
-    for i in 0..config.swarm_workers {
-        let sentinel = sentinel.clone();
-        let config = config.clone();
-        let state = state.clone();
-        let request_receiver = request_receivers.remove(&i).unwrap().clone();
-        let response_sender = ConnectedResponseSender::new(response_senders.clone());
-
-        Builder::new()
-            .name(format!("swarm-{:02}", i + 1))
-            .spawn(move || {
-                #[cfg(feature = "cpu-pinning")]
-                pin_current_if_configured_to(
-                    &config.cpu_pinning,
-                    config.socket_workers,
-                    config.swarm_workers,
-                    WorkerIndex::SwarmWorker(i),
-                );
-
-                workers::swarm::run_swarm_worker(
-                    sentinel,
-                    config,
-                    state,
+            if config.shard_per_core {
+                shards.push(Shard {
+                    index: SwarmWorkerIndex(i),
+                    swarm_worker: SwarmWorker::new(config, state, SwarmWorkerIndex(i), snapshot),
                     request_receiver,
                     response_sender,
-                    SwarmWorkerIndex(i),
-                )
-            })
-            .with_context(|| "spawn swarm worker")?;
-    }
+                    command_receiver,
+                    sockets: ::std::mem::take(&mut shard_sockets[i]),
+                });
+
+                continue;
+            }
+
+            let name = format!("swarm-{:02}", i + 1);
+            let worker_guard = worker_watcher.register(name.clone());
+
+            Builder::new()
+                .name(name)
+                .spawn(move || {
+                    #[cfg(feature = "cpu-pinning")]
+                    pin_current_if_configured_to(
+                        &config.cpu_pinning,
+                        config.socket_workers,
+                        config.swarm_workers,
+                        WorkerIndex::SwarmWorker(i),
+                    );
+
+                    worker_guard.report(workers::swarm::run_swarm_worker(
+                        sentinel,
+                        config,
+                        state,
+                        request_receiver,
+                        response_sender,
+                        SwarmWorkerIndex(i),
+                        snapshot,
+                        opt_shared_request_receiver,
+                        command_receiver,
+                    ));
+                })
+                .with_context(|| "spawn swarm worker")?;
+        }
+
+        let mut shards = shards.into_iter();
+        let mut request_senders = request_senders.into_iter();
+        let mut response_receivers = response_receivers.into_iter();
+
+        for i in 0..config.socket_workers {
+            let sentinel = sentinel.clone();
+            let state = state.clone();
+            let config = config.clone();
+            let connection_validator = connection_validator.clone();
+            let request_sender = ConnectedRequestSender::new(
+                SocketWorkerIndex(i),
+                request_senders.next().unwrap(),
+                &state,
+            );
+            let response_receiver = response_receivers.next().unwrap();
+            let opt_shard = shards.next();
+            let priv_dropper = priv_dropper.clone();
+            let name = format!("socket-{:02}", i + 1);
+            let worker_guard = worker_watcher.register(name.clone());
+
+            Builder::new()
+                .name(name)
+                .spawn(move || {
+                    #[cfg(feature = "cpu-pinning")]
+                    pin_current_if_configured_to(
+                        &config.cpu_pinning,
+                        config.socket_workers,
+                        config.swarm_workers,
+                        WorkerIndex::SocketWorker(i),
+                    );
+
+                    worker_guard.report(workers::socket::run_socket_worker(
+                        sentinel,
+                        state,
+                        config,
+                        connection_validator,
+                        request_sender,
+                        response_receiver,
+                        opt_shard,
+                        priv_dropper,
+                    ));
+                })
+                .with_context(|| "spawn socket worker")?;
+        }
+
+        if config.statistics.active() {
+            let sentinel = sentinel.clone();
+            let state = state.clone();
+            let config = config.clone();
+
+            Builder::new()
+                .name("statistics".into())
+                .spawn(move || {
+                    #[cfg(feature = "cpu-pinning")]
+                    pin_current_if_configured_to(
+                        &config.cpu_pinning,
+                        config.socket_workers,
+                        config.swarm_workers,
+                        WorkerIndex::Util,
+                    );
+
+                    workers::statistics::run_statistics_worker(sentinel, config, state);
+                })
+                .with_context(|| "spawn statistics worker")?;
+        }
 
-    for i in 0..config.socket_workers {
-        let sentinel = sentinel.clone();
-        let state = state.clone();
-        let config = config.clone();
-        let connection_validator = connection_validator.clone();
-        let request_sender =
-            ConnectedRequestSender::new(SocketWorkerIndex(i), request_senders.clone());
-        let response_receiver = response_receivers.remove(&i).unwrap();
-        let priv_dropper = priv_dropper.clone();
-
-        Builder::new()
-            .name(format!("socket-{:02}", i + 1))
-            .spawn(move || {
-                #[cfg(feature = "cpu-pinning")]
-                pin_current_if_configured_to(
-                    &config.cpu_pinning,
-                    config.socket_workers,
-                    config.swarm_workers,
-                    WorkerIndex::SocketWorker(i),
-                );
-
-                workers::socket::run_socket_worker(
-                    sentinel,
-                    state,
-                    config,
-                    i,
-                    connection_validator,
-                    request_sender,
-                    response_receiver,
-                    priv_dropper,
-                );
-            })
-            .with_context(|| "spawn socket worker")?;
+        #[cfg(feature = "cpu-pinning")]
+        pin_current_if_configured_to(
+            &config.cpu_pinning,
+            config.socket_workers,
+            config.swarm_workers,
+            WorkerIndex::Util,
+        );
+
+        Ok(Self {
+            config,
+            state,
+            worker_watcher,
+        })
     }
 
-    if config.statistics.active() {
-        let sentinel = sentinel.clone();
-        let state = state.clone();
-        let config = config.clone();
-
-        Builder::new()
-            .name("statistics".into())
-            .spawn(move || {
-                #[cfg(feature = "cpu-pinning")]
-                pin_current_if_configured_to(
-                    &config.cpu_pinning,
-                    config.socket_workers,
-                    config.swarm_workers,
-                    WorkerIndex::Util,
-                );
-
-                workers::statistics::run_statistics_worker(sentinel, config, state);
-            })
-            .with_context(|| "spawn statistics worker")?;
+    /// Reload access lists
+    pub fn reload(&self) {
+        let _ = reload(&self.config, &self.state);
     }
 
-    #[cfg(feature = "cpu-pinning")]
-    pin_current_if_configured_to(
-        &config.cpu_pinning,
-        config.socket_workers,
-        config.swarm_workers,
-        WorkerIndex::Util,
-    );
+    /// Tell workers to finish pending work within the grace period. Returns
+    /// deadline to pass to [`Tracker::wait`].
+    pub fn trigger_shutdown(&self) -> Instant {
+        self.state
+            .shutdown
+            .trigger(Duration::from_secs(self.config.shutdown_grace_period))
+    }
 
-    for signal in &mut signals {
-        match signal {
-            SIGUSR1 => {
-                let _ = update_access_list(&config.access_list, &state.access_list);
-            }
-            SIGTERM => {
-                if sentinel_watcher.panic_was_triggered() {
-                    return Err(anyhow::anyhow!("worker thread panicked"));
-                }
+    /// Wait for workers to exit, returning first worker error, if any
+    pub fn wait(self, deadline: Instant) -> ::anyhow::Result<()> {
+        self.worker_watcher.wait(deadline)
+    }
+}
 
-                break;
-            }
-            _ => unreachable!(),
-        }
+/// Reload access lists, returning first error, if any
+/// Check that shard-per-core mode isn't combined with unsupported features
+#[cfg_attr(not(feature = "io-uring"), allow(unused_variables))]
+fn check_shard_per_core_config(
+    config: &Config,
+    shared_swarm: bool,
+    sockets_inherited: bool,
+) -> anyhow::Result<()> {
+    if shared_swarm {
+        return Err(anyhow::anyhow!(
+            "shard_per_core can't be combined with a shared swarm"
+        ));
+    }
+    #[cfg(feature = "io-uring")]
+    if config.network.use_io_uring {
+        return Err(anyhow::anyhow!(
+            "shard_per_core can't be combined with network.use_io_uring"
+        ));
+    }
+    // Shard sockets are always created by the socket workers, so inherited
+    // ones would silently be ignored
+    if sockets_inherited {
+        return Err(anyhow::anyhow!(
+            "shard_per_core can't be combined with socket activation"
+        ));
     }
 
     Ok(())
 }
+
+fn reload(config: &Config, state: &State) -> anyhow::Result<()> {
+    let results = [
+        update_access_list(&config.access_list, &state.access_list),
+        update_ip_access_list(&config.ip_access_list, &state.ip_access_list),
+        update_url_access_list(&config.url_access_list, &state.url_access_list),
+    ];
+
+    results.into_iter().collect()
+}
+
+/// Pass on torrent commands to responsible swarm worker
+fn spawn_control_server_for_tracker(
+    config: &Config,
+    state: &State,
+    command_senders: Vec<Sender<SwarmCommandRequest>>,
+) -> anyhow::Result<()> {
+    let reload_config = config.clone();
+    let reload_state = state.clone();
+    let swarm_config = config.clone();
+
+    spawn_control_server(
+        &config.control,
+        state.metrics_registry.clone(),
+        move || reload(&reload_config, &reload_state),
+        move |command| {
+            let worker_index =
+                SwarmWorkerIndex::from_info_hash(&swarm_config, InfoHash(command.info_hash()));
+            let (response_sender, response_receiver) = ::std::sync::mpsc::channel();
+
+            command_senders[worker_index.0]
+                .send(SwarmCommandRequest {
+                    command,
+                    response_sender,
+                })
+                .map_err(|_| anyhow::anyhow!("swarm worker has exited"))?;
+
+            Ok(response_receiver)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_shard_per_core_config() {
+        let config = Config {
+            shard_per_core: true,
+            ..Default::default()
+        };
+
+        assert!(check_shard_per_core_config(&config, false, false).is_ok());
+        assert!(check_shard_per_core_config(&config, true, false).is_err());
+
+        let err = check_shard_per_core_config(&config, false, true).unwrap_err();
+
+        assert!(err.to_string().contains("socket activation"));
+    }
+}
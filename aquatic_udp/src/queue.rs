@@ -0,0 +1,544 @@
+//! Batched single-producer single-consumer queues for passing requests and
+//! responses between workers
+//!
+//! Every producing worker has its own queue to every consuming worker.
+//! Items are collected into batches on the producer side, which are passed
+//! on through a bounded lock-free ring buffer once they are full or when the
+//! producer flushes them. When a ring buffer is full, the batch is kept by
+//! the producer until a later flush, and further items are rejected once it
+//! is full too, so that the caller can account for them.
+//!
+//! Consumers are notified of new batches through a crossbeam channel with
+//! room for a single message, which lets them wait in `select!` together
+//! with other channels. The channel is disconnected once all producers have
+//! been dropped. Consumers that can't wait on channels can additionally set
+//! a [`Waker`], which is called whenever a notification is sent.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crossbeam_channel::{bounded, never, Receiver, RecvTimeoutError, Sender, TryRecvError};
+
+/// Function called by producers to wake up consumer
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+/// Create queues from each of `num_producers` producers to each of
+/// `num_consumers` consumers
+///
+/// Each queue holds up to `capacity` (rounded up to a power of two) batches
+/// of at most `batch_size` items.
+pub fn batch_queues<T>(
+    num_producers: usize,
+    num_consumers: usize,
+    capacity: usize,
+    batch_size: usize,
+) -> (Vec<BatchSender<T>>, Vec<BatchReceiver<T>>) {
+    let batch_size = batch_size.max(1);
+
+    let mut senders = (0..num_producers)
+        .map(|_| BatchSender {
+            queues: Vec::new(),
+            batch_size,
+        })
+        .collect::<Vec<_>>();
+    let mut receivers = Vec::new();
+
+    for _ in 0..num_consumers {
+        let (notification_sender, notification_receiver) = bounded(1);
+        let waker = Arc::new(Mutex::new(None));
+
+        let mut consumers = Vec::new();
+
+        for sender in senders.iter_mut() {
+            let (producer, consumer) = ring_buffer(capacity);
+
+            sender.queues.push(SenderQueue {
+                producer,
+                batch: Vec::with_capacity(batch_size),
+                notification_sender: notification_sender.clone(),
+                waker: waker.clone(),
+            });
+            consumers.push(consumer);
+        }
+
+        receivers.push(BatchReceiver {
+            consumers,
+            notification_receiver,
+            waker,
+            batch: Vec::new().into_iter(),
+            next_consumer: 0,
+        });
+    }
+
+    (senders, receivers)
+}
+
+/// Producer side of queues to all consumers
+pub struct BatchSender<T> {
+    queues: Vec<SenderQueue<T>>,
+    batch_size: usize,
+}
+
+impl<T> BatchSender<T> {
+    /// Add item to batch for consumer, passing on batch if it is full
+    ///
+    /// Returns item if a full batch is already waiting for room in queue.
+    pub fn send(&mut self, consumer_index: usize, item: T) -> Result<(), T> {
+        let batch_size = self.batch_size;
+        let queue = &mut self.queues[consumer_index];
+
+        if queue.batch.len() >= batch_size && !queue.flush(batch_size) {
+            return Err(item);
+        }
+
+        queue.batch.push(item);
+
+        if queue.batch.len() >= batch_size {
+            queue.flush(batch_size);
+        }
+
+        Ok(())
+    }
+
+    /// Pass on all non-empty batches
+    ///
+    /// Returns number of batches that are kept, because their queues are
+    /// full.
+    pub fn flush(&mut self) -> usize {
+        let batch_size = self.batch_size;
+
+        self.queues
+            .iter_mut()
+            .map(|queue| queue.flush(batch_size))
+            .filter(|flushed| !flushed)
+            .count()
+    }
+}
+
+struct SenderQueue<T> {
+    // Declared before notification sender, so that consumer sees that
+    // producer is gone when notification channel is disconnected
+    producer: Producer<Vec<T>>,
+    batch: Vec<T>,
+    notification_sender: Sender<()>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl<T> SenderQueue<T> {
+    /// Returns false if there is no room for batch
+    fn flush(&mut self, batch_size: usize) -> bool {
+        if self.batch.is_empty() {
+            return true;
+        }
+
+        match self.producer.push(::std::mem::take(&mut self.batch)) {
+            Ok(()) => {
+                self.batch.reserve(batch_size);
+
+                // If channel is full, consumer has not yet been woken up
+                // since previous notification, and if it is disconnected,
+                // consumer has exited
+                if self.notification_sender.try_send(()).is_ok() {
+                    if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+                        waker();
+                    }
+                }
+
+                true
+            }
+            Err(batch) => {
+                self.batch = batch;
+
+                false
+            }
+        }
+    }
+}
+
+/// Consumer side of queues from all producers
+pub struct BatchReceiver<T> {
+    consumers: Vec<Consumer<Vec<T>>>,
+    notification_receiver: Receiver<()>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    /// Remaining items of batch currently being received
+    batch: ::std::vec::IntoIter<T>,
+    /// Queue to check first for next batch, so that producers are treated
+    /// fairly
+    next_consumer: usize,
+}
+
+impl<T> BatchReceiver<T> {
+    /// Returns `TryRecvError::Disconnected` once all producers have been
+    /// dropped and all items have been received
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(item) = self.batch.next() {
+            return Ok(item);
+        }
+
+        // Checked before looking for batches, since producers might push
+        // batches right before being dropped
+        let disconnected = self
+            .consumers
+            .iter()
+            .all(|consumer| !consumer.producer_alive());
+
+        for _ in 0..self.consumers.len() {
+            let consumer_index = self.next_consumer;
+
+            self.next_consumer = (consumer_index + 1) % self.consumers.len();
+
+            if let Some(batch) = self.consumers[consumer_index].pop() {
+                self.batch = batch.into_iter();
+
+                if let Some(item) = self.batch.next() {
+                    return Ok(item);
+                }
+            }
+        }
+
+        if disconnected {
+            Err(TryRecvError::Disconnected)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(item) => return Ok(item),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => (),
+            }
+
+            match self.notification_receiver.recv_deadline(deadline) {
+                Ok(()) => (),
+                Err(RecvTimeoutError::Timeout) => return Err(RecvTimeoutError::Timeout),
+                // All producers have been dropped, so next call to try_recv
+                // will return all remaining items and then report this
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.notification_receiver = never();
+                }
+            }
+        }
+    }
+
+    /// Receive items until queues are empty
+    pub fn try_iter(&mut self) -> impl Iterator<Item = T> + '_ {
+        ::std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    /// Channel receiving a message after batches have been passed on, for
+    /// use in `select!`
+    ///
+    /// Consume messages before calling [`BatchReceiver::try_recv`] to not
+    /// miss notifications.
+    pub fn notifications(&self) -> Receiver<()> {
+        self.notification_receiver.clone()
+    }
+
+    /// Set function to call along with sending notifications
+    ///
+    /// Producers only notify again once the previous notification has been
+    /// consumed, so consume it before calling [`BatchReceiver::try_recv`].
+    pub fn set_waker(&self, waker: Waker) {
+        *self.waker.lock().unwrap() = Some(waker);
+    }
+}
+
+/// Create bounded lock-free queue with one producer and one consumer
+fn ring_buffer<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+
+    let ring = Arc::new(Ring {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        mask: capacity - 1,
+        head: CachePadded(AtomicUsize::new(0)),
+        tail: CachePadded(AtomicUsize::new(0)),
+        producer_alive: AtomicBool::new(true),
+    });
+
+    let producer = Producer {
+        ring: ring.clone(),
+        tail: 0,
+        cached_head: 0,
+    };
+    let consumer = Consumer {
+        ring,
+        head: 0,
+        cached_tail: 0,
+    };
+
+    (producer, consumer)
+}
+
+/// Pads and aligns value to avoid false sharing between producer and
+/// consumer
+#[repr(align(128))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// Position of next item to pop. Only written to by consumer.
+    head: CachePadded<AtomicUsize>,
+    /// Position of next item to push. Only written to by producer.
+    tail: CachePadded<AtomicUsize>,
+    producer_alive: AtomicBool,
+}
+
+// Safety: slots are only accessed by either the producer or the consumer at
+// any time, as coordinated by head and tail
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.0.get_mut();
+        let tail = *self.tail.0.get_mut();
+
+        while head != tail {
+            // Safety: slots between head and tail are initialized
+            unsafe {
+                self.slots[head & self.mask]
+                    .get_mut()
+                    .as_mut_ptr()
+                    .drop_in_place();
+            }
+
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    /// Local copy of tail
+    tail: usize,
+    /// Head as last loaded, to avoid touching consumer cache line when
+    /// there is known to be room
+    cached_head: usize,
+}
+
+impl<T> Producer<T> {
+    fn push(&mut self, item: T) -> Result<(), T> {
+        if self.tail.wrapping_sub(self.cached_head) == self.ring.slots.len() {
+            self.cached_head = self.ring.head.load(Ordering::Acquire);
+
+            if self.tail.wrapping_sub(self.cached_head) == self.ring.slots.len() {
+                return Err(item);
+            }
+        }
+
+        let slot = &self.ring.slots[self.tail & self.ring.mask];
+
+        // Safety: slot is not between head and tail, so consumer doesn't
+        // access it
+        unsafe {
+            (*slot.get()).write(item);
+        }
+
+        self.tail = self.tail.wrapping_add(1);
+        self.ring.tail.store(self.tail, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.ring.producer_alive.store(false, Ordering::Release);
+    }
+}
+
+struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    /// Local copy of head
+    head: usize,
+    /// Tail as last loaded, to avoid touching producer cache line when
+    /// there are known to be items
+    cached_tail: usize,
+}
+
+impl<T> Consumer<T> {
+    fn pop(&mut self) -> Option<T> {
+        if self.head == self.cached_tail {
+            self.cached_tail = self.ring.tail.load(Ordering::Acquire);
+
+            if self.head == self.cached_tail {
+                return None;
+            }
+        }
+
+        let slot = &self.ring.slots[self.head & self.ring.mask];
+
+        // Safety: slot is between head and tail, so it is initialized and
+        // not accessed by producer. Head is moved past it below, so item is
+        // not read again.
+        let item = unsafe { (*slot.get()).as_ptr().read() };
+
+        self.head = self.head.wrapping_add(1);
+        self.ring.head.store(self.head, Ordering::Release);
+
+        Some(item)
+    }
+
+    fn producer_alive(&self) -> bool {
+        self.ring.producer_alive.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_batch_queues() {
+        let (mut senders, mut receivers) = batch_queues::<(usize, usize)>(2, 2, 4, 3);
+
+        for (producer_index, sender) in senders.iter_mut().enumerate() {
+            for i in 0..10 {
+                sender.send(i % 2, (producer_index, i)).unwrap();
+            }
+
+            assert_eq!(sender.flush(), 0);
+        }
+
+        for (consumer_index, receiver) in receivers.iter_mut().enumerate() {
+            let mut received = receiver.try_iter().collect::<Vec<_>>();
+
+            received.sort_unstable();
+
+            let expected = (0..2)
+                .flat_map(|producer_index| {
+                    (0..10)
+                        .filter(|i| i % 2 == consumer_index)
+                        .map(move |i| (producer_index, i))
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(received, expected);
+            assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        }
+
+        drop(senders);
+
+        for receiver in receivers.iter_mut() {
+            assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+        }
+    }
+
+    #[test]
+    fn test_batch_queues_full() {
+        let (mut senders, mut receivers) = batch_queues::<usize>(1, 1, 1, 2);
+        let sender = &mut senders[0];
+
+        // First batch is passed on, second one is kept by sender
+        for i in 0..4 {
+            sender.send(0, i).unwrap();
+        }
+
+        assert_eq!(sender.send(0, 4), Err(4));
+        assert_eq!(sender.flush(), 1);
+
+        assert_eq!(receivers[0].try_iter().collect::<Vec<_>>(), vec![0, 1]);
+
+        assert_eq!(sender.flush(), 0);
+        sender.send(0, 5).unwrap();
+        assert_eq!(sender.flush(), 1);
+
+        assert_eq!(receivers[0].try_iter().collect::<Vec<_>>(), vec![2, 3]);
+
+        assert_eq!(sender.flush(), 0);
+
+        assert_eq!(receivers[0].try_iter().collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn test_batch_queues_waker() {
+        let (mut senders, mut receivers) = batch_queues::<usize>(1, 1, 4, 1);
+        let wake_count = Arc::new(AtomicUsize::new(0));
+
+        {
+            let wake_count = wake_count.clone();
+
+            receivers[0].set_waker(Arc::new(move || {
+                wake_count.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+
+        // Not woken up again until notification has been consumed
+        senders[0].send(0, 0).unwrap();
+        senders[0].send(0, 1).unwrap();
+
+        assert_eq!(wake_count.load(Ordering::SeqCst), 1);
+
+        receivers[0].notifications().try_recv().unwrap();
+        senders[0].send(0, 2).unwrap();
+
+        assert_eq!(wake_count.load(Ordering::SeqCst), 2);
+        assert_eq!(receivers[0].try_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_batch_queues_threads() {
+        const NUM_ITEMS: usize = 100_000;
+
+        let (senders, mut receivers) = batch_queues::<Box<usize>>(1, 1, 2, 16);
+
+        let handle = ::std::thread::spawn(move || {
+            let mut sender = senders.into_iter().next().unwrap();
+            let mut i = 0;
+
+            while i < NUM_ITEMS {
+                match sender.send(0, Box::new(i)) {
+                    Ok(()) => i += 1,
+                    Err(_) => ::std::thread::yield_now(),
+                }
+
+                if i % 100 == 0 {
+                    sender.flush();
+                }
+            }
+
+            while sender.flush() != 0 {
+                ::std::thread::yield_now();
+            }
+        });
+
+        let receiver = &mut receivers[0];
+        let mut expected = 0;
+
+        loop {
+            match receiver.recv_deadline(Instant::now() + Duration::from_secs(10)) {
+                Ok(item) => {
+                    assert_eq!(*item, expected);
+
+                    expected += 1;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => panic!("timeout"),
+            }
+        }
+
+        handle.join().unwrap();
+
+        assert_eq!(expected, NUM_ITEMS);
+    }
+}
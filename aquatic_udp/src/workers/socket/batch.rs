@@ -0,0 +1,404 @@
+//! Receiving and sending several datagrams per system call
+//!
+//! On Linux, `recvmmsg` and `sendmmsg` are used when the batch size is
+//! larger than one. Otherwise, datagrams are received and sent one at a
+//! time with `recv_from` and `send_to`.
+
+use std::io;
+use std::net::SocketAddr;
+
+use mio::net::UdpSocket;
+
+use crate::common::BUFFER_SIZE;
+
+pub struct RecvBatch {
+    buffers: Vec<[u8; BUFFER_SIZE]>,
+    /// Length and source address of received datagram in buffer with the
+    /// same index. Source address is missing if it is not an IP address.
+    received: Vec<(usize, Option<SocketAddr>)>,
+    #[cfg(target_os = "linux")]
+    headers: linux::Headers,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+
+        Self {
+            buffers: vec![[0; BUFFER_SIZE]; batch_size],
+            received: Vec::with_capacity(batch_size),
+            #[cfg(target_os = "linux")]
+            headers: linux::Headers::new(batch_size),
+        }
+    }
+
+    /// Receive at least one datagram, or fail with `ErrorKind::WouldBlock`
+    /// if none are available
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+
+        #[cfg(target_os = "linux")]
+        if self.buffers.len() > 1 {
+            return self
+                .headers
+                .recv(socket, &mut self.buffers, &mut self.received);
+        }
+
+        let (bytes_read, src) = socket.recv_from(&mut self.buffers[0])?;
+
+        self.received.push((bytes_read, Some(src)));
+
+        Ok(())
+    }
+
+    /// Datagrams received by last call to [`Self::recv`]
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received.iter().zip(self.buffers.iter()).filter_map(
+            |((bytes_read, opt_src), buffer)| opt_src.map(|src| (&buffer[..*bytes_read], src)),
+        )
+    }
+}
+
+/// Datagrams waiting to be sent, each with associated data of type `T`
+pub struct SendBatch<T> {
+    buffers: Vec<[u8; BUFFER_SIZE]>,
+    lengths: Vec<usize>,
+    addrs: Vec<SocketAddr>,
+    data: Vec<T>,
+    #[cfg(target_os = "linux")]
+    headers: linux::Headers,
+}
+
+impl<T> SendBatch<T> {
+    pub fn new(batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+
+        Self {
+            buffers: vec![[0; BUFFER_SIZE]; batch_size],
+            lengths: Vec::with_capacity(batch_size),
+            addrs: Vec::with_capacity(batch_size),
+            data: Vec::with_capacity(batch_size),
+            #[cfg(target_os = "linux")]
+            headers: linux::Headers::new(batch_size),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.data.len() == self.buffers.len()
+    }
+
+    /// Buffer to write next datagram to. The batch must not be full.
+    pub fn next_buffer(&mut self) -> &mut [u8] {
+        &mut self.buffers[self.data.len()][..]
+    }
+
+    /// Add datagram written to buffer returned by [`Self::next_buffer`]
+    pub fn push(&mut self, bytes_written: usize, addr: SocketAddr, data: T) {
+        self.lengths.push(bytes_written);
+        self.addrs.push(addr);
+        self.data.push(data);
+    }
+
+    /// Send all datagrams in order, calling `f` with the associated data
+    /// and result of each
+    pub fn flush<F>(&mut self, socket: &UdpSocket, mut f: F)
+    where
+        F: FnMut(T, io::Result<usize>),
+    {
+        // Taken temporarily to be able to call methods while draining it
+        let mut data = ::std::mem::take(&mut self.data);
+        let mut data_iter = data.drain(..);
+        let mut start = 0;
+
+        while start < self.lengths.len() {
+            match self.send(socket, start) {
+                Ok(num_sent) => {
+                    for length in &self.lengths[start..start + num_sent] {
+                        f(data_iter.next().unwrap(), Ok(*length));
+                    }
+
+                    start += num_sent;
+                }
+                // Only the first datagram is known to have failed
+                Err(err) => {
+                    f(data_iter.next().unwrap(), Err(err));
+
+                    start += 1;
+                }
+            }
+        }
+
+        drop(data_iter);
+
+        self.data = data;
+        self.lengths.clear();
+        self.addrs.clear();
+    }
+
+    /// Send at least one datagram starting at index `start`, returning how
+    /// many were sent
+    fn send(&mut self, socket: &UdpSocket, start: usize) -> io::Result<usize> {
+        let end = self.lengths.len();
+
+        #[cfg(target_os = "linux")]
+        if self.buffers.len() > 1 {
+            return self.headers.send(
+                socket,
+                &self.buffers[start..end],
+                &self.lengths[start..end],
+                &self.addrs[start..end],
+            );
+        }
+
+        socket.send_to(
+            &self.buffers[start][..self.lengths[start]],
+            self.addrs[start],
+        )?;
+
+        Ok(1)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem::{size_of, zeroed};
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr::null_mut;
+
+    use libc::{c_uint, c_void, iovec, mmsghdr, sockaddr_storage, socklen_t};
+    use mio::net::UdpSocket;
+    use socket2::SockAddr;
+
+    use crate::common::BUFFER_SIZE;
+
+    /// Message headers and addresses passed to `recvmmsg` and `sendmmsg`
+    ///
+    /// The headers point into buffers owned by the caller, so they are
+    /// filled in right before each call.
+    pub struct Headers {
+        headers: Vec<mmsghdr>,
+        iovecs: Vec<iovec>,
+        addrs: Vec<sockaddr_storage>,
+        sock_addrs: Vec<SockAddr>,
+    }
+
+    impl Headers {
+        pub fn new(batch_size: usize) -> Self {
+            // Safety: all-zero bytes is a valid value for these C structs
+            unsafe {
+                Self {
+                    headers: vec![zeroed(); batch_size],
+                    iovecs: vec![zeroed(); batch_size],
+                    addrs: vec![zeroed(); batch_size],
+                    sock_addrs: Vec::with_capacity(batch_size),
+                }
+            }
+        }
+
+        pub fn recv(
+            &mut self,
+            socket: &UdpSocket,
+            buffers: &mut [[u8; BUFFER_SIZE]],
+            received: &mut Vec<(usize, Option<SocketAddr>)>,
+        ) -> io::Result<()> {
+            let num_headers = buffers.len();
+
+            for (((header, iovec), addr), buffer) in self
+                .headers
+                .iter_mut()
+                .zip(self.iovecs.iter_mut())
+                .zip(self.addrs.iter_mut())
+                .zip(buffers.iter_mut())
+            {
+                iovec.iov_base = buffer.as_mut_ptr() as *mut c_void;
+                iovec.iov_len = buffer.len();
+
+                // Safety: see Headers::new
+                *header = unsafe { zeroed() };
+                header.msg_hdr.msg_name = addr as *mut sockaddr_storage as *mut c_void;
+                header.msg_hdr.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+            }
+
+            // Safety: headers point to valid buffers and addresses, which
+            // outlive the call
+            let num_received = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    self.headers.as_mut_ptr(),
+                    num_headers as c_uint,
+                    0,
+                    null_mut(),
+                )
+            };
+
+            if num_received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for (header, addr) in self
+                .headers
+                .iter()
+                .zip(self.addrs.iter())
+                .take(num_received as usize)
+            {
+                // Safety: address was written by kernel with given length
+                let sock_addr = unsafe { SockAddr::new(*addr, header.msg_hdr.msg_namelen) };
+
+                // Pushed even without IP address, so that entries stay
+                // aligned with buffers
+                received.push((header.msg_len as usize, sock_addr.as_socket()));
+            }
+
+            Ok(())
+        }
+
+        pub fn send(
+            &mut self,
+            socket: &UdpSocket,
+            buffers: &[[u8; BUFFER_SIZE]],
+            lengths: &[usize],
+            addrs: &[SocketAddr],
+        ) -> io::Result<usize> {
+            let num_headers = buffers.len();
+
+            self.sock_addrs.clear();
+            self.sock_addrs
+                .extend(addrs.iter().copied().map(SockAddr::from));
+
+            for ((((header, iovec), sock_addr), buffer), length) in self
+                .headers
+                .iter_mut()
+                .zip(self.iovecs.iter_mut())
+                .zip(self.sock_addrs.iter())
+                .zip(buffers.iter())
+                .zip(lengths.iter())
+            {
+                // Kernel doesn't write to buffers or addresses when sending
+                iovec.iov_base = buffer.as_ptr() as *mut c_void;
+                iovec.iov_len = *length;
+
+                // Safety: see Headers::new
+                *header = unsafe { zeroed() };
+                header.msg_hdr.msg_name = sock_addr.as_ptr() as *mut c_void;
+                header.msg_hdr.msg_namelen = sock_addr.len();
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+            }
+
+            // Safety: headers point to valid buffers and addresses, which
+            // outlive the call
+            let num_sent = unsafe {
+                libc::sendmmsg(
+                    socket.as_raw_fd(),
+                    self.headers.as_mut_ptr(),
+                    num_headers as c_uint,
+                    0,
+                )
+            };
+
+            if num_sent < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(num_sent as usize)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_send_and_recv(batch_size: usize) {
+        let receiver = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let receiver_addr = receiver.local_addr().unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+
+        let mut send_batch = SendBatch::new(batch_size);
+
+        for i in 0..batch_size.max(1) {
+            let buffer = send_batch.next_buffer();
+
+            buffer[0] = i as u8;
+            buffer[1] = i as u8;
+
+            send_batch.push(2, receiver_addr, i);
+        }
+
+        assert!(send_batch.is_full());
+
+        let mut sent = Vec::new();
+
+        send_batch.flush(&sender, |i, result| sent.push((i, result.unwrap())));
+
+        assert!(send_batch.is_empty());
+        assert_eq!(
+            sent,
+            (0..batch_size.max(1)).map(|i| (i, 2)).collect::<Vec<_>>()
+        );
+
+        let mut recv_batch = RecvBatch::new(batch_size);
+        let mut received = Vec::new();
+
+        // Datagrams on loopback are available right after sending, but
+        // not necessarily all in the first call
+        while received.len() < sent.len() {
+            recv_batch.recv(&receiver).unwrap();
+
+            for (bytes, src) in recv_batch.iter() {
+                assert_eq!(src, sender_addr);
+
+                received.push(bytes.to_vec());
+            }
+        }
+
+        assert_eq!(
+            received,
+            (0..batch_size.max(1))
+                .map(|i| vec![i as u8, i as u8])
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            recv_batch.recv(&receiver).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_recv_batch_iter_skips_missing_addresses() {
+        let src: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+
+        let mut recv_batch = RecvBatch::new(3);
+
+        recv_batch.buffers[0][0] = 0;
+        recv_batch.buffers[1][0] = 1;
+        recv_batch.buffers[2][0] = 2;
+        recv_batch.received = vec![(1, Some(src)), (1, None), (1, Some(src))];
+
+        assert_eq!(
+            recv_batch.iter().collect::<Vec<_>>(),
+            vec![(&[0u8][..], src), (&[2u8][..], src)]
+        );
+    }
+
+    #[test]
+    fn test_send_and_recv_single() {
+        test_send_and_recv(1);
+    }
+
+    #[test]
+    fn test_send_and_recv_batch() {
+        test_send_and_recv(8);
+    }
+}
@@ -1,18 +1,25 @@
+mod batch;
+mod rate_limiter;
 mod requests;
 mod responses;
+pub mod shard;
 mod storage;
+#[cfg(feature = "io-uring")]
+mod uring;
 pub mod validator;
 
+use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{never, select, TryRecvError};
 use mio::net::UdpSocket;
 use mio::{Events, Interest, Poll, Token};
 use socket2::{Domain, Protocol, Socket, Type};
 
 use aquatic_common::{
-    access_list::create_access_list_cache, privileges::PrivilegeDropper, CanonicalSocketAddr,
+    privileges::PrivilegeDropper, socket_activation::take_inherited_socket, CanonicalSocketAddr,
     PanicSentinel, ValidUntil,
 };
 use aquatic_udp_protocol::*;
@@ -20,38 +27,98 @@ use aquatic_udp_protocol::*;
 use crate::common::*;
 use crate::config::Config;
 
-use requests::read_requests;
-use responses::send_responses;
+use batch::{RecvBatch, SendBatch};
+use requests::{read_requests, RequestHandler};
+use responses::{flush_responses, send_connected_response, send_responses};
+use shard::Shard;
 use storage::PendingScrapeResponseSlab;
 use validator::ConnectionValidator;
 
+/// Socket bound to one of the configured addresses
+pub struct Listener {
+    pub index: ListenerIndex,
+    pub socket: UdpSocket,
+    /// Address from config, which determines how response addresses are
+    /// formatted
+    pub address: SocketAddr,
+    /// Encoded responses waiting to be sent
+    pub send_batch: SendBatch<(Response, CanonicalSocketAddr)>,
+}
+
 pub fn run_socket_worker(
     _sentinel: PanicSentinel,
     state: State,
     config: Config,
-    token_num: usize,
-    mut connection_validator: ConnectionValidator,
-    request_sender: ConnectedRequestSender,
-    response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    connection_validator: ConnectionValidator,
+    mut request_sender: ConnectedRequestSender,
+    mut response_receiver: ConnectedResponseReceiver,
+    // Set in shard-per-core mode, in which sockets are created up front
+    mut opt_shard: Option<Shard>,
     priv_dropper: PrivilegeDropper,
-) {
-    let mut buffer = [0u8; BUFFER_SIZE];
+) -> anyhow::Result<()> {
+    let sockets = if let Some(shard) = opt_shard.as_mut() {
+        ::std::mem::take(&mut shard.sockets)
+    } else {
+        let mut sockets = Vec::new();
+
+        for address in config.network.address.iter().copied() {
+            let socket = create_socket(&config, address)
+                .with_context(|| format!("create socket bound to {}", address))?;
+
+            sockets.push((address, socket));
+        }
+
+        sockets
+    };
+
+    priv_dropper.after_socket_creation()?;
+
+    let mut request_handler = RequestHandler::new(
+        &state,
+        connection_validator,
+        opt_shard.as_ref().map(|shard| shard.index),
+    );
+
+    #[cfg(feature = "io-uring")]
+    if config.network.use_io_uring {
+        return uring::run_socket_worker(
+            state,
+            config,
+            request_handler,
+            request_sender,
+            response_receiver,
+            sockets,
+        );
+    }
+
+    let mut recv_batch = RecvBatch::new(config.network.batch_size);
+
+    let mut listeners = sockets
+        .into_iter()
+        .enumerate()
+        .map(|(i, (address, socket))| Listener {
+            index: ListenerIndex(i),
+            socket: UdpSocket::from_std(socket),
+            address,
+            send_batch: SendBatch::new(config.network.batch_size),
+        })
+        .collect::<Vec<_>>();
 
-    let mut socket =
-        UdpSocket::from_std(create_socket(&config, priv_dropper).expect("create socket"));
-    let mut poll = Poll::new().expect("create poll");
+    let mut poll = Poll::new().with_context(|| "create poll")?;
 
     let interests = Interest::READABLE;
 
-    poll.registry()
-        .register(&mut socket, Token(token_num), interests)
-        .unwrap();
+    // Listener index is used as token
+    for listener in listeners.iter_mut() {
+        poll.registry()
+            .register(&mut listener.socket, Token(listener.index.0), interests)
+            .with_context(|| "register socket with poll")?;
+    }
 
     let mut events = Events::with_capacity(config.network.poll_event_capacity);
     let mut pending_scrape_responses = PendingScrapeResponseSlab::default();
-    let mut access_list_cache = create_access_list_cache(&state.access_list);
 
-    let mut local_responses: Vec<(Response, CanonicalSocketAddr)> = Vec::new();
+    let mut local_responses: Vec<(Response, ListenerIndex, CanonicalSocketAddr)> = Vec::new();
     let mut opt_resend_buffer = (config.network.resend_buffer_max_len > 0).then_some(Vec::new());
 
     let poll_timeout = Duration::from_millis(config.network.poll_timeout_ms);
@@ -64,35 +131,48 @@ pub fn run_socket_worker(
 
     let mut iter_counter = 0usize;
 
-    loop {
-        poll.poll(&mut events, Some(poll_timeout))
-            .expect("failed polling");
+    while !state.shutdown.is_triggered() {
+        match poll.poll(&mut events, Some(poll_timeout)) {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).with_context(|| "poll"),
+        }
 
         for event in events.iter() {
-            let token = event.token();
+            if !event.is_readable() {
+                continue;
+            }
 
-            if (token.0 == token_num) & event.is_readable() {
+            if let Some(listener) = listeners.get_mut(event.token().0) {
                 read_requests(
                     &config,
                     &state,
-                    &mut connection_validator,
+                    &mut request_handler,
                     &mut pending_scrape_responses,
-                    &mut access_list_cache,
-                    &mut socket,
-                    &mut buffer,
-                    &request_sender,
+                    listener,
+                    &mut recv_batch,
+                    &mut request_sender,
                     &mut local_responses,
                     pending_scrape_valid_until,
                 );
             }
         }
 
+        request_sender.flush();
+
+        if let Some(shard) = opt_shard.as_mut() {
+            shard.handle_requests(
+                &mut request_handler,
+                &mut pending_scrape_responses,
+                &mut local_responses,
+            );
+        }
+
         send_responses(
             &state,
             &config,
-            &mut socket,
-            &mut buffer,
-            &response_receiver,
+            &mut listeners,
+            &mut response_receiver,
             &mut pending_scrape_responses,
             local_responses.drain(..),
             &mut opt_resend_buffer,
@@ -107,34 +187,132 @@ pub fn run_socket_worker(
 
             if now > last_pending_scrape_cleaning + pending_scrape_cleaning_duration {
                 pending_scrape_responses.clean();
+                request_handler.clean(&config, now);
 
                 last_pending_scrape_cleaning = now;
             }
+
+            if let Some(shard) = opt_shard.as_mut() {
+                shard.swarm_worker.run_periodic_tasks();
+            }
         }
 
         iter_counter = iter_counter.wrapping_add(1);
     }
+
+    // Stop handling requests. Swarm workers exit and drop their response
+    // senders once they've handled remaining requests and all request
+    // senders have been dropped. The same goes for the own shard, which
+    // keeps handling requests from other socket workers until then.
+    request_sender.flush();
+    drop(request_sender);
+
+    let deadline = state.shutdown.deadline().unwrap_or_else(Instant::now);
+
+    let mut response_notifications = response_receiver.notifications();
+    let mut shard_request_notifications = opt_shard
+        .as_ref()
+        .map(|shard| shard.request_receiver.notifications())
+        .unwrap_or_else(never);
+    let mut snapshot_result = Ok(());
+
+    loop {
+        let responses_disconnected = loop {
+            match response_receiver.try_recv() {
+                Ok((response, listener_index, addr)) => {
+                    send_connected_response(
+                        &state,
+                        &config,
+                        &mut listeners,
+                        &mut pending_scrape_responses,
+                        response,
+                        listener_index,
+                        addr,
+                        &mut None,
+                    );
+                }
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+
+        // Don't wait for batches to fill up
+        for listener in listeners.iter_mut() {
+            flush_responses(&state, &config, listener, &mut None);
+        }
+
+        if responses_disconnected {
+            break;
+        }
+
+        // Drop shard, including its response sender, once other socket
+        // workers have stopped passing on requests
+        if let Some(shard) = opt_shard.as_mut() {
+            if shard.handle_forwarded_requests() {
+                shard_request_notifications = never();
+
+                if let Some(mut shard) = opt_shard.take() {
+                    snapshot_result = shard.swarm_worker.write_final_snapshot();
+                }
+            }
+        }
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        select! {
+            recv(response_notifications) -> result => {
+                if result.is_err() {
+                    response_notifications = never();
+                }
+            },
+            recv(shard_request_notifications) -> result => {
+                if result.is_err() {
+                    shard_request_notifications = never();
+                }
+            },
+            default(timeout) => {
+                ::log::warn!("socket worker didn't finish sending responses before deadline");
+
+                break;
+            }
+        }
+    }
+
+    if let Some(mut shard) = opt_shard.take() {
+        snapshot_result = shard.swarm_worker.write_final_snapshot();
+    }
+
+    snapshot_result
 }
 
-fn create_socket(
-    config: &Config,
-    priv_dropper: PrivilegeDropper,
-) -> anyhow::Result<::std::net::UdpSocket> {
-    let socket = if config.network.address.is_ipv4() {
-        Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?
+fn create_socket(config: &Config, address: SocketAddr) -> anyhow::Result<::std::net::UdpSocket> {
+    let socket = if let Some(socket) =
+        take_inherited_socket(Type::DGRAM, address).with_context(|| "take inherited socket")?
+    {
+        socket
     } else {
-        Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?
-    };
+        let socket = if address.is_ipv4() {
+            Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?
+        } else {
+            Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?
+        };
+
+        if config.network.only_ipv6 {
+            socket
+                .set_only_v6(true)
+                .with_context(|| "socket: set only ipv6")?;
+        }
 
-    if config.network.only_ipv6 {
         socket
-            .set_only_v6(true)
-            .with_context(|| "socket: set only ipv6")?;
-    }
+            .set_reuse_port(true)
+            .with_context(|| "socket: set reuse port")?;
 
-    socket
-        .set_reuse_port(true)
-        .with_context(|| "socket: set reuse port")?;
+        socket
+            .bind(&address.into())
+            .with_context(|| format!("socket: bind to {}", address))?;
+
+        socket
+    };
 
     socket
         .set_nonblocking(true)
@@ -152,11 +330,5 @@ fn create_socket(
         }
     }
 
-    socket
-        .bind(&config.network.address.into())
-        .with_context(|| format!("socket: bind to {}", config.network.address))?;
-
-    priv_dropper.after_socket_creation()?;
-
     Ok(socket.into())
 }
@@ -0,0 +1,236 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+
+use crate::config::RateLimitConfig;
+
+/// Minimum interval between attempts to evict buckets when table is full
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(config.burst),
+            last_update: now,
+        }
+    }
+
+    fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_update)
+            .as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * f64::from(config.requests_per_second))
+            .min(f64::from(config.burst));
+        self.last_update = now;
+    }
+
+    fn try_take(&mut self, config: &RateLimitConfig, now: Instant) -> bool {
+        self.refill(config, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token bucket rate limiter keyed by client IP address
+///
+/// IPv6 addresses are grouped by configured prefix length. At most
+/// `max_tracked_addresses` buckets are stored. When the table is full,
+/// refilled buckets are evicted. If that doesn't free up space, requests
+/// from untracked addresses share a single overflow bucket, so that filling
+/// the table with spoofed addresses doesn't disable rate limiting.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    overflow: Option<TokenBucket>,
+    last_eviction: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Returns true if request should be handled
+    pub fn check(&mut self, config: &RateLimitConfig, ip: IpAddr, now: Instant) -> bool {
+        let key = Self::key(config, ip);
+
+        if let Some(bucket) = self.buckets.get_mut(&key) {
+            return bucket.try_take(config, now);
+        }
+
+        if self.buckets.len() >= config.max_tracked_addresses {
+            self.evict(config, now);
+        }
+
+        if self.buckets.len() < config.max_tracked_addresses {
+            self.buckets
+                .entry(key)
+                .or_insert_with(|| TokenBucket::new(config, now))
+                .try_take(config, now)
+        } else {
+            self.overflow
+                .get_or_insert_with(|| TokenBucket::new(config, now))
+                .try_take(config, now)
+        }
+    }
+
+    /// Remove buckets that have been refilled, since they are equivalent
+    /// to new ones
+    pub fn clean(&mut self, config: &RateLimitConfig, now: Instant) {
+        self.remove_refilled(config, now);
+
+        self.buckets.shrink_to_fit();
+    }
+
+    /// Remove refilled buckets, at most once per `EVICTION_INTERVAL` to
+    /// avoid scanning the full table on every request
+    fn evict(&mut self, config: &RateLimitConfig, now: Instant) {
+        if let Some(last_eviction) = self.last_eviction {
+            if now.saturating_duration_since(last_eviction) < EVICTION_INTERVAL {
+                return;
+            }
+        }
+
+        self.last_eviction = Some(now);
+
+        self.remove_refilled(config, now);
+    }
+
+    fn remove_refilled(&mut self, config: &RateLimitConfig, now: Instant) {
+        let max_tokens = f64::from(config.burst);
+
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(config, now);
+
+            bucket.tokens < max_tokens
+        });
+    }
+
+    fn key(config: &RateLimitConfig, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(ip) => {
+                let prefix_len = u32::from(config.ipv6_prefix_len.min(128));
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let config = RateLimitConfig {
+            active: true,
+            requests_per_second: 2,
+            burst: 3,
+            ipv6_prefix_len: 64,
+            max_tracked_addresses: 3,
+            ..Default::default()
+        };
+
+        let mut rate_limiter = RateLimiter::default();
+        let mut now = Instant::now();
+
+        let check = |rate_limiter: &mut RateLimiter, ip: &str, now: Instant| {
+            rate_limiter.check(&config, ip.parse().unwrap(), now)
+        };
+
+        // Burst is allowed, then requests are limited
+        for _ in 0..3 {
+            assert!(check(&mut rate_limiter, "10.0.0.1", now));
+        }
+        assert!(!check(&mut rate_limiter, "10.0.0.1", now));
+
+        // Other addresses have their own buckets
+        assert!(check(&mut rate_limiter, "10.0.0.2", now));
+
+        // IPv6 addresses in the same /64 share a bucket
+        for _ in 0..3 {
+            assert!(check(&mut rate_limiter, "2001:db8::1", now));
+        }
+        assert!(!check(&mut rate_limiter, "2001:db8::ffff", now));
+        assert_eq!(rate_limiter.buckets.len(), 3);
+
+        // Addresses not fitting in table share an overflow bucket
+        for _ in 0..2 {
+            assert!(check(&mut rate_limiter, "10.0.0.3", now));
+        }
+        assert!(check(&mut rate_limiter, "10.0.0.4", now));
+        assert!(!check(&mut rate_limiter, "10.0.0.3", now));
+        assert!(!check(&mut rate_limiter, "10.0.0.5", now));
+        assert_eq!(rate_limiter.buckets.len(), 3);
+
+        // Tokens are refilled over time
+        now += Duration::from_millis(500);
+
+        assert!(check(&mut rate_limiter, "10.0.0.1", now));
+        assert!(!check(&mut rate_limiter, "10.0.0.1", now));
+
+        // Only buckets that haven't been fully refilled are kept
+        now += Duration::from_millis(500);
+
+        rate_limiter.clean(&config, now);
+
+        assert_eq!(rate_limiter.buckets.len(), 2);
+
+        now += Duration::from_millis(1000);
+
+        rate_limiter.clean(&config, now);
+
+        assert!(rate_limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_eviction() {
+        let config = RateLimitConfig {
+            active: true,
+            requests_per_second: 1,
+            burst: 1,
+            ipv6_prefix_len: 64,
+            max_tracked_addresses: 2,
+            ..Default::default()
+        };
+
+        let mut rate_limiter = RateLimiter::default();
+        let mut now = Instant::now();
+
+        let check = |rate_limiter: &mut RateLimiter, ip: &str, now: Instant| {
+            rate_limiter.check(&config, ip.parse().unwrap(), now)
+        };
+
+        assert!(check(&mut rate_limiter, "10.0.0.1", now));
+        assert!(check(&mut rate_limiter, "10.0.0.2", now));
+
+        // Table is full and no buckets are refilled, so overflow bucket is
+        // used
+        assert!(check(&mut rate_limiter, "10.0.0.3", now));
+        assert!(!check(&mut rate_limiter, "10.0.0.4", now));
+
+        // Refilled buckets are evicted to make room for new addresses
+        now += Duration::from_secs(1);
+
+        assert!(check(&mut rate_limiter, "10.0.0.3", now));
+        assert!(!check(&mut rate_limiter, "10.0.0.3", now));
+        assert!(rate_limiter
+            .buckets
+            .contains_key(&"10.0.0.3".parse().unwrap()));
+        assert_eq!(rate_limiter.buckets.len(), 1);
+    }
+}
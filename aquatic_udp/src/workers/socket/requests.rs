@@ -1,184 +1,535 @@
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
+use std::vec::Drain;
 
-use mio::net::UdpSocket;
-
-use aquatic_common::{access_list::AccessListCache, CanonicalSocketAddr, ValidUntil};
+use aquatic_common::{
+    access_list::{create_access_list_cache, AccessListCache},
+    ip_access_list::{create_ip_access_list_cache, IpAccessListCache},
+    url_access_list::{create_url_access_list_cache, UrlAccessListCache},
+    CanonicalSocketAddr, ValidUntil,
+};
 use aquatic_udp_protocol::*;
 
 use crate::common::*;
-use crate::config::Config;
+use crate::config::{Config, RateLimitAction};
 
+use super::batch::RecvBatch;
+use super::rate_limiter::RateLimiter;
 use super::storage::PendingScrapeResponseSlab;
 use super::validator::ConnectionValidator;
+use super::Listener;
 
-pub fn read_requests(
-    config: &Config,
-    state: &State,
-    connection_validator: &mut ConnectionValidator,
-    pending_scrape_responses: &mut PendingScrapeResponseSlab,
-    access_list_cache: &mut AccessListCache,
-    socket: &mut UdpSocket,
-    buffer: &mut [u8],
-    request_sender: &ConnectedRequestSender,
-    local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
-    pending_scrape_valid_until: ValidUntil,
-) {
-    let mut requests_received_ipv4: usize = 0;
-    let mut requests_received_ipv6: usize = 0;
-    let mut bytes_received_ipv4: usize = 0;
-    let mut bytes_received_ipv6 = 0;
+/// Socket worker state used for handling incoming datagrams, independent of
+/// how they are received
+pub struct RequestHandler {
+    connection_validator: ConnectionValidator,
+    access_list_cache: AccessListCache,
+    ip_access_list_cache: IpAccessListCache,
+    url_access_list_cache: UrlAccessListCache,
+    rate_limiter: RateLimiter,
+    /// Swarm worker whose requests are handled by this socket worker itself
+    /// (shard-per-core mode)
+    opt_own_shard: Option<SwarmWorkerIndex>,
+    own_shard_requests: Vec<(ListenerIndex, ConnectedRequest, CanonicalSocketAddr)>,
+}
 
-    loop {
-        match socket.recv_from(&mut buffer[..]) {
-            Ok((bytes_read, src)) => {
-                if src.port() == 0 {
-                    ::log::info!("Ignored request from {} because source port is zero", src);
+impl RequestHandler {
+    pub fn new(
+        state: &State,
+        connection_validator: ConnectionValidator,
+        opt_own_shard: Option<SwarmWorkerIndex>,
+    ) -> Self {
+        Self {
+            connection_validator,
+            access_list_cache: create_access_list_cache(&state.access_list),
+            ip_access_list_cache: create_ip_access_list_cache(&state.ip_access_list),
+            url_access_list_cache: create_url_access_list_cache(&state.url_access_list),
+            rate_limiter: RateLimiter::default(),
+            opt_own_shard,
+            own_shard_requests: Vec::new(),
+        }
+    }
+
+    /// Requests for own shard, collected instead of being sent to a swarm
+    /// worker
+    pub fn drain_own_shard_requests(
+        &mut self,
+    ) -> Drain<'_, (ListenerIndex, ConnectedRequest, CanonicalSocketAddr)> {
+        self.own_shard_requests.drain(..)
+    }
+
+    pub fn clean(&mut self, config: &Config, now: Instant) {
+        if config.rate_limit.active {
+            self.rate_limiter.clean(&config.rate_limit, now);
+        }
+    }
+
+    /// Parse and handle datagram. Responses that can be sent without
+    /// involving swarm workers are added to `local_responses`.
+    pub fn handle_datagram(
+        &mut self,
+        config: &Config,
+        pending_scrape_responses: &mut PendingScrapeResponseSlab,
+        request_sender: &mut ConnectedRequestSender,
+        local_responses: &mut Vec<(Response, ListenerIndex, CanonicalSocketAddr)>,
+        counters: &mut RequestCounters,
+        pending_scrape_valid_until: ValidUntil,
+        now: Instant,
+        listener_index: ListenerIndex,
+        bytes: &[u8],
+        src: SocketAddr,
+    ) {
+        if src.port() == 0 {
+            ::log::info!("Ignored request from {} because source port is zero", src);
+
+            return;
+        }
+
+        let src = CanonicalSocketAddr::new(src);
+
+        if !self
+            .ip_access_list_cache
+            .load()
+            .allows(config.ip_access_list.mode, src.get().ip())
+        {
+            if src.is_ipv4() {
+                counters.requests_blocked_ipv4 += 1;
+            } else {
+                counters.requests_blocked_ipv6 += 1;
+            }
+
+            return;
+        }
+
+        // Update statistics for converted address
+        if src.is_ipv4() {
+            counters.bytes_received_ipv4 += bytes.len();
+        } else {
+            counters.bytes_received_ipv6 += bytes.len();
+        }
+
+        // Checked before parsing, so that floods are dropped cheaply
+        if config.rate_limit.active
+            && !self
+                .rate_limiter
+                .check(&config.rate_limit, src.get().ip(), now)
+        {
+            if src.is_ipv4() {
+                counters.requests_rate_limited_ipv4 += 1;
+            } else {
+                counters.requests_rate_limited_ipv6 += 1;
+            }
 
-                    continue;
+            if config.rate_limit.action == RateLimitAction::Error {
+                if let Some(response) = self.create_rate_limit_response(bytes, src) {
+                    local_responses.push((response, listener_index, src));
                 }
+            }
+
+            return;
+        }
+
+        let res_request = Request::from_bytes(bytes, config.protocol.max_scrape_torrents);
+
+        if src.is_ipv4() {
+            if res_request.is_ok() {
+                counters.requests_received_ipv4 += 1;
+            } else {
+                counters.invalid_requests_ipv4 += 1;
+            }
+        } else if res_request.is_ok() {
+            counters.requests_received_ipv6 += 1;
+        } else {
+            counters.invalid_requests_ipv6 += 1;
+        }
 
-                let res_request =
-                    Request::from_bytes(&buffer[..bytes_read], config.protocol.max_scrape_torrents);
+        self.handle_request(
+            config,
+            pending_scrape_responses,
+            request_sender,
+            local_responses,
+            pending_scrape_valid_until,
+            res_request,
+            listener_index,
+            src,
+        );
+    }
+
+    /// Create error response for rate limited announce or scrape request
+    ///
+    /// Only the request header is parsed. Nothing is returned unless the
+    /// connection ID is valid, so that responses can't be directed at spoofed
+    /// source addresses.
+    fn create_rate_limit_response(
+        &mut self,
+        bytes: &[u8],
+        src: CanonicalSocketAddr,
+    ) -> Option<Response> {
+        let header = bytes.get(..16)?;
+
+        let connection_id = ConnectionId(i64::from_be_bytes(header[..8].try_into().unwrap()));
+        let action = i32::from_be_bytes(header[8..12].try_into().unwrap());
+        let transaction_id = TransactionId(i32::from_be_bytes(header[12..].try_into().unwrap()));
+
+        // Connect requests don't carry a connection ID
+        if !matches!(action, 1 | 2)
+            || !self
+                .connection_validator
+                .connection_id_valid(src, connection_id)
+        {
+            return None;
+        }
+
+        Some(Response::Error(ErrorResponse {
+            transaction_id,
+            message: "Rate limit exceeded".into(),
+        }))
+    }
 
-                let src = CanonicalSocketAddr::new(src);
+    fn handle_request(
+        &mut self,
+        config: &Config,
+        pending_scrape_responses: &mut PendingScrapeResponseSlab,
+        request_sender: &mut ConnectedRequestSender,
+        local_responses: &mut Vec<(Response, ListenerIndex, CanonicalSocketAddr)>,
+        pending_scrape_valid_until: ValidUntil,
+        res_request: Result<Request, RequestParseError>,
+        listener_index: ListenerIndex,
+        src: CanonicalSocketAddr,
+    ) {
+        let access_list_mode = config.access_list.mode;
 
-                // Update statistics for converted address
-                if src.is_ipv4() {
-                    if res_request.is_ok() {
-                        requests_received_ipv4 += 1;
+        match res_request {
+            Ok(Request::Connect(request)) => {
+                let connection_id = self.connection_validator.create_connection_id(src);
+
+                let response = Response::Connect(ConnectResponse {
+                    connection_id,
+                    transaction_id: request.transaction_id,
+                });
+
+                local_responses.push((response, listener_index, src))
+            }
+            Ok(Request::Announce(request)) => {
+                if self
+                    .connection_validator
+                    .connection_id_valid(src, request.connection_id)
+                {
+                    // URL data can't be trusted if an option was truncated
+                    if config.url_access_list.mode.is_on()
+                        && (request.options_truncated
+                            || !self
+                                .url_access_list_cache
+                                .load()
+                                .allows(config.url_access_list.mode, request.url_data().as_deref()))
+                    {
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: "URL not allowed".into(),
+                        });
+
+                        local_responses.push((response, listener_index, src));
+
+                        return;
                     }
-                    bytes_received_ipv4 += bytes_read;
-                } else {
-                    if res_request.is_ok() {
-                        requests_received_ipv6 += 1;
+
+                    if self
+                        .access_list_cache
+                        .load()
+                        .allows(access_list_mode, &request.info_hash.0)
+                    {
+                        let worker_index =
+                            SwarmWorkerIndex::from_info_hash(config, request.info_hash);
+
+                        self.forward_request(
+                            request_sender,
+                            worker_index,
+                            listener_index,
+                            ConnectedRequest::Announce(request),
+                            src,
+                        );
+                    } else {
+                        let response = Response::Error(ErrorResponse {
+                            transaction_id: request.transaction_id,
+                            message: "Info hash not allowed".into(),
+                        });
+
+                        local_responses.push((response, listener_index, src))
                     }
-                    bytes_received_ipv6 += bytes_read;
                 }
-
-                handle_request(
-                    config,
-                    connection_validator,
-                    pending_scrape_responses,
-                    access_list_cache,
-                    request_sender,
-                    local_responses,
-                    pending_scrape_valid_until,
-                    res_request,
-                    src,
-                );
             }
-            Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                break;
+            Ok(Request::Scrape(request)) => {
+                if self
+                    .connection_validator
+                    .connection_id_valid(src, request.connection_id)
+                {
+                    let split_requests = pending_scrape_responses.prepare_split_requests(
+                        config,
+                        request,
+                        pending_scrape_valid_until,
+                    );
+
+                    for (swarm_worker_index, request) in split_requests {
+                        self.forward_request(
+                            request_sender,
+                            swarm_worker_index,
+                            listener_index,
+                            ConnectedRequest::Scrape(request),
+                            src,
+                        );
+                    }
+                }
             }
             Err(err) => {
-                ::log::warn!("recv_from error: {:#}", err);
+                ::log::debug!("Request::from_bytes error: {:?}", err);
+
+                if let RequestParseError::Sendable {
+                    connection_id,
+                    transaction_id,
+                    err,
+                } = err
+                {
+                    if self
+                        .connection_validator
+                        .connection_id_valid(src, connection_id)
+                    {
+                        let response = ErrorResponse {
+                            transaction_id,
+                            message: err.right_or("Parse error").into(),
+                        };
+
+                        local_responses.push((response.into(), listener_index, src));
+                    }
+                }
             }
         }
     }
 
-    if config.statistics.active() {
-        state
-            .statistics_ipv4
-            .requests_received
-            .fetch_add(requests_received_ipv4, Ordering::Release);
-        state
-            .statistics_ipv6
-            .requests_received
-            .fetch_add(requests_received_ipv6, Ordering::Release);
-        state
-            .statistics_ipv4
-            .bytes_received
-            .fetch_add(bytes_received_ipv4, Ordering::Release);
-        state
-            .statistics_ipv6
-            .bytes_received
-            .fetch_add(bytes_received_ipv6, Ordering::Release);
+    /// Pass request on to swarm worker, or keep it for handling by this socket
+    /// worker if it owns the shard
+    fn forward_request(
+        &mut self,
+        request_sender: &mut ConnectedRequestSender,
+        swarm_worker_index: SwarmWorkerIndex,
+        listener_index: ListenerIndex,
+        request: ConnectedRequest,
+        src: CanonicalSocketAddr,
+    ) {
+        if self.opt_own_shard == Some(swarm_worker_index) {
+            self.own_shard_requests.push((listener_index, request, src));
+        } else {
+            request_sender.try_send_to(swarm_worker_index, listener_index, request, src);
+        }
+    }
+}
+
+/// Request statistics for a listener, collected locally and then added to
+/// shared statistics and metrics in one go
+#[derive(Default)]
+pub struct RequestCounters {
+    requests_received_ipv4: usize,
+    requests_received_ipv6: usize,
+    requests_blocked_ipv4: usize,
+    requests_blocked_ipv6: usize,
+    requests_rate_limited_ipv4: usize,
+    requests_rate_limited_ipv6: usize,
+    invalid_requests_ipv4: usize,
+    invalid_requests_ipv6: usize,
+    bytes_received_ipv4: usize,
+    bytes_received_ipv6: usize,
+}
+
+impl RequestCounters {
+    /// Add counts to shared statistics and metrics and reset them
+    pub fn publish(&mut self, config: &Config, state: &State, listener_index: ListenerIndex) {
+        let requests_received = self.requests_received_ipv4 + self.requests_received_ipv6;
+        let bytes_received = self.bytes_received_ipv4 + self.bytes_received_ipv6;
+
+        if config.statistics.active() {
+            let listener_statistics = &state.listener_statistics[listener_index.0];
+
+            listener_statistics
+                .requests_received
+                .fetch_add(requests_received, Ordering::Release);
+            listener_statistics
+                .bytes_received
+                .fetch_add(bytes_received, Ordering::Release);
+
+            state
+                .statistics_ipv4
+                .requests_received
+                .fetch_add(self.requests_received_ipv4, Ordering::Release);
+            state
+                .statistics_ipv6
+                .requests_received
+                .fetch_add(self.requests_received_ipv6, Ordering::Release);
+            state
+                .statistics_ipv4
+                .requests_blocked
+                .fetch_add(self.requests_blocked_ipv4, Ordering::Release);
+            state
+                .statistics_ipv6
+                .requests_blocked
+                .fetch_add(self.requests_blocked_ipv6, Ordering::Release);
+            state
+                .statistics_ipv4
+                .bytes_received
+                .fetch_add(self.bytes_received_ipv4, Ordering::Release);
+            state
+                .statistics_ipv6
+                .bytes_received
+                .fetch_add(self.bytes_received_ipv6, Ordering::Release);
+        }
+
+        if config.metrics.active {
+            let metrics = &state.metrics;
+
+            let listener_metrics = &metrics.listeners[listener_index.0];
+
+            listener_metrics
+                .requests_received
+                .add(requests_received as u64);
+            listener_metrics.bytes_received.add(bytes_received as u64);
+
+            metrics
+                .ipv4
+                .requests_received
+                .add(self.requests_received_ipv4 as u64);
+            metrics
+                .ipv6
+                .requests_received
+                .add(self.requests_received_ipv6 as u64);
+            metrics
+                .ipv4
+                .invalid_requests
+                .add(self.invalid_requests_ipv4 as u64);
+            metrics
+                .ipv6
+                .invalid_requests
+                .add(self.invalid_requests_ipv6 as u64);
+            metrics
+                .ipv4
+                .requests_blocked
+                .add(self.requests_blocked_ipv4 as u64);
+            metrics
+                .ipv6
+                .requests_blocked
+                .add(self.requests_blocked_ipv6 as u64);
+            metrics
+                .ipv4
+                .requests_rate_limited
+                .add(self.requests_rate_limited_ipv4 as u64);
+            metrics
+                .ipv6
+                .requests_rate_limited
+                .add(self.requests_rate_limited_ipv6 as u64);
+            metrics
+                .ipv4
+                .bytes_received
+                .add(self.bytes_received_ipv4 as u64);
+            metrics
+                .ipv6
+                .bytes_received
+                .add(self.bytes_received_ipv6 as u64);
+        }
+
+        *self = Self::default();
     }
 }
 
-fn handle_request(
+pub fn read_requests(
     config: &Config,
-    connection_validator: &mut ConnectionValidator,
+    state: &State,
+    request_handler: &mut RequestHandler,
     pending_scrape_responses: &mut PendingScrapeResponseSlab,
-    access_list_cache: &mut AccessListCache,
-    request_sender: &ConnectedRequestSender,
-    local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+    listener: &mut Listener,
+    recv_batch: &mut RecvBatch,
+    request_sender: &mut ConnectedRequestSender,
+    local_responses: &mut Vec<(Response, ListenerIndex, CanonicalSocketAddr)>,
     pending_scrape_valid_until: ValidUntil,
-    res_request: Result<Request, RequestParseError>,
-    src: CanonicalSocketAddr,
 ) {
-    let access_list_mode = config.access_list.mode;
-
-    match res_request {
-        Ok(Request::Connect(request)) => {
-            let connection_id = connection_validator.create_connection_id(src);
+    let mut counters = RequestCounters::default();
 
-            let response = Response::Connect(ConnectResponse {
-                connection_id,
-                transaction_id: request.transaction_id,
-            });
+    let now = Instant::now();
 
-            local_responses.push((response, src))
-        }
-        Ok(Request::Announce(request)) => {
-            if connection_validator.connection_id_valid(src, request.connection_id) {
-                if access_list_cache
-                    .load()
-                    .allows(access_list_mode, &request.info_hash.0)
-                {
-                    let worker_index = SwarmWorkerIndex::from_info_hash(config, request.info_hash);
-
-                    request_sender.try_send_to(
-                        worker_index,
-                        ConnectedRequest::Announce(request),
-                        src,
-                    );
-                } else {
-                    let response = Response::Error(ErrorResponse {
-                        transaction_id: request.transaction_id,
-                        message: "Info hash not allowed".into(),
-                    });
+    loop {
+        match recv_batch.recv(&listener.socket) {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                break;
+            }
+            Err(err) => {
+                ::log::warn!("recv error: {:#}", err);
 
-                    local_responses.push((response, src))
-                }
+                continue;
             }
         }
-        Ok(Request::Scrape(request)) => {
-            if connection_validator.connection_id_valid(src, request.connection_id) {
-                let split_requests = pending_scrape_responses.prepare_split_requests(
-                    config,
-                    request,
-                    pending_scrape_valid_until,
-                );
-
-                for (swarm_worker_index, request) in split_requests {
-                    request_sender.try_send_to(
-                        swarm_worker_index,
-                        ConnectedRequest::Scrape(request),
-                        src,
-                    );
-                }
-            }
+
+        for (bytes, src) in recv_batch.iter() {
+            request_handler.handle_datagram(
+                config,
+                pending_scrape_responses,
+                request_sender,
+                local_responses,
+                &mut counters,
+                pending_scrape_valid_until,
+                now,
+                listener.index,
+                bytes,
+                src,
+            );
         }
-        Err(err) => {
-            ::log::debug!("Request::from_bytes error: {:?}", err);
-
-            if let RequestParseError::Sendable {
-                connection_id,
-                transaction_id,
-                err,
-            } = err
-            {
-                if connection_validator.connection_id_valid(src, connection_id) {
-                    let response = ErrorResponse {
-                        transaction_id,
-                        message: err.right_or("Parse error").into(),
-                    };
-
-                    local_responses.push((response.into(), src));
-                }
+    }
+
+    counters.publish(config, state, listener.index);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_rate_limit_response() {
+        let config = Config::default();
+        let state = State::new(1, &config.network.address);
+        let mut connection_validator = ConnectionValidator::new(&config).unwrap();
+        let mut handler = RequestHandler::new(&state, connection_validator.clone(), None);
+
+        let src = CanonicalSocketAddr::new(SocketAddr::from(([192, 0, 2, 1], 1000)));
+        let spoofed_src = CanonicalSocketAddr::new(SocketAddr::from(([192, 0, 2, 2], 1000)));
+
+        let connection_id = connection_validator.create_connection_id(src);
+
+        let header = |action: i32| {
+            let mut bytes = Vec::new();
+
+            bytes.extend_from_slice(&connection_id.0.to_be_bytes());
+            bytes.extend_from_slice(&action.to_be_bytes());
+            bytes.extend_from_slice(&123i32.to_be_bytes());
+
+            bytes
+        };
+
+        match handler.create_rate_limit_response(&header(1), src) {
+            Some(Response::Error(response)) => {
+                assert_eq!(response.transaction_id, TransactionId(123));
             }
+            _ => panic!("expected error response"),
         }
+
+        assert!(handler
+            .create_rate_limit_response(&header(2), src)
+            .is_some());
+        assert!(handler
+            .create_rate_limit_response(&header(1), spoofed_src)
+            .is_none());
+        assert!(handler
+            .create_rate_limit_response(&header(0), src)
+            .is_none());
+        assert!(handler
+            .create_rate_limit_response(&header(1)[..15], src)
+            .is_none());
     }
 }
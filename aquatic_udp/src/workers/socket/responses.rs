@@ -1,10 +1,9 @@
 use std::io::{Cursor, ErrorKind};
+use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use std::vec::Drain;
 
-use crossbeam_channel::Receiver;
 use libc::ENOBUFS;
-use mio::net::UdpSocket;
 
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::*;
@@ -13,68 +12,130 @@ use crate::common::*;
 use crate::config::Config;
 
 use super::storage::PendingScrapeResponseSlab;
+use super::Listener;
+
+type ResendBuffer = Vec<(Response, ListenerIndex, CanonicalSocketAddr)>;
 
 pub fn send_responses(
     state: &State,
     config: &Config,
-    socket: &mut UdpSocket,
-    buffer: &mut [u8],
-    response_receiver: &Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    listeners: &mut [Listener],
+    response_receiver: &mut ConnectedResponseReceiver,
     pending_scrape_responses: &mut PendingScrapeResponseSlab,
-    local_responses: Drain<(Response, CanonicalSocketAddr)>,
-    opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+    local_responses: Drain<(Response, ListenerIndex, CanonicalSocketAddr)>,
+    opt_resend_buffer: &mut Option<ResendBuffer>,
 ) {
     if let Some(resend_buffer) = opt_resend_buffer {
-        for (response, addr) in resend_buffer.drain(..) {
-            send_response(state, config, socket, buffer, response, addr, &mut None);
+        for (response, listener_index, addr) in resend_buffer.drain(..) {
+            send_response(
+                state,
+                config,
+                &mut listeners[listener_index.0],
+                response,
+                addr,
+                &mut None,
+            );
+        }
+
+        // Responses failing again are not added back to the resend buffer
+        for listener in listeners.iter_mut() {
+            flush_responses(state, config, listener, &mut None);
         }
     }
 
-    for (response, addr) in local_responses {
+    for (response, listener_index, addr) in local_responses {
         send_response(
             state,
             config,
-            socket,
-            buffer,
+            &mut listeners[listener_index.0],
             response,
             addr,
             opt_resend_buffer,
         );
     }
 
-    for (response, addr) in response_receiver.try_iter() {
-        let opt_response = match response {
-            ConnectedResponse::Scrape(r) => pending_scrape_responses
-                .add_and_get_finished(r)
-                .map(Response::Scrape),
-            ConnectedResponse::AnnounceIpv4(r) => Some(Response::AnnounceIpv4(r)),
-            ConnectedResponse::AnnounceIpv6(r) => Some(Response::AnnounceIpv6(r)),
-        };
+    for (response, listener_index, addr) in response_receiver.try_iter() {
+        send_connected_response(
+            state,
+            config,
+            listeners,
+            pending_scrape_responses,
+            response,
+            listener_index,
+            addr,
+            opt_resend_buffer,
+        );
+    }
 
-        if let Some(response) = opt_response {
-            send_response(
-                state,
-                config,
-                socket,
-                buffer,
-                response,
-                addr,
-                opt_resend_buffer,
-            );
-        }
+    for listener in listeners.iter_mut() {
+        flush_responses(state, config, listener, opt_resend_buffer);
     }
 }
 
+/// Send response from swarm worker, unless it is part of a scrape response
+/// that is still waiting for other swarm workers
+pub fn send_connected_response(
+    state: &State,
+    config: &Config,
+    listeners: &mut [Listener],
+    pending_scrape_responses: &mut PendingScrapeResponseSlab,
+    response: ConnectedResponse,
+    listener_index: ListenerIndex,
+    addr: CanonicalSocketAddr,
+    opt_resend_buffer: &mut Option<ResendBuffer>,
+) {
+    if let Some(response) = finish_connected_response(pending_scrape_responses, response) {
+        send_response(
+            state,
+            config,
+            &mut listeners[listener_index.0],
+            response,
+            addr,
+            opt_resend_buffer,
+        );
+    }
+}
+
+/// Convert response from swarm worker to a response that can be sent,
+/// unless it is part of a scrape response that is still waiting for other
+/// swarm workers
+pub fn finish_connected_response(
+    pending_scrape_responses: &mut PendingScrapeResponseSlab,
+    response: ConnectedResponse,
+) -> Option<Response> {
+    match response {
+        ConnectedResponse::Scrape(r) => pending_scrape_responses
+            .add_and_get_finished(r)
+            .map(Response::Scrape),
+        ConnectedResponse::AnnounceIpv4(r) => Some(Response::AnnounceIpv4(r)),
+        ConnectedResponse::AnnounceIpv6(r) => Some(Response::AnnounceIpv6(r)),
+    }
+}
+
+/// Address to send response to over socket bound to `listener_address`
+pub fn response_addr(
+    listener_address: SocketAddr,
+    canonical_addr: CanonicalSocketAddr,
+) -> SocketAddr {
+    if listener_address.is_ipv4() {
+        canonical_addr
+            .get_ipv4()
+            .expect("found peer ipv6 address while running bound to ipv4 address")
+    } else {
+        canonical_addr.get_ipv6_mapped()
+    }
+}
+
+/// Add response to batch of listener, sending the batch if it is full
 fn send_response(
     state: &State,
     config: &Config,
-    socket: &mut UdpSocket,
-    buffer: &mut [u8],
+    listener: &mut Listener,
     response: Response,
     canonical_addr: CanonicalSocketAddr,
-    resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+    opt_resend_buffer: &mut Option<ResendBuffer>,
 ) {
-    let mut cursor = Cursor::new(buffer);
+    let mut cursor = Cursor::new(listener.send_batch.next_buffer());
 
     if let Err(err) = response.write(&mut cursor) {
         ::log::error!("Converting response to bytes failed: {:#}", err);
@@ -84,60 +145,151 @@ fn send_response(
 
     let bytes_written = cursor.position() as usize;
 
-    let addr = if config.network.address.is_ipv4() {
-        canonical_addr
-            .get_ipv4()
-            .expect("found peer ipv6 address while running bound to ipv4 address")
-    } else {
-        canonical_addr.get_ipv6_mapped()
-    };
+    let addr = response_addr(listener.address, canonical_addr);
+
+    listener
+        .send_batch
+        .push(bytes_written, addr, (response, canonical_addr));
+
+    if listener.send_batch.is_full() {
+        flush_responses(state, config, listener, opt_resend_buffer);
+    }
+}
+
+/// Send responses in batch of listener
+pub fn flush_responses(
+    state: &State,
+    config: &Config,
+    listener: &mut Listener,
+    opt_resend_buffer: &mut Option<ResendBuffer>,
+) {
+    if listener.send_batch.is_empty() {
+        return;
+    }
+
+    let listener_index = listener.index;
+
+    listener.send_batch.flush(
+        &listener.socket,
+        |(response, canonical_addr), result| match result {
+            Ok(amt) => update_sent_statistics(
+                state,
+                config,
+                listener_index,
+                &response,
+                canonical_addr,
+                amt,
+            ),
+            Err(err) => handle_send_error(
+                config,
+                listener_index,
+                response,
+                canonical_addr,
+                err,
+                opt_resend_buffer,
+            ),
+        },
+    );
+}
+
+fn handle_send_error(
+    config: &Config,
+    listener_index: ListenerIndex,
+    response: Response,
+    canonical_addr: CanonicalSocketAddr,
+    err: ::std::io::Error,
+    opt_resend_buffer: &mut Option<ResendBuffer>,
+) {
+    match opt_resend_buffer {
+        Some(resend_buffer)
+            if (err.raw_os_error() == Some(ENOBUFS)) || (err.kind() == ErrorKind::WouldBlock) =>
+        {
+            if resend_buffer.len() < config.network.resend_buffer_max_len {
+                ::log::info!(
+                    "Adding response to resend queue, since sending it to {} failed with: {:#}",
+                    canonical_addr.get(),
+                    err
+                );
 
-    match socket.send_to(&cursor.get_ref()[..bytes_written], addr) {
-        Ok(amt) if config.statistics.active() => {
-            let stats = if canonical_addr.is_ipv4() {
-                &state.statistics_ipv4
+                resend_buffer.push((response, listener_index, canonical_addr));
             } else {
-                &state.statistics_ipv6
-            };
-
-            stats.bytes_sent.fetch_add(amt, Ordering::Relaxed);
-
-            match response {
-                Response::Connect(_) => {
-                    stats.responses_sent_connect.fetch_add(1, Ordering::Relaxed);
-                }
-                Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
-                    stats
-                        .responses_sent_announce
-                        .fetch_add(1, Ordering::Relaxed);
-                }
-                Response::Scrape(_) => {
-                    stats.responses_sent_scrape.fetch_add(1, Ordering::Relaxed);
-                }
-                Response::Error(_) => {
-                    stats.responses_sent_error.fetch_add(1, Ordering::Relaxed);
-                }
+                ::log::warn!("Response resend buffer full, dropping response");
+            }
+        }
+        _ => {
+            ::log::warn!(
+                "Sending response to {} failed: {:#}",
+                canonical_addr.get(),
+                err
+            );
+        }
+    }
+}
+
+pub fn update_sent_statistics(
+    state: &State,
+    config: &Config,
+    listener_index: ListenerIndex,
+    response: &Response,
+    canonical_addr: CanonicalSocketAddr,
+    amt: usize,
+) {
+    if config.statistics.active() {
+        let listener_statistics = &state.listener_statistics[listener_index.0];
+
+        listener_statistics
+            .responses_sent
+            .fetch_add(1, Ordering::Relaxed);
+        listener_statistics
+            .bytes_sent
+            .fetch_add(amt, Ordering::Relaxed);
+
+        let stats = if canonical_addr.is_ipv4() {
+            &state.statistics_ipv4
+        } else {
+            &state.statistics_ipv6
+        };
+
+        stats.bytes_sent.fetch_add(amt, Ordering::Relaxed);
+
+        match response {
+            Response::Connect(_) => {
+                stats.responses_sent_connect.fetch_add(1, Ordering::Relaxed);
+            }
+            Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
+                stats
+                    .responses_sent_announce
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Response::Scrape(_) => {
+                stats.responses_sent_scrape.fetch_add(1, Ordering::Relaxed);
+            }
+            Response::Error(_) => {
+                stats.responses_sent_error.fetch_add(1, Ordering::Relaxed);
             }
         }
-        Ok(_) => (),
-        Err(err) => {
-            match resend_buffer {
-                Some(resend_buffer)
-                    if (err.raw_os_error() == Some(ENOBUFS))
-                        || (err.kind() == ErrorKind::WouldBlock) =>
-                {
-                    if resend_buffer.len() < config.network.resend_buffer_max_len {
-                        ::log::info!("Adding response to resend queue, since sending it to {} failed with: {:#}", addr, err);
-
-                        resend_buffer.push((response, canonical_addr));
-                    } else {
-                        ::log::warn!("Response resend buffer full, dropping response");
-                    }
-                }
-                _ => {
-                    ::log::warn!("Sending response to {} failed: {:#}", addr, err);
-                }
+    }
+    if config.metrics.active {
+        let listener_metrics = &state.metrics.listeners[listener_index.0];
+
+        listener_metrics.responses_sent.increment();
+        listener_metrics.bytes_sent.add(amt as u64);
+
+        let metrics = if canonical_addr.is_ipv4() {
+            &state.metrics.ipv4
+        } else {
+            &state.metrics.ipv6
+        };
+
+        metrics.bytes_sent.add(amt as u64);
+
+        match response {
+            Response::Connect(_) => metrics.responses_sent_connect.increment(),
+            Response::AnnounceIpv4(_) | Response::AnnounceIpv6(_) => {
+                metrics.responses_sent_announce.increment()
             }
+            Response::Scrape(_) => metrics.responses_sent_scrape.increment(),
+            Response::Error(_) => metrics.responses_sent_error.increment(),
         }
     }
 }
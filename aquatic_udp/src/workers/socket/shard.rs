@@ -0,0 +1,290 @@
+//! Shard-per-core mode, where each socket worker also acts as swarm worker
+//! for its own shard of torrents
+//!
+//! A classic BPF program attached to the SO_REUSEPORT group of each address
+//! steers announce and scrape requests to the socket with the index given
+//! by [`SwarmWorkerIndex::from_info_hash`] for the (first) info hash, so
+//! that they can be handled without passing through channels. Other
+//! packets are distributed by the kernel as usual. Parts of scrape requests
+//! for torrents in other shards are still passed to the responsible socket
+//! worker over channels.
+
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use crossbeam_channel::{Receiver, TryRecvError};
+
+use aquatic_common::control::SwarmCommandRequest;
+use aquatic_common::CanonicalSocketAddr;
+use aquatic_udp_protocol::Response;
+
+use crate::common::*;
+use crate::config::Config;
+use crate::workers::swarm::SwarmWorker;
+
+use super::requests::RequestHandler;
+use super::responses::finish_connected_response;
+use super::storage::PendingScrapeResponseSlab;
+
+/// Swarm worker state and channels owned by a socket worker
+pub struct Shard {
+    pub index: SwarmWorkerIndex,
+    pub swarm_worker: SwarmWorker,
+    /// Requests from other socket workers
+    pub request_receiver: ConnectedRequestReceiver,
+    pub response_sender: ConnectedResponseSender,
+    pub command_receiver: Receiver<SwarmCommandRequest>,
+    /// Sockets created in order by [`create_shard_sockets`]
+    pub sockets: Vec<(SocketAddr, ::std::net::UdpSocket)>,
+}
+
+impl Shard {
+    /// Handle requests for this shard received by this socket worker and
+    /// those passed on by other socket workers, as well as control commands
+    pub fn handle_requests(
+        &mut self,
+        request_handler: &mut RequestHandler,
+        pending_scrape_responses: &mut PendingScrapeResponseSlab,
+        local_responses: &mut Vec<(Response, ListenerIndex, CanonicalSocketAddr)>,
+    ) {
+        for (listener_index, request, src) in request_handler.drain_own_shard_requests() {
+            let response = self.swarm_worker.handle_request(request, src);
+
+            if let Some(response) = finish_connected_response(pending_scrape_responses, response) {
+                local_responses.push((response, listener_index, src));
+            }
+        }
+
+        self.handle_forwarded_requests();
+
+        for request in self.command_receiver.try_iter() {
+            self.swarm_worker.handle_command(request);
+        }
+    }
+
+    /// Handle requests passed on by other socket workers
+    ///
+    /// Returns true once all other socket workers have stopped passing on
+    /// requests.
+    pub fn handle_forwarded_requests(&mut self) -> bool {
+        let disconnected = loop {
+            match self.request_receiver.try_recv() {
+                Ok((sender_index, listener_index, request, src)) => {
+                    let response = self.swarm_worker.handle_request(request, src);
+
+                    self.response_sender
+                        .try_send_to(sender_index, listener_index, response, src);
+                }
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+
+        self.response_sender.flush();
+
+        disconnected
+    }
+}
+
+/// Create sockets for all socket workers and attach the steering program
+///
+/// Sockets are created one worker at a time, so that their indices in the
+/// SO_REUSEPORT groups match the worker indices. Other processes must not
+/// bind sockets to the same addresses.
+pub fn create_shard_sockets(
+    config: &Config,
+) -> anyhow::Result<Vec<Vec<(SocketAddr, ::std::net::UdpSocket)>>> {
+    let mut worker_sockets = Vec::new();
+
+    for _ in 0..config.socket_workers {
+        let mut sockets = Vec::new();
+
+        for address in config.network.address.iter().copied() {
+            let socket = super::create_socket(config, address)
+                .with_context(|| format!("create socket bound to {}", address))?;
+
+            sockets.push((address, socket));
+        }
+
+        worker_sockets.push(sockets);
+    }
+
+    // The program applies to the whole group, so attaching it to one
+    // socket per address is enough
+    if let Some(sockets) = worker_sockets.first() {
+        for (address, socket) in sockets {
+            attach_steering_program(socket, config.socket_workers).with_context(|| {
+                format!("attach steering program to socket bound to {}", address)
+            })?;
+        }
+    }
+
+    Ok(worker_sockets)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach_steering_program(
+    _socket: &::std::net::UdpSocket,
+    _num_sockets: usize,
+) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("shard_per_core is only supported on Linux"))
+}
+
+/// Attach program returning index of socket to handle packet
+///
+/// When attached with SO_ATTACH_REUSEPORT_CBPF to a UDP socket, the program
+/// sees the UDP payload. Returning an index that is out of range makes the
+/// kernel fall back to hash based socket selection.
+#[cfg(target_os = "linux")]
+fn attach_steering_program(
+    socket: &::std::net::UdpSocket,
+    num_sockets: usize,
+) -> anyhow::Result<()> {
+    use std::mem::size_of;
+    use std::os::raw::c_void;
+    use std::os::unix::io::AsRawFd;
+
+    use libc::{setsockopt, sock_filter, sock_fprog, SOL_SOCKET, SO_ATTACH_REUSEPORT_CBPF};
+
+    // Values of constants were copied from the following Linux source files:
+    //   - include/uapi/linux/bpf_common.h
+    //   - include/uapi/linux/filter.h
+
+    // Instruction classes
+    const BPF_LD: u16 = 0x00;
+    const BPF_ALU: u16 = 0x04;
+    const BPF_JMP: u16 = 0x05;
+    const BPF_RET: u16 = 0x06;
+
+    // Sizes
+    const BPF_W: u16 = 0x00;
+    const BPF_B: u16 = 0x10;
+
+    // Modes
+    const BPF_ABS: u16 = 0x20;
+
+    // Operations
+    const BPF_MOD: u16 = 0x90;
+    const BPF_JEQ: u16 = 0x10;
+
+    // Sources
+    const BPF_K: u16 = 0x00;
+    const BPF_A: u16 = 0x10;
+
+    // Offsets in request
+    const ACTION_OFFSET: u32 = 8;
+    const INFO_HASH_OFFSET: u32 = 16;
+
+    // Values of action field
+    const ACTION_ANNOUNCE: u32 = 1;
+    const ACTION_SCRAPE: u32 = 2;
+
+    fn instruction(code: u16, jt: u8, jf: u8, k: u32) -> sock_filter {
+        sock_filter { code, jt, jf, k }
+    }
+
+    // Jump offsets are relative to the next instruction. Loads past the
+    // end of the packet make the program return 0.
+    let mut filter = [
+        // A = action (big-endian u32)
+        instruction(BPF_LD | BPF_W | BPF_ABS, 0, 0, ACTION_OFFSET),
+        // If announce, go to info hash load
+        instruction(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, ACTION_ANNOUNCE),
+        // If scrape, go to info hash load, otherwise to fallback
+        instruction(BPF_JMP | BPF_JEQ | BPF_K, 0, 3, ACTION_SCRAPE),
+        // A = first byte of (first) info hash
+        instruction(BPF_LD | BPF_B | BPF_ABS, 0, 0, INFO_HASH_OFFSET),
+        // A = A % num_sockets, as in SwarmWorkerIndex::from_info_hash
+        instruction(BPF_ALU | BPF_MOD | BPF_K, 0, 0, num_sockets as u32),
+        // Return A
+        instruction(BPF_RET | BPF_A, 0, 0, 0),
+        // Return out of range index
+        instruction(BPF_RET | BPF_K, 0, 0, u32::MAX),
+    ];
+
+    let program = sock_fprog {
+        filter: filter.as_mut_ptr(),
+        len: filter.len() as u16,
+    };
+
+    let program_ptr: *const sock_fprog = &program;
+
+    // Safety: program points to valid filter, which kernel copies
+    let result = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_CBPF,
+            program_ptr as *const c_void,
+            size_of::<sock_fprog>() as u32,
+        )
+    };
+
+    if result != 0 {
+        Err(::std::io::Error::last_os_error().into())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    use super::*;
+
+    fn create_reuseport_socket(port: u16) -> UdpSocket {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+
+        socket.set_reuse_port(true).unwrap();
+        socket
+            .bind(&SocketAddr::from(([127, 0, 0, 1], port)).into())
+            .unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        socket.into()
+    }
+
+    #[test]
+    fn test_steering_program() {
+        const NUM_SOCKETS: usize = 3;
+
+        let first = create_reuseport_socket(0);
+        let port = first.local_addr().unwrap().port();
+
+        let mut sockets = vec![first];
+
+        for _ in 1..NUM_SOCKETS {
+            sockets.push(create_reuseport_socket(port));
+        }
+
+        attach_steering_program(&sockets[0], NUM_SOCKETS).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buffer = [0u8; 128];
+
+        for first_info_hash_byte in 0..10u8 {
+            for action in [1u32, 2] {
+                let mut request = [0u8; 36];
+
+                request[8..12].copy_from_slice(&action.to_be_bytes());
+                request[16] = first_info_hash_byte;
+
+                client
+                    .send_to(&request, SocketAddr::from(([127, 0, 0, 1], port)))
+                    .unwrap();
+
+                let expected = usize::from(first_info_hash_byte) % NUM_SOCKETS;
+
+                let (len, _) = sockets[expected].recv_from(&mut buffer).unwrap();
+
+                assert_eq!(&buffer[..len], &request[..]);
+            }
+        }
+    }
+}
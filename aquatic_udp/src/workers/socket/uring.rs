@@ -0,0 +1,955 @@
+//! Socket worker backend using io_uring
+//!
+//! Sockets are registered with the ring as fixed files. Requests are
+//! received with one multishot recvmsg operation per socket, which picks
+//! buffers from a ring of provided buffers shared with the kernel.
+//! Responses are sent with sendmsg from a fixed set of send slots, so at
+//! most `ring_size` responses are in flight at a time. Swarm workers wake
+//! up the worker when passing on responses by writing to an eventfd, which
+//! is read from with an operation on the ring.
+//!
+//! Provided buffer rings require Linux 5.19 and multishot recvmsg requires
+//! Linux 6.0.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::io::Cursor;
+use std::mem::{size_of, zeroed};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use crossbeam_channel::{Receiver, RecvTimeoutError, TryRecvError};
+use io_uring::cqueue::{buffer_select, more};
+use io_uring::types::{BufRingEntry, Fd, Fixed, RecvMsgOut, Timespec};
+use io_uring::{opcode, squeue, IoUring};
+use libc::{c_void, iovec, msghdr, sockaddr_storage, socklen_t, EINTR, ENOBUFS};
+use socket2::SockAddr;
+
+use aquatic_common::{CanonicalSocketAddr, ValidUntil};
+use aquatic_udp_protocol::*;
+
+use crate::common::*;
+use crate::config::Config;
+
+use super::requests::{RequestCounters, RequestHandler};
+use super::responses::{finish_connected_response, response_addr, update_sent_statistics};
+use super::storage::PendingScrapeResponseSlab;
+
+/// Buffer group id of the provided buffer ring
+const BUF_GROUP: u16 = 0;
+
+/// Length of `io_uring_recvmsg_out` header, which multishot recvmsg writes
+/// to the start of each buffer
+const RECVMSG_OUT_HEADER_LEN: usize = 16;
+
+/// Each received datagram occupies one buffer, laid out as a header,
+/// the source address and the payload
+const RECV_BUFFER_LEN: usize = RECVMSG_OUT_HEADER_LEN + size_of::<sockaddr_storage>() + BUFFER_SIZE;
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UserData {
+    Recv { listener: usize },
+    Send { slot: usize },
+    Timeout,
+    Cancel,
+    Wake,
+}
+
+impl UserData {
+    fn encode(self) -> u64 {
+        let (kind, index) = match self {
+            Self::Recv { listener } => (0u64, listener),
+            Self::Send { slot } => (1, slot),
+            Self::Timeout => (2, 0),
+            Self::Cancel => (3, 0),
+            Self::Wake => (4, 0),
+        };
+
+        (kind << 32) | index as u64
+    }
+
+    fn decode(user_data: u64) -> Self {
+        let index = (user_data & u64::from(u32::MAX)) as usize;
+
+        match user_data >> 32 {
+            0 => Self::Recv { listener: index },
+            1 => Self::Send { slot: index },
+            2 => Self::Timeout,
+            3 => Self::Cancel,
+            _ => Self::Wake,
+        }
+    }
+}
+
+/// Eventfd, closed when dropped
+///
+/// Not set to nonblocking mode, since io_uring would then complete reads
+/// with EAGAIN instead of waiting for the counter to be written to.
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> anyhow::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+
+        if fd < 0 {
+            Err(::std::io::Error::last_os_error()).with_context(|| "create eventfd")
+        } else {
+            Ok(Self(fd))
+        }
+    }
+
+    fn notify(&self) {
+        let value = 1u64;
+
+        // Counter can't realistically overflow, which would block
+        unsafe {
+            libc::write(
+                self.0,
+                &value as *const u64 as *const c_void,
+                size_of::<u64>(),
+            );
+        }
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Receive buffers and the ring through which they are provided to the
+/// kernel
+struct BufRing {
+    entries: *mut BufRingEntry,
+    entries_layout: Layout,
+    buffers: Box<[u8]>,
+    mask: u16,
+    /// Tail not yet made visible to the kernel
+    local_tail: u16,
+}
+
+impl BufRing {
+    fn new(ring_size: u16) -> anyhow::Result<Self> {
+        // Kernel requires ring memory to be page aligned
+        let entries_layout = Layout::from_size_align(
+            size_of::<BufRingEntry>() * usize::from(ring_size),
+            PAGE_SIZE,
+        )?;
+
+        // Safety: layout has non-zero size since ring_size is a power of two
+        let entries = unsafe { alloc_zeroed(entries_layout) } as *mut BufRingEntry;
+
+        if entries.is_null() {
+            return Err(anyhow::anyhow!("allocate buffer ring"));
+        }
+
+        let mut buf_ring = Self {
+            entries,
+            entries_layout,
+            buffers: vec![0; RECV_BUFFER_LEN * usize::from(ring_size)].into_boxed_slice(),
+            mask: ring_size - 1,
+            local_tail: 0,
+        };
+
+        for bid in 0..ring_size {
+            buf_ring.recycle(bid);
+        }
+
+        buf_ring.publish();
+
+        Ok(buf_ring)
+    }
+
+    fn buffer(&self, bid: u16) -> &[u8] {
+        let start = usize::from(bid) * RECV_BUFFER_LEN;
+
+        &self.buffers[start..start + RECV_BUFFER_LEN]
+    }
+
+    /// Hand buffer back to kernel. Takes effect on next call to
+    /// [`Self::publish`].
+    fn recycle(&mut self, bid: u16) {
+        let addr = self.buffer(bid).as_ptr() as u64;
+
+        // Safety: index is within the allocated entries, and the kernel
+        // doesn't read entries past the published tail
+        let entry = unsafe { &mut *self.entries.add(usize::from(self.local_tail & self.mask)) };
+
+        entry.set_addr(addr);
+        entry.set_len(RECV_BUFFER_LEN as u32);
+        entry.set_bid(bid);
+
+        self.local_tail = self.local_tail.wrapping_add(1);
+    }
+
+    fn publish(&self) {
+        // Safety: entries is a valid, page aligned ring. The kernel reads
+        // the tail concurrently, so it is updated atomically.
+        unsafe {
+            let tail = BufRingEntry::tail(self.entries) as *const AtomicU16;
+
+            (*tail).store(self.local_tail, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        // Safety: allocated in Self::new with the same layout
+        unsafe { dealloc(self.entries as *mut u8, self.entries_layout) }
+    }
+}
+
+/// Response being sent. The message header points into the slot itself,
+/// so slots must not be moved while a send is in flight.
+struct SendSlot {
+    msghdr: msghdr,
+    iovec: iovec,
+    addr: SockAddr,
+    buffer: [u8; BUFFER_SIZE],
+    response: Option<(Response, ListenerIndex, CanonicalSocketAddr)>,
+}
+
+impl SendSlot {
+    fn new() -> Self {
+        // Safety: all-zero bytes is a valid value for these C structs
+        unsafe {
+            Self {
+                msghdr: zeroed(),
+                iovec: zeroed(),
+                addr: SockAddr::from(SocketAddr::from(([0, 0, 0, 0], 0))),
+                buffer: [0; BUFFER_SIZE],
+                response: None,
+            }
+        }
+    }
+}
+
+struct Listener {
+    socket: ::std::net::UdpSocket,
+    /// Address from config, which determines how response addresses are
+    /// formatted
+    address: SocketAddr,
+    counters: RequestCounters,
+    received_requests: bool,
+    recv_armed: bool,
+}
+
+struct SocketWorker {
+    // Dropped first, so that the kernel stops using the memory below
+    ring: IoUring,
+    state: State,
+    config: Config,
+    request_handler: RequestHandler,
+    /// Dropped when shutting down to stop forwarding requests
+    opt_request_sender: Option<ConnectedRequestSender>,
+    response_receiver: ConnectedResponseReceiver,
+    response_notifications: Receiver<()>,
+    /// Written to by swarm workers after passing on responses
+    wake_fd: Arc<EventFd>,
+    wake_buffer: Box<u64>,
+    wake_armed: bool,
+    listeners: Vec<Listener>,
+    buf_ring: BufRing,
+    /// Template passed to multishot recvmsg, which only uses the name and
+    /// control lengths
+    recv_msghdr: Box<msghdr>,
+    send_slots: Vec<SendSlot>,
+    free_send_slots: Vec<usize>,
+    timeout_timespec: Box<Timespec>,
+    timeout_armed: bool,
+    /// Set once receiving, timeouts and wakeups have been cancelled
+    operations_cancelled: bool,
+    pending_scrape_responses: PendingScrapeResponseSlab,
+    local_responses: Vec<(Response, ListenerIndex, CanonicalSocketAddr)>,
+    completions: Vec<(UserData, i32, u32)>,
+}
+
+pub fn run_socket_worker(
+    state: State,
+    config: Config,
+    request_handler: RequestHandler,
+    request_sender: ConnectedRequestSender,
+    response_receiver: ConnectedResponseReceiver,
+    sockets: Vec<(SocketAddr, ::std::net::UdpSocket)>,
+) -> anyhow::Result<()> {
+    let ring_size = config.network.ring_size;
+
+    if !ring_size.is_power_of_two() || ring_size > 1 << 15 {
+        return Err(anyhow::anyhow!(
+            "network.ring_size must be a power of two no larger than 32768"
+        ));
+    }
+
+    let ring = IoUring::builder()
+        .setup_cqsize(u32::from(ring_size) * 4)
+        .build(u32::from(ring_size))
+        .with_context(|| "create io_uring")?;
+
+    let mut listeners = Vec::new();
+
+    for (address, socket) in sockets {
+        // Let io_uring wait for sockets to become ready instead of
+        // returning EAGAIN
+        socket
+            .set_nonblocking(false)
+            .with_context(|| "socket: set blocking")?;
+
+        listeners.push(Listener {
+            socket,
+            address,
+            counters: RequestCounters::default(),
+            received_requests: false,
+            recv_armed: false,
+        });
+    }
+
+    let fds = listeners
+        .iter()
+        .map(|listener| listener.socket.as_raw_fd())
+        .collect::<Vec<_>>();
+
+    ring.submitter()
+        .register_files(&fds)
+        .with_context(|| "io_uring: register sockets")?;
+
+    let buf_ring = BufRing::new(ring_size)?;
+
+    ring.submitter()
+        .register_buf_ring(buf_ring.entries as u64, ring_size, BUF_GROUP)
+        .with_context(|| "io_uring: register buffer ring (requires Linux 5.19 or later)")?;
+
+    // Safety: all-zero bytes is a valid value for msghdr
+    let mut recv_msghdr: Box<msghdr> = Box::new(unsafe { zeroed() });
+
+    recv_msghdr.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+
+    let poll_timeout = Duration::from_millis(config.network.poll_timeout_ms.max(1));
+
+    let wake_fd = Arc::new(EventFd::new()?);
+
+    {
+        let wake_fd = wake_fd.clone();
+
+        response_receiver.set_waker(Arc::new(move || wake_fd.notify()));
+    }
+
+    let mut worker = SocketWorker {
+        ring,
+        state,
+        config,
+        request_handler,
+        opt_request_sender: Some(request_sender),
+        response_notifications: response_receiver.notifications(),
+        response_receiver,
+        wake_fd,
+        wake_buffer: Box::new(0),
+        wake_armed: false,
+        listeners,
+        buf_ring,
+        recv_msghdr,
+        send_slots: (0..ring_size).map(|_| SendSlot::new()).collect(),
+        free_send_slots: (0..usize::from(ring_size)).rev().collect(),
+        timeout_timespec: Box::new(
+            Timespec::new()
+                .sec(poll_timeout.as_secs())
+                .nsec(poll_timeout.subsec_nanos()),
+        ),
+        timeout_armed: false,
+        operations_cancelled: false,
+        pending_scrape_responses: PendingScrapeResponseSlab::default(),
+        local_responses: Vec::new(),
+        completions: Vec::new(),
+    };
+
+    let result = worker.run();
+
+    worker.wait_for_in_flight_operations();
+
+    if let Err(err) = worker.ring.submitter().unregister_buf_ring(BUF_GROUP) {
+        ::log::warn!("io_uring: unregister buffer ring: {:#}", err);
+    }
+
+    result
+}
+
+impl SocketWorker {
+    fn run(&mut self) -> anyhow::Result<()> {
+        for listener_index in 0..self.listeners.len() {
+            self.arm_recv(listener_index)?;
+        }
+
+        self.arm_timeout()?;
+        self.arm_wake()?;
+
+        let pending_scrape_cleaning_duration =
+            Duration::from_secs(self.config.cleaning.pending_scrape_cleaning_interval);
+
+        let mut pending_scrape_valid_until =
+            ValidUntil::new(self.config.cleaning.max_pending_scrape_age);
+        let mut last_pending_scrape_cleaning = Instant::now();
+
+        let mut iter_counter = 0usize;
+
+        while !self.state.shutdown.is_triggered() {
+            self.submit_and_wait()?;
+            self.handle_completions(pending_scrape_valid_until)?;
+
+            if let Some(request_sender) = self.opt_request_sender.as_mut() {
+                request_sender.flush();
+            }
+
+            for listener_index in 0..self.listeners.len() {
+                let listener = &mut self.listeners[listener_index];
+
+                if listener.received_requests {
+                    listener.counters.publish(
+                        &self.config,
+                        &self.state,
+                        ListenerIndex(listener_index),
+                    );
+
+                    listener.received_requests = false;
+                }
+
+                if !self.listeners[listener_index].recv_armed {
+                    self.arm_recv(listener_index)?;
+                }
+            }
+
+            if !self.timeout_armed {
+                self.arm_timeout()?;
+            }
+            if !self.wake_armed {
+                self.arm_wake()?;
+            }
+
+            self.send_local_responses()?;
+
+            // Consumed before receiving responses, so that swarm workers
+            // wake up this worker again when passing on more of them
+            let _ = self.response_notifications.try_recv();
+
+            while !self.free_send_slots.is_empty() {
+                match self.response_receiver.try_recv() {
+                    Ok((response, listener_index, addr)) => {
+                        self.send_connected_response(response, listener_index, addr)?
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                }
+            }
+
+            // Run periodic ValidUntil updates and state cleaning
+            if iter_counter % 256 == 0 {
+                let now = Instant::now();
+
+                pending_scrape_valid_until =
+                    ValidUntil::new_with_now(now, self.config.cleaning.max_pending_scrape_age);
+
+                if now > last_pending_scrape_cleaning + pending_scrape_cleaning_duration {
+                    self.pending_scrape_responses.clean();
+                    self.request_handler.clean(&self.config, now);
+
+                    last_pending_scrape_cleaning = now;
+                }
+            }
+
+            iter_counter = iter_counter.wrapping_add(1);
+        }
+
+        self.shutdown(pending_scrape_valid_until)
+    }
+
+    /// Stop receiving requests and send remaining responses until the
+    /// shutdown deadline
+    fn shutdown(&mut self, pending_scrape_valid_until: ValidUntil) -> anyhow::Result<()> {
+        self.cancel_operations()?;
+
+        // Swarm workers exit and drop their response senders once they've
+        // handled remaining requests and all request senders have been
+        // dropped.
+        if let Some(mut request_sender) = self.opt_request_sender.take() {
+            request_sender.flush();
+        }
+
+        let deadline = self.state.shutdown.deadline().unwrap_or_else(Instant::now);
+        let poll_timeout = Duration::from_millis(self.config.network.poll_timeout_ms.max(1));
+
+        loop {
+            if self.free_send_slots.is_empty() {
+                self.submit_and_wait()?;
+            } else {
+                self.ring.submit().with_context(|| "io_uring: submit")?;
+            }
+
+            self.handle_completions(pending_scrape_valid_until)?;
+
+            if self.free_send_slots.is_empty() {
+                continue;
+            }
+
+            match self
+                .response_receiver
+                .recv_deadline(Instant::now() + poll_timeout)
+            {
+                Ok((response, listener_index, addr)) => {
+                    self.send_connected_response(response, listener_index, addr)?;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if Instant::now() > deadline {
+                ::log::warn!("socket worker didn't finish sending responses before deadline");
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn submit_and_wait(&mut self) -> anyhow::Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Ok(_) => Ok(()),
+            Err(err) if err.raw_os_error() == Some(EINTR) => Ok(()),
+            // Completion queue is full, so just handle completions
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => Ok(()),
+            Err(err) => Err(err).with_context(|| "io_uring: submit and wait"),
+        }
+    }
+
+    fn push_entry(&mut self, entry: squeue::Entry) -> anyhow::Result<()> {
+        loop {
+            // Safety: all memory referenced by entries is owned by self and
+            // outlives the operations, since they are waited for before
+            // self is dropped
+            if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                return Ok(());
+            }
+
+            self.ring.submit().with_context(|| "io_uring: submit")?;
+        }
+    }
+
+    fn arm_recv(&mut self, listener_index: usize) -> anyhow::Result<()> {
+        let entry = opcode::RecvMsgMulti::new(
+            Fixed(listener_index as u32),
+            &*self.recv_msghdr as *const msghdr,
+            BUF_GROUP,
+        )
+        .build()
+        .user_data(
+            UserData::Recv {
+                listener: listener_index,
+            }
+            .encode(),
+        );
+
+        self.push_entry(entry)?;
+
+        self.listeners[listener_index].recv_armed = true;
+
+        Ok(())
+    }
+
+    fn arm_timeout(&mut self) -> anyhow::Result<()> {
+        let entry = opcode::Timeout::new(&*self.timeout_timespec as *const Timespec)
+            .build()
+            .user_data(UserData::Timeout.encode());
+
+        self.push_entry(entry)?;
+
+        self.timeout_armed = true;
+
+        Ok(())
+    }
+
+    fn arm_wake(&mut self) -> anyhow::Result<()> {
+        let entry = opcode::Read::new(
+            Fd(self.wake_fd.0),
+            &mut *self.wake_buffer as *mut u64 as *mut u8,
+            size_of::<u64>() as u32,
+        )
+        .build()
+        .user_data(UserData::Wake.encode());
+
+        self.push_entry(entry)?;
+
+        self.wake_armed = true;
+
+        Ok(())
+    }
+
+    fn cancel_operations(&mut self) -> anyhow::Result<()> {
+        if self.operations_cancelled {
+            return Ok(());
+        }
+
+        self.operations_cancelled = true;
+
+        let mut to_cancel = Vec::new();
+
+        for (listener_index, listener) in self.listeners.iter().enumerate() {
+            if listener.recv_armed {
+                to_cancel.push(UserData::Recv {
+                    listener: listener_index,
+                });
+            }
+        }
+
+        if self.timeout_armed {
+            to_cancel.push(UserData::Timeout);
+        }
+        if self.wake_armed {
+            to_cancel.push(UserData::Wake);
+        }
+
+        for user_data in to_cancel {
+            let entry = opcode::AsyncCancel::new(user_data.encode())
+                .build()
+                .user_data(UserData::Cancel.encode());
+
+            self.push_entry(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for kernel to finish using memory referenced by operations
+    fn wait_for_in_flight_operations(&mut self) {
+        if self.cancel_operations().is_err() {
+            return;
+        }
+
+        while self.timeout_armed
+            || self.wake_armed
+            || self.listeners.iter().any(|listener| listener.recv_armed)
+            || self.free_send_slots.len() < self.send_slots.len()
+        {
+            if let Err(err) = self.ring.submit_and_wait(1) {
+                if err.raw_os_error() != Some(EINTR) {
+                    ::log::error!("io_uring: wait for operations: {:#}", err);
+
+                    return;
+                }
+            }
+
+            self.collect_completions();
+
+            for (user_data, result, flags) in self.completions.drain(..) {
+                match user_data {
+                    UserData::Recv { listener } => {
+                        if !more(flags) {
+                            self.listeners[listener].recv_armed = false;
+                        }
+                    }
+                    UserData::Send { slot } => {
+                        if result < 0 {
+                            ::log::warn!(
+                                "Sending response failed: {:#}",
+                                ::std::io::Error::from_raw_os_error(-result)
+                            );
+                        }
+
+                        self.send_slots[slot].response = None;
+                        self.free_send_slots.push(slot);
+                    }
+                    UserData::Timeout => {
+                        self.timeout_armed = false;
+                    }
+                    UserData::Wake => {
+                        self.wake_armed = false;
+                    }
+                    UserData::Cancel => (),
+                }
+            }
+        }
+    }
+
+    fn collect_completions(&mut self) {
+        self.completions.extend(
+            self.ring
+                .completion()
+                .map(|cqe| (UserData::decode(cqe.user_data()), cqe.result(), cqe.flags())),
+        );
+    }
+
+    fn handle_completions(&mut self, pending_scrape_valid_until: ValidUntil) -> anyhow::Result<()> {
+        self.collect_completions();
+
+        let now = Instant::now();
+
+        // Taken temporarily to be able to call methods while draining it
+        let mut completions = ::std::mem::take(&mut self.completions);
+
+        for (user_data, result, flags) in completions.drain(..) {
+            match user_data {
+                UserData::Recv { listener } => {
+                    if !more(flags) {
+                        self.listeners[listener].recv_armed = false;
+                    }
+
+                    if result < 0 {
+                        match -result {
+                            // Out of buffers, so kernel stopped receiving.
+                            // Receiving is restarted once buffers have been
+                            // recycled.
+                            ENOBUFS => (),
+                            libc::ECANCELED => (),
+                            libc::EINVAL => {
+                                return Err(anyhow::anyhow!(
+                                    "io_uring: multishot recvmsg failed (requires Linux 6.0 or later)"
+                                ));
+                            }
+                            errno => {
+                                ::log::warn!(
+                                    "recv error: {:#}",
+                                    ::std::io::Error::from_raw_os_error(errno)
+                                );
+                            }
+                        }
+
+                        continue;
+                    }
+
+                    if let Some(bid) = buffer_select(flags) {
+                        self.handle_datagram(
+                            listener,
+                            bid,
+                            result as usize,
+                            pending_scrape_valid_until,
+                            now,
+                        );
+
+                        self.buf_ring.recycle(bid);
+                    }
+                }
+                UserData::Send { slot } => {
+                    let (response, listener_index, canonical_addr) = self.send_slots[slot]
+                        .response
+                        .take()
+                        .expect("send slot without response");
+
+                    if result >= 0 {
+                        update_sent_statistics(
+                            &self.state,
+                            &self.config,
+                            listener_index,
+                            &response,
+                            canonical_addr,
+                            result as usize,
+                        );
+                    } else {
+                        ::log::warn!(
+                            "Sending response to {} failed: {:#}",
+                            canonical_addr.get(),
+                            ::std::io::Error::from_raw_os_error(-result)
+                        );
+                    }
+
+                    self.free_send_slots.push(slot);
+                }
+                UserData::Timeout => {
+                    self.timeout_armed = false;
+                }
+                UserData::Wake => {
+                    self.wake_armed = false;
+                }
+                UserData::Cancel => (),
+            }
+        }
+
+        self.completions = completions;
+
+        self.buf_ring.publish();
+
+        Ok(())
+    }
+
+    fn handle_datagram(
+        &mut self,
+        listener_index: usize,
+        bid: u16,
+        len: usize,
+        pending_scrape_valid_until: ValidUntil,
+        now: Instant,
+    ) {
+        let request_sender = if let Some(request_sender) = self.opt_request_sender.as_mut() {
+            request_sender
+        } else {
+            return;
+        };
+
+        let buffer = &self.buf_ring.buffer(bid)[..len];
+
+        let msg = if let Ok(msg) = RecvMsgOut::parse(buffer, &self.recv_msghdr) {
+            msg
+        } else {
+            ::log::warn!("io_uring: couldn't parse received message");
+
+            return;
+        };
+
+        let name = msg.name_data();
+
+        // Safety: all-zero bytes is a valid value for sockaddr_storage
+        let mut storage: sockaddr_storage = unsafe { zeroed() };
+
+        // Safety: name is no longer than sockaddr_storage, since that is
+        // the length passed in the message header
+        let sock_addr = unsafe {
+            ::std::ptr::copy_nonoverlapping(
+                name.as_ptr(),
+                &mut storage as *mut sockaddr_storage as *mut u8,
+                name.len(),
+            );
+
+            SockAddr::new(storage, name.len() as socklen_t)
+        };
+
+        // Only IPv4 and IPv6 addresses are possible on UDP sockets
+        let src = if let Some(src) = sock_addr.as_socket() {
+            src
+        } else {
+            return;
+        };
+
+        let listener = &mut self.listeners[listener_index];
+
+        self.request_handler.handle_datagram(
+            &self.config,
+            &mut self.pending_scrape_responses,
+            request_sender,
+            &mut self.local_responses,
+            &mut listener.counters,
+            pending_scrape_valid_until,
+            now,
+            ListenerIndex(listener_index),
+            msg.payload_data(),
+            src,
+        );
+
+        listener.received_requests = true;
+    }
+
+    fn send_local_responses(&mut self) -> anyhow::Result<()> {
+        // Responses that don't fit in free slots are sent in a later
+        // iteration
+        let num_to_send = self.local_responses.len().min(self.free_send_slots.len());
+
+        let mut local_responses = ::std::mem::take(&mut self.local_responses);
+
+        for (response, listener_index, addr) in local_responses.drain(..num_to_send) {
+            self.send_response(response, listener_index, addr)?;
+        }
+
+        // Keep at most as many responses waiting as can be sent at a time
+        let max_len = self.send_slots.len();
+
+        if local_responses.len() > max_len {
+            ::log::warn!(
+                "Response send queue full, dropping {} responses",
+                local_responses.len() - max_len
+            );
+
+            local_responses.truncate(max_len);
+        }
+
+        self.local_responses = local_responses;
+
+        Ok(())
+    }
+
+    fn send_connected_response(
+        &mut self,
+        response: ConnectedResponse,
+        listener_index: ListenerIndex,
+        addr: CanonicalSocketAddr,
+    ) -> anyhow::Result<()> {
+        if let Some(response) =
+            finish_connected_response(&mut self.pending_scrape_responses, response)
+        {
+            self.send_response(response, listener_index, addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Submit response for sending. There must be a free send slot.
+    fn send_response(
+        &mut self,
+        response: Response,
+        listener_index: ListenerIndex,
+        canonical_addr: CanonicalSocketAddr,
+    ) -> anyhow::Result<()> {
+        let slot_index = self.free_send_slots.pop().expect("no free send slot");
+        let slot = &mut self.send_slots[slot_index];
+
+        let mut cursor = Cursor::new(&mut slot.buffer[..]);
+
+        if let Err(err) = response.write(&mut cursor) {
+            ::log::error!("Converting response to bytes failed: {:#}", err);
+
+            self.free_send_slots.push(slot_index);
+
+            return Ok(());
+        }
+
+        let bytes_written = cursor.position() as usize;
+
+        slot.addr = SockAddr::from(response_addr(
+            self.listeners[listener_index.0].address,
+            canonical_addr,
+        ));
+
+        slot.iovec.iov_base = slot.buffer.as_mut_ptr() as *mut c_void;
+        slot.iovec.iov_len = bytes_written;
+
+        // Safety: see SendSlot::new
+        slot.msghdr = unsafe { zeroed() };
+        slot.msghdr.msg_name = slot.addr.as_ptr() as *mut c_void;
+        slot.msghdr.msg_namelen = slot.addr.len();
+        slot.msghdr.msg_iov = &mut slot.iovec;
+        slot.msghdr.msg_iovlen = 1;
+
+        slot.response = Some((response, listener_index, canonical_addr));
+
+        let entry = opcode::SendMsg::new(
+            Fixed(listener_index.0 as u32),
+            &slot.msghdr as *const msghdr,
+        )
+        .build()
+        .user_data(UserData::Send { slot: slot_index }.encode());
+
+        self.push_entry(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_data_encode_decode() {
+        for user_data in [
+            UserData::Recv { listener: 0 },
+            UserData::Recv { listener: 3 },
+            UserData::Send { slot: 0 },
+            UserData::Send { slot: 32767 },
+            UserData::Timeout,
+            UserData::Cancel,
+            UserData::Wake,
+        ] {
+            assert_eq!(UserData::decode(user_data.encode()), user_data);
+        }
+    }
+}
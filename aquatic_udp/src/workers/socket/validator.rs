@@ -1,5 +1,5 @@
 use std::net::IpAddr;
-use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use constant_time_eq::constant_time_eq;
@@ -8,91 +8,223 @@ use getrandom::getrandom;
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::ConnectionId;
 
-use crate::config::Config;
+use crate::config::{Config, ConnectionIdConfig};
+
+const MIN_SECRET_LEN: usize = 16;
 
 /// HMAC (BLAKE3) based ConnectionID creator and validator
 ///
 /// Structure of created ConnectionID (bytes making up inner i64):
-/// - &[0..4]: connection expiration time as number of seconds after
-///   ConnectionValidator instance was created, encoded as u32 bytes.
-///   Value fits around 136 years.
+/// - &[0..4]: connection expiration time as UNIX timestamp, encoded as
+///   big-endian u32 bytes. Value fits until year 2106.
 /// - &[4..8]: truncated keyed BLAKE3 hash of above 4 bytes and octets of
 ///   client IP address
 ///
+/// Keys are derived from a secret and the number of key rotation intervals
+/// since the UNIX epoch, so instances using the same secret and settings
+/// create and accept the same connection IDs without communicating.
+/// Connection IDs created with the previous key are accepted too.
+///
 /// The purpose of using ConnectionIDs is to prevent IP spoofing, mainly to
 /// prevent the tracker from being used as an amplification vector for DDoS
 /// attacks. By including 32 bits of BLAKE3 keyed hash output in its contents,
 /// such abuse should be rendered impractical.
 #[derive(Clone)]
 pub struct ConnectionValidator {
-    start_time: Instant,
+    master_key: [u8; 32],
+    key_rotation_interval: u64,
     max_connection_age: u32,
+    /// Key rotation interval that current keys were derived for
+    key_epoch: u64,
     keyed_hasher: blake3::Hasher,
+    previous_keyed_hasher: blake3::Hasher,
 }
 
 impl ConnectionValidator {
     /// Create new instance. Must be created once and cloned if used in several
     /// threads.
     pub fn new(config: &Config) -> anyhow::Result<Self> {
-        let mut key = [0; 32];
+        let secret = read_secret(&config.connection_id)?;
+
+        Self::from_secret(
+            &secret,
+            config.connection_id.key_rotation_interval,
+            config.cleaning.max_connection_age,
+        )
+    }
 
-        getrandom(&mut key)
-            .with_context(|| "Couldn't get random bytes for ConnectionValidator key")?;
+    fn from_secret(
+        secret: &[u8],
+        key_rotation_interval: u64,
+        max_connection_age: u32,
+    ) -> anyhow::Result<Self> {
+        if key_rotation_interval != 0 && key_rotation_interval < u64::from(max_connection_age) {
+            return Err(anyhow::anyhow!(
+                "connection_id.key_rotation_interval must be 0 or at least cleaning.max_connection_age"
+            ));
+        }
 
-        let keyed_hasher = blake3::Hasher::new_keyed(&key);
+        let master_key = blake3::derive_key("aquatic_udp connection id master key", secret);
+
+        let mut validator = Self {
+            master_key,
+            key_rotation_interval,
+            max_connection_age,
+            key_epoch: 0,
+            keyed_hasher: create_keyed_hasher(&master_key, 0),
+            previous_keyed_hasher: create_keyed_hasher(&master_key, 0),
+        };
 
-        Ok(Self {
-            keyed_hasher,
-            start_time: Instant::now(),
-            max_connection_age: config.cleaning.max_connection_age,
-        })
+        validator.update_keys(unix_time());
+
+        Ok(validator)
     }
 
     pub fn create_connection_id(&mut self, source_addr: CanonicalSocketAddr) -> ConnectionId {
+        self.create_connection_id_at(source_addr, unix_time())
+    }
+
+    pub fn connection_id_valid(
+        &mut self,
+        source_addr: CanonicalSocketAddr,
+        connection_id: ConnectionId,
+    ) -> bool {
+        self.connection_id_valid_at(source_addr, connection_id, unix_time())
+    }
+
+    fn create_connection_id_at(
+        &mut self,
+        source_addr: CanonicalSocketAddr,
+        now: u64,
+    ) -> ConnectionId {
+        self.update_keys(now);
+
         let valid_until =
-            (self.start_time.elapsed().as_secs() as u32 + self.max_connection_age).to_ne_bytes();
+            (now + u64::from(self.max_connection_age)).min(u64::from(u32::MAX)) as u32;
+        let valid_until = valid_until.to_be_bytes();
 
-        let hash = self.hash(valid_until, source_addr.get().ip());
+        let hash = hash(&mut self.keyed_hasher, valid_until, source_addr.get().ip());
 
         let mut connection_id_bytes = [0u8; 8];
 
-        (&mut connection_id_bytes[..4]).copy_from_slice(&valid_until);
-        (&mut connection_id_bytes[4..]).copy_from_slice(&hash);
+        connection_id_bytes[..4].copy_from_slice(&valid_until);
+        connection_id_bytes[4..].copy_from_slice(&hash);
 
         ConnectionId(i64::from_ne_bytes(connection_id_bytes))
     }
 
-    pub fn connection_id_valid(
+    fn connection_id_valid_at(
         &mut self,
         source_addr: CanonicalSocketAddr,
         connection_id: ConnectionId,
+        now: u64,
     ) -> bool {
+        self.update_keys(now);
+
         let bytes = connection_id.0.to_ne_bytes();
-        let (valid_until, hash) = bytes.split_at(4);
+        let (valid_until, connection_id_hash) = bytes.split_at(4);
         let valid_until: [u8; 4] = valid_until.try_into().unwrap();
-
-        if !constant_time_eq(hash, &self.hash(valid_until, source_addr.get().ip())) {
+        let ip = source_addr.get().ip();
+
+        // The previous key only needs to be tried for connection IDs created
+        // before the last key rotation
+        let valid_hash = constant_time_eq(
+            connection_id_hash,
+            &hash(&mut self.keyed_hasher, valid_until, ip),
+        ) || constant_time_eq(
+            connection_id_hash,
+            &hash(&mut self.previous_keyed_hasher, valid_until, ip),
+        );
+
+        if !valid_hash {
             return false;
         }
 
-        u32::from_ne_bytes(valid_until) > self.start_time.elapsed().as_secs() as u32
+        u32::from_be_bytes(valid_until) > now as u32
     }
 
-    fn hash(&mut self, valid_until: [u8; 4], ip_addr: IpAddr) -> [u8; 4] {
-        self.keyed_hasher.update(&valid_until);
+    /// Derive new keys if a key rotation interval has passed
+    fn update_keys(&mut self, now: u64) {
+        let key_epoch = now.checked_div(self.key_rotation_interval).unwrap_or(0);
 
-        match ip_addr {
-            IpAddr::V4(ip) => self.keyed_hasher.update(&ip.octets()),
-            IpAddr::V6(ip) => self.keyed_hasher.update(&ip.octets()),
-        };
+        if key_epoch != self.key_epoch {
+            self.keyed_hasher = create_keyed_hasher(&self.master_key, key_epoch);
+            self.previous_keyed_hasher =
+                create_keyed_hasher(&self.master_key, key_epoch.saturating_sub(1));
+            self.key_epoch = key_epoch;
+        }
+    }
+}
+
+fn read_secret(config: &ConnectionIdConfig) -> anyhow::Result<Vec<u8>> {
+    let key_file_set = !config.key_file.as_os_str().is_empty();
+    let secret_set = !config.secret.is_empty();
 
-        let mut hash = [0u8; 4];
+    let secret = match (key_file_set, secret_set) {
+        (true, true) => {
+            return Err(anyhow::anyhow!(
+                "only one of connection_id.key_file and connection_id.secret can be set"
+            ));
+        }
+        (true, false) => {
+            let mut secret = ::std::fs::read(&config.key_file).with_context(|| {
+                format!("read connection ID key file {}", config.key_file.display())
+            })?;
 
-        self.keyed_hasher.finalize_xof().fill(&mut hash);
-        self.keyed_hasher.reset();
+            while matches!(secret.last(), Some(b'\n' | b'\r')) {
+                secret.pop();
+            }
 
-        hash
+            secret
+        }
+        (false, true) => config.secret.as_bytes().to_vec(),
+        (false, false) => {
+            let mut secret = vec![0; 32];
+
+            getrandom(&mut secret)
+                .with_context(|| "Couldn't get random bytes for ConnectionValidator key")?;
+
+            return Ok(secret);
+        }
+    };
+
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(anyhow::anyhow!(
+            "connection ID secret must be at least {} bytes long",
+            MIN_SECRET_LEN
+        ));
     }
+
+    Ok(secret)
+}
+
+fn create_keyed_hasher(master_key: &[u8; 32], key_epoch: u64) -> blake3::Hasher {
+    let key = blake3::keyed_hash(master_key, &key_epoch.to_be_bytes());
+
+    blake3::Hasher::new_keyed(key.as_bytes())
+}
+
+fn hash(keyed_hasher: &mut blake3::Hasher, valid_until: [u8; 4], ip_addr: IpAddr) -> [u8; 4] {
+    keyed_hasher.update(&valid_until);
+
+    match ip_addr {
+        IpAddr::V4(ip) => keyed_hasher.update(&ip.octets()),
+        IpAddr::V6(ip) => keyed_hasher.update(&ip.octets()),
+    };
+
+    let mut hash = [0u8; 4];
+
+    keyed_hasher.finalize_xof().fill(&mut hash);
+    keyed_hasher.reset();
+
+    hash
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
@@ -120,6 +252,7 @@ mod tests {
             let mut config = Config::default();
 
             config.cleaning.max_connection_age = max_connection_age;
+            config.connection_id.key_rotation_interval = 0;
 
             ConnectionValidator::new(&config).unwrap()
         };
@@ -140,4 +273,65 @@ mod tests {
             quickcheck::TestResult::from_bool(original_valid)
         }
     }
+
+    #[test]
+    fn test_connection_validator_shared_secret() {
+        let addr = CanonicalSocketAddr::new(SocketAddr::from(([127, 0, 0, 1], 1)));
+        let secret = b"0123456789abcdef0123456789abcdef";
+
+        let mut a = ConnectionValidator::from_secret(secret, 3600, 120).unwrap();
+        let mut b = ConnectionValidator::from_secret(secret, 3600, 120).unwrap();
+        let mut c =
+            ConnectionValidator::from_secret(b"another secret, 32 bytes long...", 3600, 120)
+                .unwrap();
+
+        let now = 1_000_000;
+        let connection_id = a.create_connection_id_at(addr, now);
+
+        assert!(b.connection_id_valid_at(addr, connection_id, now));
+        assert!(!c.connection_id_valid_at(addr, connection_id, now));
+    }
+
+    #[test]
+    fn test_connection_validator_key_rotation() {
+        let addr = CanonicalSocketAddr::new(SocketAddr::from(([127, 0, 0, 1], 1)));
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let key_rotation_interval = 3600;
+        let max_connection_age = 120;
+
+        let mut validator =
+            ConnectionValidator::from_secret(secret, key_rotation_interval, max_connection_age)
+                .unwrap();
+
+        // Created right before key rotation
+        let created_at = key_rotation_interval * 1000 - 1;
+        let connection_id = validator.create_connection_id_at(addr, created_at);
+
+        // Accepted with previous key after rotation
+        assert!(validator.connection_id_valid_at(addr, connection_id, created_at + 1));
+        assert!(validator.connection_id_valid_at(
+            addr,
+            connection_id,
+            created_at + u64::from(max_connection_age) - 1
+        ));
+        // Expired
+        assert!(!validator.connection_id_valid_at(
+            addr,
+            connection_id,
+            created_at + u64::from(max_connection_age)
+        ));
+    }
+
+    #[test]
+    fn test_connection_validator_invalid_settings() {
+        assert!(ConnectionValidator::from_secret(&[0; 32], 60, 120).is_err());
+        assert!(ConnectionValidator::from_secret(&[0; 32], 0, 120).is_ok());
+
+        let config = ConnectionIdConfig {
+            secret: "too short".into(),
+            ..Default::default()
+        };
+
+        assert!(read_secret(&config).is_err());
+    }
 }
@@ -26,12 +26,15 @@ const STYLESHEET_CONTENTS: &str = concat!(
 #[derive(Clone, Copy, Debug)]
 struct CollectedStatistics {
     requests_per_second: f64,
+    blocked_requests_per_second: f64,
     responses_per_second_connect: f64,
     responses_per_second_announce: f64,
     responses_per_second_scrape: f64,
     responses_per_second_error: f64,
     bytes_received_per_second: f64,
     bytes_sent_per_second: f64,
+    dropped_requests_per_second: f64,
+    dropped_responses_per_second: f64,
     num_torrents: usize,
     num_peers: usize,
 }
@@ -39,6 +42,7 @@ struct CollectedStatistics {
 impl CollectedStatistics {
     fn from_shared(statistics: &Arc<Statistics>, last: &mut Instant) -> Self {
         let requests_received = statistics.requests_received.fetch_and(0, Ordering::Relaxed) as f64;
+        let requests_blocked = statistics.requests_blocked.fetch_and(0, Ordering::Relaxed) as f64;
         let responses_sent_connect = statistics
             .responses_sent_connect
             .fetch_and(0, Ordering::Relaxed) as f64;
@@ -53,6 +57,8 @@ impl CollectedStatistics {
             .fetch_and(0, Ordering::Relaxed) as f64;
         let bytes_received = statistics.bytes_received.fetch_and(0, Ordering::Relaxed) as f64;
         let bytes_sent = statistics.bytes_sent.fetch_and(0, Ordering::Relaxed) as f64;
+        let requests_dropped = statistics.requests_dropped.fetch_and(0, Ordering::Relaxed) as f64;
+        let responses_dropped = statistics.responses_dropped.fetch_and(0, Ordering::Relaxed) as f64;
         let num_torrents = Self::sum_atomic_usizes(&statistics.torrents);
         let num_peers = Self::sum_atomic_usizes(&statistics.peers);
 
@@ -64,12 +70,15 @@ impl CollectedStatistics {
 
         Self {
             requests_per_second: requests_received / elapsed,
+            blocked_requests_per_second: requests_blocked / elapsed,
             responses_per_second_connect: responses_sent_connect / elapsed,
             responses_per_second_announce: responses_sent_announce / elapsed,
             responses_per_second_scrape: responses_sent_scrape / elapsed,
             responses_per_second_error: responses_sent_error / elapsed,
             bytes_received_per_second: bytes_received / elapsed,
             bytes_sent_per_second: bytes_sent / elapsed,
+            dropped_requests_per_second: requests_dropped / elapsed,
+            dropped_responses_per_second: responses_dropped / elapsed,
             num_torrents,
             num_peers,
         }
@@ -93,6 +102,8 @@ impl Into<FormattedStatistics> for CollectedStatistics {
         FormattedStatistics {
             requests_per_second: (self.requests_per_second as usize)
                 .to_formatted_string(&Locale::en),
+            blocked_requests_per_second: (self.blocked_requests_per_second as usize)
+                .to_formatted_string(&Locale::en),
             responses_per_second_total: (responses_per_second_total as usize)
                 .to_formatted_string(&Locale::en),
             responses_per_second_connect: (self.responses_per_second_connect as usize)
@@ -105,6 +116,10 @@ impl Into<FormattedStatistics> for CollectedStatistics {
                 .to_formatted_string(&Locale::en),
             rx_mbits: format!("{:.2}", rx_mbits),
             tx_mbits: format!("{:.2}", tx_mbits),
+            dropped_requests_per_second: (self.dropped_requests_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            dropped_responses_per_second: (self.dropped_responses_per_second as usize)
+                .to_formatted_string(&Locale::en),
             num_torrents: self.num_torrents.to_formatted_string(&Locale::en),
             num_peers: self.num_peers.to_formatted_string(&Locale::en),
         }
@@ -114,6 +129,7 @@ impl Into<FormattedStatistics> for CollectedStatistics {
 #[derive(Clone, Debug, Serialize)]
 struct FormattedStatistics {
     requests_per_second: String,
+    blocked_requests_per_second: String,
     responses_per_second_total: String,
     responses_per_second_connect: String,
     responses_per_second_announce: String,
@@ -121,17 +137,69 @@ struct FormattedStatistics {
     responses_per_second_error: String,
     rx_mbits: String,
     tx_mbits: String,
+    dropped_requests_per_second: String,
+    dropped_responses_per_second: String,
     num_torrents: String,
     num_peers: String,
 }
 
+#[derive(Clone, Debug, Serialize)]
+struct FormattedListenerStatistics {
+    address: String,
+    requests_per_second: String,
+    responses_per_second: String,
+    rx_mbits: String,
+    tx_mbits: String,
+}
+
+impl FormattedListenerStatistics {
+    fn collect(statistics: &[ListenerStatistics], last: &mut Instant) -> Vec<Self> {
+        let now = Instant::now();
+
+        let elapsed = (now - *last).as_secs_f64();
+
+        *last = now;
+
+        statistics
+            .iter()
+            .map(|statistics| {
+                let requests_received =
+                    statistics.requests_received.fetch_and(0, Ordering::Relaxed) as f64;
+                let responses_sent =
+                    statistics.responses_sent.fetch_and(0, Ordering::Relaxed) as f64;
+                let bytes_received =
+                    statistics.bytes_received.fetch_and(0, Ordering::Relaxed) as f64;
+                let bytes_sent = statistics.bytes_sent.fetch_and(0, Ordering::Relaxed) as f64;
+
+                let rx_mbits = bytes_received * 8.0 / 1_000_000.0 / elapsed;
+                let tx_mbits = bytes_sent * 8.0 / 1_000_000.0 / elapsed;
+
+                Self {
+                    address: statistics.address.to_string(),
+                    requests_per_second: ((requests_received / elapsed) as usize)
+                        .to_formatted_string(&Locale::en),
+                    responses_per_second: ((responses_sent / elapsed) as usize)
+                        .to_formatted_string(&Locale::en),
+                    rx_mbits: format!("{:.2}", rx_mbits),
+                    tx_mbits: format!("{:.2}", tx_mbits),
+                }
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct TemplateData {
     stylesheet: String,
     ipv4_active: bool,
     ipv6_active: bool,
+    ip_access_list_active: bool,
     ipv4: FormattedStatistics,
     ipv6: FormattedStatistics,
+    /// Only shown when tracker has several listeners, since numbers are
+    /// otherwise the same as the totals
+    show_listeners: bool,
+    listeners: Vec<FormattedListenerStatistics>,
     last_updated: String,
     peer_update_interval: String,
 }
@@ -153,6 +221,9 @@ pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: St
 
     let mut last_ipv4 = Instant::now();
     let mut last_ipv6 = Instant::now();
+    let mut last_listeners = Instant::now();
+
+    let show_listeners = state.listener_statistics.len() > 1;
 
     loop {
         ::std::thread::sleep(Duration::from_secs(config.statistics.interval));
@@ -161,10 +232,20 @@ pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: St
             CollectedStatistics::from_shared(&state.statistics_ipv4, &mut last_ipv4).into();
         let statistics_ipv6 =
             CollectedStatistics::from_shared(&state.statistics_ipv6, &mut last_ipv6).into();
+        let listener_statistics =
+            FormattedListenerStatistics::collect(&state.listener_statistics, &mut last_listeners);
 
         if config.statistics.print_to_stdout {
             println!("General:");
             println!("  access list entries: {}", state.access_list.load().len());
+            println!(
+                "  IP access list entries: {}",
+                state.ip_access_list.load().len()
+            );
+            println!(
+                "  URL access list entries: {}",
+                state.url_access_list.load().len()
+            );
 
             if config.network.ipv4_active() {
                 println!("IPv4:");
@@ -174,6 +255,13 @@ pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: St
                 println!("IPv6:");
                 print_to_stdout(&config, &statistics_ipv6);
             }
+            if show_listeners {
+                println!("Listeners:");
+
+                for statistics in listener_statistics.iter() {
+                    print_listener_to_stdout(statistics);
+                }
+            }
 
             println!();
         }
@@ -183,8 +271,11 @@ pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: St
                 stylesheet: STYLESHEET_CONTENTS.to_string(),
                 ipv4_active: config.network.ipv4_active(),
                 ipv6_active: config.network.ipv6_active(),
+                ip_access_list_active: config.ip_access_list.mode.is_on(),
                 ipv4: statistics_ipv4,
                 ipv6: statistics_ipv6,
+                show_listeners,
+                listeners: listener_statistics,
                 last_updated: OffsetDateTime::now_utc()
                     .format(&Rfc2822)
                     .unwrap_or("(formatting error)".into()),
@@ -200,6 +291,14 @@ pub fn run_statistics_worker(_sentinel: PanicSentinel, config: Config, state: St
 
 fn print_to_stdout(config: &Config, statistics: &FormattedStatistics) {
     println!("  requests/second: {:>10}", statistics.requests_per_second);
+
+    if config.ip_access_list.mode.is_on() {
+        println!(
+            "  blocked requests/second: {:>10}",
+            statistics.blocked_requests_per_second
+        );
+    }
+
     println!("  responses/second");
     println!(
         "    total:         {:>10}",
@@ -225,6 +324,15 @@ fn print_to_stdout(config: &Config, statistics: &FormattedStatistics) {
         "  bandwidth: {:>7} Mbit/s in, {:7} Mbit/s out",
         statistics.rx_mbits, statistics.tx_mbits,
     );
+    println!("  dropped between workers/second");
+    println!(
+        "    requests:      {:>10}",
+        statistics.dropped_requests_per_second
+    );
+    println!(
+        "    responses:     {:>10}",
+        statistics.dropped_responses_per_second
+    );
     println!("  number of torrents: {}", statistics.num_torrents);
     println!(
         "  number of peers: {} (updated every {} seconds)",
@@ -232,6 +340,22 @@ fn print_to_stdout(config: &Config, statistics: &FormattedStatistics) {
     );
 }
 
+fn print_listener_to_stdout(statistics: &FormattedListenerStatistics) {
+    println!("  {}", statistics.address);
+    println!(
+        "    requests/second:  {:>10}",
+        statistics.requests_per_second
+    );
+    println!(
+        "    responses/second: {:>10}",
+        statistics.responses_per_second
+    );
+    println!(
+        "    bandwidth: {:>7} Mbit/s in, {:7} Mbit/s out",
+        statistics.rx_mbits, statistics.tx_mbits,
+    );
+}
+
 fn save_html_to_file(
     config: &Config,
     tt: &TinyTemplate,
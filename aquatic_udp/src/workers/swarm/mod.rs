@@ -1,19 +1,30 @@
 mod storage;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::time::Instant;
 
-use crossbeam_channel::Receiver;
+use anyhow::Context;
+use crossbeam_channel::{never, select, Receiver, TryRecvError};
 use rand::{rngs::SmallRng, SeedableRng};
 
+use aquatic_common::announce_events::{AnnounceRecord, AnnounceRecordEvent, AnnounceRecordSender};
+use aquatic_common::control::{ControlResponse, SwarmCommand, SwarmCommandRequest};
+use aquatic_common::full_scrape::FullScrapeStatistics;
+use aquatic_common::ip_access_list::IpAccessList;
+use aquatic_common::shared_swarm::{
+    SharedAnnounceEvent, SharedAnnounceRequest, SharedAnnounceResponse, SharedScrapeRequest,
+    SharedScrapeResponse, SharedSwarmRequest,
+};
+use aquatic_common::snapshot::{SnapshotWriter, SwarmSnapshot};
 use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ValidUntil};
 
 use aquatic_udp_protocol::*;
 
 use crate::common::*;
-use crate::config::Config;
+use crate::config::{AnnouncedIpPolicy, Config};
+use crate::SNAPSHOT_KIND;
 
 use storage::{Peer, TorrentMap, TorrentMaps};
 
@@ -21,91 +32,285 @@ pub fn run_swarm_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
-    request_receiver: Receiver<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
-    response_sender: ConnectedResponseSender,
+    mut request_receiver: ConnectedRequestReceiver,
+    mut response_sender: ConnectedResponseSender,
     worker_index: SwarmWorkerIndex,
-) {
-    let mut torrents = TorrentMaps::default();
-    let mut rng = SmallRng::from_entropy();
-
+    snapshot: SwarmSnapshot,
+    // Requests from socket workers of other trackers in the same process
+    opt_shared_request_receiver: Option<Receiver<SharedSwarmRequest>>,
+    command_receiver: Receiver<SwarmCommandRequest>,
+) -> anyhow::Result<()> {
     let timeout = Duration::from_millis(config.request_channel_recv_timeout_ms);
-    let mut peer_valid_until = ValidUntil::new(config.cleaning.max_peer_age);
-
-    let cleaning_interval = Duration::from_secs(config.cleaning.torrent_cleaning_interval);
-    let statistics_update_interval = Duration::from_secs(config.statistics.interval);
 
-    let mut last_cleaning = Instant::now();
-    let mut last_statistics_update = Instant::now();
+    let mut worker = SwarmWorker::new(config, state, worker_index, snapshot);
 
     let mut iter_counter = 0usize;
 
-    loop {
-        if let Ok((sender_index, request, src)) = request_receiver.recv_timeout(timeout) {
-            let response = match (request, src.get().ip()) {
-                (ConnectedRequest::Announce(request), IpAddr::V4(ip)) => {
-                    let response = handle_announce_request(
-                        &config,
-                        &mut rng,
-                        &mut torrents.ipv4,
-                        request,
-                        ip,
-                        peer_valid_until,
-                    );
-
-                    ConnectedResponse::AnnounceIpv4(response)
+    let mut request_notifications = request_receiver.notifications();
+    let mut requests_disconnected = false;
+
+    let mut command_receiver = command_receiver;
+
+    let (mut shared_request_receiver, mut shared_requests_disconnected) =
+        match opt_shared_request_receiver {
+            Some(receiver) => (receiver, false),
+            None => (never(), true),
+        };
+
+    // Exit when all socket workers, including those of other trackers, have
+    // stopped handling requests
+    while !(requests_disconnected && shared_requests_disconnected) {
+        select! {
+            // Requests are received below
+            recv(request_notifications) -> result => {
+                if result.is_err() {
+                    request_notifications = never();
                 }
-                (ConnectedRequest::Announce(request), IpAddr::V6(ip)) => {
-                    let response = handle_announce_request(
-                        &config,
-                        &mut rng,
-                        &mut torrents.ipv6,
-                        request,
-                        ip,
-                        peer_valid_until,
-                    );
-
-                    ConnectedResponse::AnnounceIpv6(response)
+            },
+            recv(shared_request_receiver) -> result => match result {
+                Ok(request) => {
+                    worker.handle_shared_request(request);
                 }
-                (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => {
-                    ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv4, request))
+                Err(_) => {
+                    shared_request_receiver = never();
+                    shared_requests_disconnected = true;
                 }
-                (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => {
-                    ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv6, request))
+            },
+            recv(command_receiver) -> result => match result {
+                Ok(request) => {
+                    worker.handle_command(request);
                 }
-            };
+                // Control server doesn't keep workers running
+                Err(_) => {
+                    command_receiver = never();
+                }
+            },
+            default(timeout) => (),
+        }
 
-            response_sender.try_send_to(sender_index, response, src);
+        loop {
+            match request_receiver.try_recv() {
+                Ok((sender_index, listener_index, request, src)) => {
+                    let response = worker.handle_request(request, src);
+
+                    response_sender.try_send_to(sender_index, listener_index, response, src);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    requests_disconnected = true;
+
+                    break;
+                }
+            }
         }
 
-        // Run periodic tasks
+        response_sender.flush();
+
         if iter_counter % 128 == 0 {
-            let now = Instant::now();
+            worker.run_periodic_tasks();
+        }
 
-            peer_valid_until = ValidUntil::new_with_now(now, config.cleaning.max_peer_age);
+        iter_counter = iter_counter.wrapping_add(1);
+    }
 
-            if now > last_cleaning + cleaning_interval {
-                let (ipv4, ipv6) = torrents.clean_and_get_num_peers(&config, &state.access_list);
+    worker.write_final_snapshot()
+}
 
-                if config.statistics.active() {
-                    state.statistics_ipv4.peers[worker_index.0].store(ipv4, Ordering::Release);
-                    state.statistics_ipv6.peers[worker_index.0].store(ipv6, Ordering::Release);
-                }
+/// Torrent state of one swarm worker, along with what is needed to handle
+/// requests and periodic tasks for it
+///
+/// Owned by a swarm worker thread, or by a socket worker in shard-per-core
+/// mode.
+pub struct SwarmWorker {
+    config: Config,
+    state: State,
+    worker_index: SwarmWorkerIndex,
+    torrents: TorrentMaps,
+    rng: SmallRng,
+    peer_valid_until: ValidUntil,
+    last_cleaning: Instant,
+    last_statistics_update: Instant,
+    last_snapshot: Instant,
+    snapshot_writer: SnapshotWriter,
+}
+
+impl SwarmWorker {
+    pub fn new(
+        config: Config,
+        state: State,
+        worker_index: SwarmWorkerIndex,
+        snapshot: SwarmSnapshot,
+    ) -> Self {
+        let mut torrents = TorrentMaps::default();
+
+        torrents.restore_from_snapshot(snapshot);
+
+        Self {
+            peer_valid_until: ValidUntil::new(config.cleaning.max_peer_age),
+            snapshot_writer: SnapshotWriter::new(
+                config.snapshot.clone(),
+                SNAPSHOT_KIND,
+                worker_index.0,
+            ),
+            config,
+            state,
+            worker_index,
+            torrents,
+            rng: SmallRng::from_entropy(),
+            last_cleaning: Instant::now(),
+            last_statistics_update: Instant::now(),
+            last_snapshot: Instant::now(),
+        }
+    }
+
+    pub fn handle_request(
+        &mut self,
+        request: ConnectedRequest,
+        src: CanonicalSocketAddr,
+    ) -> ConnectedResponse {
+        handle_request(
+            &self.config,
+            &self.state,
+            &mut self.rng,
+            &mut self.torrents,
+            request,
+            src,
+            self.peer_valid_until,
+        )
+    }
+
+    pub fn handle_shared_request(&mut self, request: SharedSwarmRequest) {
+        handle_shared_request(
+            self.state.announce_records.as_ref(),
+            &mut self.rng,
+            &mut self.torrents,
+            request,
+            self.peer_valid_until,
+        );
+    }
+
+    pub fn handle_command(&mut self, request: SwarmCommandRequest) {
+        let response = handle_swarm_command(&mut self.torrents, request.command);
+
+        // Control server might have stopped waiting
+        let _ = request.response_sender.send(response);
+    }
+
+    /// Update peer ValidUntil and run cleaning, statistics updates and
+    /// snapshotting when due
+    pub fn run_periodic_tasks(&mut self) {
+        let config = &self.config;
+        let state = &self.state;
+        let worker_index = self.worker_index;
+        let torrents = &mut self.torrents;
+
+        let now = Instant::now();
 
-                last_cleaning = now;
+        self.peer_valid_until = ValidUntil::new_with_now(now, config.cleaning.max_peer_age);
+
+        if now > self.last_cleaning + Duration::from_secs(config.cleaning.torrent_cleaning_interval)
+        {
+            let (ipv4, ipv6) = torrents.clean_and_get_num_peers(config, &state.access_list);
+
+            if config.statistics.active() {
+                state.statistics_ipv4.peers[worker_index.0].store(ipv4, Ordering::Release);
+                state.statistics_ipv6.peers[worker_index.0].store(ipv6, Ordering::Release);
             }
-            if config.statistics.active()
-                && now > last_statistics_update + statistics_update_interval
-            {
-                state.statistics_ipv4.torrents[worker_index.0]
-                    .store(torrents.ipv4.num_torrents(), Ordering::Release);
-                state.statistics_ipv6.torrents[worker_index.0]
-                    .store(torrents.ipv6.num_torrents(), Ordering::Release);
-
-                last_statistics_update = now;
+            if config.metrics.active {
+                let metrics = &state.metrics;
+
+                metrics.ipv4.peers[worker_index.0].set(ipv4 as i64);
+                metrics.ipv6.peers[worker_index.0].set(ipv6 as i64);
+                metrics.ipv4.torrents[worker_index.0].set(torrents.ipv4.num_torrents() as i64);
+                metrics.ipv6.torrents[worker_index.0].set(torrents.ipv6.num_torrents() as i64);
             }
+
+            self.last_cleaning = now;
+        }
+        if config.statistics.active()
+            && now > self.last_statistics_update + Duration::from_secs(config.statistics.interval)
+        {
+            state.statistics_ipv4.torrents[worker_index.0]
+                .store(torrents.ipv4.num_torrents(), Ordering::Release);
+            state.statistics_ipv6.torrents[worker_index.0]
+                .store(torrents.ipv6.num_torrents(), Ordering::Release);
+
+            self.last_statistics_update = now;
         }
+        if config.snapshot.active
+            && now > self.last_snapshot + Duration::from_secs(config.snapshot.interval)
+        {
+            self.snapshot_writer
+                .write_in_background(|| torrents.to_snapshot());
 
-        iter_counter = iter_counter.wrapping_add(1);
+            self.last_snapshot = now;
+        }
+    }
+
+    pub fn write_final_snapshot(&mut self) -> anyhow::Result<()> {
+        if self.config.snapshot.active {
+            self.snapshot_writer
+                .write_final(self.torrents.to_snapshot())
+                .with_context(|| "write swarm snapshot on shutdown")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_request(
+    config: &Config,
+    state: &State,
+    rng: &mut SmallRng,
+    torrents: &mut TorrentMaps,
+    request: ConnectedRequest,
+    src: CanonicalSocketAddr,
+    peer_valid_until: ValidUntil,
+) -> ConnectedResponse {
+    if let (Some(announce_records), ConnectedRequest::Announce(request)) =
+        (state.announce_records.as_ref(), &request)
+    {
+        announce_records.send(|| create_announce_record(request, src.is_ipv4()));
+    }
+
+    match (request, src.get().ip()) {
+        (ConnectedRequest::Announce(request), IpAddr::V4(ip)) => {
+            let peer_ip = get_peer_ipv4(
+                config,
+                &state.announced_ip_trusted_networks,
+                ip,
+                request.ip_address,
+            );
+
+            let response = handle_announce_request(
+                config,
+                rng,
+                &mut torrents.ipv4,
+                request,
+                peer_ip,
+                peer_valid_until,
+            );
+
+            ConnectedResponse::AnnounceIpv4(response)
+        }
+        (ConnectedRequest::Announce(request), IpAddr::V6(ip)) => {
+            let response = handle_announce_request(
+                config,
+                rng,
+                &mut torrents.ipv6,
+                request,
+                ip,
+                peer_valid_until,
+            );
+
+            ConnectedResponse::AnnounceIpv6(response)
+        }
+        (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => {
+            ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv4, request))
+        }
+        (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => {
+            ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv6, request))
+        }
     }
 }
 
@@ -135,7 +340,7 @@ fn handle_announce_request<I: Ip>(
 
     let torrent_data = torrents.0.entry(request.info_hash).or_default();
 
-    torrent_data.update_peer(request.peer_id, peer);
+    torrent_data.update_peer(request.peer_id, peer, request.event);
 
     let response_peers =
         torrent_data.extract_response_peers(rng, request.peer_id, max_num_peers_to_take);
@@ -149,11 +354,30 @@ fn handle_announce_request<I: Ip>(
     }
 }
 
+/// Get IPv4 address to register peer with, taking announced ip address
+/// policy into account
+fn get_peer_ipv4(
+    config: &Config,
+    trusted_networks: &IpAccessList,
+    src_ip: Ipv4Addr,
+    opt_announced_ip: Option<Ipv4Addr>,
+) -> Ipv4Addr {
+    match (config.protocol.announced_ip_policy, opt_announced_ip) {
+        (AnnouncedIpPolicy::Always, Some(announced_ip)) => announced_ip,
+        (AnnouncedIpPolicy::Trusted, Some(announced_ip))
+            if trusted_networks.contains(IpAddr::V4(src_ip)) =>
+        {
+            announced_ip
+        }
+        _ => src_ip,
+    }
+}
+
 fn handle_scrape_request<I: Ip>(
     torrents: &mut TorrentMap<I>,
     request: PendingScrapeRequest,
 ) -> PendingScrapeResponse {
-    const EMPTY_STATS: TorrentScrapeStatistics = create_torrent_scrape_statistics(0, 0);
+    const EMPTY_STATS: TorrentScrapeStatistics = create_torrent_scrape_statistics(0, 0, 0);
 
     let torrent_stats = request
         .info_hashes
@@ -175,11 +399,299 @@ fn handle_scrape_request<I: Ip>(
     }
 }
 
+/// Handle request from socket worker of another tracker in the same process
+fn handle_shared_request(
+    opt_announce_records: Option<&AnnounceRecordSender>,
+    rng: &mut SmallRng,
+    torrents: &mut TorrentMaps,
+    request: SharedSwarmRequest,
+    peer_valid_until: ValidUntil,
+) {
+    // Sending responses only fails if socket worker stopped waiting for them
+    match request {
+        SharedSwarmRequest::Announce(request, response_sender) => {
+            if let Some(announce_records) = opt_announce_records {
+                announce_records.send(|| create_shared_announce_record(&request));
+            }
+
+            let response = handle_shared_announce_request(rng, torrents, request, peer_valid_until);
+
+            let _ = response_sender.send(response);
+        }
+        SharedSwarmRequest::Scrape(request, response_sender) => {
+            let response = handle_shared_scrape_request(torrents, request);
+
+            let _ = response_sender.send(response);
+        }
+    }
+}
+
+fn handle_shared_announce_request(
+    rng: &mut SmallRng,
+    torrents: &mut TorrentMaps,
+    request: SharedAnnounceRequest,
+    peer_valid_until: ValidUntil,
+) -> SharedAnnounceResponse {
+    let mut response = SharedAnnounceResponse::default();
+
+    if let Some(peer_ip) = request.opt_ipv4 {
+        let (statistics, peers) =
+            upsert_shared_peer(rng, &mut torrents.ipv4, &request, peer_ip, peer_valid_until);
+
+        if request.statistics_ipv4 {
+            response.statistics = statistics;
+        }
+
+        response.peers_ipv4 = peers;
+    }
+    if let Some(peer_ip) = request.opt_ipv6 {
+        let (statistics, peers) =
+            upsert_shared_peer(rng, &mut torrents.ipv6, &request, peer_ip, peer_valid_until);
+
+        if !request.statistics_ipv4 {
+            response.statistics = statistics;
+        }
+
+        response.peers_ipv6 = peers;
+    }
+
+    response
+}
+
+/// Insert/update/remove peer, return torrent statistics and response peers
+fn upsert_shared_peer<I: Ip>(
+    rng: &mut SmallRng,
+    torrents: &mut TorrentMap<I>,
+    request: &SharedAnnounceRequest,
+    peer_ip: I,
+    peer_valid_until: ValidUntil,
+) -> (FullScrapeStatistics, Vec<(I, u16)>) {
+    let event = match request.event {
+        SharedAnnounceEvent::Started => AnnounceEvent::Started,
+        SharedAnnounceEvent::Stopped => AnnounceEvent::Stopped,
+        SharedAnnounceEvent::Completed => AnnounceEvent::Completed,
+        SharedAnnounceEvent::None => AnnounceEvent::None,
+    };
+    let bytes_left = NumberOfBytes(request.bytes_left.try_into().unwrap_or(i64::MAX));
+    let peer_id = PeerId(request.peer_id);
+
+    let peer = Peer {
+        ip_address: peer_ip,
+        port: Port(request.port),
+        status: PeerStatus::from_event_and_bytes_left(event, bytes_left),
+        valid_until: peer_valid_until,
+    };
+
+    let torrent_data = torrents.0.entry(InfoHash(request.info_hash)).or_default();
+
+    torrent_data.update_peer(peer_id, peer, event);
+
+    let statistics = FullScrapeStatistics {
+        seeders: torrent_data.num_seeders(),
+        leechers: torrent_data.num_leechers(),
+        completed: torrent_data.num_completed(),
+    };
+
+    let peers = torrent_data
+        .extract_response_peers(rng, peer_id, request.max_peers)
+        .into_iter()
+        .map(|peer| (peer.ip_address, peer.port.0))
+        .collect();
+
+    (statistics, peers)
+}
+
+fn handle_shared_scrape_request(
+    torrents: &TorrentMaps,
+    request: SharedScrapeRequest,
+) -> SharedScrapeResponse {
+    fn get_statistics<I: Ip>(
+        torrents: &TorrentMap<I>,
+        info_hashes: Vec<[u8; 20]>,
+    ) -> SharedScrapeResponse {
+        info_hashes
+            .into_iter()
+            .filter_map(|info_hash| {
+                torrents.0.get(&InfoHash(info_hash)).map(|torrent_data| {
+                    let statistics = FullScrapeStatistics {
+                        seeders: torrent_data.num_seeders(),
+                        leechers: torrent_data.num_leechers(),
+                        completed: torrent_data.num_completed(),
+                    };
+
+                    (info_hash, statistics)
+                })
+            })
+            .collect()
+    }
+
+    if request.ipv4 {
+        get_statistics(&torrents.ipv4, request.info_hashes)
+    } else {
+        get_statistics(&torrents.ipv6, request.info_hashes)
+    }
+}
+
+fn handle_swarm_command(torrents: &mut TorrentMaps, command: SwarmCommand) -> ControlResponse {
+    match command {
+        SwarmCommand::Torrent { info_hash } => ControlResponse::Torrent {
+            torrent: torrents.torrent_info(InfoHash(info_hash)),
+        },
+        SwarmCommand::RemoveTorrent { info_hash } => ControlResponse::Removed {
+            removed: torrents.remove_torrent(InfoHash(info_hash)),
+        },
+        SwarmCommand::RemovePeer { info_hash, peer_id } => ControlResponse::Removed {
+            removed: torrents.remove_peer(InfoHash(info_hash), PeerId(peer_id)),
+        },
+    }
+}
+
+fn create_announce_record(request: &AnnounceRequest, ipv4: bool) -> AnnounceRecord {
+    let event = match request.event {
+        AnnounceEvent::Started => AnnounceRecordEvent::Started,
+        AnnounceEvent::Stopped => AnnounceRecordEvent::Stopped,
+        AnnounceEvent::Completed => AnnounceRecordEvent::Completed,
+        AnnounceEvent::None => AnnounceRecordEvent::None,
+    };
+
+    AnnounceRecord {
+        info_hash: request.info_hash.0,
+        peer_id: request.peer_id.0,
+        event,
+        bytes_left: request.bytes_left.0.try_into().ok(),
+        bytes_uploaded: request.bytes_uploaded.0.try_into().ok(),
+        bytes_downloaded: request.bytes_downloaded.0.try_into().ok(),
+        ipv4,
+    }
+}
+
+/// Create record for announce request from another tracker. Protocols other
+/// than UDP don't necessarily provide uploaded and downloaded byte counts,
+/// so they are not passed on.
+fn create_shared_announce_record(request: &SharedAnnounceRequest) -> AnnounceRecord {
+    let event = match request.event {
+        SharedAnnounceEvent::Started => AnnounceRecordEvent::Started,
+        SharedAnnounceEvent::Stopped => AnnounceRecordEvent::Stopped,
+        SharedAnnounceEvent::Completed => AnnounceRecordEvent::Completed,
+        SharedAnnounceEvent::None => AnnounceRecordEvent::None,
+    };
+
+    AnnounceRecord {
+        info_hash: request.info_hash,
+        peer_id: request.peer_id,
+        event,
+        bytes_left: Some(request.bytes_left as u64),
+        bytes_uploaded: None,
+        bytes_downloaded: None,
+        ipv4: request.statistics_ipv4,
+    }
+}
+
 #[inline(always)]
-const fn create_torrent_scrape_statistics(seeders: i32, leechers: i32) -> TorrentScrapeStatistics {
+const fn create_torrent_scrape_statistics(
+    seeders: i32,
+    leechers: i32,
+    completed: i32,
+) -> TorrentScrapeStatistics {
     TorrentScrapeStatistics {
         seeders: NumberOfPeers(seeders),
-        completed: NumberOfDownloads(0), // No implementation planned
+        completed: NumberOfDownloads(completed),
         leechers: NumberOfPeers(leechers),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_peer_ipv4() {
+        let trusted_networks = IpAccessList::create_from_entries(["10.0.0.0/8"]).unwrap();
+
+        let trusted_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let untrusted_ip = Ipv4Addr::new(192, 0, 2, 1);
+        let announced_ip = Ipv4Addr::new(198, 51, 100, 1);
+
+        let f = |policy, src_ip, opt_announced_ip| {
+            let mut config = Config::default();
+
+            config.protocol.announced_ip_policy = policy;
+
+            get_peer_ipv4(&config, &trusted_networks, src_ip, opt_announced_ip)
+        };
+
+        assert_eq!(
+            f(AnnouncedIpPolicy::Ignore, trusted_ip, Some(announced_ip)),
+            trusted_ip
+        );
+        assert_eq!(
+            f(AnnouncedIpPolicy::Trusted, trusted_ip, Some(announced_ip)),
+            announced_ip
+        );
+        assert_eq!(
+            f(AnnouncedIpPolicy::Trusted, untrusted_ip, Some(announced_ip)),
+            untrusted_ip
+        );
+        assert_eq!(
+            f(AnnouncedIpPolicy::Always, untrusted_ip, Some(announced_ip)),
+            announced_ip
+        );
+        assert_eq!(
+            f(AnnouncedIpPolicy::Always, untrusted_ip, None),
+            untrusted_ip
+        );
+    }
+
+    #[test]
+    fn test_handle_shared_announce_request() {
+        let mut rng = SmallRng::from_entropy();
+        let mut torrents = TorrentMaps::default();
+        let valid_until = ValidUntil::new(60);
+
+        let request = |peer_id: u8, bytes_left, opt_ipv6| SharedAnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [peer_id; 20],
+            port: 1000 + peer_id as u16,
+            bytes_left,
+            event: SharedAnnounceEvent::Started,
+            max_peers: 10,
+            opt_ipv4: Some(Ipv4Addr::new(10, 0, 0, peer_id)),
+            opt_ipv6,
+            statistics_ipv4: true,
+        };
+
+        handle_shared_announce_request(&mut rng, &mut torrents, request(1, 0, None), valid_until);
+
+        let response = handle_shared_announce_request(
+            &mut rng,
+            &mut torrents,
+            request(2, 100, Some(::std::net::Ipv6Addr::LOCALHOST)),
+            valid_until,
+        );
+
+        assert_eq!(
+            response.statistics,
+            FullScrapeStatistics {
+                seeders: 1,
+                leechers: 1,
+                completed: 0,
+            }
+        );
+        assert_eq!(
+            response.peers_ipv4,
+            vec![(Ipv4Addr::new(10, 0, 0, 1), 1001)]
+        );
+        assert!(response.peers_ipv6.is_empty());
+
+        let scrape_response = handle_shared_scrape_request(
+            &torrents,
+            SharedScrapeRequest {
+                info_hashes: vec![[1; 20], [2; 20]],
+                ipv4: false,
+            },
+        );
+
+        assert_eq!(scrape_response.len(), 1);
+        assert_eq!(scrape_response[0].1.leechers, 1);
+    }
+}
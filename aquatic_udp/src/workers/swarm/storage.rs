@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::sync::Arc;
@@ -5,7 +6,10 @@ use std::time::Instant;
 
 use aquatic_common::{
     access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache, AccessListMode},
-    extract_response_peers, AmortizedIndexMap, ValidUntil,
+    control::{PeerInfo, TorrentInfo},
+    extract_response_peers,
+    snapshot::{PeerSnapshot, SwarmSnapshot, TorrentSnapshot},
+    AmortizedIndexMap, ValidUntil,
 };
 
 use aquatic_udp_protocol::*;
@@ -39,10 +43,11 @@ pub struct TorrentData<I: Ip> {
     peers: PeerMap<I>,
     num_seeders: usize,
     num_leechers: usize,
+    num_completed: usize,
 }
 
 impl<I: Ip> TorrentData<I> {
-    pub fn update_peer(&mut self, peer_id: PeerId, peer: Peer<I>) {
+    pub fn update_peer(&mut self, peer_id: PeerId, peer: Peer<I>, event: AnnounceEvent) {
         let opt_removed_peer = match peer.status {
             PeerStatus::Leeching => {
                 self.num_leechers += 1;
@@ -57,7 +62,15 @@ impl<I: Ip> TorrentData<I> {
             PeerStatus::Stopped => self.peers.remove(&peer_id),
         };
 
-        match opt_removed_peer.map(|peer| peer.status) {
+        let opt_removed_status = opt_removed_peer.map(|peer| peer.status);
+
+        // Don't count repeated completed events from a peer that is still
+        // registered as seeding
+        if event == AnnounceEvent::Completed && opt_removed_status != Some(PeerStatus::Seeding) {
+            self.num_completed += 1;
+        }
+
+        match opt_removed_status {
             Some(PeerStatus::Leeching) => {
                 self.num_leechers -= 1;
             }
@@ -83,6 +96,24 @@ impl<I: Ip> TorrentData<I> {
         )
     }
 
+    /// Remove peer, returning whether it was registered
+    pub fn remove_peer(&mut self, peer_id: PeerId) -> bool {
+        match self.peers.remove(&peer_id).map(|peer| peer.status) {
+            Some(PeerStatus::Leeching) => {
+                self.num_leechers -= 1;
+
+                true
+            }
+            Some(PeerStatus::Seeding) => {
+                self.num_seeders -= 1;
+
+                true
+            }
+            Some(PeerStatus::Stopped) => true,
+            None => false,
+        }
+    }
+
     pub fn num_leechers(&self) -> usize {
         self.num_leechers
     }
@@ -91,10 +122,15 @@ impl<I: Ip> TorrentData<I> {
         self.num_seeders
     }
 
+    pub fn num_completed(&self) -> usize {
+        self.num_completed
+    }
+
     pub fn scrape_statistics(&self) -> TorrentScrapeStatistics {
         create_torrent_scrape_statistics(
             self.num_seeders.try_into().unwrap_or(i32::MAX),
             self.num_leechers.try_into().unwrap_or(i32::MAX),
+            self.num_completed.try_into().unwrap_or(i32::MAX),
         )
     }
 
@@ -130,6 +166,7 @@ impl<I: Ip> Default for TorrentData<I> {
             peers: Default::default(),
             num_seeders: 0,
             num_leechers: 0,
+            num_completed: 0,
         }
     }
 }
@@ -170,6 +207,84 @@ impl<I: Ip> TorrentMap<I> {
     pub fn num_torrents(&self) -> usize {
         self.0.len()
     }
+
+    fn add_to_torrent_info(
+        &self,
+        info_hash: InfoHash,
+        opt_torrent_info: &mut Option<TorrentInfo>,
+        now: Instant,
+    ) where
+        I: Into<IpAddr>,
+    {
+        if let Some(torrent) = self.0.get(&info_hash) {
+            let torrent_info = opt_torrent_info.get_or_insert_with(Default::default);
+
+            torrent_info.seeders += torrent.num_seeders;
+            torrent_info.leechers += torrent.num_leechers;
+            torrent_info.completed += torrent.num_completed;
+            torrent_info
+                .peers
+                .extend(torrent.peers.iter().map(|(peer_id, peer)| PeerInfo {
+                    peer_id: peer_id.0,
+                    ip_address: peer.ip_address.into(),
+                    port: peer.port.0,
+                    seeding: peer.status == PeerStatus::Seeding,
+                    valid_for: peer.valid_until.seconds_left(now),
+                }));
+        }
+    }
+
+    fn to_snapshot(&self, now: Instant) -> Vec<TorrentSnapshot<I>> {
+        self.0
+            .iter()
+            .map(|(info_hash, torrent)| TorrentSnapshot {
+                info_hash: info_hash.0,
+                num_completed: torrent.num_completed,
+                peers: torrent
+                    .peers
+                    .iter()
+                    .map(|(peer_id, peer)| PeerSnapshot {
+                        peer_id: peer_id.0,
+                        key: None,
+                        ip_address: peer.ip_address,
+                        port: peer.port.0,
+                        seeding: peer.status == PeerStatus::Seeding,
+                        valid_for: peer.valid_until.seconds_left(now),
+                        counted: true,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn restore_from_snapshot(&mut self, torrents: Vec<TorrentSnapshot<I>>, now: Instant) {
+        for torrent_snapshot in torrents {
+            let torrent = self
+                .0
+                .entry(InfoHash(torrent_snapshot.info_hash))
+                .or_default();
+
+            for peer in torrent_snapshot.peers {
+                let status = if peer.seeding {
+                    PeerStatus::Seeding
+                } else {
+                    PeerStatus::Leeching
+                };
+
+                let peer_id = PeerId(peer.peer_id);
+                let peer = Peer {
+                    ip_address: peer.ip_address,
+                    port: Port(peer.port),
+                    status,
+                    valid_until: ValidUntil::new_with_now(now, peer.valid_for),
+                };
+
+                torrent.update_peer(peer_id, peer, AnnounceEvent::None);
+            }
+
+            torrent.num_completed = torrent_snapshot.num_completed;
+        }
+    }
 }
 
 pub struct TorrentMaps {
@@ -202,6 +317,59 @@ impl TorrentMaps {
 
         (ipv4, ipv6)
     }
+
+    /// Statistics and peers for torrent, with IPv4 and IPv6 swarms combined
+    pub fn torrent_info(&self, info_hash: InfoHash) -> Option<TorrentInfo> {
+        let now = Instant::now();
+        let mut opt_torrent_info = None;
+
+        self.ipv4
+            .add_to_torrent_info(info_hash, &mut opt_torrent_info, now);
+        self.ipv6
+            .add_to_torrent_info(info_hash, &mut opt_torrent_info, now);
+
+        opt_torrent_info
+    }
+
+    /// Remove torrent, returning whether it was present
+    pub fn remove_torrent(&mut self, info_hash: InfoHash) -> bool {
+        let removed_ipv4 = self.ipv4.0.remove(&info_hash).is_some();
+        let removed_ipv6 = self.ipv6.0.remove(&info_hash).is_some();
+
+        removed_ipv4 || removed_ipv6
+    }
+
+    /// Remove peer from torrent, returning whether it was present
+    pub fn remove_peer(&mut self, info_hash: InfoHash, peer_id: PeerId) -> bool {
+        let removed_ipv4 = self
+            .ipv4
+            .0
+            .get_mut(&info_hash)
+            .map_or(false, |torrent| torrent.remove_peer(peer_id));
+        let removed_ipv6 = self
+            .ipv6
+            .0
+            .get_mut(&info_hash)
+            .map_or(false, |torrent| torrent.remove_peer(peer_id));
+
+        removed_ipv4 || removed_ipv6
+    }
+
+    pub fn to_snapshot(&self) -> SwarmSnapshot {
+        let now = Instant::now();
+
+        SwarmSnapshot {
+            ipv4: self.ipv4.to_snapshot(now),
+            ipv6: self.ipv6.to_snapshot(now),
+        }
+    }
+
+    pub fn restore_from_snapshot(&mut self, snapshot: SwarmSnapshot) {
+        let now = Instant::now();
+
+        self.ipv4.restore_from_snapshot(snapshot.ipv4, now);
+        self.ipv6.restore_from_snapshot(snapshot.ipv6, now);
+    }
 }
 
 #[cfg(test)]
@@ -294,4 +462,107 @@ mod tests {
 
         quickcheck(prop as fn((u16, u16)) -> TestResult);
     }
+
+    #[test]
+    fn test_num_completed() {
+        let mut torrent_data = TorrentData::<Ipv4Addr>::default();
+
+        let mut seeding_peer = gen_peer(1);
+
+        seeding_peer.status = PeerStatus::Seeding;
+
+        torrent_data.update_peer(gen_peer_id(1), gen_peer(1), AnnounceEvent::Started);
+        torrent_data.update_peer(
+            gen_peer_id(1),
+            seeding_peer.clone(),
+            AnnounceEvent::Completed,
+        );
+
+        assert_eq!(torrent_data.num_completed, 1);
+
+        // Repeated completion from peer that is still seeding is not counted
+        torrent_data.update_peer(
+            gen_peer_id(1),
+            seeding_peer.clone(),
+            AnnounceEvent::Completed,
+        );
+        torrent_data.update_peer(gen_peer_id(1), seeding_peer.clone(), AnnounceEvent::None);
+
+        assert_eq!(torrent_data.num_completed, 1);
+
+        torrent_data.update_peer(
+            gen_peer_id(2),
+            seeding_peer.clone(),
+            AnnounceEvent::Completed,
+        );
+
+        assert_eq!(torrent_data.num_completed, 2);
+
+        // Removed peers are not remembered, so completion is counted again
+        torrent_data.remove_peer(gen_peer_id(1));
+        torrent_data.update_peer(gen_peer_id(1), seeding_peer, AnnounceEvent::Completed);
+
+        assert_eq!(torrent_data.num_completed, 3);
+        assert_eq!(
+            torrent_data.scrape_statistics().completed,
+            NumberOfDownloads(3)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut torrent_maps = TorrentMaps::default();
+
+        let mut seeding_peer = gen_peer(2);
+
+        seeding_peer.status = PeerStatus::Seeding;
+        seeding_peer.valid_until = ValidUntil::new(100);
+
+        let torrent_data = torrent_maps.ipv4.0.entry(InfoHash([1; 20])).or_default();
+
+        torrent_data.update_peer(gen_peer_id(1), gen_peer(1), AnnounceEvent::Started);
+        torrent_data.update_peer(gen_peer_id(2), seeding_peer, AnnounceEvent::Completed);
+
+        let snapshot = torrent_maps.to_snapshot();
+
+        let mut restored = TorrentMaps::default();
+
+        restored.restore_from_snapshot(snapshot);
+
+        let torrent_data = restored.ipv4.0.get(&InfoHash([1; 20])).unwrap();
+
+        assert_eq!(torrent_data.num_leechers(), 1);
+        assert_eq!(torrent_data.num_seeders(), 1);
+        assert_eq!(torrent_data.num_completed, 1);
+    }
+
+    #[test]
+    fn test_torrent_info_and_removal() {
+        let mut torrent_maps = TorrentMaps::default();
+        let info_hash = InfoHash([1; 20]);
+
+        let mut seeding_peer = gen_peer(2);
+
+        seeding_peer.status = PeerStatus::Seeding;
+
+        let torrent_data = torrent_maps.ipv4.0.entry(info_hash).or_default();
+
+        torrent_data.update_peer(gen_peer_id(1), gen_peer(1), AnnounceEvent::Started);
+        torrent_data.update_peer(gen_peer_id(2), seeding_peer, AnnounceEvent::Completed);
+
+        let torrent_info = torrent_maps.torrent_info(info_hash).unwrap();
+
+        assert_eq!(torrent_info.seeders, 1);
+        assert_eq!(torrent_info.leechers, 1);
+        assert_eq!(torrent_info.completed, 1);
+        assert_eq!(torrent_info.peers.len(), 2);
+        assert!(torrent_maps.torrent_info(InfoHash([2; 20])).is_none());
+
+        assert!(torrent_maps.remove_peer(info_hash, gen_peer_id(2)));
+        assert!(!torrent_maps.remove_peer(info_hash, gen_peer_id(2)));
+        assert_eq!(torrent_maps.torrent_info(info_hash).unwrap().seeders, 0);
+
+        assert!(torrent_maps.remove_torrent(info_hash));
+        assert!(torrent_maps.torrent_info(info_hash).is_none());
+    }
 }
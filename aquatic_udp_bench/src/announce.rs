@@ -15,8 +15,8 @@ use crate::config::BenchConfig;
 
 pub fn bench_announce_handler(
     bench_config: &BenchConfig,
-    request_sender: &Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
-    response_receiver: &Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    request_sender: &Sender<ConnectedRequestItem>,
+    response_receiver: &Receiver<ConnectedResponseItem>,
     rng: &mut impl Rng,
     info_hashes: &[InfoHash],
 ) -> (usize, Duration) {
@@ -39,13 +39,15 @@ pub fn bench_announce_handler(
                 request_sender
                     .send((
                         SocketWorkerIndex(0),
+                        ListenerIndex(0),
                         ConnectedRequest::Announce(request.clone()),
                         *src,
                     ))
                     .unwrap();
             }
 
-            while let Ok((ConnectedResponse::AnnounceIpv4(r), _)) = response_receiver.try_recv() {
+            while let Ok((ConnectedResponse::AnnounceIpv4(r), _, _)) = response_receiver.try_recv()
+            {
                 num_responses += 1;
 
                 if let Some(last_peer) = r.peers.last() {
@@ -57,7 +59,7 @@ pub fn bench_announce_handler(
         let total = bench_config.num_announce_requests * (round + 1);
 
         while num_responses < total {
-            if let Ok((ConnectedResponse::AnnounceIpv4(r), _)) = response_receiver.recv() {
+            if let Ok((ConnectedResponse::AnnounceIpv4(r), _, _)) = response_receiver.recv() {
                 num_responses += 1;
 
                 if let Some(last_peer) = r.peers.last() {
@@ -103,6 +105,8 @@ pub fn create_requests(
             key: PeerKey(rng.gen()),
             peers_wanted: NumberOfPeers(rng.gen()),
             port: Port(rng.gen()),
+            options: Vec::new(),
+            options_truncated: false,
         };
 
         requests.push((
@@ -7,9 +7,10 @@
 //! Scrape:    1 873 545 requests/second,   533.75 ns/request
 //! ```
 
+use aquatic_common::snapshot::SwarmSnapshot;
 use aquatic_common::PanicSentinelWatcher;
 use aquatic_udp::workers::swarm::run_swarm_worker;
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{never, unbounded};
 use num_format::{Locale, ToFormattedString};
 use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
 use std::time::Duration;
@@ -49,11 +50,11 @@ pub fn run(bench_config: BenchConfig) -> ::anyhow::Result<()> {
     let (request_sender, request_receiver) = unbounded();
     let (response_sender, response_receiver) = unbounded();
 
-    let response_sender = ConnectedResponseSender::new(vec![response_sender]);
+    let response_sender = ConnectedResponseSender::new(vec![response_sender], Default::default());
 
     {
         let config = aquatic_config.clone();
-        let state = State::new(config.swarm_workers);
+        let state = State::new(config.swarm_workers, &config.network.address);
 
         ::std::thread::spawn(move || {
             run_swarm_worker(
@@ -63,6 +64,9 @@ pub fn run(bench_config: BenchConfig) -> ::anyhow::Result<()> {
                 request_receiver,
                 response_sender,
                 SwarmWorkerIndex(0),
+                SwarmSnapshot::default(),
+                None,
+                never(),
             )
         });
     }
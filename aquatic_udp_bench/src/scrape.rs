@@ -15,8 +15,8 @@ use crate::config::BenchConfig;
 
 pub fn bench_scrape_handler(
     bench_config: &BenchConfig,
-    request_sender: &Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
-    response_receiver: &Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    request_sender: &Sender<ConnectedRequestItem>,
+    response_receiver: &Receiver<ConnectedResponseItem>,
     rng: &mut impl Rng,
     info_hashes: &[InfoHash],
 ) -> (usize, Duration) {
@@ -52,11 +52,12 @@ pub fn bench_scrape_handler(
                 });
 
                 request_sender
-                    .send((SocketWorkerIndex(0), request, *src))
+                    .send((SocketWorkerIndex(0), ListenerIndex(0), request, *src))
                     .unwrap();
             }
 
-            while let Ok((ConnectedResponse::Scrape(response), _)) = response_receiver.try_recv() {
+            while let Ok((ConnectedResponse::Scrape(response), _, _)) = response_receiver.try_recv()
+            {
                 num_responses += 1;
 
                 if let Some(stat) = response.torrent_stats.values().last() {
@@ -68,7 +69,7 @@ pub fn bench_scrape_handler(
         let total = bench_config.num_scrape_requests * (round + 1);
 
         while num_responses < total {
-            if let Ok((ConnectedResponse::Scrape(response), _)) = response_receiver.recv() {
+            if let Ok((ConnectedResponse::Scrape(response), _, _)) = response_receiver.recv() {
                 num_responses += 1;
 
                 if let Some(stat) = response.torrent_stats.values().last() {
@@ -162,6 +162,8 @@ fn create_announce_request(
         key: PeerKey(12345),
         peers_wanted: NumberOfPeers(100),
         port: torrent_peer.port,
+        options: Vec::new(),
+        options_truncated: false,
     })
     .into()
 }
@@ -39,6 +39,90 @@ impl AnnounceEvent {
     }
 }
 
+/// Announce request option as specified in BEP 41
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum AnnounceRequestOption {
+    Nop,
+    /// Part of tracker URL path and query string. Multiple parts are
+    /// concatenated.
+    UrlData(Vec<u8>),
+    /// Option with unknown type, preserved for forward compatibility
+    Unknown {
+        option_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl AnnounceRequestOption {
+    const END_OF_OPTIONS: u8 = 0;
+    const NOP: u8 = 1;
+    const URL_DATA: u8 = 2;
+
+    /// Parse options until end of input or EndOfOptions option
+    ///
+    /// Clients pad option lists inconsistently, so parsing stops at the
+    /// first truncated option instead of failing. Returns the options read
+    /// until then and whether one was truncated.
+    fn parse_list(mut bytes: &[u8]) -> (Vec<Self>, bool) {
+        let mut options = Vec::new();
+
+        while let Some((&option_type, rest)) = bytes.split_first() {
+            bytes = rest;
+
+            match option_type {
+                Self::END_OF_OPTIONS => break,
+                Self::NOP => options.push(Self::Nop),
+                option_type => {
+                    let (&len, rest) = match bytes.split_first() {
+                        Some(split) => split,
+                        None => return (options, true),
+                    };
+
+                    let len = len as usize;
+
+                    if rest.len() < len {
+                        return (options, true);
+                    }
+
+                    let data = rest[..len].to_vec();
+
+                    bytes = &rest[len..];
+
+                    if option_type == Self::URL_DATA {
+                        options.push(Self::UrlData(data));
+                    } else {
+                        options.push(Self::Unknown { option_type, data });
+                    }
+                }
+            }
+        }
+
+        (options, false)
+    }
+
+    fn write(&self, bytes: &mut impl Write) -> Result<(), io::Error> {
+        let (option_type, data) = match self {
+            Self::Nop => return bytes.write_u8(Self::NOP),
+            Self::UrlData(data) => (Self::URL_DATA, data),
+            Self::Unknown { option_type, data } => (*option_type, data),
+        };
+
+        if data.is_empty() {
+            bytes.write_u8(option_type)?;
+            bytes.write_u8(0)?;
+        }
+
+        // Data longer than 255 bytes needs to be split into several options
+        for chunk in data.chunks(u8::MAX as usize) {
+            bytes.write_u8(option_type)?;
+            bytes.write_u8(chunk.len() as u8)?;
+            bytes.write_all(chunk)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct ConnectRequest {
     pub transaction_id: TransactionId,
@@ -58,6 +142,28 @@ pub struct AnnounceRequest {
     pub key: PeerKey,
     pub peers_wanted: NumberOfPeers,
     pub port: Port,
+    /// BEP 41 options
+    pub options: Vec<AnnounceRequestOption>,
+    /// Option list ended in the middle of an option, which was dropped
+    pub options_truncated: bool,
+}
+
+impl AnnounceRequest {
+    /// Concatenated URL data (path and query string) from BEP 41 options,
+    /// if any was sent
+    pub fn url_data(&self) -> Option<Vec<u8>> {
+        let mut opt_url_data: Option<Vec<u8>> = None;
+
+        for option in self.options.iter() {
+            if let AnnounceRequestOption::UrlData(data) = option {
+                opt_url_data
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(data);
+            }
+        }
+
+        opt_url_data
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -159,6 +265,10 @@ impl Request {
                 bytes.write_u32::<NetworkEndian>(r.key.0)?;
                 bytes.write_i32::<NetworkEndian>(r.peers_wanted.0)?;
                 bytes.write_u16::<NetworkEndian>(r.port.0)?;
+
+                for option in r.options.iter() {
+                    option.write(bytes)?;
+                }
             }
 
             Request::Scrape(r) => {
@@ -243,6 +353,11 @@ impl Request {
                     RequestParseError::sendable_io(err, connection_id, transaction_id)
                 })?;
 
+                let position = cursor.position() as usize;
+
+                let (options, options_truncated) =
+                    AnnounceRequestOption::parse_list(&cursor.into_inner()[position..]);
+
                 let opt_ip = if ip == [0; 4] {
                     None
                 } else {
@@ -262,6 +377,8 @@ impl Request {
                     key: PeerKey(key),
                     peers_wanted: NumberOfPeers(peers_wanted),
                     port: Port(port),
+                    options,
+                    options_truncated,
                 })
                 .into())
             }
@@ -320,6 +437,23 @@ mod tests {
         }
     }
 
+    impl quickcheck::Arbitrary for AnnounceRequestOption {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            // Data is kept short, since longer data is split into multiple
+            // options when written
+            let data = (0..u8::arbitrary(g)).map(|_| u8::arbitrary(g)).collect();
+
+            match u8::arbitrary(g) % 3 {
+                0 => Self::Nop,
+                1 => Self::UrlData(data),
+                _ => Self::Unknown {
+                    option_type: u8::arbitrary(g).max(3),
+                    data,
+                },
+            }
+        }
+    }
+
     impl quickcheck::Arbitrary for ConnectRequest {
         fn arbitrary(g: &mut quickcheck::Gen) -> Self {
             Self {
@@ -343,6 +477,8 @@ mod tests {
                 key: PeerKey(u32::arbitrary(g)),
                 peers_wanted: NumberOfPeers(i32::arbitrary(g)),
                 port: Port(u16::arbitrary(g)),
+                options: Vec::arbitrary(g),
+                options_truncated: false,
             }
         }
     }
@@ -394,4 +530,69 @@ mod tests {
 
         TestResult::from_bool(same_after_conversion(request.into()))
     }
+
+    #[test]
+    fn test_announce_request_options() {
+        let mut buf = Vec::new();
+
+        let request = AnnounceRequest {
+            connection_id: ConnectionId(1),
+            transaction_id: TransactionId(2),
+            info_hash: InfoHash([3; 20]),
+            peer_id: PeerId([4; 20]),
+            bytes_downloaded: NumberOfBytes(5),
+            bytes_uploaded: NumberOfBytes(6),
+            bytes_left: NumberOfBytes(7),
+            event: AnnounceEvent::Started,
+            ip_address: None,
+            key: PeerKey(8),
+            peers_wanted: NumberOfPeers(9),
+            port: Port(10),
+            options: Vec::new(),
+            options_truncated: false,
+        };
+
+        Request::from(request.clone()).write(&mut buf).unwrap();
+
+        let base_len = buf.len();
+
+        // URL data split in two parts, NOP, end of options, ignored data
+        buf.extend_from_slice(&[2, 5]);
+        buf.extend_from_slice(b"/anno");
+        buf.extend_from_slice(&[1, 2, 4]);
+        buf.extend_from_slice(b"unce");
+        buf.extend_from_slice(&[0, 2, 255]);
+
+        let parsed = match Request::from_bytes(&buf, 1).unwrap() {
+            Request::Announce(r) => r,
+            _ => panic!("not an announce request"),
+        };
+
+        assert_eq!(parsed.url_data(), Some(b"/announce".to_vec()));
+        assert_eq!(parsed.options.len(), 3);
+        assert!(!parsed.options_truncated);
+        assert_eq!(request.url_data(), None);
+
+        // Truncated option is dropped, but request is still valid
+        buf.truncate(base_len + 4);
+
+        let parsed = match Request::from_bytes(&buf, 1).unwrap() {
+            Request::Announce(r) => r,
+            _ => panic!("not an announce request"),
+        };
+
+        assert!(parsed.options.is_empty());
+        assert!(parsed.options_truncated);
+        assert_eq!(parsed.url_data(), None);
+
+        // Missing option length
+        buf.truncate(base_len + 1);
+
+        let parsed = match Request::from_bytes(&buf, 1).unwrap() {
+            Request::Announce(r) => r,
+            _ => panic!("not an announce request"),
+        };
+
+        assert!(parsed.options_truncated);
+    }
 }
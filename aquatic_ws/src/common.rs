@@ -1,14 +1,136 @@
 use std::sync::Arc;
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::announce_events::AnnounceRecordSender;
+use aquatic_common::full_scrape::FullScrapeState;
+use aquatic_common::ip_access_list::{IpAccessList, IpAccessListArcSwap};
+use aquatic_common::metrics::{Counter, Gauge, MetricsRegistry};
+use aquatic_common::shutdown::Shutdown;
 use aquatic_common::CanonicalSocketAddr;
 
 pub use aquatic_common::ValidUntil;
-use aquatic_ws_protocol::{InfoHash, PeerId};
+use aquatic_ws_protocol::{InfoHash, OutMessage, PeerId};
 
-#[derive(Default, Clone)]
+/// Metrics exposed in OpenMetrics format
+pub struct Metrics {
+    pub requests_received_announce: Counter,
+    pub requests_received_scrape: Counter,
+    pub invalid_requests: Counter,
+    pub responses_sent_announce: Counter,
+    pub responses_sent_scrape: Counter,
+    pub responses_sent_offer: Counter,
+    pub responses_sent_answer: Counter,
+    pub responses_sent_error: Counter,
+    pub bytes_received: Counter,
+    pub bytes_sent: Counter,
+    pub connections: Gauge,
+    pub connections_blocked: Counter,
+    pub torrents_ipv4: Vec<Gauge>,
+    pub torrents_ipv6: Vec<Gauge>,
+    pub peers_ipv4: Vec<Gauge>,
+    pub peers_ipv6: Vec<Gauge>,
+}
+
+impl Metrics {
+    pub fn new(registry: &MetricsRegistry, num_swarm_workers: usize) -> Self {
+        let request_counter = |request_type| {
+            registry.counter(
+                "requests_received",
+                "Valid requests received",
+                &[("type", request_type)],
+            )
+        };
+        let response_counter = |response_type| {
+            registry.counter(
+                "responses_sent",
+                "Messages sent to peers",
+                &[("type", response_type)],
+            )
+        };
+        let worker_gauges = |name, help, ip_version| -> Vec<Gauge> {
+            (0..num_swarm_workers)
+                .map(|i| {
+                    let worker = i.to_string();
+
+                    registry.gauge(
+                        name,
+                        help,
+                        &[("ip_version", ip_version), ("worker", worker.as_str())],
+                    )
+                })
+                .collect()
+        };
+
+        Self {
+            requests_received_announce: request_counter("announce"),
+            requests_received_scrape: request_counter("scrape"),
+            invalid_requests: registry.counter(
+                "invalid_requests",
+                "Messages that could not be parsed",
+                &[],
+            ),
+            responses_sent_announce: response_counter("announce"),
+            responses_sent_scrape: response_counter("scrape"),
+            responses_sent_offer: response_counter("offer"),
+            responses_sent_answer: response_counter("answer"),
+            responses_sent_error: response_counter("error"),
+            bytes_received: registry.counter("bytes_received", "Bytes received", &[]),
+            bytes_sent: registry.counter("bytes_sent", "Bytes sent", &[]),
+            connections: registry.gauge("connections", "Open connections", &[]),
+            connections_blocked: registry.counter(
+                "connections_blocked",
+                "Connections closed because of IP access list",
+                &[],
+            ),
+            torrents_ipv4: worker_gauges("torrents", "Torrents per swarm worker", "4"),
+            torrents_ipv6: worker_gauges("torrents", "Torrents per swarm worker", "6"),
+            peers_ipv4: worker_gauges("peers", "Peers per swarm worker", "4"),
+            peers_ipv6: worker_gauges("peers", "Peers per swarm worker", "6"),
+        }
+    }
+
+    pub fn count_out_message(&self, out_message: &OutMessage) {
+        match out_message {
+            OutMessage::AnnounceResponse(_) => self.responses_sent_announce.increment(),
+            OutMessage::ScrapeResponse(_) => self.responses_sent_scrape.increment(),
+            OutMessage::Offer(_) => self.responses_sent_offer.increment(),
+            OutMessage::Answer(_) => self.responses_sent_answer.increment(),
+            OutMessage::ErrorResponse(_) => self.responses_sent_error.increment(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub ip_access_list: Arc<IpAccessListArcSwap>,
+    /// Parsed from config on start
+    pub trusted_proxies: Arc<IpAccessList>,
+    /// Cached full scrape response message
+    pub full_scrape: Arc<FullScrapeState<tungstenite::Message>>,
+    pub metrics_registry: MetricsRegistry,
+    pub metrics: Arc<Metrics>,
+    /// Set if announce event export is active
+    pub announce_records: Option<AnnounceRecordSender>,
+    pub shutdown: Shutdown,
+}
+
+impl State {
+    pub fn new(num_swarm_workers: usize) -> Self {
+        let metrics_registry = MetricsRegistry::new("aquatic_ws");
+        let metrics = Arc::new(Metrics::new(&metrics_registry, num_swarm_workers));
+
+        Self {
+            access_list: Default::default(),
+            ip_access_list: Default::default(),
+            trusted_proxies: Default::default(),
+            full_scrape: Arc::new(FullScrapeState::new(num_swarm_workers)),
+            metrics_registry,
+            metrics,
+            announce_records: None,
+            shutdown: Default::default(),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -1,22 +1,30 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::access_list::{create_access_list_cache, AccessListCache};
+use aquatic_common::full_scrape::{FullScrapeRateLimiter, FullScrapeState};
+use aquatic_common::ip_access_list::create_ip_access_list_cache;
 use aquatic_common::privileges::PrivilegeDropper;
-use aquatic_common::rustls_config::RustlsConfig;
+use aquatic_common::reverse_proxy::{
+    allowed_client_ip_from_header, parse_proxy_protocol_header, proxy_protocol_bytes_needed,
+};
+use aquatic_common::rustls_config::RustlsConfigArcSwap;
+use aquatic_common::socket_activation::take_inherited_socket;
 use aquatic_common::{CanonicalSocketAddr, PanicSentinel};
 use aquatic_ws_protocol::*;
 use async_tungstenite::WebSocketStream;
+use futures::future::{FutureExt, Shared};
 use futures::stream::{SplitSink, SplitStream};
 use futures::StreamExt;
 use futures_lite::future::race;
-use futures_rustls::server::TlsStream;
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite};
 use futures_rustls::TlsAcceptor;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
 use glommio::channels::local_channel::{new_bounded, LocalReceiver, LocalSender};
@@ -27,6 +35,11 @@ use glommio::timer::{sleep, timeout, TimerActionRepeat};
 use glommio::{enclose, prelude::*};
 use hashbrown::{HashMap, HashSet};
 use slab::Slab;
+use tungstenite::handshake::server::{
+    ErrorResponse as HandshakeErrorResponse, Request as HandshakeRequest,
+    Response as HandshakeResponse,
+};
+use tungstenite::http::StatusCode;
 
 use crate::config::Config;
 
@@ -34,16 +47,31 @@ use crate::common::*;
 
 const LOCAL_CHANNEL_SIZE: usize = 16;
 
+/// After shutdown has been triggered, close connections once no responses
+/// have been sent for this long
+const SHUTDOWN_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
 struct PendingScrapeResponse {
     pending_worker_out_messages: usize,
     stats: HashMap<InfoHash, ScrapeStatistics>,
 }
 
+/// Message passed on to ConnectionWriter
+enum WriterMessage {
+    OutMessage(ConnectionMeta, OutMessage),
+    /// Cached full scrape response
+    FullScrape(Arc<tungstenite::Message>),
+}
+
+/// Resolves once shutdown has been triggered. Unlike
+/// `Shutdown::wait_for_trigger`, it is cheap to clone and poll.
+type ShutdownTriggered = Shared<JoinHandle<()>>;
+
 struct ConnectionReference {
     task_handle: Option<JoinHandle<()>>,
     /// Sender part of channel used to pass on outgoing messages from request
     /// worker
-    out_message_sender: Rc<LocalSender<(ConnectionMeta, OutMessage)>>,
+    out_message_sender: Rc<LocalSender<WriterMessage>>,
     /// Updated after sending message to peer
     valid_until: ValidUntil,
     peer_id: Option<PeerId>,
@@ -55,24 +83,47 @@ pub async fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
-    tls_config: Arc<RustlsConfig>,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
     control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
     in_message_mesh_builder: MeshBuilder<(ConnectionMeta, InMessage), Partial>,
     out_message_mesh_builder: MeshBuilder<(ConnectionMeta, OutMessage), Partial>,
     priv_dropper: PrivilegeDropper,
-) {
+) -> anyhow::Result<()> {
     let config = Rc::new(config);
-    let access_list = state.access_list;
 
-    let listener = create_tcp_listener(&config, priv_dropper).expect("create tcp listener");
+    let mut listeners = Vec::new();
+
+    if let Some(tls_config) = opt_tls_config {
+        for address in config.network.address.iter().copied() {
+            let listener = create_tcp_listener(&config, address)
+                .with_context(|| format!("create tls tcp listener bound to {}", address))?;
+
+            listeners.push((listener, Some(tls_config.clone())));
+        }
+    }
+    if config.network.enable_plaintext {
+        for address in config.network.plaintext_address.iter().copied() {
+            let listener = create_tcp_listener(&config, address)
+                .with_context(|| format!("create plaintext tcp listener bound to {}", address))?;
+
+            listeners.push((listener, None));
+        }
+    }
+
+    priv_dropper
+        .after_socket_creation()
+        .with_context(|| "drop privileges after socket creation")?;
 
     let (control_message_senders, _) = control_message_mesh_builder
         .join(Role::Producer)
         .await
-        .unwrap();
+        .map_err(|err| anyhow::anyhow!("join control message mesh: {:?}", err))?;
     let control_message_senders = Rc::new(control_message_senders);
 
-    let (in_message_senders, _) = in_message_mesh_builder.join(Role::Producer).await.unwrap();
+    let (in_message_senders, _) = in_message_mesh_builder
+        .join(Role::Producer)
+        .await
+        .map_err(|err| anyhow::anyhow!("join in message mesh: {:?}", err))?;
     let in_message_senders = Rc::new(in_message_senders);
 
     let tq_prioritized = executor().create_task_queue(
@@ -83,11 +134,19 @@ pub async fn run_socket_worker(
     let tq_regular =
         executor().create_task_queue(Shares::Static(1), Latency::NotImportant, "regular");
 
-    let (_, mut out_message_receivers) =
-        out_message_mesh_builder.join(Role::Consumer).await.unwrap();
-    let out_message_consumer_id = ConsumerId(out_message_receivers.consumer_id().unwrap());
+    let (_, mut out_message_receivers) = out_message_mesh_builder
+        .join(Role::Consumer)
+        .await
+        .map_err(|err| anyhow::anyhow!("join out message mesh: {:?}", err))?;
+    let out_message_consumer_id = ConsumerId(
+        out_message_receivers
+            .consumer_id()
+            .ok_or_else(|| anyhow::anyhow!("no consumer id"))?,
+    );
 
     let connection_slab = Rc::new(RefCell::new(Slab::new()));
+    let full_scrape_rate_limiter = Rc::new(RefCell::new(FullScrapeRateLimiter::default()));
+    let mut ip_access_list_cache = create_ip_access_list_cache(&state.ip_access_list);
 
     // Periodically clean connections
     TimerActionRepeat::repeat_into(
@@ -101,6 +160,24 @@ pub async fn run_socket_worker(
     )
     .unwrap();
 
+    if config.full_scrape.active {
+        // Only first socket worker builds full scrape response
+        let build_response = in_message_senders.producer_id() == Some(0);
+
+        TimerActionRepeat::repeat_into(
+            enclose!((config, state, full_scrape_rate_limiter) move || {
+                update_full_scrape(
+                    config.clone(),
+                    state.full_scrape.clone(),
+                    full_scrape_rate_limiter.clone(),
+                    build_response,
+                )
+            }),
+            tq_regular,
+        )
+        .unwrap();
+    }
+
     for (_, out_message_receiver) in out_message_receivers.streams() {
         spawn_local_into(
             receive_out_messages(out_message_receiver, connection_slab.clone()),
@@ -110,9 +187,25 @@ pub async fn run_socket_worker(
         .detach();
     }
 
-    let mut incoming = listener.incoming();
-
-    while let Some(stream) = incoming.next().await {
+    let shutdown_triggered: ShutdownTriggered = spawn_local(enclose!((state) async move {
+        state.shutdown.wait_for_trigger().await
+    }))
+    .detach()
+    .shared();
+
+    // Accept connections on all listeners, passing on TLS config if any
+    let mut incoming =
+        futures::stream::select_all(listeners.iter().map(|(listener, opt_tls_config)| {
+            listener
+                .incoming()
+                .map(move |stream| (stream, opt_tls_config.clone()))
+                .boxed_local()
+        }));
+
+    // Stop accepting connections on shutdown
+    while let Some((stream, opt_tls_config)) =
+        race(incoming.next(), shutdown_triggered.clone().map(|_| None)).await
+    {
         match stream {
             Ok(stream) => {
                 let peer_addr = match stream.peer_addr() {
@@ -127,6 +220,28 @@ pub async fn run_socket_worker(
                     }
                 };
 
+                // Connections from trusted proxies are checked after
+                // reading PROXY protocol header
+                let check_later = config.reverse_proxy.proxy_protocol
+                    && state.trusted_proxies.contains(peer_addr.get().ip());
+
+                if !check_later
+                    && !ip_access_list_cache
+                        .load()
+                        .allows(config.ip_access_list.mode, peer_addr.get().ip())
+                {
+                    ::log::debug!(
+                        "closing connection from blocked address {}",
+                        peer_addr.get()
+                    );
+
+                    if config.metrics.active {
+                        state.metrics.connections_blocked.increment();
+                    }
+
+                    continue;
+                }
+
                 let (out_message_sender, out_message_receiver) = new_bounded(LOCAL_CHANNEL_SIZE);
                 let out_message_sender = Rc::new(out_message_sender);
 
@@ -141,19 +256,26 @@ pub async fn run_socket_worker(
 
                 ::log::info!("accepting stream: {}", key);
 
-                let task_handle = spawn_local_into(enclose!((config, access_list, control_message_senders, in_message_senders, connection_slab, tls_config) async move {
+                let task_handle = spawn_local_into(enclose!((config, state, control_message_senders, in_message_senders, connection_slab, full_scrape_rate_limiter, shutdown_triggered) async move {
+                    let _connection_guard = config
+                        .metrics
+                        .active
+                        .then(|| state.metrics.connections.increment_scoped());
+
                     if let Err(err) = run_connection(
                         config.clone(),
-                        access_list,
+                        state,
                         in_message_senders,
                         tq_prioritized,
                         tq_regular,
                         connection_slab.clone(),
+                        full_scrape_rate_limiter,
+                        shutdown_triggered,
                         out_message_sender,
                         out_message_receiver,
                         out_message_consumer_id,
                         ConnectionId(key),
-                        tls_config,
+                        opt_tls_config,
                         stream,
                         peer_addr,
                     ).await {
@@ -201,6 +323,21 @@ pub async fn run_socket_worker(
             }
         }
     }
+
+    // Let open connections finish sending responses
+    race(
+        wait_for_connections_to_close(connection_slab),
+        state.shutdown.wait_for_deadline(),
+    )
+    .await;
+
+    Ok(())
+}
+
+async fn wait_for_connections_to_close(connection_slab: Rc<RefCell<Slab<ConnectionReference>>>) {
+    while !connection_slab.borrow().is_empty() {
+        sleep(Duration::from_millis(100)).await;
+    }
 }
 
 async fn clean_connections(
@@ -228,6 +365,49 @@ async fn clean_connections(
     ))
 }
 
+async fn update_full_scrape(
+    config: Rc<Config>,
+    full_scrape: Arc<FullScrapeState<tungstenite::Message>>,
+    rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    build_response: bool,
+) -> Option<Duration> {
+    rate_limiter
+        .borrow_mut()
+        .clean(&config.full_scrape, Instant::now());
+
+    if build_response {
+        full_scrape.set_response(create_full_scrape_response(&full_scrape));
+    }
+
+    Some(Duration::from_secs(config.full_scrape.update_interval))
+}
+
+/// Create full scrape response message from statistics contributed by swarm
+/// workers
+fn create_full_scrape_response(
+    full_scrape: &FullScrapeState<tungstenite::Message>,
+) -> tungstenite::Message {
+    let files = full_scrape
+        .collect_statistics()
+        .into_iter()
+        .map(|(info_hash, statistics)| {
+            let statistics = ScrapeStatistics {
+                complete: statistics.seeders,
+                incomplete: statistics.leechers,
+                downloaded: statistics.completed,
+            };
+
+            (InfoHash(info_hash), statistics)
+        })
+        .collect();
+
+    OutMessage::ScrapeResponse(ScrapeResponse {
+        action: ScrapeAction,
+        files,
+    })
+    .to_ws_message()
+}
+
 async fn receive_out_messages(
     mut out_message_receiver: ConnectedReceiver<(ConnectionMeta, OutMessage)>,
     connection_references: Rc<RefCell<Slab<ConnectionReference>>>,
@@ -242,7 +422,10 @@ async fn receive_out_messages(
                 reference.out_message_sender.len()
             );
 
-            match reference.out_message_sender.try_send((meta, out_message)) {
+            match reference
+                .out_message_sender
+                .try_send(WriterMessage::OutMessage(meta, out_message))
+            {
                 Ok(()) => {}
                 Err(GlommioError::Closed(_)) => {}
                 Err(GlommioError::WouldBlock(_)) => {}
@@ -259,21 +442,116 @@ async fn receive_out_messages(
 
 async fn run_connection(
     config: Rc<Config>,
-    access_list: Arc<AccessListArcSwap>,
+    state: State,
     in_message_senders: Rc<Senders<(ConnectionMeta, InMessage)>>,
     tq_prioritized: TaskQueueHandle,
     tq_regular: TaskQueueHandle,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
-    out_message_sender: Rc<LocalSender<(ConnectionMeta, OutMessage)>>,
-    out_message_receiver: LocalReceiver<(ConnectionMeta, OutMessage)>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    shutdown_triggered: ShutdownTriggered,
+    out_message_sender: Rc<LocalSender<WriterMessage>>,
+    out_message_receiver: LocalReceiver<WriterMessage>,
     out_message_consumer_id: ConsumerId,
     connection_id: ConnectionId,
-    tls_config: Arc<RustlsConfig>,
-    stream: TcpStream,
-    peer_addr: CanonicalSocketAddr,
+    opt_tls_config: Option<Arc<RustlsConfigArcSwap>>,
+    mut stream: TcpStream,
+    mut peer_addr: CanonicalSocketAddr,
 ) -> anyhow::Result<()> {
-    let tls_acceptor: TlsAcceptor = tls_config.into();
-    let stream = tls_acceptor.accept(stream).await?;
+    let is_trusted_proxy =
+        config.reverse_proxy.is_on() && state.trusted_proxies.contains(peer_addr.get().ip());
+
+    if is_trusted_proxy && config.reverse_proxy.proxy_protocol {
+        if let Some(client_addr) = read_proxy_protocol_header(&mut stream).await? {
+            peer_addr = CanonicalSocketAddr::new(client_addr);
+        }
+
+        if !state
+            .ip_access_list
+            .load()
+            .allows(config.ip_access_list.mode, peer_addr.get().ip())
+        {
+            if config.metrics.active {
+                state.metrics.connections_blocked.increment();
+            }
+
+            return Err(anyhow::anyhow!(
+                "connection from blocked address {}",
+                peer_addr.get()
+            ));
+        }
+    }
+
+    let connection_state = ConnectionState {
+        config,
+        state,
+        in_message_senders,
+        tq_prioritized,
+        tq_regular,
+        connection_slab,
+        full_scrape_rate_limiter,
+        shutdown_triggered,
+        out_message_sender,
+        out_message_receiver,
+        out_message_consumer_id,
+        connection_id,
+        is_trusted_proxy,
+        peer_addr,
+    };
+
+    match opt_tls_config {
+        Some(tls_config) => {
+            let tls_acceptor: TlsAcceptor = tls_config.load_full().into();
+            let stream = tls_acceptor.accept(stream).await?;
+
+            run_websocket_connection(connection_state, stream).await
+        }
+        None => run_websocket_connection(connection_state, stream).await,
+    }
+}
+
+/// Connection state that doesn't depend on stream type
+struct ConnectionState {
+    config: Rc<Config>,
+    state: State,
+    in_message_senders: Rc<Senders<(ConnectionMeta, InMessage)>>,
+    tq_prioritized: TaskQueueHandle,
+    tq_regular: TaskQueueHandle,
+    connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
+    shutdown_triggered: ShutdownTriggered,
+    out_message_sender: Rc<LocalSender<WriterMessage>>,
+    out_message_receiver: LocalReceiver<WriterMessage>,
+    out_message_consumer_id: ConsumerId,
+    connection_id: ConnectionId,
+    is_trusted_proxy: bool,
+    peer_addr: CanonicalSocketAddr,
+}
+
+/// Do WebSocket handshake over plaintext or TLS stream, then run reader and
+/// writer tasks until one of them returns
+async fn run_websocket_connection<S>(
+    connection_state: ConnectionState,
+    stream: S,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let ConnectionState {
+        config,
+        state,
+        in_message_senders,
+        tq_prioritized,
+        tq_regular,
+        connection_slab,
+        full_scrape_rate_limiter,
+        shutdown_triggered,
+        out_message_sender,
+        out_message_receiver,
+        out_message_consumer_id,
+        connection_id,
+        is_trusted_proxy,
+        mut peer_addr,
+    } = connection_state;
 
     let ws_config = tungstenite::protocol::WebSocketConfig {
         max_frame_size: Some(config.network.websocket_max_frame_size),
@@ -281,23 +559,89 @@ async fn run_connection(
         max_send_queue: Some(2),
         ..Default::default()
     };
-    let stream = async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?;
+
+    let client_ip_header = config.reverse_proxy.client_ip_header;
+    let mut res_client_ip = Ok(None);
+
+    let res_stream = if is_trusted_proxy && client_ip_header.is_on() {
+        let header_name = client_ip_header.header_name().unwrap_or_default();
+
+        let callback = |request: &HandshakeRequest,
+                        response: HandshakeResponse|
+         -> Result<HandshakeResponse, HandshakeErrorResponse> {
+            if let Some(value) = request.headers().get_all(header_name).iter().last() {
+                res_client_ip = allowed_client_ip_from_header(
+                    client_ip_header,
+                    &state.trusted_proxies,
+                    &state.ip_access_list.load(),
+                    config.ip_access_list.mode,
+                    value.as_bytes(),
+                );
+            }
+
+            if res_client_ip.is_err() {
+                let mut response = HandshakeErrorResponse::new(None);
+
+                *response.status_mut() = StatusCode::FORBIDDEN;
+
+                return Err(response);
+            }
+
+            Ok(response)
+        };
+
+        async_tungstenite::accept_hdr_async_with_config(stream, callback, Some(ws_config)).await
+    } else {
+        async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await
+    };
+
+    let opt_client_ip = match res_client_ip {
+        Ok(opt_client_ip) => opt_client_ip,
+        Err(client_ip) => {
+            if config.metrics.active {
+                state.metrics.connections_blocked.increment();
+            }
+
+            return Err(anyhow::anyhow!(
+                "connection from blocked address {}",
+                client_ip
+            ));
+        }
+    };
+
+    let stream = res_stream?;
+
+    if let Some(ip) = opt_client_ip {
+        peer_addr = CanonicalSocketAddr::new(SocketAddr::new(ip, peer_addr.get().port()));
+    }
+
+    // Peer address might have been changed by PROXY protocol header or
+    // client IP header
+    if let Some(reference) = connection_slab.borrow_mut().get_mut(connection_id.0) {
+        reference.peer_addr = peer_addr;
+    }
 
     let (ws_out, ws_in) = futures::StreamExt::split(stream);
 
     let pending_scrape_slab = Rc::new(RefCell::new(Slab::new()));
-    let access_list_cache = create_access_list_cache(&access_list);
+    let access_list_cache = create_access_list_cache(&state.access_list);
+    let full_scrape = state.full_scrape;
+    let metrics = state.metrics;
 
     let reader_handle = spawn_local_into(
-        enclose!((config, connection_slab, pending_scrape_slab) async move {
+        enclose!((config, connection_slab, pending_scrape_slab, metrics, shutdown_triggered) async move {
             let mut reader = ConnectionReader {
                 config,
+                metrics,
                 access_list_cache,
                 connection_slab,
+                full_scrape,
+                full_scrape_rate_limiter,
                 in_message_senders,
                 out_message_sender,
                 pending_scrape_slab,
                 out_message_consumer_id,
+                shutdown_triggered,
                 ws_in,
                 peer_addr,
                 connection_id,
@@ -316,10 +660,12 @@ async fn run_connection(
         async move {
             let mut writer = ConnectionWriter {
                 config,
+                metrics,
                 out_message_receiver,
                 connection_slab,
                 ws_out,
                 pending_scrape_slab,
+                shutdown_triggered,
                 peer_addr,
                 connection_id,
             };
@@ -336,20 +682,24 @@ async fn run_connection(
     race(reader_handle, writer_handle).await.unwrap()
 }
 
-struct ConnectionReader {
+struct ConnectionReader<S> {
     config: Rc<Config>,
+    metrics: Arc<Metrics>,
     access_list_cache: AccessListCache,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
+    full_scrape: Arc<FullScrapeState<tungstenite::Message>>,
+    full_scrape_rate_limiter: Rc<RefCell<FullScrapeRateLimiter>>,
     in_message_senders: Rc<Senders<(ConnectionMeta, InMessage)>>,
-    out_message_sender: Rc<LocalSender<(ConnectionMeta, OutMessage)>>,
+    out_message_sender: Rc<LocalSender<WriterMessage>>,
     pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
     out_message_consumer_id: ConsumerId,
-    ws_in: SplitStream<WebSocketStream<TlsStream<TcpStream>>>,
+    shutdown_triggered: ShutdownTriggered,
+    ws_in: SplitStream<WebSocketStream<S>>,
     peer_addr: CanonicalSocketAddr,
     connection_id: ConnectionId,
 }
 
-impl ConnectionReader {
+impl<S: AsyncRead + AsyncWrite + Unpin> ConnectionReader<S> {
     async fn run_in_message_loop(&mut self) -> anyhow::Result<()> {
         loop {
             ::log::debug!("read_in_message");
@@ -360,17 +710,50 @@ impl ConnectionReader {
                 yield_if_needed().await;
             }
 
-            let message = self.ws_in.next().await.unwrap()?;
+            let opt_message = race(
+                self.ws_in.next(),
+                self.shutdown_triggered.clone().map(|_| None),
+            )
+            .await;
+
+            let message = match opt_message {
+                Some(message) => message?,
+                // Stop reading on shutdown, but let writer send remaining
+                // responses
+                None if self.shutdown_triggered.peek().is_some() => {
+                    return futures_lite::future::pending().await;
+                }
+                None => return Err(anyhow::anyhow!("WebSocket stream ended")),
+            };
+
+            if self.config.metrics.active {
+                self.metrics.bytes_received.add(message.len() as u64);
+            }
 
             match InMessage::from_ws_message(message) {
                 Ok(in_message) => {
                     ::log::debug!("parsed in_message");
 
+                    if self.config.metrics.active {
+                        match in_message {
+                            InMessage::AnnounceRequest(_) => {
+                                self.metrics.requests_received_announce.increment()
+                            }
+                            InMessage::ScrapeRequest(_) => {
+                                self.metrics.requests_received_scrape.increment()
+                            }
+                        }
+                    }
+
                     self.handle_in_message(in_message).await?;
                 }
                 Err(err) => {
                     ::log::debug!("Couldn't parse in_message: {:?}", err);
 
+                    if self.config.metrics.active {
+                        self.metrics.invalid_requests.increment();
+                    }
+
                     self.send_error_response("Invalid request".into(), None, None)
                         .await?;
                 }
@@ -452,14 +835,27 @@ impl ConnectionReader {
                 let info_hashes = if let Some(info_hashes) = info_hashes {
                     info_hashes
                 } else {
-                    // If request.info_hashes is empty, don't return scrape for all
-                    // torrents, even though reference server does it. It is too expensive.
-                    self.send_error_response(
-                        "Full scrapes are not allowed".into(),
-                        Some(ErrorResponseAction::Scrape),
-                        None,
-                    )
-                    .await?;
+                    match self.get_full_scrape_response() {
+                        Ok(ws_message) => {
+                            self.out_message_sender
+                                .send(WriterMessage::FullScrape(ws_message))
+                                .await
+                                .map_err(|err| {
+                                    anyhow::anyhow!(
+                                        "ConnectionReader couldn't send full scrape response: {}",
+                                        err
+                                    )
+                                })?;
+                        }
+                        Err(failure_reason) => {
+                            self.send_error_response(
+                                failure_reason.into(),
+                                Some(ErrorResponseAction::Scrape),
+                                None,
+                            )
+                            .await?;
+                        }
+                    }
 
                     return Ok(());
                 };
@@ -519,11 +915,34 @@ impl ConnectionReader {
         });
 
         self.out_message_sender
-            .send((self.make_connection_meta(None), out_message))
+            .send(WriterMessage::OutMessage(
+                self.make_connection_meta(None),
+                out_message,
+            ))
             .await
             .map_err(|err| anyhow::anyhow!("ConnectionReader::send_error_response failed: {}", err))
     }
 
+    /// Get cached full scrape response if full scrapes are enabled and
+    /// client isn't rate limited
+    fn get_full_scrape_response(&self) -> Result<Arc<tungstenite::Message>, &'static str> {
+        if !self.config.full_scrape.active {
+            return Err("Full scrapes are not allowed");
+        }
+
+        if !self.full_scrape_rate_limiter.borrow_mut().check(
+            &self.config.full_scrape,
+            self.peer_addr.get().ip(),
+            Instant::now(),
+        ) {
+            return Err("Full scrape rate limit exceeded");
+        }
+
+        self.full_scrape
+            .load_response()
+            .ok_or("Full scrape not available yet")
+    }
+
     fn make_connection_meta(&self, pending_scrape_id: Option<PendingScrapeId>) -> ConnectionMeta {
         ConnectionMeta {
             connection_id: self.connection_id,
@@ -534,22 +953,62 @@ impl ConnectionReader {
     }
 }
 
-struct ConnectionWriter {
+struct ConnectionWriter<S> {
     config: Rc<Config>,
-    out_message_receiver: LocalReceiver<(ConnectionMeta, OutMessage)>,
+    metrics: Arc<Metrics>,
+    out_message_receiver: LocalReceiver<WriterMessage>,
     connection_slab: Rc<RefCell<Slab<ConnectionReference>>>,
-    ws_out: SplitSink<WebSocketStream<TlsStream<TcpStream>>, tungstenite::Message>,
+    ws_out: SplitSink<WebSocketStream<S>, tungstenite::Message>,
     pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
+    shutdown_triggered: ShutdownTriggered,
     peer_addr: CanonicalSocketAddr,
     connection_id: ConnectionId,
 }
 
-impl ConnectionWriter {
+impl<S: AsyncRead + AsyncWrite + Unpin> ConnectionWriter<S> {
     async fn run_out_message_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            let (meta, out_message) = self.out_message_receiver.recv().await.ok_or_else(|| {
-                anyhow::anyhow!("ConnectionWriter couldn't receive message, sender is closed")
-            })?;
+            let shutdown_triggered = self.shutdown_triggered.clone();
+
+            // Close connection once idle after shutdown has been triggered
+            let opt_message = race(
+                async {
+                    let message = self.out_message_receiver.recv().await.ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "ConnectionWriter couldn't receive message, sender is closed"
+                        )
+                    })?;
+
+                    Ok::<_, anyhow::Error>(Some(message))
+                },
+                async {
+                    shutdown_triggered.await;
+                    sleep(SHUTDOWN_IDLE_TIMEOUT).await;
+
+                    Ok(None)
+                },
+            )
+            .await?;
+
+            let message = match opt_message {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+
+            let (meta, out_message) = match message {
+                WriterMessage::OutMessage(meta, out_message) => (meta, out_message),
+                WriterMessage::FullScrape(ws_message) => {
+                    let sent = self
+                        .send_ws_message(tungstenite::Message::clone(&ws_message))
+                        .await?;
+
+                    if sent && self.config.metrics.active {
+                        self.metrics.responses_sent_scrape.increment();
+                    }
+
+                    continue;
+                }
+            };
 
             if meta.peer_addr != self.peer_addr {
                 return Err(anyhow::anyhow!("peer addresses didn't match"));
@@ -598,9 +1057,21 @@ impl ConnectionWriter {
     }
 
     async fn send_out_message(&mut self, out_message: &OutMessage) -> anyhow::Result<()> {
+        let sent = self.send_ws_message(out_message.to_ws_message()).await?;
+
+        if sent && self.config.metrics.active {
+            self.metrics.count_out_message(out_message);
+        }
+
+        Ok(())
+    }
+
+    /// Returns false if sending timed out
+    async fn send_ws_message(&mut self, ws_message: tungstenite::Message) -> anyhow::Result<bool> {
+        let ws_message_len = ws_message.len();
+
         let result = timeout(Duration::from_secs(10), async {
-            let result =
-                futures::SinkExt::send(&mut self.ws_out, out_message.to_ws_message()).await;
+            let result = futures::SinkExt::send(&mut self.ws_out, ws_message).await;
 
             Ok(result)
         })
@@ -608,6 +1079,10 @@ impl ConnectionWriter {
 
         match result {
             Ok(Ok(())) => {
+                if self.config.metrics.active {
+                    self.metrics.bytes_sent.add(ws_message_len as u64);
+                }
+
                 self.connection_slab
                     .borrow_mut()
                     .get_mut(self.connection_id.0)
@@ -619,7 +1094,7 @@ impl ConnectionWriter {
                     })?
                     .valid_until = ValidUntil::new(self.config.cleaning.max_connection_idle);
 
-                Ok(())
+                Ok(true)
             }
             Ok(Err(err)) => Err(err.into()),
             Err(err) => {
@@ -629,21 +1104,42 @@ impl ConnectionWriter {
                     err
                 );
 
-                Ok(())
+                Ok(false)
             }
         }
     }
 }
 
-fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+pub fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
     (info_hash.0[0] as usize) % config.swarm_workers
 }
 
-fn create_tcp_listener(
-    config: &Config,
-    priv_dropper: PrivilegeDropper,
-) -> anyhow::Result<TcpListener> {
-    let domain = if config.network.address.is_ipv4() {
+/// Read PROXY protocol header without consuming any data after it
+async fn read_proxy_protocol_header(stream: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    let mut buffer = Vec::new();
+
+    loop {
+        match proxy_protocol_bytes_needed(&buffer)? {
+            0 => return parse_proxy_protocol_header(&buffer),
+            bytes_needed => {
+                let start = buffer.len();
+
+                buffer.resize(start + bytes_needed, 0);
+
+                stream.read_exact(&mut buffer[start..]).await?;
+            }
+        }
+    }
+}
+
+fn create_tcp_listener(config: &Config, address: SocketAddr) -> anyhow::Result<TcpListener> {
+    if let Some(socket) = take_inherited_socket(socket2::Type::STREAM, address)
+        .with_context(|| "take inherited socket")?
+    {
+        return Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) });
+    }
+
+    let domain = if address.is_ipv4() {
         socket2::Domain::IPV4
     } else {
         socket2::Domain::IPV6
@@ -663,14 +1159,12 @@ fn create_tcp_listener(
         .with_context(|| "socket: set reuse port")?;
 
     socket
-        .bind(&config.network.address.into())
-        .with_context(|| format!("socket: bind to {}", config.network.address))?;
+        .bind(&address.into())
+        .with_context(|| format!("socket: bind to {}", address))?;
 
     socket
         .listen(config.network.tcp_backlog)
-        .with_context(|| format!("socket: listen {}", config.network.address))?;
-
-    priv_dropper.after_socket_creation()?;
+        .with_context(|| format!("socket: listen {}", address))?;
 
     Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
 }
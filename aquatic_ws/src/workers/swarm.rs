@@ -1,9 +1,16 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::announce_events::{AnnounceRecord, AnnounceRecordEvent, AnnounceRecordSender};
+use aquatic_common::control::{
+    ControlResponse, PeerInfo, SwarmCommand, SwarmCommandRequest, TorrentInfo,
+};
+use aquatic_common::full_scrape::FullScrapeStatistics;
+use futures::channel::mpsc::UnboundedReceiver;
 use futures::StreamExt;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
 use glommio::enclose;
@@ -55,6 +62,7 @@ struct TorrentData {
     pub peers: PeerMap,
     pub num_seeders: usize,
     pub num_leechers: usize,
+    pub num_completed: usize,
 }
 
 impl Default for TorrentData {
@@ -64,12 +72,14 @@ impl Default for TorrentData {
             peers: Default::default(),
             num_seeders: 0,
             num_leechers: 0,
+            num_completed: 0,
         }
     }
 }
 
 impl TorrentData {
-    pub fn remove_peer(&mut self, peer_id: PeerId) {
+    /// Remove peer, returning whether it was registered
+    pub fn remove_peer(&mut self, peer_id: PeerId) -> bool {
         if let Some(peer) = self.peers.remove(&peer_id) {
             match peer.status {
                 PeerStatus::Leeching => {
@@ -80,8 +90,31 @@ impl TorrentData {
                 }
                 PeerStatus::Stopped => (),
             }
+
+            true
+        } else {
+            false
         }
     }
+
+    fn add_to_torrent_info(&self, torrent_info: &mut TorrentInfo, now: Instant) {
+        torrent_info.seeders += self.num_seeders;
+        torrent_info.leechers += self.num_leechers;
+        torrent_info.completed += self.num_completed;
+        torrent_info
+            .peers
+            .extend(self.peers.iter().map(|(peer_id, peer)| {
+                let peer_addr = peer.connection_meta.peer_addr.get();
+
+                PeerInfo {
+                    peer_id: peer_id.0,
+                    ip_address: peer_addr.ip(),
+                    port: peer_addr.port(),
+                    seeding: peer.status == PeerStatus::Seeding,
+                    valid_for: peer.valid_until.seconds_left(now),
+                }
+            }));
+    }
 }
 
 type TorrentMap = AmortizedIndexMap<InfoHash, TorrentData>;
@@ -100,6 +133,62 @@ impl TorrentMaps {
         Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6);
     }
 
+    /// Statistics for all torrents, with IPv4 and IPv6 swarms combined
+    fn full_scrape_statistics(&self) -> Vec<([u8; 20], FullScrapeStatistics)> {
+        let mut statistics: BTreeMap<[u8; 20], FullScrapeStatistics> = BTreeMap::new();
+
+        for torrent_map in [&self.ipv4, &self.ipv6] {
+            for (info_hash, torrent_data) in torrent_map.iter() {
+                let entry = statistics.entry(info_hash.0).or_default();
+
+                entry.seeders += torrent_data.num_seeders;
+                entry.leechers += torrent_data.num_leechers;
+                entry.completed += torrent_data.num_completed;
+            }
+        }
+
+        statistics.into_iter().collect()
+    }
+
+    /// Statistics and peers for torrent, with IPv4 and IPv6 swarms combined
+    fn torrent_info(&self, info_hash: InfoHash) -> Option<TorrentInfo> {
+        let now = Instant::now();
+        let mut opt_torrent_info: Option<TorrentInfo> = None;
+
+        for torrent_map in [&self.ipv4, &self.ipv6] {
+            if let Some(torrent_data) = torrent_map.get(&info_hash) {
+                torrent_data.add_to_torrent_info(
+                    opt_torrent_info.get_or_insert_with(Default::default),
+                    now,
+                );
+            }
+        }
+
+        opt_torrent_info
+    }
+
+    /// Remove torrent, returning whether it was present
+    fn remove_torrent(&mut self, info_hash: InfoHash) -> bool {
+        let removed_ipv4 = self.ipv4.remove(&info_hash).is_some();
+        let removed_ipv6 = self.ipv6.remove(&info_hash).is_some();
+
+        removed_ipv4 || removed_ipv6
+    }
+
+    /// Remove peer from torrent, returning whether it was present
+    fn remove_peer(&mut self, info_hash: InfoHash, peer_id: PeerId) -> bool {
+        let removed_ipv4 = self
+            .ipv4
+            .get_mut(&info_hash)
+            .map_or(false, |torrent_data| torrent_data.remove_peer(peer_id));
+        let removed_ipv6 = self
+            .ipv6
+            .get_mut(&info_hash)
+            .map_or(false, |torrent_data| torrent_data.remove_peer(peer_id));
+
+        removed_ipv4 || removed_ipv6
+    }
+
     fn clean_torrent_map(
         config: &Config,
         access_list_cache: &mut AccessListCache,
@@ -141,6 +230,20 @@ impl TorrentMaps {
 
         torrent_map.shrink_to_fit();
     }
+
+    fn update_metrics(&self, metrics: &Metrics, worker_index: usize) {
+        fn num_peers(torrent_map: &TorrentMap) -> i64 {
+            torrent_map
+                .values()
+                .map(|torrent_data| torrent_data.peers.len() as i64)
+                .sum()
+        }
+
+        metrics.torrents_ipv4[worker_index].set(self.ipv4.len() as i64);
+        metrics.torrents_ipv6[worker_index].set(self.ipv6.len() as i64);
+        metrics.peers_ipv4[worker_index].set(num_peers(&self.ipv4));
+        metrics.peers_ipv6[worker_index].set(num_peers(&self.ipv6));
+    }
 }
 
 pub async fn run_swarm_worker(
@@ -150,29 +253,65 @@ pub async fn run_swarm_worker(
     control_message_mesh_builder: MeshBuilder<SwarmControlMessage, Partial>,
     in_message_mesh_builder: MeshBuilder<(ConnectionMeta, InMessage), Partial>,
     out_message_mesh_builder: MeshBuilder<(ConnectionMeta, OutMessage), Partial>,
-) {
+    command_receivers: Arc<Mutex<Vec<Option<UnboundedReceiver<SwarmCommandRequest>>>>>,
+) -> anyhow::Result<()> {
     let (_, mut control_message_receivers) = control_message_mesh_builder
         .join(Role::Consumer)
         .await
-        .unwrap();
+        .map_err(|err| anyhow::anyhow!("join control message mesh: {:?}", err))?;
 
-    let (_, mut in_message_receivers) = in_message_mesh_builder.join(Role::Consumer).await.unwrap();
-    let (out_message_senders, _) = out_message_mesh_builder.join(Role::Producer).await.unwrap();
+    let (_, mut in_message_receivers) = in_message_mesh_builder
+        .join(Role::Consumer)
+        .await
+        .map_err(|err| anyhow::anyhow!("join in message mesh: {:?}", err))?;
+    let (out_message_senders, _) = out_message_mesh_builder
+        .join(Role::Producer)
+        .await
+        .map_err(|err| anyhow::anyhow!("join out message mesh: {:?}", err))?;
 
     let out_message_senders = Rc::new(out_message_senders);
+    let consumer_index = in_message_receivers
+        .consumer_id()
+        .ok_or_else(|| anyhow::anyhow!("no consumer id"))?;
 
     let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
     let access_list = state.access_list;
+    let metrics = state.metrics;
 
     // Periodically clean torrents
-    TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
-        enclose!((config, torrents, access_list) move || async move {
+    TimerActionRepeat::repeat(enclose!((config, torrents, access_list, metrics) move || {
+        enclose!((config, torrents, access_list, metrics) move || async move {
             torrents.borrow_mut().clean(&config, &access_list);
 
+            if config.metrics.active {
+                torrents.borrow().update_metrics(&metrics, consumer_index);
+            }
+
             Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
         })()
     }));
 
+    // Periodically contribute torrent statistics to full scrape response
+    if config.full_scrape.active {
+        let full_scrape = state.full_scrape;
+
+        TimerActionRepeat::repeat(enclose!((config, torrents, full_scrape) move || {
+            enclose!((config, torrents, full_scrape) move || async move {
+                let statistics = torrents.borrow().full_scrape_statistics();
+
+                full_scrape.set_worker_statistics(consumer_index, statistics);
+
+                Some(Duration::from_secs(config.full_scrape.update_interval))
+            })()
+        }));
+    }
+
+    // Control server commands. Not awaited, since the control server
+    // doesn't keep workers running.
+    if let Some(command_receiver) = command_receivers.lock().unwrap()[consumer_index].take() {
+        spawn_local(handle_command_stream(torrents.clone(), command_receiver)).detach();
+    }
+
     let mut handles = Vec::new();
 
     for (_, receiver) in control_message_receivers.streams() {
@@ -187,6 +326,7 @@ pub async fn run_swarm_worker(
             config.clone(),
             torrents.clone(),
             out_message_senders.clone(),
+            state.announce_records.clone(),
             receiver,
         ))
         .detach();
@@ -194,9 +334,12 @@ pub async fn run_swarm_worker(
         handles.push(handle);
     }
 
+    // Message streams end when all socket workers have exited
     for handle in handles {
         handle.await;
     }
+
+    Ok(())
 }
 
 async fn handle_control_message_stream<S>(torrents: Rc<RefCell<TorrentMaps>>, mut stream: S)
@@ -229,10 +372,35 @@ where
     }
 }
 
+async fn handle_command_stream(
+    torrents: Rc<RefCell<TorrentMaps>>,
+    mut stream: UnboundedReceiver<SwarmCommandRequest>,
+) {
+    while let Some(request) = stream.next().await {
+        let mut torrent_maps = torrents.borrow_mut();
+
+        let response = match request.command {
+            SwarmCommand::Torrent { info_hash } => ControlResponse::Torrent {
+                torrent: torrent_maps.torrent_info(InfoHash(info_hash)),
+            },
+            SwarmCommand::RemoveTorrent { info_hash } => ControlResponse::Removed {
+                removed: torrent_maps.remove_torrent(InfoHash(info_hash)),
+            },
+            SwarmCommand::RemovePeer { info_hash, peer_id } => ControlResponse::Removed {
+                removed: torrent_maps.remove_peer(InfoHash(info_hash), PeerId(peer_id)),
+            },
+        };
+
+        // Control server might have stopped waiting
+        let _ = request.response_sender.send(response);
+    }
+}
+
 async fn handle_request_stream<S>(
     config: Config,
     torrents: Rc<RefCell<TorrentMaps>>,
     out_message_senders: Rc<Senders<(ConnectionMeta, OutMessage)>>,
+    opt_announce_records: Option<AnnounceRecordSender>,
     stream: S,
 ) where
     S: futures_lite::Stream<Item = (ConnectionMeta, InMessage)> + ::std::marker::Unpin,
@@ -255,6 +423,7 @@ async fn handle_request_stream<S>(
     let peer_valid_until = &peer_valid_until;
     let rng = &rng;
     let out_message_senders = &out_message_senders;
+    let opt_announce_records = &opt_announce_records;
 
     stream
         .for_each_concurrent(
@@ -263,15 +432,21 @@ async fn handle_request_stream<S>(
                 let mut out_messages = Vec::new();
 
                 match in_message {
-                    InMessage::AnnounceRequest(request) => handle_announce_request(
-                        &config,
-                        &mut rng.borrow_mut(),
-                        &mut torrents.borrow_mut(),
-                        &mut out_messages,
-                        peer_valid_until.borrow().to_owned(),
-                        meta,
-                        request,
-                    ),
+                    InMessage::AnnounceRequest(request) => {
+                        if let Some(announce_records) = opt_announce_records {
+                            announce_records.send(|| create_announce_record(&request, meta));
+                        }
+
+                        handle_announce_request(
+                            &config,
+                            &mut rng.borrow_mut(),
+                            &mut torrents.borrow_mut(),
+                            &mut out_messages,
+                            peer_valid_until.borrow().to_owned(),
+                            meta,
+                            request,
+                        )
+                    }
                     InMessage::ScrapeRequest(request) => handle_scrape_request(
                         &config,
                         &mut torrents.borrow_mut(),
@@ -296,6 +471,27 @@ async fn handle_request_stream<S>(
         .await;
 }
 
+/// Create record for export. Byte counts other than bytes left are not
+/// part of the WebTorrent protocol.
+fn create_announce_record(request: &AnnounceRequest, meta: ConnectionMeta) -> AnnounceRecord {
+    let event = match request.event {
+        Some(AnnounceEvent::Started) => AnnounceRecordEvent::Started,
+        Some(AnnounceEvent::Stopped) => AnnounceRecordEvent::Stopped,
+        Some(AnnounceEvent::Completed) => AnnounceRecordEvent::Completed,
+        Some(AnnounceEvent::Update) | None => AnnounceRecordEvent::None,
+    };
+
+    AnnounceRecord {
+        info_hash: request.info_hash.0,
+        peer_id: request.peer_id.0,
+        event,
+        bytes_left: request.bytes_left.map(|bytes| bytes as u64),
+        bytes_uploaded: None,
+        bytes_downloaded: None,
+        ipv4: meta.peer_addr.is_ipv4(),
+    }
+}
+
 fn handle_announce_request(
     config: &Config,
     rng: &mut SmallRng,
@@ -325,6 +521,8 @@ fn handle_announce_request(
 
     // Insert/update/remove peer who sent this request
     {
+        let completed = matches!(request.event, Some(AnnounceEvent::Completed));
+
         let peer_status = PeerStatus::from_event_and_bytes_left(
             request.event.unwrap_or_default(),
             request.bytes_left,
@@ -350,7 +548,14 @@ fn handle_announce_request(
             PeerStatus::Stopped => torrent_data.peers.remove(&request.peer_id),
         };
 
-        match opt_removed_peer.map(|peer| peer.status) {
+        let opt_removed_status = opt_removed_peer.map(|peer| peer.status);
+
+        // Skip completed events from peers already known to be seeding
+        if completed && opt_removed_status != Some(PeerStatus::Seeding) {
+            torrent_data.num_completed += 1;
+        }
+
+        match opt_removed_status {
             Some(PeerStatus::Leeching) => {
                 torrent_data.num_leechers -= 1;
             }
@@ -428,6 +633,7 @@ fn handle_announce_request(
         info_hash: request.info_hash,
         complete: torrent_data.num_seeders,
         incomplete: torrent_data.num_leechers,
+        downloaded: torrent_data.num_completed,
         announce_interval: config.protocol.peer_announce_interval,
     });
 
@@ -464,7 +670,7 @@ fn handle_scrape_request(
         if let Some(torrent_data) = torrent_map.get(&info_hash) {
             let stats = ScrapeStatistics {
                 complete: torrent_data.num_seeders,
-                downloaded: 0, // No implementation planned
+                downloaded: torrent_data.num_completed,
                 incomplete: torrent_data.num_leechers,
             };
 
@@ -474,3 +680,67 @@ fn handle_scrape_request(
 
     out_messages.push((meta, OutMessage::ScrapeResponse(out_message)));
 }
+
+#[cfg(test)]
+mod tests {
+    use aquatic_common::CanonicalSocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn test_num_completed() {
+        let config = Config::default();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut torrent_maps = TorrentMaps::default();
+        let mut out_messages = Vec::new();
+        let info_hash = InfoHash([0; 20]);
+
+        let meta = ConnectionMeta {
+            out_message_consumer_id: ConsumerId(0),
+            connection_id: ConnectionId(0),
+            peer_addr: CanonicalSocketAddr::new("192.0.2.1:1000".parse().unwrap()),
+            pending_scrape_id: None,
+        };
+
+        let mut announce = |torrent_maps: &mut TorrentMaps, peer_id: u8| {
+            let request = AnnounceRequest {
+                action: AnnounceAction,
+                info_hash,
+                peer_id: PeerId([peer_id; 20]),
+                bytes_left: Some(0),
+                event: Some(AnnounceEvent::Completed),
+                offers: None,
+                numwant: None,
+                answer: None,
+                to_peer_id: None,
+                offer_id: None,
+            };
+
+            handle_announce_request(
+                &config,
+                &mut rng,
+                torrent_maps,
+                &mut out_messages,
+                ValidUntil::new(60),
+                meta,
+                request,
+            );
+
+            torrent_maps.ipv4.get(&info_hash).unwrap().num_completed
+        };
+
+        assert_eq!(announce(&mut torrent_maps, 1), 1);
+        // Peer is still seeding
+        assert_eq!(announce(&mut torrent_maps, 1), 1);
+        assert_eq!(announce(&mut torrent_maps, 2), 2);
+
+        // Removed peers are not remembered, so completion is counted again
+        torrent_maps
+            .ipv4
+            .get_mut(&info_hash)
+            .unwrap()
+            .remove_peer(PeerId([1; 20]));
+
+        assert_eq!(announce(&mut torrent_maps, 1), 3);
+    }
+}
@@ -146,6 +146,7 @@ mod tests {
                 info_hash: Arbitrary::arbitrary(g),
                 complete: Arbitrary::arbitrary(g),
                 incomplete: Arbitrary::arbitrary(g),
+                downloaded: Arbitrary::arbitrary(g),
                 announce_interval: Arbitrary::arbitrary(g),
             }
         }
@@ -9,6 +9,9 @@ pub struct AnnounceResponse {
     /// Client checks if this is null, not clear why
     pub complete: usize,
     pub incomplete: usize,
+    /// Number of completed downloads
+    #[serde(default)]
+    pub downloaded: usize,
     #[serde(rename = "interval")]
     pub announce_interval: usize, // Default 2 min probably
 }